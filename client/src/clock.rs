@@ -0,0 +1,77 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use cashu_escrow_common::error::EscrowError;
+use nostr_sdk::Timestamp;
+use reqwest::header::{ACCEPT, DATE};
+
+/// Largest difference between the escrow start time of the coordinator and the current time.
+///
+/// The escrow token is refundable from the contract expiry on, a skewed start time hints at a clock the refund
+/// locktime can't be trusted with.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// Clock skews beyond this are logged as warning, though still accepted up to [`MAX_CLOCK_SKEW_SECS`].
+pub const CLOCK_SKEW_WARNING_SECS: u64 = 60;
+
+/// Tells the current time the escrow start time of the coordinator is checked against.
+#[async_trait]
+pub trait TimeSource: Send + Sync {
+    async fn now(&self) -> Result<Timestamp, EscrowError>;
+}
+
+/// The clock of this machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl TimeSource for SystemClock {
+    async fn now(&self) -> Result<Timestamp, EscrowError> {
+        Ok(Timestamp::now())
+    }
+}
+
+/// The time a relay reports in the `Date` header of its NIP-11 information document, for machines whose own clock
+/// can't be trusted.
+#[derive(Debug, Clone)]
+pub struct RelayClock {
+    url: String,
+    http_client: reqwest::Client,
+}
+
+impl RelayClock {
+    /// Asks the relay at the websocket `relay_url`, e.g. `wss://relay.damus.io`.
+    pub fn new(relay_url: &str) -> Self {
+        let url = match relay_url.split_once("://") {
+            Some(("wss", rest)) => format!("https://{}", rest),
+            Some(("ws", rest)) => format!("http://{}", rest),
+            _ => relay_url.to_string(),
+        };
+        Self {
+            url,
+            http_client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TimeSource for RelayClock {
+    async fn now(&self) -> Result<Timestamp, EscrowError> {
+        let response = self
+            .http_client
+            .get(&self.url)
+            .header(ACCEPT, "application/nostr+json")
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to ask {} for the time: {}", self.url, e))?;
+        let date = response
+            .headers()
+            .get(DATE)
+            .and_then(|date| date.to_str().ok())
+            .ok_or_else(|| anyhow!("{} reported no time", self.url))?;
+        let time = chrono::DateTime::parse_from_rfc2822(date)
+            .map_err(|e| anyhow!("Invalid time {} of {}: {}", date, self.url, e))?;
+        let time = u64::try_from(time.timestamp())
+            .map_err(|_| anyhow!("Invalid time {} of {}", date, self.url))?;
+        Ok(Timestamp::from(time))
+    }
+}
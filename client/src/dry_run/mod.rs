@@ -0,0 +1,408 @@
+//! In-memory doubles of the nostr transport and the ecash wallet, to run a full trade without relays or a mint.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::*;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use cashu_escrow_common::{
+    envelope::CoordinatorMessage,
+    error::EscrowError,
+    model::{
+        ContractAccepted, ContractSubmission, EscrowRegistration, TradeContract, TradeReceipt,
+        TradeReceiptContent, DEFAULT_REQUIRED_SIGNATURES,
+    },
+    nostr::EscrowTransport,
+};
+use cdk::{
+    mint_url::MintUrl,
+    nuts::{BlindedMessage, CurrencyUnit, Id, Proof, SecretKey, SigFlag, Token},
+    secret::Secret,
+    Amount,
+};
+use ecash::{ClientEcashWallet, EscrowWallet, RedeemOutputs};
+use escrow_client::{InitEscrowClient, TradeMode};
+use nostr_sdk::{hashes::hex::DisplayHex, Keys, PublicKey as NostrPubkey, Timestamp};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+const DRY_RUN_MINT_URL: &str = "https://dry-run.mint.invalid";
+const DRY_RUN_KEYSET_ID: &str = "009a1f293253e41e";
+const DRY_RUN_TIMEOUT_SECS: u64 = 5;
+
+type Message = (NostrPubkey, String);
+
+/// Delivers the messages between the [`MockTransport`]s created from it.
+#[derive(Clone, Default)]
+pub struct MockNetwork {
+    inboxes: Arc<Mutex<HashMap<NostrPubkey, UnboundedSender<Message>>>>,
+}
+
+impl MockNetwork {
+    /// Creates the transport of `keys`, receiving every message sent to its public key from now on.
+    pub fn transport(&self, keys: Keys) -> MockTransport {
+        let (sender, receiver) = unbounded_channel();
+        self.inboxes
+            .lock()
+            .expect("Mock network lock poisoned")
+            .insert(keys.public_key(), sender);
+        MockTransport {
+            keys,
+            network: self.clone(),
+            receiver,
+            pending_messages: VecDeque::new(),
+        }
+    }
+}
+
+pub struct MockTransport {
+    keys: Keys,
+    network: MockNetwork,
+    receiver: UnboundedReceiver<Message>,
+    pending_messages: VecDeque<Message>,
+}
+
+#[async_trait]
+impl EscrowTransport for MockTransport {
+    fn public_key(&self) -> NostrPubkey {
+        self.keys.public_key()
+    }
+
+    fn accept_contract(&self, contract: &TradeContract) -> Result<ContractAccepted, EscrowError> {
+        ContractAccepted::sign(contract, &self.keys)
+    }
+
+    fn sign_trade_receipt(
+        &self,
+        content: TradeReceiptContent,
+    ) -> Result<TradeReceipt, EscrowError> {
+        TradeReceipt::sign(content, &self.keys)
+    }
+
+    async fn wait_for_connection(
+        &self,
+        _min_relays: usize,
+        _timeout: Duration,
+    ) -> Result<(), EscrowError> {
+        Ok(())
+    }
+
+    /// Delivers the message directly, as if accepted by a single relay.
+    async fn send_to(&self, receiver: NostrPubkey, message: &str) -> Result<usize, EscrowError> {
+        let inboxes = self
+            .network
+            .inboxes
+            .lock()
+            .expect("Mock network lock poisoned");
+        let inbox = inboxes
+            .get(&receiver)
+            .ok_or_else(|| anyhow!("Unknown receiver {}", receiver))?;
+        inbox
+            .send((self.keys.public_key(), message.to_string()))
+            .map_err(|_| EscrowError::RelayDisconnected)?;
+        Ok(1)
+    }
+
+    async fn receive_from_any(
+        &mut self,
+        senders: &[NostrPubkey],
+        timeout: Option<Duration>,
+    ) -> Result<Message, EscrowError> {
+        let Some(&first_sender) = senders.first() else {
+            return Err(anyhow!("No senders to wait for messages of").into());
+        };
+        if let Some(index) = self
+            .pending_messages
+            .iter()
+            .position(|(from, _)| senders.contains(from))
+        {
+            return Ok(self.pending_messages.remove(index).expect("Index is valid"));
+        }
+        let mut events_seen = 0;
+        let receive_future = async {
+            loop {
+                match self.receiver.recv().await {
+                    Some((from, message)) if senders.contains(&from) => break Ok((from, message)),
+                    Some(message) => {
+                        events_seen += 1;
+                        self.pending_messages.push_back(message);
+                    }
+                    None => break Err(EscrowError::RelayDisconnected),
+                }
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, receive_future)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(EscrowError::Timeout {
+                        from: first_sender,
+                        waited: timeout,
+                        events_seen,
+                    })
+                }),
+            None => receive_future.await,
+        }
+    }
+
+    fn requeue_message(&mut self, sender: NostrPubkey, message: String) {
+        self.pending_messages.push_front((sender, message));
+    }
+}
+
+/// Wallet creating unbacked escrow tokens, signing them with a real trade key.
+pub struct MockWallet {
+    secret: SecretKey,
+    trade_pubkey: String,
+    balance: Amount,
+}
+
+impl MockWallet {
+    pub fn new(balance: Amount) -> Self {
+        let secret = SecretKey::generate();
+        let trade_pubkey = secret.public_key().to_string();
+        Self {
+            secret,
+            trade_pubkey,
+            balance,
+        }
+    }
+}
+
+#[async_trait]
+impl EscrowWallet for MockWallet {
+    fn trade_pubkey(&self) -> &str {
+        &self.trade_pubkey
+    }
+
+    async fn ensure_escrow_funds(&self, contract: &TradeContract) -> Result<(), EscrowError> {
+        let need = Amount::from(contract.buyer_total_sat()?);
+        if self.balance < need {
+            return Err(EscrowError::InsufficientFunds {
+                have: self.balance,
+                need,
+            });
+        }
+        Ok(())
+    }
+
+    async fn create_escrow_token(
+        &self,
+        contract: &TradeContract,
+        _escrow_registrations: &[EscrowRegistration],
+    ) -> Result<Token, EscrowError> {
+        let milestone_tokens = contract
+            .milestone_amounts()?
+            .into_iter()
+            .map(|milestone_amount| mock_token(contract, milestone_amount.into()))
+            .collect::<Result<Vec<_>, EscrowError>>()?;
+        ClientEcashWallet::join_milestone_tokens(&milestone_tokens)
+    }
+
+    async fn create_coordinator_fee_token(
+        &self,
+        contract: &TradeContract,
+        _escrow_registration: &EscrowRegistration,
+    ) -> Result<Token, EscrowError> {
+        mock_token(contract, contract.coordinator_fee_sat)
+    }
+
+    async fn validate_escrow_token(
+        &self,
+        escrow_token: &Token,
+        contract: &TradeContract,
+        _escrow_registrations: &[EscrowRegistration],
+    ) -> Result<(), EscrowError> {
+        let expected = Amount::from(contract.trade_amount_sat);
+        let actual = escrow_token.value()?;
+        if actual != expected {
+            return Err(EscrowError::AmountMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    fn sign_escrow_token(&self, escrow_token: &Token) -> Result<Vec<String>, EscrowError> {
+        escrow_token
+            .proofs()
+            .values()
+            .flatten()
+            .map(|proof| Ok(self.secret.sign(&proof.secret.to_bytes())?.to_string()))
+            .collect()
+    }
+
+    async fn redeem_escrow_token(&self, escrow_token: &Token) -> Result<Amount, EscrowError> {
+        Ok(escrow_token.value()?)
+    }
+
+    async fn create_redeem_outputs(
+        &self,
+        escrow_token: &Token,
+    ) -> Result<RedeemOutputs, EscrowError> {
+        RedeemOutputs::random(Id::from_str(DRY_RUN_KEYSET_ID)?, escrow_token.value()?)
+    }
+
+    fn sign_redeem_outputs(&self, outputs: &[BlindedMessage]) -> Result<Vec<String>, EscrowError> {
+        RedeemOutputs::sign(outputs, &self.secret)
+    }
+
+    async fn redeem_escrow_token_into(
+        &self,
+        escrow_token: &Token,
+        redeem_outputs: &RedeemOutputs,
+    ) -> Result<Amount, EscrowError> {
+        let amount = redeem_outputs.amount()?;
+        if amount > escrow_token.value()? {
+            return Err(anyhow!("Outputs of {} sat exceed the escrow token", amount).into());
+        }
+        Ok(amount)
+    }
+}
+
+/// A token of unbacked proofs worth `amount_sat`.
+fn mock_token(contract: &TradeContract, amount_sat: u64) -> Result<Token, EscrowError> {
+    let keyset_id = Id::from_str(DRY_RUN_KEYSET_ID)?;
+    let proofs = Amount::from(amount_sat)
+        .split()
+        .into_iter()
+        .map(|amount| {
+            Proof::new(
+                amount,
+                keyset_id,
+                Secret::generate(),
+                SecretKey::generate().public_key(),
+            )
+        })
+        .collect();
+    Ok(Token::new(
+        contract.mint_url.clone(),
+        proofs,
+        Some(contract.trade_description.clone()),
+        Some(contract.unit),
+    ))
+}
+
+/// Registers the contract submitted by both traders like the coordinator does, over any transport, e.g. a relay in
+/// tests.
+pub async fn run_mock_coordinator<T: EscrowTransport>(
+    mut transport: T,
+    contract: &TradeContract,
+) -> Result<(), EscrowError> {
+    let mut submissions = Vec::new();
+    for trader in [contract.npubkey_buyer, contract.npubkey_seller] {
+        let submission: ContractSubmission = transport
+            .receive_payload(trader, Some(Duration::from_secs(DRY_RUN_TIMEOUT_SECS)))
+            .await?;
+        submission
+            .acceptance
+            .verify(&submission.contract, &trader)?;
+        submissions.push((trader, submission));
+    }
+    let coordinator_secret = SecretKey::generate();
+    let escrow_start_time = Timestamp::now();
+    for (trader, submission) in submissions {
+        let registration = EscrowRegistration::new(
+            submission.contract.escrow_id()?.to_lower_hex_string(),
+            coordinator_secret.public_key(),
+            escrow_start_time,
+            submission.contract.coordinator_fee_sat,
+            submission.nonce,
+        );
+        transport
+            .send_coordinator_message(trader, &CoordinatorMessage::Registration(registration))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Runs a full trade of a buyer and a seller in-process, returning the amount the seller redeemed.
+pub async fn run_dry_run_trade(trade_amount_sat: u64) -> Result<Amount, EscrowError> {
+    let network = MockNetwork::default();
+    let buyer_keys = Keys::generate();
+    let seller_keys = Keys::generate();
+    let coordinator_keys = Keys::generate();
+    let buyer_wallet = MockWallet::new(Amount::from(trade_amount_sat));
+    let seller_wallet = MockWallet::new(Amount::ZERO);
+
+    let contract = TradeContract {
+        trade_description: "Dry run trade".to_string(),
+        trade_amount_sat,
+        coordinator_fee_sat: 0,
+        unit: CurrencyUnit::Sat,
+        mint_url: MintUrl::from_str(DRY_RUN_MINT_URL)?,
+        npubkey_seller: seller_keys.public_key(),
+        npubkey_buyer: buyer_keys.public_key(),
+        npubkey_coordinator: coordinator_keys.public_key(),
+        expiry: Timestamp::now() + Duration::from_secs(60 * 60),
+        seller_ecash_public_key: seller_wallet.trade_pubkey().to_string(),
+        buyer_ecash_public_key: buyer_wallet.trade_pubkey().to_string(),
+        buyer_refund_public_key: None,
+        milestones: Vec::new(),
+        oracle_pubkey: None,
+        required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+        sig_flag: SigFlag::SigAll,
+        additional_coordinators: Vec::new(),
+        coordinator_threshold: None,
+        fiat_price: None,
+        digital_goods: false,
+    };
+
+    let buyer = InitEscrowClient::new(
+        network.transport(buyer_keys.clone()),
+        buyer_wallet,
+        contract.clone(),
+        TradeMode::Buyer,
+    )
+    .with_message_timeout_secs(DRY_RUN_TIMEOUT_SECS);
+    let seller = InitEscrowClient::new(
+        network.transport(seller_keys.clone()),
+        seller_wallet,
+        contract.clone(),
+        TradeMode::Seller,
+    )
+    .with_message_timeout_secs(DRY_RUN_TIMEOUT_SECS);
+    let coordinator_transport = network.transport(coordinator_keys.clone());
+
+    let buyer_trade = async {
+        buyer
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?
+            .do_your_trade_duties()
+            .await
+    };
+    let seller_trade = async {
+        seller
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?
+            .do_your_trade_duties()
+            .await?
+            .redeem_escrow_token()
+            .await
+    };
+    let (_, _, redeemed_amount) = tokio::try_join!(
+        run_mock_coordinator(coordinator_transport, &contract),
+        buyer_trade,
+        seller_trade
+    )?;
+    Ok(redeemed_amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dry_run_trade_pays_the_seller() {
+        let redeemed = run_dry_run_trade(5000).await.unwrap();
+
+        assert_eq!(redeemed, Amount::from(5000));
+    }
+}
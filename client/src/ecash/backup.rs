@@ -0,0 +1,96 @@
+use std::{fs, path::Path};
+
+use super::*;
+
+use nostr_sdk::{
+    nips::nip44::{self, Version},
+    Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+/// Longest part of a backup encrypted at once, below the NIP-44 plaintext limit of 65535 bytes.
+const MAX_BACKUP_CHUNK_LEN: usize = 60_000;
+
+/// The unspent proofs of a wallet, as one token per mint which any cashu wallet can receive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProofBackup {
+    pub created_at: Timestamp,
+    pub tokens: Vec<String>,
+}
+
+impl ClientEcashWallet {
+    /// Writes the unspent proofs of all accepted mints to `path`, encrypted to the trade key of the wallet.
+    ///
+    /// Read the backup with [`Self::read_backup`] and the trade key. Together with the refund path of the escrow
+    /// token, the buyer recovers the funds even if the wallet state is lost during the trade.
+    pub async fn export_backup(&self, path: &Path) -> Result<ProofBackup, EscrowError> {
+        let mut tokens = Vec::new();
+        for (mint_url, mint_wallet) in &self.mint_wallets {
+            let proofs = mint_wallet.get_proofs().await?;
+            if proofs.is_empty() {
+                continue;
+            }
+            let token = Token::new(
+                mint_url.clone(),
+                proofs,
+                Some("cashu-escrow-kit backup".to_string()),
+                Some(mint_wallet.unit),
+            );
+            tokens.push(token.to_string());
+        }
+        let backup = ProofBackup {
+            created_at: Timestamp::now(),
+            tokens,
+        };
+
+        let (secret_key, public_key) = backup_keys(&self._secret)?;
+        let plaintext = serde_json::to_string(&backup)?;
+        let mut chunks = Vec::new();
+        let mut rest = plaintext.as_str();
+        while !rest.is_empty() {
+            let mut split = rest.len().min(MAX_BACKUP_CHUNK_LEN);
+            while !rest.is_char_boundary(split) {
+                split -= 1;
+            }
+            let (chunk, remainder) = rest.split_at(split);
+            chunks.push(
+                nip44::encrypt(&secret_key, &public_key, chunk, Version::V2)
+                    .map_err(|e| anyhow!("Failed to encrypt the wallet backup: {}", e))?,
+            );
+            rest = remainder;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, chunks.join("\n"))?;
+        debug!(
+            "Saved the backup of {} tokens to {}",
+            backup.tokens.len(),
+            path.display()
+        );
+        Ok(backup)
+    }
+
+    /// Decrypts the backup at `path` written by a wallet of `trade_secret`.
+    pub fn read_backup(path: &Path, trade_secret: &SecretKey) -> Result<ProofBackup, EscrowError> {
+        let (secret_key, public_key) = backup_keys(trade_secret)?;
+        let mut plaintext = String::new();
+        for chunk in fs::read_to_string(path)?.lines() {
+            plaintext.push_str(
+                &nip44::decrypt(&secret_key, &public_key, chunk)
+                    .map_err(|e| anyhow!("Failed to decrypt the wallet backup: {}", e))?,
+            );
+        }
+        Ok(serde_json::from_str(&plaintext)?)
+    }
+}
+
+/// The trade key as nostr keys, the backup is encrypted to itself.
+fn backup_keys(
+    trade_secret: &SecretKey,
+) -> Result<(nostr_sdk::SecretKey, nostr_sdk::PublicKey), EscrowError> {
+    let keys = NostrKeys::new(nostr_sdk::SecretKey::from_slice(
+        trade_secret.as_secret_bytes(),
+    )?);
+    Ok((keys.secret_key()?.clone(), keys.public_key()))
+}
@@ -1,81 +1,749 @@
+mod backup;
+mod outputs;
+mod payout;
+mod selection;
+
 use super::*;
 
-use cashu_escrow_common::model::{EscrowRegistration, TradeContract};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bip39::Mnemonic;
+use cashu_escrow_common::{
+    error::EscrowError,
+    model::{milestone_proofs, EscrowRegistration, TradeContract},
+};
 use cdk::{
-    amount::SplitTarget,
+    amount::{Amount, SplitTarget},
     cdk_database::WalletMemoryDatabase,
-    nuts::{Conditions, CurrencyUnit, PublicKey, SecretKey, SigFlag, SpendingConditions, Token},
-    secp256k1::rand::Rng,
+    mint_url::MintUrl,
+    nuts::{
+        BlindedMessage, Conditions, CurrencyUnit, KeySetInfo, P2PKWitness, Proofs, PublicKey,
+        SecretKey, SpendingConditions, State, Token, Witness,
+    },
+    secp256k1::{rand::Rng, schnorr::Signature},
     wallet::{SendKind, Wallet},
 };
+use nostr_sdk::{hashes::hex::DisplayHex, Keys as NostrKeys};
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map::Entry, HashMap};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+pub use backup::ProofBackup;
+pub use outputs::RedeemOutputs;
+pub use payout::LightningPayout;
+pub use selection::ProofSelection;
+
+const TRADE_KEY_DERIVATION_TAG: &[u8] = b"cashu-escrow-kit/trade-key";
+/// Lightning fee reserve a mint typically holds back when melting, in percent of the melted amount.
+const LIGHTNING_FEE_RESERVE_PERCENT: u64 = 1;
+const MIN_LIGHTNING_FEE_RESERVE_SAT: u64 = 2;
+/// The seller is warned if redeeming the escrow token would cost more than this share of the trade amount.
+const REDEEM_FEE_WARNING_PERCENT: u64 = 2;
+
+/// The ecash operations of an escrow trade.
+///
+/// Implemented by [`ClientEcashWallet`], test doubles can replace it to run trades without a mint.
+#[async_trait]
+pub trait EscrowWallet: Send + Sync {
+    /// The public key the escrow token is locked to for this trader.
+    fn trade_pubkey(&self) -> &str;
+
+    /// Fails with [`EscrowError::InsufficientFunds`] if the wallet can't fund the escrow of the contract.
+    async fn ensure_escrow_funds(&self, contract: &TradeContract) -> Result<(), EscrowError>;
+
+    /// Creates the escrow token, locked to the escrow keys of the coordinator registrations in the order of the
+    /// contract coordinators.
+    async fn create_escrow_token(
+        &self,
+        contract: &TradeContract,
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<Token, EscrowError>;
+
+    /// Creates the coordinator fee token, locked to the coordinator escrow pubkey.
+    async fn create_coordinator_fee_token(
+        &self,
+        contract: &TradeContract,
+        escrow_registration: &EscrowRegistration,
+    ) -> Result<Token, EscrowError>;
+
+    /// Checks that the escrow token is issued by an active keyset of the mint, locked to the seller key and the registered
+    /// coordinator escrow keys on the conditions of the contract, and worth exactly the trade amount.
+    async fn validate_escrow_token(
+        &self,
+        escrow_token: &Token,
+        contract: &TradeContract,
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<(), EscrowError>;
+
+    /// Signs the secret of every escrow token proof with the trade key, in the order of the proofs.
+    fn sign_escrow_token(&self, escrow_token: &Token) -> Result<Vec<String>, EscrowError>;
+
+    /// Swaps the escrow token into unlocked funds of this wallet, returning the received amount.
+    async fn redeem_escrow_token(&self, escrow_token: &Token) -> Result<Amount, EscrowError>;
+
+    /// Creates the outputs to redeem the escrow token into, worth its value after the input fee of the mint.
+    ///
+    /// An escrow token locked with `SIG_ALL` is redeemed with [`Self::redeem_escrow_token_into`], into outputs the
+    /// co-signers signed along with the proofs.
+    async fn create_redeem_outputs(
+        &self,
+        escrow_token: &Token,
+    ) -> Result<RedeemOutputs, EscrowError>;
+
+    /// Signs the blinded secret of every output with the trade key, in the order of the outputs.
+    fn sign_redeem_outputs(&self, outputs: &[BlindedMessage]) -> Result<Vec<String>, EscrowError>;
+
+    /// Swaps the escrow token into the signed `redeem_outputs`, returning the received amount.
+    async fn redeem_escrow_token_into(
+        &self,
+        escrow_token: &Token,
+        redeem_outputs: &RedeemOutputs,
+    ) -> Result<Amount, EscrowError>;
+
+    /// Makes the funds reserved for the escrow of `escrow_id_hex` spendable again, e.g. once the trade is cancelled.
+    async fn release_escrow_funds(&self, _escrow_id_hex: &str) -> Result<(), EscrowError> {
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct ClientEcashWallet {
     _secret: SecretKey,
+    /// Wallet of the default mint.
     pub wallet: Wallet,
-    pub trade_pubkey: String,
+    /// Wallets of all accepted mints, including the default mint.
+    mint_wallets: HashMap<MintUrl, Wallet>,
+    trade_pubkey: String,
+    proof_selection: ProofSelection,
+    /// Where to back up the proofs before locking them into an escrow token.
+    backup_file: Option<PathBuf>,
+    /// The proofs selected for the escrow token of each escrow id and not swapped yet, reserved in the wallet store so
+    /// concurrent trades can't select them as well.
+    reserved_proofs: Mutex<HashMap<String, Vec<(MintUrl, PublicKey)>>>,
+    /// Held while selecting and reserving proofs.
+    selection_lock: tokio::sync::Mutex<()>,
 }
 
 impl ClientEcashWallet {
-    pub async fn new(mint_url: &str) -> anyhow::Result<Self> {
-        let localstore = WalletMemoryDatabase::default();
-        let _secret = SecretKey::generate();
-        let trade_pubkey: String = _secret.public_key().to_string();
+    /// Creates a wallet for the default `mint_url` and every further accepted mint.
+    ///
+    /// Escrow tokens of other mints are rejected, escrow tokens are locked to the key `trade_secret`.
+    pub async fn new(
+        mint_url: &str,
+        accepted_mint_urls: &[String],
+        trade_secret: SecretKey,
+    ) -> Result<Self, EscrowError> {
         let seed = rand::thread_rng().gen::<[u8; 32]>();
+        Self::from_seed(mint_url, accepted_mint_urls, trade_secret, &seed)
+    }
+
+    /// Creates the wallets from the bip39 `mnemonic`, so its funds survive restarts of the client.
+    ///
+    /// The trade key is derived from the seed as well, see [`Self::trade_secret_from_mnemonic`]. The wallets start
+    /// empty, [`Self::restore`] recovers the proofs of earlier runs from the mints.
+    pub async fn from_mnemonic(
+        mint_url: &str,
+        accepted_mint_urls: &[String],
+        mnemonic: &str,
+    ) -> Result<Self, EscrowError> {
+        let seed = mnemonic_seed(mnemonic)?;
+        let trade_secret = Self::trade_secret_from_seed(&seed)?;
+        Self::from_seed(mint_url, accepted_mint_urls, trade_secret, &seed)
+    }
+
+    fn from_seed(
+        mint_url: &str,
+        accepted_mint_urls: &[String],
+        trade_secret: SecretKey,
+        seed: &[u8],
+    ) -> Result<Self, EscrowError> {
+        let localstore = Arc::new(WalletMemoryDatabase::default());
+        let _secret = trade_secret;
+        let trade_pubkey: String = _secret.public_key().to_string();
         info!("Trade ecash pubkey: {}", trade_pubkey);
 
-        let wallet = Wallet::new(
-            mint_url,
-            CurrencyUnit::Sat,
-            Arc::new(localstore),
-            &seed,
-            None,
-        )?;
+        let wallet = Wallet::new(mint_url, CurrencyUnit::Sat, localstore.clone(), seed, None)?;
+        let mut mint_wallets = HashMap::from([(wallet.mint_url.clone(), wallet.clone())]);
+        for accepted_mint_url in accepted_mint_urls {
+            let mint_wallet = Wallet::new(
+                accepted_mint_url,
+                CurrencyUnit::Sat,
+                localstore.clone(),
+                seed,
+                None,
+            )?;
+            mint_wallets.insert(mint_wallet.mint_url.clone(), mint_wallet);
+        }
 
         Ok(Self {
             _secret,
             wallet,
+            mint_wallets,
             trade_pubkey,
+            proof_selection: ProofSelection::default(),
+            backup_file: None,
+            reserved_proofs: Mutex::default(),
+            selection_lock: tokio::sync::Mutex::default(),
         })
     }
 
+    /// Recovers the unspent proofs of the wallet seed from every accepted mint, returning the restored amount.
+    pub async fn restore(&self) -> Result<Amount, EscrowError> {
+        let mut restored = Amount::ZERO;
+        for (mint_url, mint_wallet) in &self.mint_wallets {
+            let amount = mint_wallet.restore().await?;
+            debug!("Restored {} sat of mint {}", amount, mint_url);
+            restored = restored.checked_add(amount).ok_or_else(|| {
+                EscrowError::AmountOverflow(format!("restored amount of {}", mint_url))
+            })?;
+        }
+        Ok(restored)
+    }
+
+    /// Picks the proofs swapped into the escrow token with `proof_selection`.
+    pub fn with_proof_selection(mut self, proof_selection: ProofSelection) -> Self {
+        self.proof_selection = proof_selection;
+        self
+    }
+
+    /// Exports an encrypted backup of the proofs to `backup_file` before every escrow token is created, see
+    /// [`Self::export_backup`].
+    pub fn with_backup_file(mut self, backup_file: Option<PathBuf>) -> Self {
+        self.backup_file = backup_file;
+        self
+    }
+
+    /// Derives the trade key from the seed of the bip39 `mnemonic`, so the trade pubkey stays the same across runs.
+    pub fn trade_secret_from_mnemonic(mnemonic: &str) -> Result<SecretKey, EscrowError> {
+        Self::trade_secret_from_seed(&mnemonic_seed(mnemonic)?)
+    }
+
+    fn trade_secret_from_seed(seed: &[u8]) -> Result<SecretKey, EscrowError> {
+        let mut hasher = Sha256::new();
+        hasher.update(TRADE_KEY_DERIVATION_TAG);
+        hasher.update(seed);
+        Ok(SecretKey::from_slice(&hasher.finalize())?)
+    }
+
+    /// Derives the trade key from the nostr identity, so the trade pubkey stays the same across runs.
+    pub fn trade_secret_from_nostr_keys(nostr_keys: &NostrKeys) -> Result<SecretKey, EscrowError> {
+        let mut hasher = Sha256::new();
+        hasher.update(TRADE_KEY_DERIVATION_TAG);
+        hasher.update(nostr_keys.secret_key()?.as_secret_bytes());
+        Ok(SecretKey::from_slice(&hasher.finalize())?)
+    }
+
+    /// The default mint.
+    pub fn mint_url(&self) -> &MintUrl {
+        &self.wallet.mint_url
+    }
+
+    /// Hex of the public trade key, which escrow tokens are locked to in their P2PK spending conditions.
+    pub fn spending_pubkey_hex(&self) -> &str {
+        &self.trade_pubkey
+    }
+
+    pub fn accepted_mints(&self) -> Vec<&MintUrl> {
+        self.mint_wallets.keys().collect()
+    }
+
+    /// The wallet of `mint_url`, failing if the mint is not accepted.
+    pub fn mint_wallet(&self, mint_url: &MintUrl) -> Result<&Wallet, EscrowError> {
+        self.mint_wallets
+            .get(mint_url)
+            .ok_or_else(|| anyhow!("Mint {} is not accepted", mint_url).into())
+    }
+
+    pub async fn balance(&self) -> Result<Amount, EscrowError> {
+        Ok(self.wallet.total_balance().await?)
+    }
+
+    /// Asks the mint for the state of every escrow token proof, returned with the proof amounts in the order of the proofs.
+    pub async fn check_token_state(
+        &self,
+        escrow_token: &Token,
+    ) -> Result<Vec<(Amount, State)>, EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        let proof_states = self
+            .mint_wallet(&mint_url)?
+            .check_proofs_spent(proofs.clone())
+            .await?;
+        proofs
+            .iter()
+            .map(|proof| {
+                let y = proof.y()?;
+                let proof_state = proof_states
+                    .iter()
+                    .find(|proof_state| proof_state.y == y)
+                    .ok_or_else(|| anyhow!("Mint reported no state for proof {}", y))?;
+                Ok((proof.amount, proof_state.state))
+            })
+            .collect()
+    }
+
+    /// Asks the mint `mint_url` for the keysets it currently signs with.
+    pub async fn active_keysets(&self, mint_url: &MintUrl) -> Result<Vec<KeySetInfo>, EscrowError> {
+        let keysets = self.mint_wallet(mint_url)?.get_mint_keysets().await?;
+        Ok(keysets.into_iter().filter(|keyset| keyset.active).collect())
+    }
+
+    /// The seller key and the buyer and coordinator escrow keys, any `required_signatures` of them spend the token.
     fn assemble_escrow_conditions(
         contract: &TradeContract,
-        escrow_registration: &EscrowRegistration,
-    ) -> anyhow::Result<SpendingConditions> {
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<SpendingConditions, EscrowError> {
+        let coordinator_count = contract.coordinators().len();
+        if escrow_registrations.len() != coordinator_count {
+            return Err(anyhow!(
+                "Got {} coordinator registrations for the {} coordinators of the contract",
+                escrow_registrations.len(),
+                coordinator_count
+            )
+            .into());
+        }
         let seller_pubkey = PublicKey::from_str(&contract.seller_ecash_public_key)?;
         let buyer_pubkey = PublicKey::from_str(&contract.buyer_ecash_public_key)?;
-        let coordinator_escrow_pubkey = escrow_registration.coordinator_escrow_pubkey;
-        let start_timestamp = escrow_registration.escrow_start_time;
+        let refund_pubkey = PublicKey::from_str(contract.buyer_refund_public_key())?;
+        let mut pubkeys = vec![buyer_pubkey];
+        pubkeys.extend(
+            escrow_registrations
+                .iter()
+                .map(|registration| registration.coordinator_escrow_pubkey),
+        );
 
-        let locktime = start_timestamp.as_u64() + contract.time_limit;
+        // after the contract expiry the buyer can reclaim the token alone with the refund key
+        let locktime = contract.expiry.as_u64();
 
         let spending_conditions = SpendingConditions::new_p2pk(
             seller_pubkey,
             Some(Conditions::new(
                 Some(locktime),
-                Some(vec![buyer_pubkey, coordinator_escrow_pubkey]),
-                Some(vec![buyer_pubkey]),
-                Some(2),
-                Some(SigFlag::SigAll),
+                Some(pubkeys),
+                Some(vec![refund_pubkey]),
+                Some(contract.required_signatures),
+                Some(contract.sig_flag),
             )?),
         );
         Ok(spending_conditions)
     }
 
-    pub async fn create_escrow_token(
+    /// Checks that every proof is locked to the seller key and the escrow keys of the coordinator registrations, on
+    /// exactly the conditions agreed in the contract.
+    fn verify_escrow_conditions(
+        proofs: &Proofs,
+        contract: &TradeContract,
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<(), EscrowError> {
+        let expected = Self::assemble_escrow_conditions(contract, escrow_registrations)?;
+        let seller_pubkey = PublicKey::from_str(&contract.seller_ecash_public_key)?;
+        for (index, proof) in proofs.iter().enumerate() {
+            let mismatch = |reason: String| EscrowError::ConditionsMismatch { index, reason };
+            let proof_conditions = SpendingConditions::try_from(&proof.secret)
+                .map_err(|e| mismatch(format!("no spending conditions: {}", e)))?;
+            let SpendingConditions::P2PKConditions { data, conditions } = &proof_conditions else {
+                return Err(mismatch("locked by a hash instead of keys".to_string()));
+            };
+            if *data != seller_pubkey {
+                return Err(mismatch(format!(
+                    "locked to {} instead of the seller key {}",
+                    data, seller_pubkey
+                )));
+            }
+            let pubkeys = conditions
+                .as_ref()
+                .and_then(|conditions| conditions.pubkeys.as_deref())
+                .unwrap_or_default();
+            let sig_flag = conditions
+                .as_ref()
+                .map(|conditions| conditions.sig_flag)
+                .unwrap_or_default();
+            if sig_flag != contract.sig_flag {
+                return Err(mismatch(format!(
+                    "signature flag {} instead of the agreed {}",
+                    sig_flag, contract.sig_flag
+                )));
+            }
+            if let Some(registration) = escrow_registrations
+                .iter()
+                .find(|registration| !pubkeys.contains(&registration.coordinator_escrow_pubkey))
+            {
+                return Err(mismatch(format!(
+                    "missing the coordinator escrow key {}",
+                    registration.coordinator_escrow_pubkey
+                )));
+            }
+            // the buyer key, the required signatures and the refund
+            if proof_conditions != expected {
+                return Err(mismatch(
+                    "other conditions than agreed in the contract".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the release signatures of `signer` to the escrow token proofs after verifying them.
+    pub fn add_release_signatures(
+        escrow_token: &Token,
+        signer: &PublicKey,
+        signatures: &[String],
+    ) -> Result<Token, EscrowError> {
+        let (mint_url, mut proofs) = Self::escrow_proofs(escrow_token)?;
+        if proofs.len() != signatures.len() {
+            return Err(anyhow!(
+                "Got {} release signatures for {} proofs",
+                signatures.len(),
+                proofs.len()
+            )
+            .into());
+        }
+        for (proof, signature) in proofs.iter_mut().zip(signatures) {
+            let parsed_signature = Signature::from_str(signature)
+                .map_err(|e| anyhow!("Invalid release signature {}: {}", signature, e))?;
+            signer.verify(&proof.secret.to_bytes(), &parsed_signature)?;
+            match proof.witness.as_mut() {
+                Some(witness) => witness.add_signatures(vec![signature.clone()]),
+                None => {
+                    proof.witness = Some(Witness::P2PKWitness(P2PKWitness {
+                        signatures: vec![signature.clone()],
+                    }))
+                }
+            }
+        }
+        Ok(Token::new(
+            mint_url,
+            proofs,
+            escrow_token.memo().clone(),
+            *escrow_token.unit(),
+        ))
+    }
+
+    /// Adds the escrow signatures of `signer` over the proofs of all `milestone_tokens` one after another, as a
+    /// coordinator signs the milestones it awards in a dispute.
+    pub fn add_milestone_signatures(
+        milestone_tokens: &[Token],
+        signer: &PublicKey,
+        signatures: &[String],
+    ) -> Result<Vec<Token>, EscrowError> {
+        let mut remaining = signatures;
+        let mut signed_tokens = Vec::with_capacity(milestone_tokens.len());
+        for milestone_token in milestone_tokens {
+            let (_, proofs) = Self::escrow_proofs(milestone_token)?;
+            if remaining.len() < proofs.len() {
+                return Err(anyhow!(
+                    "Got {} signatures for the proofs of {} milestones",
+                    signatures.len(),
+                    milestone_tokens.len()
+                )
+                .into());
+            }
+            let (milestone_signatures, rest) = remaining.split_at(proofs.len());
+            signed_tokens.push(Self::add_release_signatures(
+                milestone_token,
+                signer,
+                milestone_signatures,
+            )?);
+            remaining = rest;
+        }
+        if !remaining.is_empty() {
+            return Err(anyhow!(
+                "Got {} signatures beyond the proofs of {} milestones",
+                remaining.len(),
+                milestone_tokens.len()
+            )
+            .into());
+        }
+        Ok(signed_tokens)
+    }
+
+    /// Splits the escrow token into one token per milestone, taking the proofs in order.
+    ///
+    /// Fails if the proofs don't add up to the milestone amounts one after another.
+    pub fn milestone_tokens(
+        escrow_token: &Token,
+        milestones: &[Amount],
+    ) -> Result<Vec<Token>, EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        Ok(milestone_proofs(proofs, milestones)?
+            .into_iter()
+            .map(|proofs| {
+                Token::new(
+                    mint_url.clone(),
+                    proofs,
+                    escrow_token.memo().clone(),
+                    *escrow_token.unit(),
+                )
+            })
+            .collect())
+    }
+
+    /// Joins milestone tokens of the same mint into a single token.
+    pub fn join_milestone_tokens(milestone_tokens: &[Token]) -> Result<Token, EscrowError> {
+        let first_token = milestone_tokens
+            .first()
+            .ok_or_else(|| anyhow!("No milestone tokens to join"))?;
+        let (mint_url, _) = Self::escrow_proofs(first_token)?;
+        let mut proofs = Proofs::new();
+        for milestone_token in milestone_tokens {
+            let (milestone_mint_url, milestone_proofs) = Self::escrow_proofs(milestone_token)?;
+            if milestone_mint_url != mint_url {
+                return Err(anyhow!("Milestone tokens of different mints").into());
+            }
+            proofs.extend(milestone_proofs);
+        }
+        Ok(Token::new(
+            mint_url,
+            proofs,
+            first_token.memo().clone(),
+            *first_token.unit(),
+        ))
+    }
+
+    /// Selects unspent proofs of `mint_wallet` worth `amount` and the input fee of the mint for them, and reserves them
+    /// for the escrow `escrow_id_hex`.
+    async fn select_input_proofs(
+        &self,
+        mint_wallet: &Wallet,
+        amount: Amount,
+        escrow_id_hex: &str,
+    ) -> Result<Proofs, EscrowError> {
+        let _selection = self.selection_lock.lock().await;
+        let selected = self.select_unspent_proofs(mint_wallet, amount).await?;
+        let ys = selected
+            .iter()
+            .map(|proof| proof.y())
+            .collect::<Result<Vec<_>, _>>()?;
+        mint_wallet
+            .localstore
+            .reserve_proofs(ys.clone())
+            .await
+            .map_err(cdk::Error::from)?;
+        self.reserved_proofs
+            .lock()
+            .expect("Reserved proofs lock poisoned")
+            .entry(escrow_id_hex.to_string())
+            .or_default()
+            .extend(ys.into_iter().map(|y| (mint_wallet.mint_url.clone(), y)));
+        Ok(selected)
+    }
+
+    /// Makes the proofs reserved for the escrow `escrow_id_hex` and not swapped since unspent again.
+    pub async fn release_reservation(&self, escrow_id_hex: &str) -> Result<(), EscrowError> {
+        let reserved = self
+            .reserved_proofs
+            .lock()
+            .expect("Reserved proofs lock poisoned")
+            .remove(escrow_id_hex)
+            .unwrap_or_default();
+        let mut reserved_by_mint: HashMap<MintUrl, Vec<PublicKey>> = HashMap::new();
+        for (mint_url, y) in reserved {
+            reserved_by_mint.entry(mint_url).or_default().push(y);
+        }
+        for (mint_url, ys) in reserved_by_mint {
+            debug!(
+                "Releasing {} reserved proofs of escrow {}",
+                ys.len(),
+                escrow_id_hex
+            );
+            // swapped proofs are gone from the store already, only the others are released
+            self.mint_wallet(&mint_url)?
+                .localstore
+                .set_unspent_proofs(ys)
+                .await
+                .map_err(cdk::Error::from)?;
+        }
+        Ok(())
+    }
+
+    async fn select_unspent_proofs(
+        &self,
+        mint_wallet: &Wallet,
+        amount: Amount,
+    ) -> Result<Proofs, EscrowError> {
+        // the active keyset fees must be known to estimate the input fee
+        mint_wallet.get_active_mint_keyset().await?;
+        let unspent_proofs = mint_wallet.get_proofs().await?;
+        let have = Amount::try_sum(unspent_proofs.iter().map(|proof| proof.amount))?;
+        let mut fee = Amount::ZERO;
+        // more proofs can raise the fee, so the selection is repeated until it covers its own fee
+        loop {
+            let need = amount.checked_add(fee).ok_or_else(|| {
+                EscrowError::AmountOverflow(format!("{} sat plus a fee of {} sat", amount, fee))
+            })?;
+            let selected = self
+                .proof_selection
+                .select(&unspent_proofs, need)
+                .ok_or(EscrowError::InsufficientFunds { have, need })?;
+            let selected_fee = mint_wallet.get_proofs_fee(&selected).await?;
+            if selected_fee <= fee {
+                debug!(
+                    "Selected {} proofs for {} sat with a fee of {} sat",
+                    selected.len(),
+                    amount,
+                    selected_fee
+                );
+                return Ok(selected);
+            }
+            fee = selected_fee;
+        }
+    }
+
+    /// Swaps reserved proofs into the escrow proofs of every milestone of `contract`.
+    async fn swap_milestone_proofs(
+        &self,
+        mint_wallet: &Wallet,
+        contract: &TradeContract,
+        spending_conditions: SpendingConditions,
+        escrow_id_hex: &str,
+    ) -> Result<Proofs, EscrowError> {
+        // every milestone gets its own proofs, so the milestones can be released separately
+        let mut proofs = Proofs::new();
+        for milestone_amount in contract.milestone_amounts()? {
+            let input_proofs = self
+                .select_input_proofs(mint_wallet, milestone_amount, escrow_id_hex)
+                .await?;
+            let milestone_proofs = mint_wallet
+                .swap(
+                    Some(milestone_amount),
+                    SplitTarget::None,
+                    input_proofs,
+                    Some(spending_conditions.clone()),
+                    true,
+                )
+                .await?
+                .ok_or_else(|| anyhow!("Mint returned no proofs for the milestone"))?;
+            proofs.extend(milestone_proofs);
+        }
+        Ok(proofs)
+    }
+
+    /// Estimates the fees of redeeming `escrow_token` and melting the redeemed amount over lightning.
+    ///
+    /// Redeeming swaps the proofs, melting spends about as many proofs again, so the input fee of the mint is paid
+    /// twice. The lightning fee reserve is only known for an invoice, it is estimated from the usual mint reserve.
+    pub async fn estimate_redeem_fee(&self, escrow_token: &Token) -> Result<Amount, EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        let mint_wallet = self.mint_wallet(&mint_url)?;
+        // the keyset fees must be known to compute the input fee
+        mint_wallet.get_active_mint_keyset().await?;
+        let input_fee = u64::from(mint_wallet.get_proofs_fee(&proofs).await?);
+        let amount = u64::from(escrow_token.value()?);
+        amount
+            .checked_mul(LIGHTNING_FEE_RESERVE_PERCENT)
+            .map(|reserve| (reserve / 100).max(MIN_LIGHTNING_FEE_RESERVE_SAT))
+            .and_then(|lightning_fee_reserve| {
+                input_fee.checked_mul(2)?.checked_add(lightning_fee_reserve)
+            })
+            .map(Amount::from)
+            .ok_or_else(|| {
+                EscrowError::AmountOverflow(format!("redeem fee of a {} sat escrow token", amount))
+            })
+    }
+
+    /// Fails unless the mint knows all `proofs` as unspent.
+    async fn ensure_unspent(mint_wallet: &Wallet, proofs: &Proofs) -> Result<(), EscrowError> {
+        let proof_states = mint_wallet.check_proofs_spent(proofs.clone()).await?;
+        if proof_states
+            .iter()
+            .any(|proof| proof.state != State::Unspent)
+        {
+            return Err(anyhow!("Escrow token is already spent or pending").into());
+        }
+        Ok(())
+    }
+
+    fn escrow_proofs(escrow_token: &Token) -> Result<(MintUrl, Proofs), EscrowError> {
+        let mint_proofs = escrow_token.proofs();
+        if mint_proofs.len() != 1 {
+            return Err(anyhow!("Escrow token must contain proofs of exactly one mint").into());
+        }
+        Ok(mint_proofs.into_iter().next().expect("Token has proofs"))
+    }
+}
+
+/// The bip39 seed of `mnemonic`, without passphrase.
+fn mnemonic_seed(mnemonic: &str) -> Result<[u8; 64], EscrowError> {
+    let mnemonic =
+        Mnemonic::from_str(mnemonic).map_err(|e| anyhow!("Invalid wallet mnemonic: {}", e))?;
+    Ok(mnemonic.to_seed(""))
+}
+
+#[async_trait]
+impl EscrowWallet for ClientEcashWallet {
+    fn trade_pubkey(&self) -> &str {
+        self.spending_pubkey_hex()
+    }
+
+    /// Fails with [`EscrowError::InsufficientFunds`] if the wallet can't fund the escrow of the contract.
+    async fn ensure_escrow_funds(&self, contract: &TradeContract) -> Result<(), EscrowError> {
+        let have = self
+            .mint_wallet(&contract.mint_url)?
+            .total_balance()
+            .await?;
+        let need = Amount::from(contract.buyer_total_sat()?);
+        if have < need {
+            return Err(EscrowError::InsufficientFunds { have, need });
+        }
+        Ok(())
+    }
+
+    async fn create_escrow_token(
+        &self,
+        contract: &TradeContract,
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<Token, EscrowError> {
+        let spending_conditions = Self::assemble_escrow_conditions(contract, escrow_registrations)?;
+        let mint_wallet = self.mint_wallet(&contract.mint_url)?;
+        if mint_wallet.unit != contract.unit {
+            return Err(EscrowError::UnitMismatch {
+                expected: contract.unit,
+                actual: mint_wallet.unit,
+            });
+        }
+        if let Some(backup_file) = &self.backup_file {
+            self.export_backup(backup_file).await?;
+        }
+        let escrow_id_hex = contract.escrow_id()?.to_lower_hex_string();
+        let proofs = match self
+            .swap_milestone_proofs(mint_wallet, contract, spending_conditions, &escrow_id_hex)
+            .await
+        {
+            Ok(proofs) => proofs,
+            Err(e) => {
+                self.release_reservation(&escrow_id_hex).await?;
+                return Err(e);
+            }
+        };
+        // the reserved proofs are spent by the swaps
+        self.reserved_proofs
+            .lock()
+            .expect("Reserved proofs lock poisoned")
+            .remove(&escrow_id_hex);
+        Ok(Token::new(
+            contract.mint_url.clone(),
+            proofs,
+            Some(contract.trade_description.clone()),
+            Some(contract.unit),
+        ))
+    }
+
+    async fn create_coordinator_fee_token(
         &self,
         contract: &TradeContract,
         escrow_registration: &EscrowRegistration,
-    ) -> anyhow::Result<Token> {
-        let spending_conditions = Self::assemble_escrow_conditions(contract, escrow_registration)?;
+    ) -> Result<Token, EscrowError> {
+        let spending_conditions =
+            SpendingConditions::new_p2pk(escrow_registration.coordinator_escrow_pubkey, None);
         let token = self
-            .wallet
+            .mint_wallet(&contract.mint_url)?
             .send(
-                contract.trade_amount_sat.into(),
-                Some(contract.trade_description.clone()),
+                contract.coordinator_fee_sat.into(),
+                Some(format!(
+                    "Coordinator fee of {}",
+                    escrow_registration.escrow_id_hex
+                )),
                 Some(spending_conditions),
                 &SplitTarget::None,
                 &SendKind::OnlineExact,
@@ -85,15 +753,377 @@ impl ClientEcashWallet {
         Ok(token)
     }
 
-    pub fn validate_escrow_token(
+    /// Checks that the escrow token is locked to the escrow conditions and worth exactly the trade amount.
+    ///
+    /// Warns if the fees of redeeming the token would eat up a noticeable part of the trade amount.
+    ///
+    /// Fails with [`EscrowError::UnitMismatch`] if the token is denominated in another unit than the contract,
+    /// with [`EscrowError::AmountMismatch`] if the token is worth more or less than the contract amount
+    /// and with [`EscrowError::DleqVerificationFailed`] if a proof lacks a valid DLEQ proof of the mint.
+    async fn validate_escrow_token(
         &self,
         escrow_token: &Token,
         contract: &TradeContract,
-        escrow_registration: &EscrowRegistration,
-    ) -> anyhow::Result<()> {
-        let spending_conditions = Self::assemble_escrow_conditions(contract, escrow_registration)?;
-        self.wallet
-            .verify_token_p2pk(&escrow_token, spending_conditions)?;
+        escrow_registrations: &[EscrowRegistration],
+    ) -> Result<(), EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        if mint_url != contract.mint_url {
+            return Err(anyhow!(
+                "Escrow token of mint {} instead of the contract mint {}",
+                mint_url,
+                contract.mint_url
+            )
+            .into());
+        }
+        // tokens without unit are denominated in sat
+        let unit = escrow_token.unit().unwrap_or_default();
+        if unit != contract.unit {
+            return Err(EscrowError::UnitMismatch {
+                expected: contract.unit,
+                actual: unit,
+            });
+        }
+        let mint_wallet = self.mint_wallet(&mint_url)?;
+        let expected = Amount::from(contract.trade_amount_sat);
+        let actual = escrow_token.value()?;
+        if actual != expected {
+            return Err(EscrowError::AmountMismatch { expected, actual });
+        }
+        Self::verify_escrow_conditions(&proofs, contract, escrow_registrations)?;
+
+        // proofs of rotated out or unknown keysets may be of compromised or deprecated keys
+        let active_keysets = self.active_keysets(&mint_url).await?;
+        let mut keyset_keys = HashMap::new();
+        for (index, proof) in proofs.iter().enumerate() {
+            if !active_keysets
+                .iter()
+                .any(|keyset| keyset.id == proof.keyset_id && keyset.unit == contract.unit)
+            {
+                return Err(EscrowError::InactiveKeyset {
+                    index,
+                    keyset_id: proof.keyset_id,
+                });
+            }
+            if let Entry::Vacant(entry) = keyset_keys.entry(proof.keyset_id) {
+                entry.insert(mint_wallet.get_keyset_keys(proof.keyset_id).await?);
+            }
+            let mint_pubkey = keyset_keys[&proof.keyset_id]
+                .amount_key(proof.amount)
+                .ok_or(EscrowError::DleqVerificationFailed { index })?;
+            proof
+                .verify_dleq(mint_pubkey)
+                .map_err(|_| EscrowError::DleqVerificationFailed { index })?;
+        }
+
+        match self.estimate_redeem_fee(escrow_token).await {
+            Ok(redeem_fee)
+                if u64::from(redeem_fee).saturating_mul(100)
+                    > contract
+                        .trade_amount_sat
+                        .saturating_mul(REDEEM_FEE_WARNING_PERCENT) =>
+            {
+                warn!(
+                    "Redeeming the escrow token costs about {} sat in fees, only {} sat of the {} sat trade amount would be received",
+                    redeem_fee,
+                    expected - redeem_fee.min(expected),
+                    expected
+                );
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "Failed to estimate the fees of redeeming the escrow token: {}",
+                e
+            ),
+        }
         Ok(())
     }
+
+    /// Signs the secret of every escrow token proof with the trade key.
+    ///
+    /// The signatures are returned in the same order as the proofs of the token.
+    fn sign_escrow_token(&self, escrow_token: &Token) -> Result<Vec<String>, EscrowError> {
+        let (_, proofs) = Self::escrow_proofs(escrow_token)?;
+        proofs
+            .iter()
+            .map(|proof| Ok(self._secret.sign(&proof.secret.to_bytes())?.to_string()))
+            .collect()
+    }
+
+    /// Swaps the escrow token proofs into unlocked proofs of this wallet, signing them with the trade key.
+    ///
+    /// Returns the amount received after the mint fees.
+    async fn redeem_escrow_token(&self, escrow_token: &Token) -> Result<Amount, EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        let mint_wallet = self.mint_wallet(&mint_url)?;
+        Self::ensure_unspent(mint_wallet, &proofs).await?;
+        let amount = mint_wallet
+            .receive_proofs(
+                proofs,
+                SplitTarget::None,
+                std::slice::from_ref(&self._secret),
+                &[],
+            )
+            .await?;
+        Ok(amount)
+    }
+
+    async fn create_redeem_outputs(
+        &self,
+        escrow_token: &Token,
+    ) -> Result<RedeemOutputs, EscrowError> {
+        self.create_outputs_for(escrow_token).await
+    }
+
+    fn sign_redeem_outputs(&self, outputs: &[BlindedMessage]) -> Result<Vec<String>, EscrowError> {
+        RedeemOutputs::sign(outputs, &self._secret)
+    }
+
+    /// Swaps the escrow token proofs into the outputs, signing both with the trade key, and keeps the received proofs.
+    async fn redeem_escrow_token_into(
+        &self,
+        escrow_token: &Token,
+        redeem_outputs: &RedeemOutputs,
+    ) -> Result<Amount, EscrowError> {
+        self.swap_into_outputs(escrow_token, redeem_outputs).await
+    }
+
+    async fn release_escrow_funds(&self, escrow_id_hex: &str) -> Result<(), EscrowError> {
+        self.release_reservation(escrow_id_hex).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cashu_escrow_common::model::DEFAULT_REQUIRED_SIGNATURES;
+    use cdk::{
+        nuts::{nut10, Id, Proof, SigFlag},
+        secret::Secret,
+    };
+    use nostr_sdk::Timestamp;
+    use std::time::Duration;
+
+    const MINT_URL: &str = "https://mint.example.com";
+
+    fn contract(wallet: &ClientEcashWallet, trade_amount_sat: u64) -> TradeContract {
+        TradeContract {
+            trade_description: "Purchase of one watermelon".to_string(),
+            trade_amount_sat,
+            coordinator_fee_sat: 0,
+            unit: CurrencyUnit::Sat,
+            mint_url: MintUrl::from_str(MINT_URL).unwrap(),
+            npubkey_seller: NostrKeys::generate().public_key(),
+            npubkey_buyer: NostrKeys::generate().public_key(),
+            npubkey_coordinator: NostrKeys::generate().public_key(),
+            expiry: Timestamp::now() + Duration::from_secs(60 * 60),
+            seller_ecash_public_key: wallet.trade_pubkey().to_string(),
+            buyer_ecash_public_key: SecretKey::generate().public_key().to_hex(),
+            buyer_refund_public_key: None,
+            milestones: Vec::new(),
+            oracle_pubkey: None,
+            required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+            sig_flag: SigFlag::SigAll,
+            additional_coordinators: Vec::new(),
+            coordinator_threshold: None,
+            fiat_price: None,
+            digital_goods: false,
+        }
+    }
+
+    fn registration(contract: &TradeContract) -> EscrowRegistration {
+        EscrowRegistration::new(
+            contract.escrow_id().unwrap().to_lower_hex_string(),
+            SecretKey::generate().public_key(),
+            Timestamp::now(),
+            contract.coordinator_fee_sat,
+            "nonce".to_string(),
+        )
+    }
+
+    fn proof(amount: u64, secret: Secret) -> Proof {
+        Proof::new(
+            Amount::from(amount),
+            Id::from_str("009a1f293253e41e").unwrap(),
+            secret,
+            SecretKey::generate().public_key(),
+        )
+    }
+
+    /// Proofs locked to the escrow conditions of `contract` and `registrations`.
+    fn locked_proofs(contract: &TradeContract, registrations: &[EscrowRegistration]) -> Proofs {
+        let conditions =
+            ClientEcashWallet::assemble_escrow_conditions(contract, registrations).unwrap();
+        let secret = Secret::try_from(nut10::Secret::from(conditions)).unwrap();
+        vec![proof(4096, secret.clone()), proof(904, secret)]
+    }
+
+    /// A token of unbacked proofs of `amounts` with random secrets.
+    fn token(contract: &TradeContract, amounts: &[u64]) -> Token {
+        let proofs = amounts
+            .iter()
+            .map(|amount| proof(*amount, Secret::generate()))
+            .collect();
+        Token::new(contract.mint_url.clone(), proofs, None, Some(contract.unit))
+    }
+
+    async fn wallet() -> ClientEcashWallet {
+        ClientEcashWallet::new(MINT_URL, &[], SecretKey::generate())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn validate_escrow_token_rejects_wrong_amounts() {
+        let wallet = wallet().await;
+        let contract = contract(&wallet, 5000);
+        let registrations = [registration(&contract)];
+
+        for (amounts, actual) in [(&[4096, 512, 256, 128][..], 4992), (&[4096, 1024], 5120)] {
+            let result = wallet
+                .validate_escrow_token(&token(&contract, amounts), &contract, &registrations)
+                .await;
+            assert!(
+                matches!(
+                    result,
+                    Err(EscrowError::AmountMismatch { expected, actual: token_amount })
+                        if expected == Amount::from(5000) && token_amount == Amount::from(actual)
+                ),
+                "accepted a token of {} sat: {:?}",
+                actual,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_escrow_conditions_rejects_other_locks() {
+        let wallet = wallet().await;
+        let contract = contract(&wallet, 5000);
+        let registrations = [registration(&contract)];
+        let other_key = SecretKey::generate().public_key().to_hex();
+
+        let proofs = locked_proofs(&contract, &registrations);
+        ClientEcashWallet::verify_escrow_conditions(&proofs, &contract, &registrations).unwrap();
+
+        let mut other_seller = contract.clone();
+        other_seller.seller_ecash_public_key = other_key.clone();
+        let mut other_refund = contract.clone();
+        other_refund.buyer_refund_public_key = Some(other_key);
+        let mut other_flag = contract.clone();
+        other_flag.sig_flag = SigFlag::SigInputs;
+        let mut other_signatures = contract.clone();
+        other_signatures.required_signatures = 1;
+        let cases = [
+            ("seller key", locked_proofs(&other_seller, &registrations)),
+            ("refund key", locked_proofs(&other_refund, &registrations)),
+            ("signature flag", locked_proofs(&other_flag, &registrations)),
+            (
+                "required signatures",
+                locked_proofs(&other_signatures, &registrations),
+            ),
+            (
+                "coordinator key",
+                locked_proofs(&contract, &[registration(&contract)]),
+            ),
+            ("no conditions", vec![proof(5000, Secret::generate())]),
+        ];
+        for (case, proofs) in cases {
+            let result =
+                ClientEcashWallet::verify_escrow_conditions(&proofs, &contract, &registrations);
+            assert!(
+                matches!(
+                    result,
+                    Err(EscrowError::ConditionsMismatch { index: 0, .. })
+                ),
+                "accepted another {}: {:?}",
+                case,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn validate_escrow_token_rejects_overflowing_token() {
+        let wallet = wallet().await;
+        let contract = contract(&wallet, 5000);
+
+        let result = wallet
+            .validate_escrow_token(
+                &token(&contract, &[u64::MAX, 5000]),
+                &contract,
+                &[registration(&contract)],
+            )
+            .await;
+        assert!(
+            matches!(result, Err(EscrowError::AmountOverflow(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn redeem_outputs_take_valid_signatures_only() {
+        let keyset_id = Id::from_str("009a1f293253e41e").unwrap();
+        let buyer_secret = SecretKey::generate();
+        let mut redeem_outputs = RedeemOutputs::random(keyset_id, Amount::from(5000)).unwrap();
+        assert_eq!(redeem_outputs.amount().unwrap(), Amount::from(5000));
+        let signatures = RedeemOutputs::sign(&redeem_outputs.outputs, &buyer_secret).unwrap();
+
+        let other_signer = SecretKey::generate().public_key();
+        assert!(redeem_outputs
+            .clone()
+            .add_signatures(&other_signer, &signatures)
+            .is_err());
+        assert!(redeem_outputs
+            .clone()
+            .add_signatures(&buyer_secret.public_key(), &signatures[1..])
+            .is_err());
+
+        redeem_outputs
+            .add_signatures(&buyer_secret.public_key(), &signatures)
+            .unwrap();
+        let pubkeys = vec![buyer_secret.public_key()];
+        for output in &redeem_outputs.outputs {
+            output.verify_p2pk(&pubkeys, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn redeem_outputs_take_milestone_signatures_in_order() {
+        let keyset_id = Id::from_str("009a1f293253e41e").unwrap();
+        let coordinator_secret = SecretKey::generate();
+        let mut milestone_outputs = [
+            RedeemOutputs::random(keyset_id, Amount::from(3)).unwrap(),
+            RedeemOutputs::random(keyset_id, Amount::from(4)).unwrap(),
+        ];
+        let all_outputs: Vec<BlindedMessage> = milestone_outputs
+            .iter()
+            .flat_map(|redeem_outputs| redeem_outputs.outputs.clone())
+            .collect();
+        let signatures = RedeemOutputs::sign(&all_outputs, &coordinator_secret).unwrap();
+        let signer = coordinator_secret.public_key();
+
+        // the signatures of one milestone are too few for both, the ones of both too many for one
+        assert!(RedeemOutputs::add_milestone_signatures(
+            milestone_outputs.clone().iter_mut(),
+            &signer,
+            &signatures[..2]
+        )
+        .is_err());
+        assert!(RedeemOutputs::add_milestone_signatures(
+            milestone_outputs[..1].to_vec().iter_mut(),
+            &signer,
+            &signatures
+        )
+        .is_err());
+
+        RedeemOutputs::add_milestone_signatures(milestone_outputs.iter_mut(), &signer, &signatures)
+            .unwrap();
+        for output in milestone_outputs
+            .iter()
+            .flat_map(|redeem_outputs| &redeem_outputs.outputs)
+        {
+            output.verify_p2pk(&vec![signer], 1).unwrap();
+        }
+    }
 }
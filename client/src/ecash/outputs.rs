@@ -0,0 +1,185 @@
+use super::*;
+
+use cdk::{
+    dhke::construct_proofs,
+    nuts::{BlindedMessage, Id, PreMintSecrets, SwapRequest},
+    secret::Secret,
+    types::ProofInfo,
+    HttpClient,
+};
+use serde::{Deserialize, Serialize};
+
+/// The blinded outputs a trader swaps escrow proofs into, with the secrets and blinding factors unblinding the
+/// signatures of the mint.
+///
+/// The mint swaps an escrow token locked with `SIG_ALL` only into outputs signed like its proofs, so the
+/// redeeming trader creates them before the release and the buyer or the coordinators sign them along with the proofs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedeemOutputs {
+    /// The blinded outputs, with the signatures collected so far.
+    pub outputs: Vec<BlindedMessage>,
+    secrets: Vec<Secret>,
+    blinding_factors: Vec<SecretKey>,
+}
+
+impl RedeemOutputs {
+    /// Outputs of random secrets worth `amount`, to be signed by the keyset `keyset_id`.
+    pub fn random(keyset_id: Id, amount: Amount) -> Result<Self, EscrowError> {
+        let pre_mint_secrets = PreMintSecrets::random(keyset_id, amount, &SplitTarget::None)?;
+        Ok(Self {
+            outputs: pre_mint_secrets.blinded_messages(),
+            secrets: pre_mint_secrets.secrets(),
+            blinding_factors: pre_mint_secrets.rs(),
+        })
+    }
+
+    pub fn amount(&self) -> Result<Amount, EscrowError> {
+        Ok(Amount::try_sum(
+            self.outputs.iter().map(|output| output.amount),
+        )?)
+    }
+
+    /// Signs the blinded secret of every output with `secret`, in the order of the outputs.
+    pub fn sign(
+        outputs: &[BlindedMessage],
+        secret: &SecretKey,
+    ) -> Result<Vec<String>, EscrowError> {
+        outputs
+            .iter()
+            .map(|output| Ok(secret.sign(&output.blinded_secret.to_bytes())?.to_string()))
+            .collect()
+    }
+
+    /// Adds the signatures of `signer` over the outputs after verifying them.
+    pub fn add_signatures(
+        &mut self,
+        signer: &PublicKey,
+        signatures: &[String],
+    ) -> Result<(), EscrowError> {
+        if self.outputs.len() != signatures.len() {
+            return Err(anyhow!(
+                "Got {} output signatures for {} outputs",
+                signatures.len(),
+                self.outputs.len()
+            )
+            .into());
+        }
+        for (output, signature) in self.outputs.iter_mut().zip(signatures) {
+            let parsed_signature = Signature::from_str(signature)
+                .map_err(|e| anyhow!("Invalid output signature {}: {}", signature, e))?;
+            signer.verify(&output.blinded_secret.to_bytes(), &parsed_signature)?;
+            match output.witness.as_mut() {
+                Some(witness) => witness.add_signatures(vec![signature.clone()]),
+                None => {
+                    output.witness = Some(Witness::P2PKWitness(P2PKWitness {
+                        signatures: vec![signature.clone()],
+                    }))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the signatures of `signer` over the outputs of all `milestone_outputs` one after another, as a coordinator
+    /// signs the outputs of the milestones it awards in a dispute.
+    pub fn add_milestone_signatures<'a>(
+        milestone_outputs: impl IntoIterator<Item = &'a mut RedeemOutputs>,
+        signer: &PublicKey,
+        signatures: &[String],
+    ) -> Result<(), EscrowError> {
+        let mut remaining = signatures;
+        for redeem_outputs in milestone_outputs {
+            if remaining.len() < redeem_outputs.outputs.len() {
+                return Err(anyhow!(
+                    "Got {} signatures for fewer outputs than claimed",
+                    signatures.len()
+                )
+                .into());
+            }
+            let (output_signatures, rest) = remaining.split_at(redeem_outputs.outputs.len());
+            redeem_outputs.add_signatures(signer, output_signatures)?;
+            remaining = rest;
+        }
+        if !remaining.is_empty() {
+            return Err(anyhow!(
+                "Got {} signatures beyond the claimed outputs",
+                remaining.len()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl ClientEcashWallet {
+    /// Outputs of the active keyset worth the value of `escrow_token` after the input fee of the mint.
+    pub(super) async fn create_outputs_for(
+        &self,
+        escrow_token: &Token,
+    ) -> Result<RedeemOutputs, EscrowError> {
+        let (mint_url, proofs) = Self::escrow_proofs(escrow_token)?;
+        let mint_wallet = self.mint_wallet(&mint_url)?;
+        let keyset_id = mint_wallet.get_active_mint_keyset().await?.id;
+        let input_fee = u64::from(mint_wallet.get_proofs_fee(&proofs).await?);
+        let value = u64::from(escrow_token.value()?);
+        let amount = value.checked_sub(input_fee).ok_or_else(|| {
+            anyhow!(
+                "The mint fee of {} sat exceeds the {} sat escrow token",
+                input_fee,
+                value
+            )
+        })?;
+        RedeemOutputs::random(keyset_id, Amount::from(amount))
+    }
+
+    /// Swaps the escrow token proofs into `redeem_outputs`, adding the signature of the trade key to every proof and
+    /// output, and stores the received proofs in the wallet.
+    ///
+    /// Returns the received amount.
+    pub(super) async fn swap_into_outputs(
+        &self,
+        escrow_token: &Token,
+        redeem_outputs: &RedeemOutputs,
+    ) -> Result<Amount, EscrowError> {
+        let (mint_url, mut proofs) = Self::escrow_proofs(escrow_token)?;
+        let mint_wallet = self.mint_wallet(&mint_url)?;
+        Self::ensure_unspent(mint_wallet, &proofs).await?;
+        let keyset_id = redeem_outputs
+            .outputs
+            .first()
+            .ok_or_else(|| anyhow!("No outputs to redeem the escrow token into"))?
+            .keyset_id;
+        let keys = mint_wallet.get_keyset_keys(keyset_id).await?;
+        for proof in proofs.iter_mut() {
+            proof.sign_p2pk(self._secret.clone())?;
+        }
+        let mut outputs = redeem_outputs.outputs.clone();
+        for output in outputs.iter_mut() {
+            output.sign_p2pk(self._secret.clone())?;
+        }
+
+        let swap_response = HttpClient::new()
+            .post_swap(
+                mint_url.clone().try_into()?,
+                SwapRequest::new(proofs, outputs),
+            )
+            .await?;
+        let received_proofs = construct_proofs(
+            swap_response.signatures,
+            redeem_outputs.blinding_factors.clone(),
+            redeem_outputs.secrets.clone(),
+            &keys,
+        )?;
+        let amount = Amount::try_sum(received_proofs.iter().map(|proof| proof.amount))?;
+        let proof_infos = received_proofs
+            .into_iter()
+            .map(|proof| ProofInfo::new(proof, mint_url.clone(), State::Unspent, mint_wallet.unit))
+            .collect::<Result<Vec<_>, _>>()?;
+        mint_wallet
+            .localstore
+            .update_proofs(proof_infos, vec![])
+            .await
+            .map_err(cdk::Error::from)?;
+        Ok(amount)
+    }
+}
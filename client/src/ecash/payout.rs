@@ -0,0 +1,197 @@
+use super::*;
+
+use nostr_sdk::bech32;
+use serde::Deserialize;
+
+/// Invoices are requested again at most this often if the mint reserves more lightning fees than estimated.
+const MAX_PAYOUT_QUOTE_ATTEMPTS: usize = 2;
+
+/// A finished lightning payment of the wallet funds.
+#[derive(Debug, Clone)]
+pub struct LightningPayout {
+    /// The amount the receiver got.
+    pub amount: Amount,
+    /// The lightning and mint fees, the funds spent beyond `amount`.
+    pub fee_paid: Amount,
+    pub preimage: Option<String>,
+}
+
+/// The LNURL-pay parameters of a lightning address or LNURL (LUD-06, LUD-16).
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PayRequest {
+    callback: String,
+    min_sendable: u64,
+    max_sendable: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct PayRequestInvoice {
+    pr: String,
+}
+
+/// The error answer of an LNURL service.
+#[derive(Deserialize, Debug)]
+struct LnurlError {
+    reason: String,
+}
+
+impl ClientEcashWallet {
+    /// Pays all unspent funds of the mint `mint_url` to the lightning address (`name@domain`) or LNURL `address`.
+    ///
+    /// Reserved funds of running trades are left. The invoice is requested for the balance less the estimated mint
+    /// fees, and once more for less if the melt quote of the mint reserves more.
+    pub async fn melt_to_lightning_address(
+        &self,
+        mint_url: &MintUrl,
+        address: &str,
+    ) -> Result<LightningPayout, EscrowError> {
+        let mint_wallet = self.mint_wallet(mint_url)?;
+        if mint_wallet.unit != CurrencyUnit::Sat {
+            return Err(anyhow!("Only sat funds can be paid to a lightning address").into());
+        }
+        let proofs = mint_wallet.get_proofs().await?;
+        let balance = Amount::try_sum(proofs.iter().map(|proof| proof.amount))?;
+        // the keyset fees must be known to compute the input fee
+        mint_wallet.get_active_mint_keyset().await?;
+        let input_fee = mint_wallet.get_proofs_fee(&proofs).await?;
+
+        let http_client = reqwest::Client::new();
+        let pay_request = fetch_pay_request(&http_client, address).await?;
+        let mut fee_reserve = Amount::from(
+            (u64::from(balance).saturating_mul(LIGHTNING_FEE_RESERVE_PERCENT) / 100)
+                .max(MIN_LIGHTNING_FEE_RESERVE_SAT),
+        );
+        let mut attempts = 0;
+        let quote = loop {
+            attempts += 1;
+            let fees = input_fee.checked_add(fee_reserve).ok_or_else(|| {
+                EscrowError::AmountOverflow(format!(
+                    "input fee of {} sat plus a fee reserve of {} sat",
+                    input_fee, fee_reserve
+                ))
+            })?;
+            let amount = u64::from(balance)
+                .checked_sub(u64::from(fees))
+                .filter(|amount| *amount > 0)
+                .map(Amount::from)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "The {} sat of {} don't cover the fees of a lightning payment",
+                        balance,
+                        mint_url
+                    )
+                })?;
+            let invoice = fetch_invoice(&http_client, &pay_request, amount).await?;
+            let quote = mint_wallet.melt_quote(invoice, None).await?;
+            let quote_total = Amount::try_sum([quote.amount, quote.fee_reserve, input_fee])?;
+            if quote_total <= balance {
+                break quote;
+            }
+            if attempts >= MAX_PAYOUT_QUOTE_ATTEMPTS {
+                return Err(anyhow!(
+                    "{} reserves {} sat in lightning fees, more than the {} sat left",
+                    mint_url,
+                    quote.fee_reserve,
+                    u64::from(balance).saturating_sub(quote.amount.into())
+                )
+                .into());
+            }
+            fee_reserve = quote.fee_reserve;
+        };
+        debug!(
+            "Paying {} sat to {}, reserving {} sat for lightning fees...",
+            quote.amount, address, quote.fee_reserve
+        );
+        let melted = mint_wallet.melt(&quote.id).await?;
+        // the change of the unused fee reserve is back in the wallet
+        let spent = u64::from(balance).saturating_sub(mint_wallet.total_balance().await?.into());
+        let fee_paid = Amount::from(spent.saturating_sub(quote.amount.into()));
+        info!(
+            "Paid {} sat to {} for {} sat in fees",
+            quote.amount, address, fee_paid
+        );
+        Ok(LightningPayout {
+            amount: quote.amount,
+            fee_paid,
+            preimage: melted.preimage,
+        })
+    }
+}
+
+/// The LNURL-pay url of a lightning address or a bech32 encoded LNURL.
+fn lnurl_pay_url(address: &str) -> Result<String, EscrowError> {
+    if let Some((user, domain)) = address.split_once('@') {
+        if user.is_empty() || domain.is_empty() {
+            return Err(anyhow!("Invalid lightning address {}", address).into());
+        }
+        return Ok(format!("https://{}/.well-known/lnurlp/{}", domain, user));
+    }
+    let lnurl = address.trim_start_matches("lightning:");
+    let (hrp, data) =
+        bech32::decode(lnurl).map_err(|e| anyhow!("Invalid LNURL {}: {}", address, e))?;
+    if hrp.to_lowercase() != "lnurl" {
+        return Err(anyhow!("Neither a lightning address nor an LNURL: {}", address).into());
+    }
+    Ok(String::from_utf8(data).map_err(|e| anyhow!("Invalid LNURL {}: {}", address, e))?)
+}
+
+async fn fetch_pay_request(
+    http_client: &reqwest::Client,
+    address: &str,
+) -> Result<PayRequest, EscrowError> {
+    let url = lnurl_pay_url(address)?;
+    fetch_lnurl_json(http_client, &url)
+        .await
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", address, e).into())
+}
+
+/// Asks the LNURL service for an invoice of `amount` sat.
+async fn fetch_invoice(
+    http_client: &reqwest::Client,
+    pay_request: &PayRequest,
+    amount: Amount,
+) -> Result<String, EscrowError> {
+    let amount_msat = u64::from(amount) * 1000;
+    if !(pay_request.min_sendable..=pay_request.max_sendable).contains(&amount_msat) {
+        return Err(anyhow!(
+            "The lightning address accepts {} to {} sat, not {} sat",
+            pay_request.min_sendable.div_ceil(1000),
+            pay_request.max_sendable / 1000,
+            amount
+        )
+        .into());
+    }
+    let separator = match pay_request.callback.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    let url = format!(
+        "{}{}amount={}",
+        pay_request.callback, separator, amount_msat
+    );
+    let invoice: PayRequestInvoice = fetch_lnurl_json(http_client, &url)
+        .await
+        .map_err(|e| anyhow!("Failed to get an invoice of {} sat: {}", amount, e))?;
+    Ok(invoice.pr)
+}
+
+/// Fetches an LNURL answer, failing with the reason of an error answer.
+async fn fetch_lnurl_json<T: for<'de> Deserialize<'de>>(
+    http_client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<T> {
+    let answer: serde_json::Value = http_client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())?
+        .json()
+        .await?;
+    if let Ok(error) = serde_json::from_value::<LnurlError>(answer.clone()) {
+        if answer.get("status").and_then(|status| status.as_str()) == Some("ERROR") {
+            return Err(anyhow!(error.reason));
+        }
+    }
+    Ok(serde_json::from_value(answer)?)
+}
@@ -0,0 +1,85 @@
+use super::*;
+
+use cdk::nuts::Proof;
+
+/// How the buyer wallet picks the proofs swapped into the escrow token.
+///
+/// Fewer proofs keep the input fees of the swap low, exact matches avoid change which links the escrow to the wallet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProofSelection {
+    /// The smallest single proof covering the amount, else the largest proofs until the amount is covered.
+    #[default]
+    FewestProofs,
+    LargestFirst,
+    SmallestFirst,
+    /// Proofs adding up to exactly the amount if the wallet holds them, else the fewest proofs.
+    ExactMatchPreferred,
+}
+
+impl FromStr for ProofSelection {
+    type Err = EscrowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fewest-proofs" => Ok(Self::FewestProofs),
+            "largest-first" => Ok(Self::LargestFirst),
+            "smallest-first" => Ok(Self::SmallestFirst),
+            "exact-match-preferred" => Ok(Self::ExactMatchPreferred),
+            _ => Err(anyhow!(
+                "Unknown proof selection {}, use fewest-proofs, largest-first, smallest-first or exact-match-preferred",
+                s
+            )
+            .into()),
+        }
+    }
+}
+
+impl ProofSelection {
+    /// Picks proofs of `proofs` worth at least `amount`, `None` if they are worth less in total.
+    pub fn select(&self, proofs: &[Proof], amount: Amount) -> Option<Proofs> {
+        let mut proofs = proofs.to_vec();
+        proofs.sort_by_key(|proof| proof.amount);
+        match self {
+            Self::FewestProofs => proofs
+                .iter()
+                .find(|proof| proof.amount >= amount)
+                .map(|proof| vec![proof.clone()])
+                .or_else(|| take_until_covered(proofs.into_iter().rev(), amount)),
+            Self::LargestFirst => take_until_covered(proofs.into_iter().rev(), amount),
+            Self::SmallestFirst => take_until_covered(proofs.into_iter(), amount),
+            Self::ExactMatchPreferred => {
+                exact_match(&proofs, amount).or_else(|| Self::FewestProofs.select(&proofs, amount))
+            }
+        }
+    }
+}
+
+fn take_until_covered(proofs: impl Iterator<Item = Proof>, amount: Amount) -> Option<Proofs> {
+    let mut selected = Proofs::new();
+    let mut selected_amount = Amount::ZERO;
+    for proof in proofs {
+        if selected_amount >= amount {
+            break;
+        }
+        // proofs worth more than a u64 in total cover any amount
+        selected_amount = selected_amount
+            .checked_add(proof.amount)
+            .unwrap_or(Amount::from(u64::MAX));
+        selected.push(proof);
+    }
+    (selected_amount >= amount).then_some(selected)
+}
+
+/// Greedily takes the largest proofs fitting into the remaining amount, which finds an exact match of power of two
+/// denominations whenever there is one.
+fn exact_match(proofs_ascending: &[Proof], amount: Amount) -> Option<Proofs> {
+    let mut selected = Proofs::new();
+    let mut remaining = amount;
+    for proof in proofs_ascending.iter().rev() {
+        if proof.amount <= remaining {
+            remaining -= proof.amount;
+            selected.push(proof.clone());
+        }
+    }
+    (remaining == Amount::ZERO && !selected.is_empty()).then_some(selected)
+}
@@ -0,0 +1,30 @@
+use super::*;
+
+/// An event of a trade, for scripts following it, see [`InitEscrowClient::with_event_sink`].
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TradeEvent {
+    /// The escrow client changed from `previous_state` to `state`.
+    StateChanged {
+        escrow_id: String,
+        trade_mode: TradeMode,
+        previous_state: String,
+        state: String,
+    },
+    /// The seller redeemed the released escrow token, or a trader the milestones awarded to it in a dispute.
+    Redeemed {
+        escrow_id: String,
+        trade_mode: TradeMode,
+        amount_sat: u64,
+    },
+    /// The trade failed, without escrow id if it failed before the registration.
+    Failed {
+        escrow_id: Option<String>,
+        error: String,
+    },
+}
+
+/// Receives the events of a trade as they happen.
+pub trait TradeEventSink: Send + Sync {
+    fn emit(&self, event: TradeEvent);
+}
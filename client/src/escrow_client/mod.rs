@@ -1,41 +1,431 @@
-use std::str::FromStr;
+mod events;
+mod negotiation;
+mod policy;
+mod snapshot;
+mod store;
+
+use std::{
+    cmp::Ordering, collections::BTreeMap, fs, ops::Range, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
+};
 
 use super::*;
 
+use anyhow::anyhow;
+use cashu_escrow_common::cli::get_user_input;
+use cashu_escrow_common::error::EscrowError;
+use cashu_escrow_common::metrics::Metrics;
+use cashu_escrow_common::model::token_hash;
 use cashu_escrow_common::{
-    model::{EscrowRegistration, TradeContract},
-    nostr::NostrClient,
+    envelope::{CoordinatorMessage, EscrowEnvelope, MessageKind},
+    model::{
+        ContractAccepted, ContractProposal, ContractSubmission, CoordinatorFeePayment, DeliveryKey,
+        DeliveryPayload, DeliveryProof, DisputeClaim, DisputeResolution, EscrowRegistration,
+        ExchangeRate, FeeReceipt, MilestoneDelivered, TokenAccepted, TokenChunk, TokenChunks,
+        TokenRejected, TokenReleaseSignature, TradeCancelled, TradeContract, TradeOutcome,
+        TradeReceipt, TradeReceiptContent, TradeRejection, MAX_DIGITAL_GOODS_LEN,
+        MAX_TOKEN_MESSAGE_LEN,
+    },
+    nostr::{message_expiration, EscrowTransport, MessageDeadline, NostrClient},
+};
+use cdk::{
+    nuts::{BlindedMessage, PublicKey as EcashPubkey, SigFlag, Token},
+    Amount,
+};
+use clock::{TimeSource, CLOCK_SKEW_WARNING_SECS, MAX_CLOCK_SKEW_SECS};
+use ecash::{ClientEcashWallet, EscrowWallet, RedeemOutputs};
+pub use events::{TradeEvent, TradeEventSink};
+pub use negotiation::{
+    negotiate_contract, ContractResponder, ProposalResponse, DEFAULT_MAX_NEGOTIATION_ROUNDS,
 };
-use cdk::nuts::Token;
-use ecash::ClientEcashWallet;
+use nostr_sdk::{hashes::hex::DisplayHex, PublicKey as NostrPubkey, Timestamp, ToBech32};
+pub use policy::SellerPolicy;
+use rand::Rng;
+use rates::{check_rate_deviation, ExchangeRateSource};
+use serde::{Deserialize, Serialize};
+pub use snapshot::{EscrowSnapshot, ResumedEscrowClient, SnapshotState};
+pub use store::{EscrowStore, StoredEscrow};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Minimum number of connected relays before the contract is sent to the coordinator.
+const MIN_CONNECTED_RELAYS: usize = 1;
+const RELAY_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time to wait for a message of the coordinator or the trade partner.
+pub const DEFAULT_MESSAGE_TIMEOUT_SECS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TradeMode {
     Buyer,
     Seller,
 }
 
-pub struct InitEscrowClient {
-    nostr_client: NostrClient,
-    ecash_wallet: ClientEcashWallet,
+/// Trade data shared by all escrow client states.
+struct EscrowClientContext<T, W> {
+    transport: T,
+    ecash_wallet: W,
     escrow_contract: TradeContract,
     trade_mode: TradeMode,
+    /// The time to wait for each message, forever if `None`.
+    message_timeout: Option<Duration>,
+    snapshot_dir: Option<PathBuf>,
+    seller_policy: SellerPolicy,
+    metrics: Arc<Metrics>,
+    receipt_dir: Option<PathBuf>,
+    send_receipt_to_coordinator: bool,
+    /// Whether the buyer confirms the funds on the terminal before they are locked into the escrow.
+    confirm_funding: bool,
+    /// Whether the traders confirm the delivery of each milestone on the terminal before it is released.
+    confirm_milestones: bool,
+    event_sink: Option<Arc<dyn TradeEventSink>>,
+    /// The goods the seller delivers for a contract of digital goods.
+    digital_goods: Option<Vec<u8>>,
+}
+
+impl<T, W> EscrowClientContext<T, W> {
+    /// Persists the snapshot if a snapshot directory is configured.
+    fn save_snapshot(
+        &self,
+        escrow_registration: &EscrowRegistration,
+        additional_registrations: &[EscrowRegistration],
+        state: SnapshotState,
+    ) -> Result<(), EscrowError> {
+        if let Some(snapshot_dir) = &self.snapshot_dir {
+            let path = EscrowSnapshot {
+                trade_mode: self.trade_mode,
+                escrow_contract: self.escrow_contract.clone(),
+                escrow_registration: escrow_registration.clone(),
+                additional_registrations: additional_registrations.to_vec(),
+                state,
+            }
+            .save(snapshot_dir)?;
+            debug!("Saved escrow snapshot to {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Logs the state transition of the escrow client in a uniform format and emits it to the event sink.
+    fn log_transition(&self, from: &str, to: &str, escrow_id_hex: &str) {
+        info!(
+            "State transition {} -> {}: escrow_id={} trade_mode={:?}",
+            from, to, escrow_id_hex, self.trade_mode
+        );
+        self.emit(TradeEvent::StateChanged {
+            escrow_id: escrow_id_hex.to_string(),
+            trade_mode: self.trade_mode,
+            previous_state: from.to_string(),
+            state: to.to_string(),
+        });
+    }
+
+    fn emit(&self, event: TradeEvent) {
+        if let Some(event_sink) = &self.event_sink {
+            event_sink.emit(event);
+        }
+    }
+
+    /// Fails with [`EscrowError::ContractExpired`] once the contract expiry passed.
+    fn ensure_not_expired(&self) -> Result<(), EscrowError> {
+        let expiry = self.escrow_contract.expiry;
+        if Timestamp::now() > expiry {
+            return Err(EscrowError::ContractExpired(expiry));
+        }
+        Ok(())
+    }
+
+    /// Whether the escrow token is locked with `SIG_ALL`, so the outputs of each redeem swap are signed as well.
+    fn signs_outputs(&self) -> bool {
+        self.escrow_contract.sig_flag == SigFlag::SigAll
+    }
+
+    fn remove_snapshot(&self, escrow_registration: &EscrowRegistration) -> Result<(), EscrowError> {
+        if let Some(snapshot_dir) = &self.snapshot_dir {
+            EscrowSnapshot::remove(snapshot_dir, &escrow_registration.escrow_id_hex)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: EscrowTransport, W: EscrowWallet> EscrowClientContext<T, W> {
+    /// The nostr pubkey of the trade partner.
+    fn counterparty(&self) -> NostrPubkey {
+        match self.trade_mode {
+            TradeMode::Buyer => self.escrow_contract.npubkey_seller,
+            TradeMode::Seller => self.escrow_contract.npubkey_buyer,
+        }
+    }
+
+    /// Fails unless the nostr and ecash keys of this trader are the ones of its trade mode in the contract.
+    ///
+    /// A trader on the wrong side of the contract would wait for messages nobody sends.
+    fn ensure_own_side(&self) -> Result<(), EscrowError> {
+        let contract = &self.escrow_contract;
+        let (npubkey, ecash_pubkey) = match self.trade_mode {
+            TradeMode::Buyer => (contract.npubkey_buyer, &contract.buyer_ecash_public_key),
+            TradeMode::Seller => (contract.npubkey_seller, &contract.seller_ecash_public_key),
+        };
+        if self.transport.public_key() != npubkey {
+            return Err(anyhow!(
+                "Trading as {:?} with {}, but the contract names {} as {:?}",
+                self.trade_mode,
+                self.transport.public_key(),
+                npubkey,
+                self.trade_mode
+            )
+            .into());
+        }
+        if EcashPubkey::from_hex(self.ecash_wallet.trade_pubkey())?
+            != EcashPubkey::from_hex(ecash_pubkey)?
+        {
+            return Err(anyhow!(
+                "Wallet trade pubkey {} is not the {:?} ecash pubkey {} of the contract",
+                self.ecash_wallet.trade_pubkey(),
+                self.trade_mode,
+                ecash_pubkey
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Redeems the `milestones` of `milestone_tokens` into the wallet, returning the received amount.
+    ///
+    /// With `SIG_ALL` each milestone is swapped into its own signed `redeem_outputs`, else they are swapped at once.
+    async fn redeem_milestones(
+        &self,
+        milestone_tokens: &[Token],
+        redeem_outputs: &BTreeMap<usize, RedeemOutputs>,
+        milestones: Range<usize>,
+    ) -> Result<Amount, EscrowError> {
+        if !self.signs_outputs() {
+            let token = ClientEcashWallet::join_milestone_tokens(&milestone_tokens[milestones])?;
+            return self.ecash_wallet.redeem_escrow_token(&token).await;
+        }
+        let mut amount = Amount::ZERO;
+        for milestone in milestones {
+            let outputs = redeem_outputs
+                .get(&milestone)
+                .ok_or_else(|| anyhow!("No redeem outputs of milestone {}", milestone))?;
+            let received = self
+                .ecash_wallet
+                .redeem_escrow_token_into(&milestone_tokens[milestone], outputs)
+                .await?;
+            amount = amount.checked_add(received).ok_or_else(|| {
+                EscrowError::AmountOverflow("amount of the redeemed milestones".to_string())
+            })?;
+        }
+        Ok(amount)
+    }
+}
+
+impl<T: EscrowTransport, W> EscrowClientContext<T, W> {
+    /// Signs the receipt of the finished trade, saves it and sends it to the coordinator if configured.
+    async fn issue_receipt(
+        &self,
+        escrow_registration: &EscrowRegistration,
+        escrow_token: &Token,
+        final_token: &Token,
+        outcome: TradeOutcome,
+    ) -> Result<TradeReceipt, EscrowError> {
+        let receipt = self.transport.sign_trade_receipt(TradeReceiptContent {
+            escrow_id_hex: escrow_registration.escrow_id_hex.clone(),
+            npubkey_buyer: self.escrow_contract.npubkey_buyer,
+            npubkey_seller: self.escrow_contract.npubkey_seller,
+            trade_amount_sat: self.escrow_contract.trade_amount_sat,
+            coordinator_fee_sat: escrow_registration.coordinator_fee_sat,
+            escrow_token_hash: token_hash(escrow_token),
+            final_token_hash: token_hash(final_token),
+            escrow_start_time: escrow_registration.escrow_start_time,
+            finished_at: Timestamp::now(),
+            outcome,
+        })?;
+        if let Some(receipt_dir) = &self.receipt_dir {
+            fs::create_dir_all(receipt_dir)?;
+            let path = receipt_dir.join(format!("{}.json", escrow_registration.escrow_id_hex));
+            fs::write(&path, serde_json::to_string_pretty(&receipt)?)?;
+            debug!("Saved trade receipt to {}", path.display());
+        }
+        if self.send_receipt_to_coordinator {
+            self.transport
+                .send_payload(self.escrow_contract.npubkey_coordinator, &receipt)
+                .await?;
+        }
+        Ok(receipt)
+    }
+}
+
+/// How often and how patiently the contract is submitted to the coordinator.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Wait time before the first resubmission, doubled after every further attempt.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+pub struct InitEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    context: EscrowClientContext<T, W>,
+    retry_policy: RetryPolicy,
+    rate_source: Option<Arc<dyn ExchangeRateSource>>,
+    time_source: Option<Arc<dyn TimeSource>>,
 }
 
 /// Initial Escrow Client state.
-impl InitEscrowClient {
+impl<T: EscrowTransport, W: EscrowWallet> InitEscrowClient<T, W> {
     pub fn new(
-        nostr_client: NostrClient,
-        ecash_wallet: ClientEcashWallet,
+        transport: T,
+        ecash_wallet: W,
         escrow_contract: TradeContract,
         trade_mode: TradeMode,
     ) -> Self {
         Self {
-            nostr_client,
-            ecash_wallet,
-            escrow_contract,
-            trade_mode,
+            context: EscrowClientContext {
+                transport,
+                ecash_wallet,
+                escrow_contract,
+                trade_mode,
+                message_timeout: Some(Duration::from_secs(DEFAULT_MESSAGE_TIMEOUT_SECS)),
+                snapshot_dir: None,
+                seller_policy: SellerPolicy::default(),
+                metrics: Arc::default(),
+                receipt_dir: None,
+                send_receipt_to_coordinator: false,
+                confirm_funding: false,
+                confirm_milestones: false,
+                event_sink: None,
+                digital_goods: None,
+            },
+            retry_policy: RetryPolicy::default(),
+            rate_source: None,
+            time_source: None,
+        }
+    }
+
+    /// Sets the time to wait for each message of the coordinator or the trade partner.
+    pub fn with_message_timeout_secs(self, message_timeout_secs: u64) -> Self {
+        self.with_message_timeout(Some(Duration::from_secs(message_timeout_secs)))
+    }
+
+    /// Sets the time to wait for each message of the coordinator or the trade partner, `None` to wait until it arrives
+    /// or the trade is interrupted, e.g. for interactive trades.
+    pub fn with_message_timeout(mut self, message_timeout: Option<Duration>) -> Self {
+        self.context.message_timeout = message_timeout;
+        self
+    }
+
+    /// Persists a snapshot after every state change to `snapshot_dir`, to resume the trade after a restart.
+    pub fn with_snapshot_dir(mut self, snapshot_dir: impl Into<PathBuf>) -> Self {
+        self.context.snapshot_dir = Some(snapshot_dir.into());
+        self
+    }
+
+    /// Sets the terms on which the seller accepts the escrow token, the buyer is notified of a rejection.
+    pub fn with_seller_policy(mut self, seller_policy: SellerPolicy) -> Self {
+        self.context.seller_policy = seller_policy;
+        self
+    }
+
+    /// Saves the signed receipt of the finished trade to `receipt_dir`, as json file named after the escrow id.
+    pub fn with_receipt_dir(mut self, receipt_dir: impl Into<PathBuf>) -> Self {
+        self.context.receipt_dir = Some(receipt_dir.into());
+        self
+    }
+
+    /// Sends the signed receipt of the finished trade to the coordinator as well.
+    pub fn with_receipt_sent_to_coordinator(mut self) -> Self {
+        self.context.send_receipt_to_coordinator = true;
+        self
+    }
+
+    /// Asks the buyer on the terminal to confirm the amounts, mint, seller and expiry before the escrow is funded, the
+    /// trade is cancelled if the buyer declines.
+    pub fn with_funding_confirmation(mut self) -> Self {
+        self.context.confirm_funding = true;
+        self
+    }
+
+    /// Asks on the terminal before each milestone of a contract with milestones, the seller to confirm its delivery
+    /// and the buyer to confirm receiving it before releasing it.
+    pub fn with_milestone_confirmation(mut self) -> Self {
+        self.context.confirm_milestones = true;
+        self
+    }
+
+    /// Counts the disputes and settled trades in `metrics`, e.g. the metrics of the [`NostrClient`] the trade runs on.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.context.metrics = metrics;
+        self
+    }
+
+    /// Emits the state changes and the redemption of the trade to `event_sink`.
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn TradeEventSink>) -> Self {
+        self.context.event_sink = Some(event_sink);
+        self
+    }
+
+    /// Delivers `goods` as seller of a contract of digital goods, at most [`MAX_DIGITAL_GOODS_LEN`] bytes.
+    pub fn with_digital_goods(mut self, goods: Vec<u8>) -> Self {
+        self.context.digital_goods = Some(goods);
+        self
+    }
+
+    /// Looks up the exchange rate of fiat priced contracts at `rate_source`, required to register them.
+    pub fn with_rate_source(mut self, rate_source: Arc<dyn ExchangeRateSource>) -> Self {
+        self.rate_source = Some(rate_source);
+        self
+    }
+
+    /// Checks the escrow start time of the coordinator against the time of `time_source` instead of the local clock.
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.time_source = Some(time_source);
+        self
+    }
+
+    /// Sets how often the contract is resubmitted if the coordinator doesn't answer in time.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// The current exchange rate of a fiat priced contract whose rate isn't locked yet.
+    async fn current_fiat_rate(&self) -> Result<Option<ExchangeRate>, EscrowError> {
+        let contract = &self.context.escrow_contract;
+        let Some(price) = contract
+            .fiat_price
+            .as_ref()
+            .filter(|_| contract.needs_fiat_rate())
+        else {
+            return Ok(None);
+        };
+        let rate_source = self.rate_source.as_ref().ok_or_else(|| {
+            anyhow!(
+                "No exchange rate source for the contract priced in {}",
+                price
+            )
+        })?;
+        Ok(Some(rate_source.exchange_rate(price.currency).await?))
+    }
+
+    /// The time of the time source, warning if the local clock is far off it.
+    async fn current_time(&self) -> Result<Timestamp, EscrowError> {
+        let Some(time_source) = &self.time_source else {
+            return Ok(Timestamp::now());
+        };
+        let now = time_source.now().await?;
+        let local_skew = now.as_u64().abs_diff(Timestamp::now().as_u64());
+        if local_skew > CLOCK_SKEW_WARNING_SECS {
+            warn!(
+                "The local clock is {} seconds off the trusted time {}",
+                local_skew, now
+            );
         }
+        Ok(now)
     }
 
     /// The trade initialization is the same for both buyer and seller.
@@ -43,132 +433,2016 @@ impl InitEscrowClient {
     /// After this the coordinator data is set, state trade registered.
     ///
     /// After this state the trade contract is effectfull as well, possible coordinator fees must be payed.
-    pub async fn register_trade(mut self) -> anyhow::Result<RegisteredEscrowClient> {
-        let coordinator_pk = &self.escrow_contract.npubkey_coordinator;
-        let contract_message = serde_json::to_string(&self.escrow_contract)?;
-        debug!("sending contract to coordinator...");
-        self.nostr_client
-            .client
-            .send_private_msg(*coordinator_pk, &contract_message, None)
+    ///
+    /// Before the registration both traders agree on the exact contract terms, see [`agree_on_contract`].
+    ///
+    /// Resubmissions carry the same nonce, so the coordinator answers them with the existing registration.
+    ///
+    /// If the coordinator answers none of the submissions, the contract is withdrawn again and
+    /// [`EscrowError::CoordinatorUnresponsive`] returned.
+    ///
+    /// A contract with additional coordinators is registered at each of them, and withdrawn from all of them if one
+    /// fails.
+    ///
+    /// For a fiat priced contract the buyer locks the current exchange rate, the seller accepts the locked rate if it
+    /// is close to its own.
+    pub async fn register_trade(mut self) -> Result<RegisteredEscrowClient<T, W>, EscrowError> {
+        self.context.ensure_not_expired()?;
+        self.context.ensure_own_side()?;
+        let current_rate = self.current_fiat_rate().await?;
+        if let (TradeMode::Buyer, Some(rate)) = (self.context.trade_mode, &current_rate) {
+            self.context.escrow_contract.lock_fiat_rate(rate.clone())?;
+            info!(
+                "Locked the trade amount of {} sat at {} sat per unit",
+                self.context.escrow_contract.trade_amount_sat, rate.sat_per_unit
+            );
+        }
+        self.context.escrow_contract.validate()?;
+        if self.context.trade_mode == TradeMode::Seller
+            && self.context.escrow_contract.digital_goods
+        {
+            let goods = self.context.digital_goods.as_ref().ok_or_else(|| {
+                anyhow!("The contract is for digital goods, but the seller has none to deliver")
+            })?;
+            if goods.len() > MAX_DIGITAL_GOODS_LEN {
+                return Err(anyhow!(
+                    "Digital goods of {} bytes exceed the limit of {} bytes",
+                    goods.len(),
+                    MAX_DIGITAL_GOODS_LEN
+                )
+                .into());
+            }
+        }
+        if self.context.trade_mode == TradeMode::Buyer {
+            self.context
+                .ecash_wallet
+                .ensure_escrow_funds(&self.context.escrow_contract)
+                .await?;
+        }
+        let transport = &mut self.context.transport;
+        transport.set_message_expiration(Some(message_expiration(
+            self.context.escrow_contract.expiry,
+        )));
+        transport
+            .wait_for_connection(MIN_CONNECTED_RELAYS, RELAY_CONNECTION_TIMEOUT)
             .await?;
+        self.context.escrow_contract = agree_on_contract(
+            transport,
+            &self.context.escrow_contract,
+            self.context.trade_mode,
+            current_rate.as_ref(),
+            self.context.message_timeout,
+        )
+        .await?;
+        let submission = ContractSubmission {
+            contract: self.context.escrow_contract.clone(),
+            nonce: rand::thread_rng().gen::<[u8; 16]>().to_lower_hex_string(),
+            acceptance: transport.accept_contract(&self.context.escrow_contract)?,
+        };
 
-        let registration_message = self.nostr_client.receive_escrow_message(20).await?;
-        let escrow_registration: EscrowRegistration = serde_json::from_str(&registration_message)?;
-        debug!(
-            "Received registration: {}",
-            &escrow_registration.escrow_id_hex
-        );
+        let mut escrow_registrations = Vec::new();
+        for coordinator_pk in self.context.escrow_contract.coordinators() {
+            match self.register_at(coordinator_pk, &submission).await {
+                Ok(registration) => escrow_registrations.push((coordinator_pk, registration)),
+                Err(e) => {
+                    // the escrow can't be set up without every coordinator
+                    self.withdraw_submission(
+                        &submission,
+                        escrow_registrations.iter().map(|(pk, _)| *pk),
+                        "registration failed at another coordinator",
+                    )
+                    .await;
+                    return Err(e);
+                }
+            }
+        }
+        let now = self.current_time().await?;
+        let escrow_contract = &self.context.escrow_contract;
+        for (index, (coordinator_pk, registration)) in escrow_registrations.iter().enumerate() {
+            debug!(
+                "Received registration: escrow_id={} coordinator={}",
+                registration.escrow_id_hex, coordinator_pk
+            );
+            // only the main coordinator charges the fee
+            let expected_fee_sat = match *coordinator_pk == escrow_contract.npubkey_coordinator {
+                true => escrow_contract.coordinator_fee_sat,
+                false => 0,
+            };
+            verify_registration(escrow_contract, registration, expected_fee_sat, now)?;
+            if escrow_registrations[..index].iter().any(|(_, other)| {
+                other.coordinator_escrow_pubkey == registration.coordinator_escrow_pubkey
+            }) {
+                return Err(EscrowError::InvalidRegistration(format!(
+                    "coordinator escrow pubkey {} is used by another coordinator",
+                    registration.coordinator_escrow_pubkey
+                )));
+            }
+        }
+        let mut escrow_registrations = escrow_registrations
+            .into_iter()
+            .map(|(_, registration)| registration);
+        let escrow_registration = escrow_registrations
+            .next()
+            .expect("The contract names a coordinator");
+        let additional_registrations: Vec<_> = escrow_registrations.collect();
+        self.context.save_snapshot(
+            &escrow_registration,
+            &additional_registrations,
+            SnapshotState::Registered,
+        )?;
+        self.context
+            .log_transition("Init", "Registered", &escrow_registration.escrow_id_hex);
         Ok(RegisteredEscrowClient {
-            nostr_client: self.nostr_client,
-            ecash_wallet: self.ecash_wallet,
-            escrow_contract: self.escrow_contract,
-            trade_mode: self.trade_mode,
+            context: self.context,
             escrow_registration,
+            additional_registrations,
+            sent_tokens: None,
         })
     }
+
+    /// Submits the contract to the coordinator `coordinator_pk` until it answers with the registration.
+    ///
+    /// If it answers none of the submissions, the contract is withdrawn from it again. The registration is only awaited
+    /// on the transport once a submission was sent, so a failed send leaves no pending receive behind, and the
+    /// subscription of the transport keeps an answer arriving before the wait.
+    async fn register_at(
+        &mut self,
+        coordinator_pk: NostrPubkey,
+        submission: &ContractSubmission,
+    ) -> Result<EscrowRegistration, EscrowError> {
+        let counterparty = self.context.counterparty();
+        let transport = &mut self.context.transport;
+        let mut backoff = self.retry_policy.backoff;
+        let mut attempt = 1;
+        loop {
+            debug!(
+                "Sending contract to coordinator: escrow_id={} coordinator={} attempt={}",
+                submission.acceptance.escrow_id_hex, coordinator_pk, attempt
+            );
+            let accepting_relays = transport.send_payload(coordinator_pk, submission).await?;
+            debug!(
+                "Contract sent: escrow_id={} accepting_relays={}",
+                submission.acceptance.escrow_id_hex, accepting_relays
+            );
+            match receive_registration(
+                transport,
+                coordinator_pk,
+                counterparty,
+                submission,
+                self.context.message_timeout,
+            )
+            .await
+            {
+                Ok(registration) => return Ok(registration),
+                Err(e)
+                    if attempt < self.retry_policy.max_attempts
+                        && matches!(e, EscrowError::Timeout { .. }) =>
+                {
+                    warn!(
+                        "No registration received: escrow_id={} coordinator={}, retrying in {:?}...",
+                        submission.acceptance.escrow_id_hex, coordinator_pk, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(EscrowError::Timeout { .. }) => {
+                    // withdraws the submission, in case the coordinator comes back and registers it later
+                    self.withdraw_submission(
+                        submission,
+                        [coordinator_pk],
+                        "coordinator unresponsive",
+                    )
+                    .await;
+                    return Err(EscrowError::CoordinatorUnresponsive {
+                        coordinator: coordinator_pk,
+                        attempts: attempt,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Cancels the submitted contract at `coordinators`, only logging failures.
+    async fn withdraw_submission(
+        &self,
+        submission: &ContractSubmission,
+        coordinators: impl IntoIterator<Item = NostrPubkey>,
+        reason: &str,
+    ) {
+        let transport = &self.context.transport;
+        let cancellation = TradeCancelled {
+            escrow_id_hex: submission.acceptance.escrow_id_hex.clone(),
+            cancelled_by: transport.public_key(),
+            reason: reason.to_string(),
+        };
+        for coordinator_pk in coordinators {
+            if let Err(e) = transport.send_payload(coordinator_pk, &cancellation).await {
+                warn!(
+                    "Failed to withdraw the contract: escrow_id={} coordinator={}: {}",
+                    submission.acceptance.escrow_id_hex, coordinator_pk, e
+                );
+            }
+        }
+    }
 }
 
-pub struct RegisteredEscrowClient {
-    nostr_client: NostrClient,
-    ecash_wallet: ClientEcashWallet,
-    escrow_contract: TradeContract,
+/// Makes sure the trade partner agrees to the exact contract terms before the contract is registered.
+///
+/// The buyer proposes the contract and waits for the signed acceptance of the seller, the seller accepts the proposal
+/// only if it matches its own contract. For a fiat priced contract the seller adopts the rate locked by the buyer, if
+/// it deviates at most [`rates::MAX_RATE_DEVIATION_PERCENT`] from `current_rate`.
+///
+/// Returns the agreed contract.
+async fn agree_on_contract(
+    transport: &mut impl EscrowTransport,
+    contract: &TradeContract,
     trade_mode: TradeMode,
+    current_rate: Option<&ExchangeRate>,
+    timeout: Option<Duration>,
+) -> Result<TradeContract, EscrowError> {
+    let mut contract = contract.clone();
+    match trade_mode {
+        TradeMode::Buyer => {
+            debug!("Proposing the contract to the seller...");
+            transport
+                .send_payload(contract.npubkey_seller, &contract)
+                .await?;
+            let seller_acceptance: ContractAccepted = transport
+                .receive_payload(contract.npubkey_seller, timeout)
+                .await?;
+            seller_acceptance.verify(&contract, &contract.npubkey_seller)?;
+        }
+        TradeMode::Seller => {
+            let proposed_contract: TradeContract = transport
+                .receive_payload(contract.npubkey_buyer, timeout)
+                .await?;
+            if let Some(current_rate) = current_rate {
+                let locked_rate = proposed_contract
+                    .fiat_price
+                    .as_ref()
+                    .and_then(|price| price.rate.clone())
+                    .ok_or_else(|| {
+                        anyhow!("The buyer proposed the contract without a fiat rate")
+                    })?;
+                check_rate_deviation(&locked_rate, current_rate)?;
+                contract.lock_fiat_rate(locked_rate)?;
+            }
+            if proposed_contract.escrow_id()? != contract.escrow_id()? {
+                return Err(anyhow!(
+                    "The buyer proposed a contract with other terms: {:?}",
+                    proposed_contract
+                )
+                .into());
+            }
+            debug!("Accepting the contract proposed by the buyer...");
+            transport
+                .send_payload(
+                    contract.npubkey_buyer,
+                    &transport.accept_contract(&contract)?,
+                )
+                .await?;
+        }
+    }
+    trace!("Both traders accepted the contract");
+    Ok(contract)
+}
+
+/// Waits for the registration answering `submission`, skipping stale registrations.
+///
+/// Fails early with [`EscrowError::TradeCancelled`] if the `counterparty` cancels the trade meanwhile. Once the
+/// counterparty sends anything else, it goes on with the trade and its messages are left for the later trade steps.
+async fn receive_registration(
+    transport: &mut impl EscrowTransport,
+    coordinator_pk: NostrPubkey,
+    counterparty: NostrPubkey,
+    submission: &ContractSubmission,
+    timeout: Option<Duration>,
+) -> Result<EscrowRegistration, EscrowError> {
+    let deadline = MessageDeadline::after(timeout);
+    // Skipped registrations count as seen events, as they arrived without being the awaited one.
+    let mut events_seen = 0;
+    let mut senders = vec![coordinator_pk, counterparty];
+    loop {
+        let remaining = deadline.remaining(coordinator_pk, events_seen)?;
+        let (sender, message) = transport
+            .receive_from_any(&senders, remaining)
+            .await
+            .map_err(|e| match e {
+                EscrowError::Timeout {
+                    events_seen: timed_out_events_seen,
+                    ..
+                } => deadline.timed_out(coordinator_pk, events_seen + timed_out_events_seen),
+                _ => e,
+            })?;
+        if sender == counterparty {
+            match EscrowEnvelope::parse(&message) {
+                Ok(envelope) if envelope.kind == MessageKind::TradeCancelled => {
+                    let cancellation: TradeCancelled = envelope.open()?;
+                    if cancellation.escrow_id_hex == submission.acceptance.escrow_id_hex {
+                        return Err(EscrowError::TradeCancelled(cancellation.reason));
+                    }
+                    debug!(
+                        "Skipping cancellation of escrow {}",
+                        cancellation.escrow_id_hex
+                    );
+                }
+                _ => {
+                    transport.requeue_message(sender, message);
+                    senders.retain(|sender| *sender != counterparty);
+                }
+            }
+            events_seen += 1;
+            continue;
+        }
+        let coordinator_message = EscrowEnvelope::parse(&message)
+            .and_then(CoordinatorMessage::open)
+            .map_err(|e| registration_parse_error(&message, e))?;
+        events_seen += 1;
+        match coordinator_message {
+            CoordinatorMessage::Registration(registration)
+                if registration.nonce == submission.nonce =>
+            {
+                return Ok(registration);
+            }
+            CoordinatorMessage::Registration(registration) => debug!(
+                "Skipping registration {} of another submission",
+                registration.escrow_id_hex
+            ),
+            CoordinatorMessage::Error(rejection) if rejection.nonce == submission.nonce => {
+                return Err(EscrowError::RegistrationRejected(rejection.reason));
+            }
+            CoordinatorMessage::Error(_) => debug!("Skipping rejection of another submission"),
+            other => debug!(
+                "Skipping {:?} message while waiting for the registration",
+                other.kind()
+            ),
+        }
+    }
+}
+
+/// Longest part of an unexpected coordinator message quoted in errors.
+const MAX_QUOTED_MESSAGE_LEN: usize = 200;
+
+/// Adds the start of the coordinator `message` to the error parsing it as registration.
+fn registration_parse_error(message: &str, error: EscrowError) -> EscrowError {
+    match error {
+        EscrowError::UnsupportedVersion { .. } => error,
+        _ => {
+            let quoted: String = message.chars().take(MAX_QUOTED_MESSAGE_LEN).collect();
+            let ellipsis = match quoted.len() < message.len() {
+                true => "...",
+                false => "",
+            };
+            anyhow!(
+                "Unexpected registration message {}{} of the coordinator: {}",
+                quoted,
+                ellipsis,
+                error
+            )
+            .into()
+        }
+    }
+}
+
+/// Checks that the coordinator registered the escrow of the sent contract for `expected_fee_sat` with a key of its own
+/// and started it at about `now`, before the contract expiry.
+fn verify_registration(
+    escrow_contract: &TradeContract,
+    escrow_registration: &EscrowRegistration,
+    expected_fee_sat: u64,
+    now: Timestamp,
+) -> Result<(), EscrowError> {
+    let expected_escrow_id_hex = escrow_contract.escrow_id()?.to_lower_hex_string();
+    if escrow_registration.escrow_id_hex != expected_escrow_id_hex {
+        return Err(EscrowError::InvalidRegistration(format!(
+            "escrow id {} does not match the contract escrow id {}",
+            escrow_registration.escrow_id_hex, expected_escrow_id_hex
+        )));
+    }
+
+    if escrow_registration.coordinator_fee_sat != expected_fee_sat {
+        return Err(EscrowError::InvalidRegistration(format!(
+            "coordinator fee of {} sat does not match the contract fee of {} sat",
+            escrow_registration.coordinator_fee_sat, expected_fee_sat
+        )));
+    }
+
+    // a coordinator key equal to a trader key would leave the escrow to fewer parties than the contract names
+    let coordinator_pubkey = escrow_registration.coordinator_escrow_pubkey;
+    let trader_pubkeys = [
+        &escrow_contract.seller_ecash_public_key,
+        &escrow_contract.buyer_ecash_public_key,
+        escrow_contract.buyer_refund_public_key(),
+    ];
+    for trader_pubkey in trader_pubkeys {
+        if EcashPubkey::from_hex(trader_pubkey)? == coordinator_pubkey {
+            return Err(EscrowError::InvalidRegistration(format!(
+                "coordinator escrow pubkey {} is a key of the traders",
+                coordinator_pubkey
+            )));
+        }
+    }
+
+    let start_time = escrow_registration.escrow_start_time;
+    let skew = start_time.as_u64().abs_diff(now.as_u64());
+    if skew > MAX_CLOCK_SKEW_SECS {
+        return Err(EscrowError::InvalidRegistration(format!(
+            "escrow start time {} is more than {} seconds off the current time {}",
+            start_time, MAX_CLOCK_SKEW_SECS, now
+        )));
+    }
+    if skew > CLOCK_SKEW_WARNING_SECS {
+        warn!(
+            "Escrow start time {} is {} seconds off the current time {}, the refund may unlock at another time than expected",
+            start_time, skew, now
+        );
+    }
+    if start_time >= escrow_contract.expiry {
+        return Err(EscrowError::InvalidRegistration(format!(
+            "escrow start time {} is not before the contract expiry {}",
+            start_time, escrow_contract.expiry
+        )));
+    }
+    Ok(())
+}
+
+pub struct RegisteredEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    context: EscrowClientContext<T, W>,
     escrow_registration: EscrowRegistration,
+    /// The registrations of the additional coordinators of the contract, in their order.
+    additional_registrations: Vec<EscrowRegistration>,
+    /// The tokens the buyer created already, sent again instead of new ones.
+    sent_tokens: Option<SentTokens>,
 }
 
-impl RegisteredEscrowClient {
+/// The escrow token and the coordinator fee token of the buyer, persisted before they are sent.
+struct SentTokens {
+    escrow_token: Token,
+    fee_token: Option<Token>,
+}
+
+impl<T: EscrowTransport, W: EscrowWallet> RegisteredEscrowClient<T, W> {
     /// Depending on the trade mode sends or receives the trade token.
     ///
-    /// After this the state is token sent or received.
-    pub async fn exchange_trade_token(mut self) -> anyhow::Result<TokenExchangedEscrowClient> {
-        match self.trade_mode {
-            TradeMode::Buyer => {
-                // todo: store the sent token in next instance
-                self.send_trade_token().await?;
-                Ok(TokenExchangedEscrowClient {
-                    _nostr_client: self.nostr_client,
-                    _ecash_wallet: self.ecash_wallet,
-                    _escrow_contract: self.escrow_contract,
-                    trade_mode: self.trade_mode,
-                })
-            }
-            TradeMode::Seller => {
-                // todo: store the received token in next instance
-                self.receive_and_validate_trade_token().await?;
-                Ok(TokenExchangedEscrowClient {
-                    _nostr_client: self.nostr_client,
-                    _ecash_wallet: self.ecash_wallet,
-                    _escrow_contract: self.escrow_contract,
-                    trade_mode: self.trade_mode,
-                })
-            }
+    /// After this the state is token sent or received, with none of the milestones released yet.
+    pub async fn exchange_trade_token(
+        mut self,
+    ) -> Result<TokenExchangedEscrowClient<T, W>, EscrowError> {
+        self.context.ensure_not_expired()?;
+        let escrow_token = match self.context.trade_mode {
+            TradeMode::Buyer => self.send_trade_token().await?,
+            TradeMode::Seller => self.receive_and_validate_trade_token().await?,
+        };
+        let milestone_tokens = ClientEcashWallet::milestone_tokens(
+            &escrow_token,
+            &self.context.escrow_contract.milestone_amounts()?,
+        )?;
+        let mut token_exchanged_client = TokenExchangedEscrowClient {
+            context: self.context,
+            escrow_registration: self.escrow_registration,
+            additional_registrations: self.additional_registrations,
+            escrow_token,
+            milestone_tokens,
+            released_milestones: 0,
+            redeem_outputs: BTreeMap::new(),
+            announced_outputs: None,
+            fee_confirmed: false,
+            delivery_key: None,
+            received_delivery_proof: None,
+            received_delivery_payload: None,
+        };
+        // saved before waiting for the seller, so a rejected token can still be reclaimed after the expiry
+        token_exchanged_client.save_snapshot()?;
+        if token_exchanged_client.context.trade_mode == TradeMode::Buyer {
+            token_exchanged_client.await_token_acceptance().await?;
+        }
+        token_exchanged_client.context.log_transition(
+            "Registered",
+            "TokenExchanged",
+            &token_exchanged_client.escrow_registration.escrow_id_hex,
+        );
+        Ok(token_exchanged_client)
+    }
+
+    /// Cancels the trade before the escrow token is exchanged, notifying the counterparty and the coordinators.
+    ///
+    /// No funds moved yet, so no coordinator fee is owed. Fails once the buyer created the escrow token, it can only be
+    /// reclaimed after the expiry then.
+    pub async fn cancel(self, reason: String) -> Result<(), EscrowError> {
+        if self.sent_tokens.is_some() {
+            return Err(anyhow!(
+                "The escrow token was sent already, it can be reclaimed after the expiry {}",
+                self.context.escrow_contract.expiry.to_human_datetime()
+            )
+            .into());
+        }
+        self.send_cancellation(reason).await
+    }
+
+    /// Notifies the counterparty and the coordinators of the cancellation, releases the funds the buyer reserved for the
+    /// escrow and removes the snapshot of the trade.
+    async fn send_cancellation(&self, reason: String) -> Result<(), EscrowError> {
+        let escrow_contract = &self.context.escrow_contract;
+        let counterparty = self.context.counterparty();
+        let cancellation = TradeCancelled {
+            escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+            cancelled_by: self.context.transport.public_key(),
+            reason,
+        };
+        debug!("Sending cancellation to the counterparty and the coordinators...");
+        for receiver in [vec![counterparty], escrow_contract.coordinators()].concat() {
+            self.context
+                .transport
+                .send_payload(receiver, &cancellation)
+                .await?;
         }
+        if self.context.trade_mode == TradeMode::Buyer {
+            self.context
+                .ecash_wallet
+                .release_escrow_funds(&self.escrow_registration.escrow_id_hex)
+                .await?;
+        }
+        self.context.remove_snapshot(&self.escrow_registration)?;
+        self.context.log_transition(
+            "Registered",
+            "Cancelled",
+            &self.escrow_registration.escrow_id_hex,
+        );
+        Ok(())
     }
 
     /// State change for the buyer. The state after that is token sent.
     ///
+    /// The created tokens are persisted before they are sent, a buyer resumed after sending them sends the same tokens
+    /// again, as new ones would spend further funds.
+    ///
     /// Returns the sent trade token by this [`EscrowClient`].
-    async fn send_trade_token(&self) -> anyhow::Result<Token> {
-        let escrow_contract = &self.escrow_contract;
-        let escrow_token = self
-            .ecash_wallet
-            .create_escrow_token(escrow_contract, &self.escrow_registration)
-            .await?;
+    async fn send_trade_token(&mut self) -> Result<Token, EscrowError> {
+        let sent_tokens = match self.sent_tokens.take() {
+            Some(sent_tokens) => {
+                info!("Sending the escrow token created before again...");
+                sent_tokens
+            }
+            None => self.create_trade_tokens().await?,
+        };
+        let escrow_contract = &self.context.escrow_contract;
+        if let Some(fee_token) = &sent_tokens.fee_token {
+            debug!("Sending fee token to the coordinator...");
+            self.context
+                .transport
+                .send_payload(
+                    escrow_contract.npubkey_coordinator,
+                    &CoordinatorFeePayment {
+                        escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+                        fee_token: fee_token.to_string(),
+                    },
+                )
+                .await?;
+        }
+        let escrow_token = sent_tokens.escrow_token;
 
         debug!("Sending token to the seller: {}", escrow_token);
 
-        self.nostr_client
-            .client
-            .send_private_msg(
-                escrow_contract.npubkey_seller,
-                &escrow_token.to_string(),
-                None,
-            )
-            .await?;
+        if escrow_token.to_string().len() > MAX_TOKEN_MESSAGE_LEN {
+            let chunks = TokenChunk::split(&self.escrow_registration.escrow_id_hex, &escrow_token);
+            debug!(
+                "Token too long for a single message, sending it in {} chunks",
+                chunks.len()
+            );
+            for chunk in &chunks {
+                self.context
+                    .transport
+                    .send_payload(escrow_contract.npubkey_seller, chunk)
+                    .await?;
+            }
+        } else {
+            self.context
+                .transport
+                .send_payload(escrow_contract.npubkey_seller, &escrow_token)
+                .await?;
+        }
         trace!("Sent Token to seller");
 
         Ok(escrow_token)
     }
 
+    /// Creates the fee token and the escrow token and saves them in the snapshot before anything is sent.
+    ///
+    /// Fails with [`EscrowError::TradeCancelled`] if the buyer declines the funding, see
+    /// [`InitEscrowClient::with_funding_confirmation`].
+    async fn create_trade_tokens(&self) -> Result<SentTokens, EscrowError> {
+        let escrow_contract = &self.context.escrow_contract;
+        let wallet = &self.context.ecash_wallet;
+        wallet.ensure_escrow_funds(escrow_contract).await?;
+        if self.context.confirm_funding && !self.confirm_funding().await? {
+            let reason = "buyer declined funding the escrow".to_string();
+            self.send_cancellation(reason.clone()).await?;
+            return Err(EscrowError::TradeCancelled(reason));
+        }
+        info!(
+            "Paying {} sat in total: {} sat trade amount and {} sat coordinator fee",
+            escrow_contract.buyer_total_sat()?,
+            escrow_contract.trade_amount_sat,
+            escrow_contract.coordinator_fee_sat
+        );
+        let fee_token = match escrow_contract.coordinator_fee_sat > 0 {
+            true => Some(
+                wallet
+                    .create_coordinator_fee_token(escrow_contract, &self.escrow_registration)
+                    .await?,
+            ),
+            false => None,
+        };
+        let escrow_token = wallet
+            .create_escrow_token(escrow_contract, &self.escrow_registrations())
+            .await?;
+        self.context.save_snapshot(
+            &self.escrow_registration,
+            &self.additional_registrations,
+            SnapshotState::TokenSent {
+                escrow_token: escrow_token.to_string(),
+                fee_token: fee_token.as_ref().map(Token::to_string),
+            },
+        )?;
+        Ok(SentTokens {
+            escrow_token,
+            fee_token,
+        })
+    }
+
     /// State change for a seller. The state after this is token received.
     ///
+    /// The buyer is notified whether the token is accepted. It is rejected if it is invalid or the contract
+    /// violates the seller policy.
+    ///
     /// Returns the received trade token by this [`EscrowClient`].
-    async fn receive_and_validate_trade_token(&mut self) -> anyhow::Result<Token> {
-        let escrow_contract = &self.escrow_contract;
-        let wallet = &self.ecash_wallet;
-
-        let message = self.nostr_client.receive_escrow_message(20).await?;
+    async fn receive_and_validate_trade_token(&mut self) -> Result<Token, EscrowError> {
+        let buyer = self.context.escrow_contract.npubkey_buyer;
+        let envelope = self
+            .context
+            .transport
+            .receive_envelope_of(
+                buyer,
+                &[
+                    MessageKind::EscrowToken,
+                    MessageKind::TokenChunk,
+                    MessageKind::TradeCancelled,
+                ],
+                self.context.message_timeout,
+            )
+            .await?;
+        if envelope.kind == MessageKind::TradeCancelled {
+            let cancellation: TradeCancelled = envelope.open()?;
+            return Err(EscrowError::TradeCancelled(cancellation.reason));
+        }
         trace!("Received Token, validating it...");
-        let escrow_token = Token::from_str(&message)?;
-        wallet.validate_escrow_token(&escrow_token, escrow_contract, &self.escrow_registration)?;
+        let escrow_id_hex = self.escrow_registration.escrow_id_hex.clone();
+        match self.validate_trade_token(envelope).await {
+            Ok(escrow_token) => {
+                debug!("Accepting the escrow token...");
+                self.context
+                    .transport
+                    .send_payload(buyer, &TokenAccepted { escrow_id_hex })
+                    .await?;
+                Ok(escrow_token)
+            }
+            Err(e) => {
+                warn!("Rejecting the escrow token: {}", e);
+                let rejection = TokenRejected {
+                    escrow_id_hex,
+                    reason: e.to_string(),
+                };
+                self.context
+                    .transport
+                    .send_payload(buyer, &rejection)
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks the seller policy and validates the token of `envelope`, receiving its remaining chunks first.
+    async fn validate_trade_token(
+        &mut self,
+        envelope: EscrowEnvelope,
+    ) -> Result<Token, EscrowError> {
+        self.context
+            .seller_policy
+            .check(&self.context.escrow_contract)?;
+        let escrow_token: Token = if envelope.kind == MessageKind::TokenChunk {
+            self.receive_token_chunks(envelope.open()?).await?
+        } else {
+            envelope.open()?
+        };
+        self.context
+            .ecash_wallet
+            .validate_escrow_token(
+                &escrow_token,
+                &self.context.escrow_contract,
+                &self.escrow_registrations(),
+            )
+            .await?;
         Ok(escrow_token)
     }
+
+    /// Asks the buyer on the terminal whether to lock the funds of the contract, returning the answer.
+    async fn confirm_funding(&self) -> Result<bool, EscrowError> {
+        let contract = &self.context.escrow_contract;
+        let prompt = format!(
+            "Funding the escrow {}\n  trade amount:    {} sat\n  coordinator fee: {} sat\n  mint:            {}\n  seller:          {}\n  expiry:          {}\nLock {} sat in total? (y/n): ",
+            self.escrow_registration.escrow_id_hex,
+            contract.trade_amount_sat,
+            contract.coordinator_fee_sat,
+            contract.mint_url,
+            contract.npubkey_seller.to_bech32().map_err(|e| anyhow!(e))?,
+            contract.expiry.to_human_datetime(),
+            contract.buyer_total_sat()?
+        );
+        loop {
+            match get_user_input(&prompt).await?.to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => warn!("Answer either y or n"),
+            }
+        }
+    }
+
+    /// The registrations of all coordinators, in the order of the contract.
+    fn escrow_registrations(&self) -> Vec<EscrowRegistration> {
+        [
+            vec![self.escrow_registration.clone()],
+            self.additional_registrations.clone(),
+        ]
+        .concat()
+    }
+
+    /// Receives the remaining chunks of a token sent in chunks, starting with `first_chunk`.
+    ///
+    /// All chunks must arrive within the message timeout.
+    async fn receive_token_chunks(
+        &mut self,
+        first_chunk: TokenChunk,
+    ) -> Result<Token, EscrowError> {
+        if first_chunk.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!(
+                "Token chunk of escrow {} instead of escrow {}",
+                first_chunk.escrow_id_hex,
+                self.escrow_registration.escrow_id_hex
+            )
+            .into());
+        }
+        let buyer = self.context.escrow_contract.npubkey_buyer;
+        let deadline = MessageDeadline::after(self.context.message_timeout);
+        let mut chunks = TokenChunks::new(first_chunk.escrow_id_hex.clone(), first_chunk.total);
+        chunks.insert(first_chunk)?;
+        let mut events_seen = 0;
+        while chunks.missing() > 0 {
+            trace!("Waiting for {} more token chunks", chunks.missing());
+            let remaining = deadline.remaining(buyer, events_seen)?;
+            let chunk: TokenChunk = self
+                .context
+                .transport
+                .receive_payload(buyer, remaining)
+                .await?;
+            chunks.insert(chunk)?;
+            events_seen += 1;
+        }
+        chunks.join()
+    }
 }
 
-pub struct TokenExchangedEscrowClient {
-    _nostr_client: NostrClient,
-    _ecash_wallet: ClientEcashWallet,
-    _escrow_contract: TradeContract,
-    trade_mode: TradeMode,
+pub struct TokenExchangedEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    context: EscrowClientContext<T, W>,
+    escrow_registration: EscrowRegistration,
+    additional_registrations: Vec<EscrowRegistration>,
+    escrow_token: Token,
+    /// The escrow token split by milestone, for the seller the released ones include the buyer signatures.
+    milestone_tokens: Vec<Token>,
+    released_milestones: usize,
+    /// The outputs this trader redeems milestones into by their index, with `SIG_ALL` only. For the seller the released
+    /// ones include the buyer signatures.
+    redeem_outputs: BTreeMap<usize, RedeemOutputs>,
+    /// The outputs the seller announced for the next milestone, signed by the buyer with its release.
+    announced_outputs: Option<Vec<BlindedMessage>>,
+    /// Whether the seller received the fee receipt of the coordinator.
+    fee_confirmed: bool,
+    /// The key of the digital goods the seller delivered, released to the buyer once the escrow is released.
+    delivery_key: Option<DeliveryKey>,
+    /// The delivery proof the buyer received while waiting for the digital goods, the seller may send either first.
+    received_delivery_proof: Option<DeliveryProof>,
+    /// The digital goods the buyer received while waiting for the delivery proof.
+    received_delivery_payload: Option<DeliveryPayload>,
 }
 
-impl TokenExchangedEscrowClient {
+impl<T: EscrowTransport, W: EscrowWallet> TokenExchangedEscrowClient<T, W> {
+    /// Waits for the seller to accept the escrow token, failing with [`EscrowError::TokenRejected`] if it doesn't.
+    async fn await_token_acceptance(&mut self) -> Result<(), EscrowError> {
+        let envelope = self
+            .context
+            .transport
+            .receive_envelope_of(
+                self.context.escrow_contract.npubkey_seller,
+                &[MessageKind::TokenAccepted, MessageKind::TokenRejected],
+                self.context.message_timeout,
+            )
+            .await?;
+        let escrow_id_hex = match envelope.kind {
+            MessageKind::TokenAccepted => {
+                let acceptance: TokenAccepted = envelope.open()?;
+                acceptance.escrow_id_hex
+            }
+            MessageKind::TokenRejected => {
+                let rejection: TokenRejected = envelope.open()?;
+                warn!(
+                    "The seller rejected the escrow token, it can be reclaimed after the expiry {}",
+                    self.context.escrow_contract.expiry.to_human_datetime()
+                );
+                return Err(EscrowError::TokenRejected(rejection.reason));
+            }
+            kind => {
+                return Err(anyhow!(
+                    "Expected the seller to accept the escrow token, got a {:?} message",
+                    kind
+                )
+                .into())
+            }
+        };
+        if escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!("Acceptance of the token of escrow {}", escrow_id_hex).into());
+        }
+        debug!("The seller accepted the escrow token");
+        Ok(())
+    }
+
     /// Depending on the trade mode deliver product/service or sign the token after receiving the service.
     ///
-    /// The state after this operation is duties fulfilled.
-    pub async fn do_your_trade_duties(&self) -> anyhow::Result<()> {
-        // todo: as seller send product and proof of delivery (oracle) to seller.
-        // await signature or begin dispute
-
-        // todo: as buyer either send signature or begin dispute
-        match self.trade_mode {
-            TradeMode::Buyer => {
-                trace!("Payed invoince and waiting for delivery...");
+    /// Releases all remaining milestones one after another, the state after this operation is settled. For a contract
+    /// with milestones the seller announces the delivery of each one and the buyer releases it only then.
+    ///
+    /// If the contract names an oracle, the buyer releases only after receiving its delivery proof from the seller.
+    /// For digital goods the seller sends them encrypted before the buyer releases, and their key once all milestones
+    /// are released, the buyer decrypts them then.
+    pub async fn do_your_trade_duties(mut self) -> Result<SettledEscrowClient<T, W>, EscrowError> {
+        if self.context.trade_mode == TradeMode::Seller
+            && self.escrow_registration.coordinator_fee_sat > 0
+            && !self.fee_confirmed
+        {
+            self.verify_fee_paid().await?;
+        }
+        if self.context.trade_mode == TradeMode::Buyer
+            && self.context.escrow_contract.oracle_pubkey.is_some()
+            && self.released_milestones < self.milestone_tokens.len()
+        {
+            self.await_delivery_proof().await?;
+        }
+        let digital_goods = self.context.escrow_contract.digital_goods;
+        if self.context.trade_mode == TradeMode::Seller
+            && digital_goods
+            && self.delivery_key.is_none()
+        {
+            self.deliver_digital_goods().await?;
+        }
+        // a resumed buyer receives the payload again with the replayed messages
+        let delivery_payload = match self.context.trade_mode {
+            TradeMode::Buyer if digital_goods => Some(self.await_delivery_payload().await?),
+            _ => None,
+        };
+        // with SIG_ALL the seller announces each milestone with the outputs the buyer signs when releasing it
+        let staged_delivery = self.milestone_tokens.len() > 1 || self.context.signs_outputs();
+        while self.released_milestones < self.milestone_tokens.len() {
+            match self.context.trade_mode {
+                TradeMode::Buyer => {
+                    trace!("Payed invoince and waiting for delivery...");
+                    if staged_delivery {
+                        self.await_milestone_delivery().await?;
+                    }
+                    self.release_next_milestone().await?;
+                }
+                TradeMode::Seller => {
+                    trace!("Got payment and proceeding with delivery...");
+                    if staged_delivery {
+                        self.announce_milestone_delivery().await?;
+                    }
+                    self.await_next_milestone().await?;
+                }
+            }
+        }
+        let digital_goods = match (&self.delivery_key, delivery_payload) {
+            (Some(delivery_key), _) => {
+                debug!("Releasing the key of the digital goods to the buyer...");
+                self.context
+                    .transport
+                    .send_payload(self.context.escrow_contract.npubkey_buyer, delivery_key)
+                    .await?;
+                None
             }
-            TradeMode::Seller => {
-                trace!("Got payment and proceeding with delivery...");
+            (None, Some(delivery_payload)) => {
+                let goods = delivery_payload.decrypt(&self.await_delivery_key().await?)?;
+                info!("Decrypted {} bytes of digital goods", goods.len());
+                Some(goods)
             }
+            (None, None) => None,
+        };
+        let final_token = match self.context.trade_mode {
+            TradeMode::Buyer => self.escrow_token.clone(),
+            TradeMode::Seller => ClientEcashWallet::join_milestone_tokens(&self.milestone_tokens)?,
+        };
+        let receipt = self
+            .context
+            .issue_receipt(
+                &self.escrow_registration,
+                &self.escrow_token,
+                &final_token,
+                TradeOutcome::Settled,
+            )
+            .await?;
+        self.context.remove_snapshot(&self.escrow_registration)?;
+        self.context.log_transition(
+            "TokenExchanged",
+            "Settled",
+            &self.escrow_registration.escrow_id_hex,
+        );
+        self.context.metrics.record_trade_settled();
+        Ok(SettledEscrowClient {
+            context: self.context,
+            escrow_token: final_token,
+            milestone_tokens: self.milestone_tokens,
+            redeem_outputs: self.redeem_outputs,
+            receipt,
+            digital_goods,
+        })
+    }
+
+    /// Waits as seller for the receipt of the coordinator confirming the buyer paid the coordinator fee.
+    ///
+    /// The seller shouldn't deliver before, fails if no valid receipt arrives within the message timeout.
+    pub async fn verify_fee_paid(&mut self) -> Result<FeeReceipt, EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can verify the fee payment").into());
+        }
+        debug!("Waiting for the fee receipt of the coordinator...");
+        let coordinator = self.context.escrow_contract.npubkey_coordinator;
+        let deadline = MessageDeadline::after(self.context.message_timeout);
+        let mut events_seen = 0;
+        let fee_receipt = loop {
+            let remaining = deadline.remaining(coordinator, events_seen)?;
+            match self
+                .context
+                .transport
+                .receive_coordinator_message(coordinator, remaining)
+                .await?
+            {
+                CoordinatorMessage::FeeReceived(fee_receipt) => break fee_receipt,
+                other => debug!(
+                    "Skipping {:?} message while waiting for the fee receipt",
+                    other.kind()
+                ),
+            }
+            events_seen += 1;
+        };
+        fee_receipt.verify(&self.escrow_registration, &coordinator)?;
+        info!(
+            "Coordinator confirmed the fee of {} sat for {}",
+            fee_receipt.fee_sat, fee_receipt.escrow_id_hex
+        );
+        self.fee_confirmed = true;
+        self.save_snapshot()?;
+        Ok(fee_receipt)
+    }
+
+    /// Sends the delivery proof of the oracle as seller to the buyer and the coordinators.
+    pub async fn submit_delivery_proof(
+        &self,
+        delivery_proof: &DeliveryProof,
+    ) -> Result<(), EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can submit a delivery proof").into());
+        }
+        debug!("Sending delivery proof to buyer and coordinators...");
+        let escrow_contract = &self.context.escrow_contract;
+        for receiver in [
+            vec![escrow_contract.npubkey_buyer],
+            escrow_contract.coordinators(),
+        ]
+        .concat()
+        {
+            self.context
+                .transport
+                .send_payload(receiver, delivery_proof)
+                .await?;
         }
         Ok(())
     }
+
+    /// Waits as buyer for the delivery proof of the contract oracle, sent by the seller.
+    pub async fn await_delivery_proof(&mut self) -> Result<DeliveryProof, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can await a delivery proof").into());
+        }
+        let oracle_pubkey = self
+            .context
+            .escrow_contract
+            .oracle_pubkey
+            .ok_or_else(|| anyhow!("Contract names no oracle"))?;
+        self.await_delivery_message(MessageKind::DeliveryProof)
+            .await?;
+        let delivery_proof = self
+            .received_delivery_proof
+            .take()
+            .expect("Delivery proof is received");
+        if delivery_proof.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!(
+                "Received delivery proof for unknown escrow {}",
+                delivery_proof.escrow_id_hex
+            )
+            .into());
+        }
+        if delivery_proof.oracle_pubkey != oracle_pubkey {
+            return Err(anyhow!(
+                "Delivery proof of {} instead of the contract oracle {}",
+                delivery_proof.oracle_pubkey,
+                oracle_pubkey
+            )
+            .into());
+        }
+        delivery_proof.verify()?;
+        info!("Oracle {} attested the delivery", oracle_pubkey);
+        Ok(delivery_proof)
+    }
+
+    /// Sends the digital goods encrypted as seller to the buyer, keeping their key until the escrow is released.
+    pub async fn deliver_digital_goods(&mut self) -> Result<(), EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can deliver digital goods").into());
+        }
+        let goods = self
+            .context
+            .digital_goods
+            .as_ref()
+            .ok_or_else(|| anyhow!("No digital goods to deliver"))?;
+        let (delivery_payload, delivery_key) = DeliveryPayload::encrypt(
+            self.escrow_registration.escrow_id_hex.clone(),
+            goods,
+            rand::thread_rng().gen(),
+        )?;
+        // saved before sending, so a resumed seller releases the key of the goods the buyer received
+        self.delivery_key = Some(delivery_key);
+        self.save_snapshot()?;
+        debug!(
+            "Sending {} bytes of digital goods to the buyer...",
+            goods.len()
+        );
+        self.context
+            .transport
+            .send_payload(
+                self.context.escrow_contract.npubkey_buyer,
+                &delivery_payload,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Waits as buyer for the encrypted digital goods of the seller.
+    pub async fn await_delivery_payload(&mut self) -> Result<DeliveryPayload, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can await digital goods").into());
+        }
+        self.await_delivery_message(MessageKind::DeliveryPayload)
+            .await?;
+        let delivery_payload = self
+            .received_delivery_payload
+            .take()
+            .expect("Delivery payload is received");
+        if delivery_payload.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!(
+                "Received digital goods for unknown escrow {}",
+                delivery_payload.escrow_id_hex
+            )
+            .into());
+        }
+        info!("Received the encrypted digital goods of the seller");
+        Ok(delivery_payload)
+    }
+
+    /// Waits as buyer for the delivery proof or the digital goods of the seller, whichever `kind` names, keeping the
+    /// other one should the seller send it first.
+    async fn await_delivery_message(&mut self, kind: MessageKind) -> Result<(), EscrowError> {
+        let seller = self.context.escrow_contract.npubkey_seller;
+        let deadline = MessageDeadline::after(self.context.message_timeout);
+        let mut events_seen = 0;
+        loop {
+            let received = match kind {
+                MessageKind::DeliveryProof => self.received_delivery_proof.is_some(),
+                _ => self.received_delivery_payload.is_some(),
+            };
+            if received {
+                return Ok(());
+            }
+            let remaining = deadline.remaining(seller, events_seen)?;
+            let envelope = self
+                .context
+                .transport
+                .receive_envelope_of(
+                    seller,
+                    &[MessageKind::DeliveryProof, MessageKind::DeliveryPayload],
+                    remaining,
+                )
+                .await?;
+            if envelope.kind == MessageKind::DeliveryProof {
+                self.received_delivery_proof = Some(envelope.open()?);
+            } else {
+                self.received_delivery_payload = Some(envelope.open()?);
+            }
+            events_seen += 1;
+        }
+    }
+
+    /// Waits as buyer for the key of the digital goods, released by the seller after the escrow.
+    async fn await_delivery_key(&mut self) -> Result<DeliveryKey, EscrowError> {
+        debug!("Waiting for the key of the digital goods...");
+        self.context
+            .transport
+            .receive_envelope_of(
+                self.context.escrow_contract.npubkey_seller,
+                &[MessageKind::DeliveryKey],
+                self.context.message_timeout,
+            )
+            .await?
+            .open()
+    }
+
+    /// The escrow token sent by the buyer, as validated by the seller.
+    pub fn escrow_token(&self) -> &Token {
+        &self.escrow_token
+    }
+
+    /// Number of milestones of the contract, released one after another.
+    pub fn milestone_count(&self) -> usize {
+        self.milestone_tokens.len()
+    }
+
+    pub fn released_milestones(&self) -> usize {
+        self.released_milestones
+    }
+
+    /// Tells the buyer as seller that the next milestone is delivered, after confirming it on the terminal if
+    /// [`InitEscrowClient::with_milestone_confirmation`] is set.
+    ///
+    /// With `SIG_ALL` the announcement carries the outputs the seller redeems the milestone into.
+    ///
+    /// Returns the index of the delivered milestone.
+    pub async fn announce_milestone_delivery(&mut self) -> Result<usize, EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can announce a milestone delivery").into());
+        }
+        let milestone = self.released_milestones;
+        if milestone >= self.milestone_tokens.len() {
+            return Err(anyhow!("All milestones are released already").into());
+        }
+        if self.context.confirm_milestones {
+            get_user_input(&format!(
+                "Press enter once milestone {} of {} is delivered: ",
+                milestone + 1,
+                self.milestone_tokens.len()
+            ))
+            .await?;
+        }
+        let redeem_outputs = match self.context.signs_outputs() {
+            true => self.prepare_redeem_outputs(milestone).await?,
+            false => Vec::new(),
+        };
+        debug!("Announcing the delivery of milestone {}...", milestone);
+        self.context
+            .transport
+            .send_payload(
+                self.context.escrow_contract.npubkey_buyer,
+                &MilestoneDelivered {
+                    escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+                    milestone,
+                    redeem_outputs,
+                },
+            )
+            .await?;
+        Ok(milestone)
+    }
+
+    /// The outputs this trader redeems `milestone` into, created on first use.
+    async fn prepare_redeem_outputs(
+        &mut self,
+        milestone: usize,
+    ) -> Result<Vec<BlindedMessage>, EscrowError> {
+        if !self.redeem_outputs.contains_key(&milestone) {
+            let redeem_outputs = self
+                .context
+                .ecash_wallet
+                .create_redeem_outputs(&self.milestone_tokens[milestone])
+                .await?;
+            self.redeem_outputs.insert(milestone, redeem_outputs);
+            // saved before they are sent, so a resumed trader sends the same outputs again
+            self.save_snapshot()?;
+        }
+        Ok(self.redeem_outputs[&milestone].outputs.clone())
+    }
+
+    /// Waits as buyer for the seller to deliver the next milestone, skipping the announcements of released ones, and
+    /// asks on the terminal whether to release it if [`InitEscrowClient::with_milestone_confirmation`] is set.
+    ///
+    /// Fails if the buyer withholds the release, the trade can be resumed or disputed then.
+    pub async fn await_milestone_delivery(&mut self) -> Result<usize, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can await a milestone delivery").into());
+        }
+        let milestone = self.released_milestones;
+        let milestone_amount = *self
+            .context
+            .escrow_contract
+            .milestone_amounts()?
+            .get(milestone)
+            .ok_or_else(|| anyhow!("All milestones are released already"))?;
+        let seller = self.context.escrow_contract.npubkey_seller;
+        let deadline = MessageDeadline::after(self.context.message_timeout);
+        let mut events_seen = 0;
+        let redeem_outputs = loop {
+            let remaining = deadline.remaining(seller, events_seen)?;
+            let delivered: MilestoneDelivered = self
+                .context
+                .transport
+                .receive_envelope_of(seller, &[MessageKind::MilestoneDelivered], remaining)
+                .await?
+                .open()?;
+            events_seen += 1;
+            if delivered.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+                return Err(anyhow!(
+                    "Received milestone delivery for unknown escrow {}",
+                    delivered.escrow_id_hex
+                )
+                .into());
+            }
+            match delivered.milestone.cmp(&milestone) {
+                Ordering::Less => debug!(
+                    "Skipping delivery of released milestone {}",
+                    delivered.milestone
+                ),
+                Ordering::Equal => break delivered.redeem_outputs,
+                Ordering::Greater => {
+                    return Err(anyhow!(
+                        "Seller delivered milestone {} before milestone {}",
+                        delivered.milestone,
+                        milestone
+                    )
+                    .into())
+                }
+            }
+        };
+        self.announced_outputs = Some(redeem_outputs);
+        info!(
+            "Seller delivered milestone {} of {}",
+            milestone + 1,
+            self.milestone_tokens.len()
+        );
+        if self.context.confirm_milestones {
+            let prompt = format!(
+                "Release {} sat for milestone {}? (y/n): ",
+                milestone_amount,
+                milestone + 1
+            );
+            loop {
+                match get_user_input(&prompt).await?.to_lowercase().as_str() {
+                    "y" | "yes" => break,
+                    "n" | "no" => {
+                        return Err(anyhow!(
+                            "Withheld the release of milestone {}, resume the trade to release it or open a dispute",
+                            milestone + 1
+                        )
+                        .into())
+                    }
+                    _ => warn!("Answer either y or n"),
+                }
+            }
+        }
+        Ok(milestone)
+    }
+
+    /// Signs the escrow token proofs of the next milestone as buyer and sends the signatures to the seller.
+    ///
+    /// With `SIG_ALL` the buyer signs the outputs the seller announced for the milestone as well, see
+    /// [`Self::await_milestone_delivery`].
+    ///
+    /// Returns the index of the released milestone.
+    pub async fn release_next_milestone(&mut self) -> Result<usize, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can release a milestone").into());
+        }
+        let milestone = self.released_milestones;
+        let output_signatures = match self.context.signs_outputs() {
+            true => {
+                let outputs = self.announced_outputs.take().ok_or_else(|| {
+                    anyhow!(
+                        "The seller announced no outputs for milestone {}",
+                        milestone
+                    )
+                })?;
+                self.context.ecash_wallet.sign_redeem_outputs(&outputs)?
+            }
+            false => Vec::new(),
+        };
+        let milestone_token = self
+            .milestone_tokens
+            .get(milestone)
+            .ok_or_else(|| anyhow!("All milestones are released already"))?;
+        let release_signature = TokenReleaseSignature {
+            escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+            milestone,
+            signatures: self
+                .context
+                .ecash_wallet
+                .sign_escrow_token(milestone_token)?,
+            output_signatures,
+        };
+        debug!(
+            "Sending release signature of milestone {} to the seller...",
+            milestone
+        );
+        self.context
+            .transport
+            .send_payload(
+                self.context.escrow_contract.npubkey_seller,
+                &release_signature,
+            )
+            .await?;
+        self.released_milestones += 1;
+        self.save_snapshot()?;
+        info!(
+            "Released milestone {} of {}",
+            self.released_milestones,
+            self.milestone_tokens.len()
+        );
+        Ok(milestone)
+    }
+
+    /// Waits as seller for the release signatures of the next milestone from the buyer.
+    ///
+    /// Returns the index of the released milestone.
+    pub async fn await_next_milestone(&mut self) -> Result<usize, EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can await a milestone release").into());
+        }
+        let milestone = self.released_milestones;
+        if milestone >= self.milestone_tokens.len() {
+            return Err(anyhow!("All milestones are released already").into());
+        }
+        // skips the other messages of the buyer, e.g. replayed token chunks of a resumed seller
+        let release_signature: TokenReleaseSignature = self
+            .context
+            .transport
+            .receive_envelope_of(
+                self.context.escrow_contract.npubkey_buyer,
+                &[MessageKind::TokenReleaseSignature],
+                self.context.message_timeout,
+            )
+            .await?
+            .open()?;
+        if release_signature.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!(
+                "Received release signature for unknown escrow {}",
+                release_signature.escrow_id_hex
+            )
+            .into());
+        }
+        if release_signature.milestone != milestone {
+            return Err(anyhow!(
+                "Received release signature for milestone {} instead of milestone {}",
+                release_signature.milestone,
+                milestone
+            )
+            .into());
+        }
+        let buyer_pubkey =
+            EcashPubkey::from_str(&self.context.escrow_contract.buyer_ecash_public_key)?;
+        let signed_token = ClientEcashWallet::add_release_signatures(
+            &self.milestone_tokens[milestone],
+            &buyer_pubkey,
+            &release_signature.signatures,
+        )?;
+        if self.context.signs_outputs() {
+            let mut redeem_outputs =
+                self.redeem_outputs
+                    .get(&milestone)
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow!("No redeem outputs announced for milestone {}", milestone)
+                    })?;
+            redeem_outputs.add_signatures(&buyer_pubkey, &release_signature.output_signatures)?;
+            self.redeem_outputs.insert(milestone, redeem_outputs);
+        }
+        self.milestone_tokens[milestone] = signed_token;
+        self.released_milestones += 1;
+        self.save_snapshot()?;
+        info!(
+            "Received valid release signature of milestone {} of {} from the buyer",
+            self.released_milestones,
+            self.milestone_tokens.len()
+        );
+        Ok(milestone)
+    }
+
+    fn save_snapshot(&self) -> Result<(), EscrowError> {
+        self.context.save_snapshot(
+            &self.escrow_registration,
+            &self.additional_registrations,
+            SnapshotState::TokenExchanged {
+                escrow_token: self.escrow_token.to_string(),
+                released_milestone_tokens: self.milestone_tokens[..self.released_milestones]
+                    .iter()
+                    .map(Token::to_string)
+                    .collect(),
+                redeem_outputs: self.redeem_outputs.clone(),
+                fee_confirmed: self.fee_confirmed,
+                delivery_key: self.delivery_key.clone(),
+            },
+        )
+    }
+
+    /// Sweeps the unreleased milestones of the escrow token back into the buyer wallet once the contract expired,
+    /// returning the reclaimed amount.
+    ///
+    /// Fails with [`EscrowError::LocktimeNotReached`] before the contract expiry, as the mint rejects the refund until then.
+    /// A token refundable to a separate refund key can't be reclaimed here, only by the holder of the refund key.
+    ///
+    /// With `SIG_ALL` the outputs of the refund are signed by the refund key alone. Mints checking the output signatures
+    /// against the escrow keys only, like the cdk 0.4 mint, reject such a refund.
+    pub async fn reclaim_after_timeout(self) -> Result<Amount, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can reclaim the escrow token").into());
+        }
+        let refund_pubkey = self.context.escrow_contract.buyer_refund_public_key();
+        if refund_pubkey != self.context.ecash_wallet.trade_pubkey() {
+            return Err(anyhow!(
+                "The escrow token is refunded to the key {}, reclaim it with the wallet of that key",
+                refund_pubkey
+            )
+            .into());
+        }
+        let locktime = self.context.escrow_contract.expiry;
+        if Timestamp::now() <= locktime {
+            return Err(EscrowError::LocktimeNotReached(locktime));
+        }
+        let unreleased_token = ClientEcashWallet::join_milestone_tokens(
+            &self.milestone_tokens[self.released_milestones..],
+        )
+        .map_err(|_| anyhow!("All milestones are released, nothing to reclaim"))?;
+        let wallet = &self.context.ecash_wallet;
+        let amount = match self.context.signs_outputs() {
+            true => {
+                let redeem_outputs = wallet.create_redeem_outputs(&unreleased_token).await?;
+                wallet
+                    .redeem_escrow_token_into(&unreleased_token, &redeem_outputs)
+                    .await?
+            }
+            false => wallet.redeem_escrow_token(&unreleased_token).await?,
+        };
+        self.context
+            .issue_receipt(
+                &self.escrow_registration,
+                &self.escrow_token,
+                &unreleased_token,
+                TradeOutcome::Reclaimed,
+            )
+            .await?;
+        self.context.remove_snapshot(&self.escrow_registration)?;
+        self.context.log_transition(
+            "TokenExchanged",
+            "Reclaimed",
+            &self.escrow_registration.escrow_id_hex,
+        );
+        Ok(amount)
+    }
+
+    /// Opens a dispute as buyer, notifying the coordinators and the seller.
+    ///
+    /// The state after this is disputed.
+    pub async fn begin_dispute(
+        mut self,
+        reason: String,
+    ) -> Result<DisputedEscrowClient<T, W>, EscrowError> {
+        if self.context.trade_mode != TradeMode::Buyer {
+            return Err(anyhow!("Only the buyer can begin a dispute").into());
+        }
+        let dispute_claim = DisputeClaim {
+            escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+            claimant: self.context.transport.public_key(),
+            reason,
+            escrow_token: Some(self.escrow_token.to_string()),
+            redeem_outputs: self.claim_redeem_outputs().await?,
+        };
+        debug!("Sending dispute claim to coordinators and seller...");
+        let escrow_contract = &self.context.escrow_contract;
+        for receiver in [
+            escrow_contract.coordinators(),
+            vec![escrow_contract.npubkey_seller],
+        ]
+        .concat()
+        {
+            self.context
+                .transport
+                .send_payload(receiver, &dispute_claim)
+                .await?;
+        }
+        self.context.metrics.record_dispute_opened();
+        self.into_disputed(dispute_claim)
+    }
+
+    /// Waits as seller for the dispute claim of the buyer and answers it to the coordinators.
+    ///
+    /// The state after this is disputed.
+    pub async fn respond_to_dispute(
+        mut self,
+        response: String,
+        timeout: Option<Duration>,
+    ) -> Result<DisputedEscrowClient<T, W>, EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can respond to a dispute").into());
+        }
+        let buyer_claim: DisputeClaim = self
+            .context
+            .transport
+            .receive_payload(self.context.escrow_contract.npubkey_buyer, timeout)
+            .await?;
+        if buyer_claim.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+            return Err(anyhow!(
+                "Received dispute claim for unknown escrow {}",
+                buyer_claim.escrow_id_hex
+            )
+            .into());
+        }
+        debug!("Buyer opened a dispute: {}", buyer_claim.reason);
+
+        let dispute_response = DisputeClaim {
+            escrow_id_hex: self.escrow_registration.escrow_id_hex.clone(),
+            claimant: self.context.transport.public_key(),
+            reason: response,
+            escrow_token: Some(self.escrow_token.to_string()),
+            redeem_outputs: self.claim_redeem_outputs().await?,
+        };
+        for coordinator in self.context.escrow_contract.coordinators() {
+            self.context
+                .transport
+                .send_payload(coordinator, &dispute_response)
+                .await?;
+        }
+        self.into_disputed(dispute_response)
+    }
+
+    /// The outputs of the unreleased milestones for the dispute claim, the coordinators sign the ones they award to this
+    /// trader. Empty unless the escrow is locked with `SIG_ALL`.
+    async fn claim_redeem_outputs(
+        &mut self,
+    ) -> Result<BTreeMap<usize, Vec<BlindedMessage>>, EscrowError> {
+        let mut claimed = BTreeMap::new();
+        if self.context.signs_outputs() {
+            for milestone in self.released_milestones..self.milestone_tokens.len() {
+                claimed.insert(milestone, self.prepare_redeem_outputs(milestone).await?);
+            }
+        }
+        Ok(claimed)
+    }
+
+    fn into_disputed(
+        self,
+        dispute_claim: DisputeClaim,
+    ) -> Result<DisputedEscrowClient<T, W>, EscrowError> {
+        let disputed = DisputedEscrowClient {
+            context: self.context,
+            escrow_registration: self.escrow_registration,
+            additional_registrations: self.additional_registrations,
+            escrow_token: self.escrow_token,
+            milestone_tokens: self.milestone_tokens,
+            released_milestones: self.released_milestones,
+            redeem_outputs: self.redeem_outputs,
+            dispute_claim,
+        };
+        disputed.save_snapshot()?;
+        disputed.context.log_transition(
+            "TokenExchanged",
+            "Disputed",
+            &disputed.escrow_registration.escrow_id_hex,
+        );
+        Ok(disputed)
+    }
+}
+
+pub struct DisputedEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    context: EscrowClientContext<T, W>,
+    escrow_registration: EscrowRegistration,
+    additional_registrations: Vec<EscrowRegistration>,
+    escrow_token: Token,
+    /// The escrow token split by milestone, for the seller the released ones include the buyer signatures.
+    milestone_tokens: Vec<Token>,
+    released_milestones: usize,
+    /// The outputs this trader redeems milestones into by their index, with `SIG_ALL` only.
+    redeem_outputs: BTreeMap<usize, RedeemOutputs>,
+    dispute_claim: DisputeClaim,
+}
+
+impl<T: EscrowTransport, W: EscrowWallet> DisputedEscrowClient<T, W> {
+    /// The claim this trader sent to the coordinators.
+    pub fn dispute_claim(&self) -> &DisputeClaim {
+        &self.dispute_claim
+    }
+
+    /// Waits for the arbitration decisions of the coordinators, until the coordinator threshold of the contract decided
+    /// alike, and returns the agreeing resolutions.
+    ///
+    /// The coordinators are awaited in the order of the contract, all within `timeout`, forever if `None`.
+    pub async fn await_resolution(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<DisputeResolution>, EscrowError> {
+        let coordinators = self.context.escrow_contract.coordinators();
+        let threshold = self.context.escrow_contract.coordinator_threshold() as usize;
+        let deadline = MessageDeadline::after(timeout);
+        let mut resolutions: Vec<DisputeResolution> = Vec::new();
+        for coordinator in &coordinators {
+            let remaining = deadline.remaining(*coordinator, resolutions.len())?;
+            let resolution = match self
+                .context
+                .transport
+                .receive_coordinator_message(*coordinator, remaining)
+                .await?
+            {
+                CoordinatorMessage::DisputeResolution(resolution) => resolution,
+                other => {
+                    return Err(anyhow!(
+                        "Expected a dispute resolution of {}, got a {:?} message",
+                        coordinator,
+                        other.kind()
+                    )
+                    .into())
+                }
+            };
+            if resolution.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+                return Err(anyhow!(
+                    "Received dispute resolution for unknown escrow {}",
+                    resolution.escrow_id_hex
+                )
+                .into());
+            }
+            resolution.verify(coordinator)?;
+            debug!(
+                "Coordinator {} decided dispute: {:?}",
+                coordinator, resolution.decision
+            );
+            let decision = resolution.decision;
+            resolutions.push(resolution);
+            let agreeing: Vec<DisputeResolution> = resolutions
+                .iter()
+                .filter(|resolution| resolution.decision == decision)
+                .cloned()
+                .collect();
+            if agreeing.len() >= threshold {
+                return Ok(agreeing);
+            }
+        }
+        Err(anyhow!(
+            "The coordinators did not decide the dispute alike, {} agreeing decisions are needed",
+            threshold
+        )
+        .into())
+    }
+
+    /// Redeems the milestones the coordinators awarded to this trader into its wallet, returning the received amount.
+    ///
+    /// `resolutions` must decide alike and be signed by at least the coordinator threshold of distinct contract
+    /// coordinators, e.g. as returned by [`Self::await_resolution`]. The seller keeps the milestones the buyer released
+    /// before the dispute, the buyer gets back the other milestones not awarded to the seller. The awarded proofs are
+    /// spent with the trade key and the escrow signatures of the coordinators. With `SIG_ALL` the coordinators sign the
+    /// outputs this trader claimed for the awarded milestones as well.
+    pub async fn apply_resolution(
+        self,
+        resolutions: &[DisputeResolution],
+    ) -> Result<Amount, EscrowError> {
+        let decision = resolutions
+            .first()
+            .ok_or_else(|| anyhow!("No dispute resolution to apply"))?
+            .decision;
+        let coordinators = self.context.escrow_contract.coordinators();
+        let escrow_registrations = [
+            vec![self.escrow_registration.clone()],
+            self.additional_registrations.clone(),
+        ]
+        .concat();
+        let milestone_count = self.milestone_tokens.len();
+        let seller_milestones = decision.seller_milestones(milestone_count);
+        // the milestones signed by the coordinators for this trader, and the ones it receives
+        let (signed, awarded) = match self.context.trade_mode {
+            TradeMode::Seller => (
+                0..seller_milestones,
+                0..seller_milestones.max(self.released_milestones),
+            ),
+            TradeMode::Buyer => (
+                seller_milestones..milestone_count,
+                seller_milestones.max(self.released_milestones)..milestone_count,
+            ),
+        };
+
+        let mut milestone_tokens = self.milestone_tokens.clone();
+        let mut redeem_outputs = self.redeem_outputs.clone();
+        let mut signers: Vec<NostrPubkey> = Vec::new();
+        for resolution in resolutions {
+            if resolution.escrow_id_hex != self.escrow_registration.escrow_id_hex {
+                return Err(anyhow!(
+                    "Dispute resolution for unknown escrow {}",
+                    resolution.escrow_id_hex
+                )
+                .into());
+            }
+            if resolution.decision != decision {
+                return Err(anyhow!(
+                    "Dispute resolutions decide {:?} and {:?}",
+                    decision,
+                    resolution.decision
+                )
+                .into());
+            }
+            let (coordinator, registration) = coordinators
+                .iter()
+                .zip(&escrow_registrations)
+                .find(|(coordinator, _)| resolution.verify(coordinator).is_ok())
+                .ok_or_else(|| {
+                    anyhow!("Dispute resolution not signed by a contract coordinator")
+                })?;
+            if signers.contains(coordinator) {
+                return Err(anyhow!("Got two dispute resolutions of {}", coordinator).into());
+            }
+            signers.push(*coordinator);
+            let signed_tokens = ClientEcashWallet::add_milestone_signatures(
+                &milestone_tokens[signed.clone()],
+                &registration.coordinator_escrow_pubkey,
+                &resolution.token_signatures,
+            )?;
+            milestone_tokens.splice(signed.clone(), signed_tokens);
+            if self.context.signs_outputs() {
+                let claimed = &self.dispute_claim.redeem_outputs;
+                RedeemOutputs::add_milestone_signatures(
+                    redeem_outputs
+                        .iter_mut()
+                        .filter(|(milestone, _)| {
+                            signed.contains(milestone) && claimed.contains_key(milestone)
+                        })
+                        .map(|(_, outputs)| outputs),
+                    &registration.coordinator_escrow_pubkey,
+                    &resolution.output_signatures,
+                )?;
+            }
+        }
+        let threshold = self.context.escrow_contract.coordinator_threshold() as usize;
+        if signers.len() < threshold {
+            return Err(anyhow!(
+                "Got {} dispute resolutions, the contract needs {} agreeing coordinators",
+                signers.len(),
+                threshold
+            )
+            .into());
+        }
+        info!(
+            "Applying the dispute resolution {:?}, {} of {} milestones go to this trader",
+            decision,
+            awarded.len(),
+            milestone_count
+        );
+
+        let amount = if awarded.is_empty() {
+            Amount::ZERO
+        } else {
+            let awarded_token =
+                ClientEcashWallet::join_milestone_tokens(&milestone_tokens[awarded.clone()])?;
+            let amount = self
+                .context
+                .redeem_milestones(&milestone_tokens, &redeem_outputs, awarded)
+                .await?;
+            self.context
+                .issue_receipt(
+                    &self.escrow_registration,
+                    &self.escrow_token,
+                    &awarded_token,
+                    TradeOutcome::Resolved,
+                )
+                .await?;
+            self.context.emit(TradeEvent::Redeemed {
+                escrow_id: self.escrow_registration.escrow_id_hex.clone(),
+                trade_mode: self.context.trade_mode,
+                amount_sat: amount.into(),
+            });
+            amount
+        };
+        self.context.remove_snapshot(&self.escrow_registration)?;
+        self.context.log_transition(
+            "Disputed",
+            "Resolved",
+            &self.escrow_registration.escrow_id_hex,
+        );
+        Ok(amount)
+    }
+
+    fn save_snapshot(&self) -> Result<(), EscrowError> {
+        self.context.save_snapshot(
+            &self.escrow_registration,
+            &self.additional_registrations,
+            SnapshotState::Disputed {
+                escrow_token: self.escrow_token.to_string(),
+                released_milestone_tokens: self.milestone_tokens[..self.released_milestones]
+                    .iter()
+                    .map(Token::to_string)
+                    .collect(),
+                redeem_outputs: self.redeem_outputs.clone(),
+                dispute_claim: self.dispute_claim.clone(),
+            },
+        )
+    }
+}
+
+pub struct SettledEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    context: EscrowClientContext<T, W>,
+    escrow_token: Token,
+    /// The escrow token split by milestone, for the seller including the buyer signatures.
+    milestone_tokens: Vec<Token>,
+    /// The outputs the seller redeems the milestones into, signed by the buyer, with `SIG_ALL` only.
+    redeem_outputs: BTreeMap<usize, RedeemOutputs>,
+    receipt: TradeReceipt,
+    digital_goods: Option<Vec<u8>>,
+}
+
+impl<T: EscrowTransport, W: EscrowWallet> SettledEscrowClient<T, W> {
+    /// The escrow token, for the seller including the release signatures of the buyer.
+    pub fn escrow_token(&self) -> &Token {
+        &self.escrow_token
+    }
+
+    /// The outputs the seller redeems each milestone into by its index, including the buyer signatures. Empty unless
+    /// the escrow token is locked with `SIG_ALL`.
+    pub fn redeem_outputs(&self) -> &BTreeMap<usize, RedeemOutputs> {
+        &self.redeem_outputs
+    }
+
+    /// The receipt of the settled trade, signed by this trader.
+    pub fn receipt(&self) -> &TradeReceipt {
+        &self.receipt
+    }
+
+    /// The digital goods the buyer received, decrypted with the key the seller released.
+    pub fn digital_goods(&self) -> Option<&[u8]> {
+        self.digital_goods.as_deref()
+    }
+
+    /// The wallet the escrow token is redeemed into, e.g. to pay the funds out afterwards.
+    pub fn ecash_wallet(&self) -> &W {
+        &self.context.ecash_wallet
+    }
+
+    /// Redeems the released escrow token into the seller wallet, returning the received amount.
+    pub async fn redeem_escrow_token(&self) -> Result<Amount, EscrowError> {
+        if self.context.trade_mode != TradeMode::Seller {
+            return Err(anyhow!("Only the seller can redeem the escrow token").into());
+        }
+        let amount = self
+            .context
+            .redeem_milestones(
+                &self.milestone_tokens,
+                &self.redeem_outputs,
+                0..self.milestone_tokens.len(),
+            )
+            .await?;
+        self.context.emit(TradeEvent::Redeemed {
+            escrow_id: self.receipt.content.escrow_id_hex.clone(),
+            trade_mode: self.context.trade_mode,
+            amount_sat: amount.into(),
+        });
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dry_run::{MockNetwork, MockWallet};
+    use cashu_escrow_common::model::DEFAULT_REQUIRED_SIGNATURES;
+    use cdk::{
+        mint_url::MintUrl,
+        nuts::{CurrencyUnit, SecretKey, SigFlag},
+    };
+    use nostr_sdk::Keys;
+    use serde_json::json;
+
+    struct Trader {
+        keys: Keys,
+        wallet: MockWallet,
+    }
+
+    impl Trader {
+        fn new() -> Self {
+            Self {
+                keys: Keys::generate(),
+                wallet: MockWallet::new(Amount::from(10_000)),
+            }
+        }
+    }
+
+    fn contract(seller: &Trader, buyer: &Trader, expiry: Timestamp) -> TradeContract {
+        TradeContract {
+            trade_description: "Purchase of one watermelon".to_string(),
+            trade_amount_sat: 5000,
+            coordinator_fee_sat: 50,
+            unit: CurrencyUnit::Sat,
+            mint_url: MintUrl::from_str("https://mint.example.com").unwrap(),
+            npubkey_seller: seller.keys.public_key(),
+            npubkey_buyer: buyer.keys.public_key(),
+            npubkey_coordinator: Keys::generate().public_key(),
+            expiry,
+            seller_ecash_public_key: seller.wallet.trade_pubkey().to_string(),
+            buyer_ecash_public_key: buyer.wallet.trade_pubkey().to_string(),
+            buyer_refund_public_key: None,
+            milestones: Vec::new(),
+            oracle_pubkey: None,
+            required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+            sig_flag: SigFlag::SigAll,
+            additional_coordinators: Vec::new(),
+            coordinator_threshold: None,
+            fiat_price: None,
+            digital_goods: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn register_trade_refuses_expired_contract() {
+        let (seller, buyer) = (Trader::new(), Trader::new());
+        let expiry = Timestamp::now() - Duration::from_secs(60);
+        let contract = contract(&seller, &buyer, expiry);
+        let network = MockNetwork::default();
+
+        let result = InitEscrowClient::new(
+            network.transport(buyer.keys),
+            buyer.wallet,
+            contract,
+            TradeMode::Buyer,
+        )
+        .register_trade()
+        .await;
+        assert!(
+            matches!(result, Err(EscrowError::ContractExpired(at)) if at == expiry),
+            "registered an expired contract"
+        );
+    }
+
+    #[test]
+    fn registration_rejects_trader_and_garbage_coordinator_keys() {
+        let (seller, buyer) = (Trader::new(), Trader::new());
+        let contract = contract(
+            &seller,
+            &buyer,
+            Timestamp::now() + Duration::from_secs(60 * 60),
+        );
+        let registration = |coordinator_escrow_pubkey| {
+            EscrowRegistration::new(
+                contract.escrow_id().unwrap().to_lower_hex_string(),
+                coordinator_escrow_pubkey,
+                Timestamp::now(),
+                contract.coordinator_fee_sat,
+                "nonce".to_string(),
+            )
+        };
+        let verify = |registration: &EscrowRegistration| {
+            verify_registration(
+                &contract,
+                registration,
+                contract.coordinator_fee_sat,
+                Timestamp::now(),
+            )
+        };
+
+        verify(&registration(SecretKey::generate().public_key())).unwrap();
+        for trader_pubkey in [seller.wallet.trade_pubkey(), buyer.wallet.trade_pubkey()] {
+            let result = verify(&registration(EcashPubkey::from_hex(trader_pubkey).unwrap()));
+            assert!(
+                matches!(result, Err(EscrowError::InvalidRegistration(_))),
+                "accepted the trader key {}: {:?}",
+                trader_pubkey,
+                result
+            );
+        }
+
+        let mut received =
+            serde_json::to_value(registration(SecretKey::generate().public_key())).unwrap();
+        let uncompressed_pubkey = format!("04{}", "11".repeat(64));
+        let off_curve_pubkey = format!("02{}", "00".repeat(32));
+        for garbage_pubkey in ["garbage", &uncompressed_pubkey, &off_curve_pubkey] {
+            received["coordinator_escrow_pubkey"] = json!(garbage_pubkey);
+            assert!(
+                serde_json::from_value::<EscrowRegistration>(received.clone()).is_err(),
+                "parsed the coordinator pubkey {}",
+                garbage_pubkey
+            );
+        }
+    }
 }
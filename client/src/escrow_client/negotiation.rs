@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+
+use super::*;
+
+/// Rounds of proposals after which a negotiation fails, if the traders don't agree before.
+pub const DEFAULT_MAX_NEGOTIATION_ROUNDS: u32 = 5;
+
+/// The answer of a trader to the contract proposed by the trade partner.
+#[derive(Debug, Clone)]
+pub enum ProposalResponse {
+    Accept,
+    /// Proposes other amounts, another expiry or another mint.
+    Counter(Box<TradeContract>),
+    Reject(String),
+}
+
+/// Decides on the contract proposals of the trade partner, e.g. by asking the user.
+#[async_trait]
+pub trait ContractResponder: Send {
+    async fn respond(&mut self, proposal: &TradeContract) -> Result<ProposalResponse, EscrowError>;
+}
+
+/// Accepts a proposal complying with the policy and rejects any other.
+#[async_trait]
+impl ContractResponder for SellerPolicy {
+    async fn respond(&mut self, proposal: &TradeContract) -> Result<ProposalResponse, EscrowError> {
+        Ok(match self.check(proposal) {
+            Ok(()) => ProposalResponse::Accept,
+            Err(e) => ProposalResponse::Reject(e.to_string()),
+        })
+    }
+}
+
+/// Negotiates the contract with the trade partner before the registration, returning the contract both agreed on.
+///
+/// With an `initial_proposal` this trader opens the negotiation, else it waits for the proposal of the partner.
+/// Every counter proposal may only change the amounts, the expiry and the mint. Fails if the partner rejects a
+/// proposal or the traders don't agree within `max_rounds` proposals.
+///
+/// The agreed contract is then registered with [`InitEscrowClient::new`] as usual.
+pub async fn negotiate_contract(
+    transport: &mut impl EscrowTransport,
+    partner: NostrPubkey,
+    initial_proposal: Option<TradeContract>,
+    responder: &mut impl ContractResponder,
+    max_rounds: u32,
+    timeout: Option<Duration>,
+) -> Result<TradeContract, EscrowError> {
+    let mut own_proposal = match initial_proposal {
+        Some(contract) => {
+            contract.validate()?;
+            let proposal = ContractProposal { round: 0, contract };
+            debug!("Proposing the contract to {}...", partner);
+            transport.send_payload(partner, &proposal).await?;
+            Some(proposal)
+        }
+        None => None,
+    };
+    loop {
+        let envelope = transport.receive_envelope(partner, timeout).await?;
+        match envelope.kind {
+            MessageKind::ContractAccepted => {
+                let own_proposal = own_proposal
+                    .ok_or_else(|| anyhow!("Acceptance received before proposing a contract"))?;
+                let acceptance: ContractAccepted = envelope.open()?;
+                acceptance.verify(&own_proposal.contract, &partner)?;
+                debug!(
+                    "{} accepted the proposal of round {}",
+                    partner, own_proposal.round
+                );
+                return Ok(own_proposal.contract);
+            }
+            MessageKind::TradeRejection => {
+                let rejection: TradeRejection = envelope.open()?;
+                return Err(
+                    anyhow!("{} rejected the proposal: {}", partner, rejection.reason).into(),
+                );
+            }
+            MessageKind::ContractProposal => {
+                let proposal: ContractProposal = envelope.open()?;
+                if let Some(own_proposal) = &own_proposal {
+                    own_proposal.check_counter(&proposal.contract)?;
+                } else {
+                    proposal.contract.validate()?;
+                }
+                let round = proposal.round + 1;
+                let response = if round >= max_rounds {
+                    ProposalResponse::Reject(format!("no agreement after {} rounds", max_rounds))
+                } else {
+                    responder.respond(&proposal.contract).await?
+                };
+                match response {
+                    ProposalResponse::Accept => {
+                        debug!("Accepting the proposal of round {}", proposal.round);
+                        transport
+                            .send_payload(partner, &transport.accept_contract(&proposal.contract)?)
+                            .await?;
+                        return Ok(proposal.contract);
+                    }
+                    ProposalResponse::Counter(contract) => {
+                        let counter = ContractProposal {
+                            round,
+                            contract: *contract,
+                        };
+                        // the own counter must not change more than the partner could
+                        ContractProposal {
+                            round: proposal.round,
+                            contract: proposal.contract,
+                        }
+                        .check_counter(&counter.contract)?;
+                        debug!("Countering with the proposal of round {}", round);
+                        transport.send_payload(partner, &counter).await?;
+                        own_proposal = Some(counter);
+                    }
+                    ProposalResponse::Reject(reason) => {
+                        let rejection = TradeRejection {
+                            escrow_id_hex: proposal.contract.escrow_id()?.to_lower_hex_string(),
+                            reason: reason.clone(),
+                        };
+                        transport.send_payload(partner, &rejection).await?;
+                        return Err(
+                            anyhow!("Rejected the proposal of {}: {}", partner, reason).into()
+                        );
+                    }
+                }
+            }
+            kind => {
+                return Err(anyhow!(
+                    "Expected a contract proposal or answer, got a {:?} message",
+                    kind
+                )
+                .into())
+            }
+        }
+    }
+}
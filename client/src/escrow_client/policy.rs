@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+
+use super::*;
+
+use cdk::mint_url::MintUrl;
+
+/// Terms on which a seller accepts trades without manual interaction.
+///
+/// The default policy accepts every trade.
+#[derive(Debug, Clone, Default)]
+pub struct SellerPolicy {
+    /// Buyers the seller trades with, any buyer if empty.
+    pub allowed_buyers: HashSet<NostrPubkey>,
+    pub min_amount_sat: u64,
+    pub max_amount_sat: Option<u64>,
+    /// Mints the escrow token may be issued by, any mint accepted by the wallet if empty.
+    pub accepted_mints: HashSet<MintUrl>,
+}
+
+impl SellerPolicy {
+    /// Fails with [`EscrowError::PolicyViolation`] if the contract violates the policy.
+    pub fn check(&self, contract: &TradeContract) -> Result<(), EscrowError> {
+        if !self.allowed_buyers.is_empty() && !self.allowed_buyers.contains(&contract.npubkey_buyer)
+        {
+            return Err(EscrowError::PolicyViolation(format!(
+                "buyer {} is not allowed",
+                contract.npubkey_buyer
+            )));
+        }
+        if contract.trade_amount_sat < self.min_amount_sat {
+            return Err(EscrowError::PolicyViolation(format!(
+                "trade amount of {} sat is below the minimum of {} sat",
+                contract.trade_amount_sat, self.min_amount_sat
+            )));
+        }
+        if let Some(max_amount_sat) = self.max_amount_sat {
+            if contract.trade_amount_sat > max_amount_sat {
+                return Err(EscrowError::PolicyViolation(format!(
+                    "trade amount of {} sat is above the maximum of {} sat",
+                    contract.trade_amount_sat, max_amount_sat
+                )));
+            }
+        }
+        if !self.accepted_mints.is_empty() && !self.accepted_mints.contains(&contract.mint_url) {
+            return Err(EscrowError::PolicyViolation(format!(
+                "mint {} is not accepted",
+                contract.mint_url
+            )));
+        }
+        Ok(())
+    }
+}
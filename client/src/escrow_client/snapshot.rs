@@ -0,0 +1,322 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use super::*;
+
+/// The state of a persisted escrow trade.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "state")]
+pub enum SnapshotState {
+    Registered,
+    /// The buyer created the escrow token and the fee token, saved before sending them so they are re-sent on resume
+    /// instead of created anew.
+    TokenSent {
+        escrow_token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fee_token: Option<String>,
+    },
+    /// The escrow token is stored in its serialized form.
+    TokenExchanged {
+        escrow_token: String,
+        /// The milestone tokens released so far, for the seller including the buyer signatures.
+        #[serde(default)]
+        released_milestone_tokens: Vec<String>,
+        /// The outputs this trader redeems milestones into by their index, with `SIG_ALL` only.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        redeem_outputs: BTreeMap<usize, RedeemOutputs>,
+        #[serde(default)]
+        fee_confirmed: bool,
+        /// The key of the digital goods the seller delivered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delivery_key: Option<DeliveryKey>,
+    },
+    /// A trader disputed the trade, the coordinators didn't resolve it yet.
+    Disputed {
+        escrow_token: String,
+        /// The milestone tokens released before the dispute, for the seller including the buyer signatures.
+        #[serde(default)]
+        released_milestone_tokens: Vec<String>,
+        /// The outputs this trader redeems milestones into by their index, with `SIG_ALL` only.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        redeem_outputs: BTreeMap<usize, RedeemOutputs>,
+        /// The claim this trader sent to the coordinators.
+        dispute_claim: DisputeClaim,
+    },
+}
+
+impl fmt::Display for SnapshotState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotState::Registered => write!(f, "registered"),
+            SnapshotState::TokenSent { .. } => write!(f, "token sent, awaiting its acceptance"),
+            SnapshotState::TokenExchanged {
+                released_milestone_tokens,
+                ..
+            } => write!(
+                f,
+                "token exchanged, {} milestones released",
+                released_milestone_tokens.len()
+            ),
+            SnapshotState::Disputed { .. } => write!(f, "disputed, awaiting the resolution"),
+        }
+    }
+}
+
+/// A resumable snapshot of an escrow client, stored as a json file named after the escrow id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EscrowSnapshot {
+    pub trade_mode: TradeMode,
+    pub escrow_contract: TradeContract,
+    pub escrow_registration: EscrowRegistration,
+    /// The registrations of the additional coordinators of the contract, in their order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_registrations: Vec<EscrowRegistration>,
+    pub state: SnapshotState,
+}
+
+impl EscrowSnapshot {
+    pub fn path(snapshot_dir: &Path, escrow_id_hex: &str) -> PathBuf {
+        snapshot_dir.join(format!("{}.json", escrow_id_hex))
+    }
+
+    /// Writes the snapshot atomically, replacing an older snapshot of the same escrow.
+    pub fn save(&self, snapshot_dir: &Path) -> Result<PathBuf, EscrowError> {
+        fs::create_dir_all(snapshot_dir)?;
+        let path = Self::path(snapshot_dir, &self.escrow_registration.escrow_id_hex);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(path)
+    }
+
+    /// The registrations of all coordinators, in the order of the contract.
+    pub fn escrow_registrations(&self) -> Vec<EscrowRegistration> {
+        [
+            vec![self.escrow_registration.clone()],
+            self.additional_registrations.clone(),
+        ]
+        .concat()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, EscrowError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn remove(snapshot_dir: &Path, escrow_id_hex: &str) -> Result<(), EscrowError> {
+        let path = Self::path(snapshot_dir, escrow_id_hex);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// An escrow client restored from a snapshot, in the state the trade was persisted in.
+pub enum ResumedEscrowClient<T = NostrClient, W = ClientEcashWallet> {
+    Registered(RegisteredEscrowClient<T, W>),
+    TokenExchanged(TokenExchangedEscrowClient<T, W>),
+    Disputed(DisputedEscrowClient<T, W>),
+}
+
+impl<T: EscrowTransport, W: EscrowWallet> ResumedEscrowClient<T, W> {
+    /// Restores the escrow client from the snapshot at `path`.
+    ///
+    /// The passed transport, e.g. a [`NostrClient`], subscribes to the messages of the trader again. The wallet must hold
+    /// the trade key used in the contract, else the trade could not be finished.
+    ///
+    /// A buyer who created the escrow token already is resumed as registered, sending the same token again.
+    ///
+    /// The messages sent since the registration are received again, so a seller resumed while waiting for the escrow
+    /// token gets it even if it was sent meanwhile. Replayed messages of earlier trade steps are skipped.
+    pub fn resume_from(
+        path: &Path,
+        mut transport: T,
+        ecash_wallet: W,
+        message_timeout: Option<Duration>,
+    ) -> Result<Self, EscrowError> {
+        let snapshot = EscrowSnapshot::load(path)?;
+        let contract_trade_pubkey = match snapshot.trade_mode {
+            TradeMode::Buyer => &snapshot.escrow_contract.buyer_ecash_public_key,
+            TradeMode::Seller => &snapshot.escrow_contract.seller_ecash_public_key,
+        };
+        if contract_trade_pubkey != ecash_wallet.trade_pubkey() {
+            return Err(anyhow!(
+                "Wallet trade pubkey {} does not match the contract trade pubkey {}",
+                ecash_wallet.trade_pubkey(),
+                contract_trade_pubkey
+            )
+            .into());
+        }
+        debug!(
+            "Resuming escrow {} from {}",
+            snapshot.escrow_registration.escrow_id_hex,
+            path.display()
+        );
+
+        transport.set_message_expiration(Some(message_expiration(snapshot.escrow_contract.expiry)));
+        // the messages sent while the trader was offline, e.g. the escrow token to a waiting seller
+        transport.replay_messages_since(snapshot.escrow_registration.escrow_start_time);
+        let context = EscrowClientContext {
+            transport,
+            ecash_wallet,
+            escrow_contract: snapshot.escrow_contract,
+            trade_mode: snapshot.trade_mode,
+            message_timeout,
+            snapshot_dir: path.parent().map(Path::to_path_buf),
+            seller_policy: SellerPolicy::default(),
+            metrics: Arc::default(),
+            receipt_dir: None,
+            send_receipt_to_coordinator: false,
+            confirm_funding: false,
+            confirm_milestones: false,
+            event_sink: None,
+            digital_goods: None,
+        };
+        context.ensure_own_side()?;
+        Ok(match snapshot.state {
+            SnapshotState::Registered => Self::Registered(RegisteredEscrowClient {
+                context,
+                escrow_registration: snapshot.escrow_registration,
+                additional_registrations: snapshot.additional_registrations,
+                sent_tokens: None,
+            }),
+            SnapshotState::TokenSent {
+                escrow_token,
+                fee_token,
+            } => {
+                if context.trade_mode != TradeMode::Buyer {
+                    return Err(anyhow!("Only the buyer sends the escrow token").into());
+                }
+                Self::Registered(RegisteredEscrowClient {
+                    context,
+                    escrow_registration: snapshot.escrow_registration,
+                    additional_registrations: snapshot.additional_registrations,
+                    sent_tokens: Some(SentTokens {
+                        escrow_token: Token::from_str(&escrow_token)?,
+                        fee_token: fee_token.as_deref().map(Token::from_str).transpose()?,
+                    }),
+                })
+            }
+            SnapshotState::TokenExchanged {
+                escrow_token,
+                released_milestone_tokens,
+                redeem_outputs,
+                fee_confirmed,
+                delivery_key,
+            } => {
+                let (escrow_token, milestone_tokens) =
+                    restore_milestone_tokens(&context, &escrow_token, &released_milestone_tokens)?;
+                Self::TokenExchanged(TokenExchangedEscrowClient {
+                    context,
+                    escrow_registration: snapshot.escrow_registration,
+                    additional_registrations: snapshot.additional_registrations,
+                    escrow_token,
+                    milestone_tokens,
+                    released_milestones: released_milestone_tokens.len(),
+                    redeem_outputs,
+                    announced_outputs: None,
+                    fee_confirmed,
+                    delivery_key,
+                    received_delivery_proof: None,
+                    received_delivery_payload: None,
+                })
+            }
+            SnapshotState::Disputed {
+                escrow_token,
+                released_milestone_tokens,
+                redeem_outputs,
+                dispute_claim,
+            } => {
+                let (escrow_token, milestone_tokens) =
+                    restore_milestone_tokens(&context, &escrow_token, &released_milestone_tokens)?;
+                Self::Disputed(DisputedEscrowClient {
+                    context,
+                    escrow_registration: snapshot.escrow_registration,
+                    additional_registrations: snapshot.additional_registrations,
+                    escrow_token,
+                    milestone_tokens,
+                    released_milestones: released_milestone_tokens.len(),
+                    redeem_outputs,
+                    dispute_claim,
+                })
+            }
+        })
+    }
+
+    /// Saves the signed receipt of the resumed trade to `receipt_dir` once it finishes.
+    pub fn with_receipt_dir(mut self, receipt_dir: impl Into<PathBuf>) -> Self {
+        let receipt_dir = Some(receipt_dir.into());
+        match &mut self {
+            Self::Registered(client) => client.context.receipt_dir = receipt_dir,
+            Self::TokenExchanged(client) => client.context.receipt_dir = receipt_dir,
+            Self::Disputed(client) => client.context.receipt_dir = receipt_dir,
+        }
+        self
+    }
+
+    /// Emits the state changes and the redemption of the resumed trade to `event_sink`.
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn TradeEventSink>) -> Self {
+        match &mut self {
+            Self::Registered(client) => client.context.event_sink = Some(event_sink),
+            Self::TokenExchanged(client) => client.context.event_sink = Some(event_sink),
+            Self::Disputed(client) => client.context.event_sink = Some(event_sink),
+        }
+        self
+    }
+
+    /// Delivers `goods` as seller of a contract of digital goods, unless they were delivered before the restart.
+    pub fn with_digital_goods(mut self, goods: Vec<u8>) -> Self {
+        let goods = Some(goods);
+        match &mut self {
+            Self::Registered(client) => client.context.digital_goods = goods,
+            Self::TokenExchanged(client) => client.context.digital_goods = goods,
+            Self::Disputed(client) => client.context.digital_goods = goods,
+        }
+        self
+    }
+
+    /// Asks on the terminal before each remaining milestone of the resumed trade, like
+    /// [`InitEscrowClient::with_milestone_confirmation`].
+    pub fn with_milestone_confirmation(mut self) -> Self {
+        match &mut self {
+            Self::Registered(client) => client.context.confirm_milestones = true,
+            Self::TokenExchanged(client) => client.context.confirm_milestones = true,
+            Self::Disputed(client) => client.context.confirm_milestones = true,
+        }
+        self
+    }
+
+    /// Counts the disputes and settled trades of the resumed trade in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        match &mut self {
+            Self::Registered(client) => client.context.metrics = metrics,
+            Self::TokenExchanged(client) => client.context.metrics = metrics,
+            Self::Disputed(client) => client.context.metrics = metrics,
+        }
+        self
+    }
+}
+
+/// The escrow token and its milestone tokens, the released ones replaced by their persisted tokens.
+fn restore_milestone_tokens<T, W>(
+    context: &EscrowClientContext<T, W>,
+    escrow_token: &str,
+    released_milestone_tokens: &[String],
+) -> Result<(Token, Vec<Token>), EscrowError> {
+    let escrow_token = Token::from_str(escrow_token)?;
+    let mut milestone_tokens = ClientEcashWallet::milestone_tokens(
+        &escrow_token,
+        &context.escrow_contract.milestone_amounts()?,
+    )?;
+    if released_milestone_tokens.len() > milestone_tokens.len() {
+        return Err(anyhow!("Snapshot releases more milestones than the contract has").into());
+    }
+    for (milestone_token, released_token) in
+        milestone_tokens.iter_mut().zip(released_milestone_tokens)
+    {
+        *milestone_token = Token::from_str(released_token)?;
+    }
+    Ok((escrow_token, milestone_tokens))
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::*;
+
+/// A persisted escrow of an [`EscrowStore`], with the time its snapshot was last written.
+#[derive(Debug, Clone)]
+pub struct StoredEscrow {
+    pub path: PathBuf,
+    pub snapshot: EscrowSnapshot,
+    pub last_activity: Timestamp,
+}
+
+impl StoredEscrow {
+    pub fn escrow_id_hex(&self) -> &str {
+        &self.snapshot.escrow_registration.escrow_id_hex
+    }
+
+    /// The nostr pubkey of the trade partner.
+    pub fn counterparty(&self) -> NostrPubkey {
+        match self.snapshot.trade_mode {
+            TradeMode::Buyer => self.snapshot.escrow_contract.npubkey_seller,
+            TradeMode::Seller => self.snapshot.escrow_contract.npubkey_buyer,
+        }
+    }
+}
+
+/// The escrow snapshots of a snapshot directory, see [`InitEscrowClient::with_snapshot_dir`].
+#[derive(Debug, Clone)]
+pub struct EscrowStore {
+    snapshot_dir: PathBuf,
+}
+
+impl EscrowStore {
+    pub fn new(snapshot_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            snapshot_dir: snapshot_dir.into(),
+        }
+    }
+
+    pub fn snapshot_dir(&self) -> &Path {
+        &self.snapshot_dir
+    }
+
+    /// All in-flight escrows of the snapshot directory, the most recently active first.
+    ///
+    /// Files which are no escrow snapshot are skipped, a missing snapshot directory holds no escrows.
+    pub fn list(&self) -> Result<Vec<StoredEscrow>, EscrowError> {
+        if !self.snapshot_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut escrows = Vec::new();
+        for entry in fs::read_dir(&self.snapshot_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let snapshot = match EscrowSnapshot::load(&path) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("Skipping invalid escrow snapshot {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let modified_secs = fs::metadata(&path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs());
+            escrows.push(StoredEscrow {
+                path,
+                snapshot,
+                last_activity: Timestamp::from(modified_secs),
+            });
+        }
+        escrows.sort_by_key(|escrow| std::cmp::Reverse(escrow.last_activity));
+        Ok(escrows)
+    }
+
+    /// Resumes the escrow `escrow_id_hex` with [`ResumedEscrowClient::resume_from`].
+    pub fn resume<T: EscrowTransport, W: EscrowWallet>(
+        &self,
+        escrow_id_hex: &str,
+        transport: T,
+        ecash_wallet: W,
+        message_timeout: Option<Duration>,
+    ) -> Result<ResumedEscrowClient<T, W>, EscrowError> {
+        let path = EscrowSnapshot::path(&self.snapshot_dir, escrow_id_hex);
+        if !path.exists() {
+            return Err(anyhow!(
+                "No snapshot of escrow {} in {}",
+                escrow_id_hex,
+                self.snapshot_dir.display()
+            )
+            .into());
+        }
+        ResumedEscrowClient::resume_from(&path, transport, ecash_wallet, message_timeout)
+    }
+}
@@ -1,5 +1,9 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+pub mod clock;
+pub mod dry_run;
 pub mod ecash;
 pub mod escrow_client;
+pub mod local_trade;
+pub mod rates;
@@ -0,0 +1,133 @@
+//! A full trade over real relays, a real mint and a running coordinator, to check the nostr transport and the ecash
+//! wallet end to end, e.g. against a local nutshell mint with a fake funding source and a local relay.
+
+use std::time::Duration;
+
+use super::*;
+
+use cashu_escrow_common::{
+    error::EscrowError,
+    model::{TradeContract, DEFAULT_REQUIRED_SIGNATURES},
+    nostr::{shutdown_client, MessagingScheme, NostrClient},
+};
+use cdk::{
+    amount::SplitTarget,
+    mint_url::MintUrl,
+    nuts::{CurrencyUnit, SigFlag},
+    Amount,
+};
+use ecash::ClientEcashWallet;
+use escrow_client::{InitEscrowClient, TradeMode};
+use nostr_sdk::{Keys, PublicKey as NostrPubkey, Timestamp};
+
+/// Where the local trade runs and on which terms.
+#[derive(Debug, Clone)]
+pub struct LocalTradeConfig {
+    /// Mint paying its mint quotes without a real payment, like nutshell with the `FakeWallet` backend.
+    pub mint_url: MintUrl,
+    pub relays: Vec<String>,
+    pub messaging_scheme: MessagingScheme,
+    /// The coordinator, which must be running on `relays` already.
+    pub coordinator: NostrPubkey,
+    pub coordinator_fee_sat: u64,
+    pub trade_amount_sat: u64,
+    pub message_timeout_secs: u64,
+}
+
+/// Funds a fresh buyer at the mint and trades with a fresh seller, returning the amount the seller redeemed.
+pub async fn run_local_trade(config: LocalTradeConfig) -> Result<Amount, EscrowError> {
+    let buyer_keys = Keys::generate();
+    let seller_keys = Keys::generate();
+    let buyer_secret = ClientEcashWallet::trade_secret_from_nostr_keys(&buyer_keys)?;
+    let seller_secret = ClientEcashWallet::trade_secret_from_nostr_keys(&seller_keys)?;
+    let mint_url = config.mint_url.to_string();
+    let buyer_wallet = ClientEcashWallet::new(&mint_url, &[], buyer_secret).await?;
+    let seller_wallet = ClientEcashWallet::new(&mint_url, &[], seller_secret).await?;
+
+    let contract = TradeContract {
+        trade_description: "Local trade".to_string(),
+        trade_amount_sat: config.trade_amount_sat,
+        coordinator_fee_sat: config.coordinator_fee_sat,
+        unit: CurrencyUnit::Sat,
+        mint_url: buyer_wallet.mint_url().clone(),
+        npubkey_seller: seller_keys.public_key(),
+        npubkey_buyer: buyer_keys.public_key(),
+        npubkey_coordinator: config.coordinator,
+        expiry: Timestamp::now() + Duration::from_secs(60 * 60),
+        seller_ecash_public_key: seller_wallet.spending_pubkey_hex().to_string(),
+        buyer_ecash_public_key: buyer_wallet.spending_pubkey_hex().to_string(),
+        buyer_refund_public_key: None,
+        milestones: Vec::new(),
+        oracle_pubkey: None,
+        required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+        sig_flag: SigFlag::SigAll,
+        additional_coordinators: Vec::new(),
+        coordinator_threshold: None,
+        fiat_price: None,
+        digital_goods: false,
+    };
+
+    debug!(
+        "Funding the buyer with {} sat...",
+        contract.buyer_total_sat()?
+    );
+    let buyer_mint_wallet = buyer_wallet.mint_wallet(buyer_wallet.mint_url())?;
+    let mint_quote = buyer_mint_wallet
+        .mint_quote(Amount::from(contract.buyer_total_sat()?))
+        .await?;
+    buyer_mint_wallet
+        .mint(&mint_quote.id, SplitTarget::None, None)
+        .await?;
+
+    let buyer_client = NostrClient::new(
+        buyer_keys,
+        Some(config.relays.clone()),
+        config.messaging_scheme,
+        None,
+    )
+    .await?;
+    let seller_client = NostrClient::new(
+        seller_keys,
+        Some(config.relays.clone()),
+        config.messaging_scheme,
+        None,
+    )
+    .await?;
+    let buyer_relays = buyer_client.client.clone();
+    let seller_relays = seller_client.client.clone();
+
+    let buyer = InitEscrowClient::new(
+        buyer_client,
+        buyer_wallet,
+        contract.clone(),
+        TradeMode::Buyer,
+    )
+    .with_message_timeout_secs(config.message_timeout_secs);
+    let seller = InitEscrowClient::new(seller_client, seller_wallet, contract, TradeMode::Seller)
+        .with_message_timeout_secs(config.message_timeout_secs);
+    let buyer_trade = async {
+        buyer
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?
+            .do_your_trade_duties()
+            .await
+    };
+    let seller_trade = async {
+        seller
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?
+            .do_your_trade_duties()
+            .await?
+            .redeem_escrow_token()
+            .await
+    };
+    let result = tokio::try_join!(buyer_trade, seller_trade);
+    shutdown_client(&buyer_relays).await?;
+    shutdown_client(&seller_relays).await?;
+    let (_, redeemed_amount) = result?;
+    Ok(redeemed_amount)
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use cashu_escrow_common::{
+    error::EscrowError,
+    model::{ExchangeRate, FiatCurrency},
+};
+use nostr_sdk::Timestamp;
+
+/// Price api used when no other is configured, answering the bitcoin price per currency as json object.
+pub const DEFAULT_PRICE_API_URL: &str = "https://mempool.space/api/v1/prices";
+
+/// Largest difference in percent between the rate locked by the buyer and the rate of the seller.
+pub const MAX_RATE_DEVIATION_PERCENT: u64 = 2;
+
+const SAT_PER_BTC: u64 = 100_000_000;
+
+/// Looks up how many sat a fiat unit is worth, to lock the rate of fiat priced contracts.
+#[async_trait]
+pub trait ExchangeRateSource: Send + Sync {
+    async fn exchange_rate(&self, currency: FiatCurrency) -> Result<ExchangeRate, EscrowError>;
+}
+
+/// Rates of a price api answering like `{"USD": 65000, "EUR": 60000}`, the price of a bitcoin per currency.
+#[derive(Debug, Clone)]
+pub struct PriceApiRateSource {
+    url: String,
+    http_client: reqwest::Client,
+}
+
+impl PriceApiRateSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            http_client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Default for PriceApiRateSource {
+    fn default() -> Self {
+        Self::new(DEFAULT_PRICE_API_URL)
+    }
+}
+
+#[async_trait]
+impl ExchangeRateSource for PriceApiRateSource {
+    async fn exchange_rate(&self, currency: FiatCurrency) -> Result<ExchangeRate, EscrowError> {
+        let prices: HashMap<String, serde_json::Value> = self
+            .http_client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| anyhow!("Failed to fetch the prices of {}: {}", self.url, e))?
+            .json()
+            .await
+            .map_err(|e| anyhow!("Invalid prices of {}: {}", self.url, e))?;
+        let btc_price = prices
+            .get(&currency.to_string())
+            .and_then(|price| price.as_f64())
+            .filter(|price| *price >= 1.0)
+            .ok_or_else(|| anyhow!("No {} price in the prices of {}", currency, self.url))?;
+        Ok(ExchangeRate {
+            sat_per_unit: (SAT_PER_BTC as f64 / btc_price).round() as u64,
+            source: self.url.clone(),
+            fetched_at: Timestamp::now(),
+        })
+    }
+}
+
+/// Fails if `locked` deviates more than [`MAX_RATE_DEVIATION_PERCENT`] from the `current` rate.
+pub fn check_rate_deviation(
+    locked: &ExchangeRate,
+    current: &ExchangeRate,
+) -> Result<(), EscrowError> {
+    let deviation = locked.sat_per_unit.abs_diff(current.sat_per_unit);
+    if deviation * 100 > current.sat_per_unit * MAX_RATE_DEVIATION_PERCENT {
+        return Err(anyhow!(
+            "Locked rate of {} sat per unit deviates more than {}% from the current rate of {} sat per unit",
+            locked.sat_per_unit,
+            MAX_RATE_DEVIATION_PERCENT,
+            current.sat_per_unit
+        )
+        .into());
+    }
+    Ok(())
+}
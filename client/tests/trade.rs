@@ -0,0 +1,250 @@
+//! End-to-end trades of a buyer and a seller registered at a mock coordinator, exchanging all messages over an
+//! in-memory nostr relay and trading unbacked tokens of mock wallets.
+
+use std::{str::FromStr, time::Duration};
+
+use cashu_escrow_client::{
+    dry_run::{run_mock_coordinator, MockWallet},
+    ecash::EscrowWallet,
+    escrow_client::{InitEscrowClient, SettledEscrowClient, TradeMode},
+};
+use cashu_escrow_common::{
+    error::EscrowError,
+    model::{DeliveryProof, TradeContract, DEFAULT_REQUIRED_SIGNATURES},
+    nostr::{MessagingScheme, MockRelay, NostrClient},
+};
+use cdk::{
+    mint_url::MintUrl,
+    nuts::{CurrencyUnit, SigFlag},
+    Amount,
+};
+use nostr_sdk::{hashes::hex::DisplayHex, Keys, Timestamp};
+
+const TRADE_AMOUNT_SAT: u64 = 5000;
+const MESSAGE_TIMEOUT_SECS: u64 = 10;
+
+type EscrowClient = InitEscrowClient<NostrClient, MockWallet>;
+
+/// The contract, keys and wallets of the parties of a trade, before they connect to the relay.
+struct Trade {
+    relay: MockRelay,
+    contract: TradeContract,
+    buyer_keys: Keys,
+    seller_keys: Keys,
+    coordinator_keys: Keys,
+    buyer_wallet: MockWallet,
+    seller_wallet: MockWallet,
+}
+
+impl Trade {
+    async fn new(customize: impl FnOnce(&mut TradeContract)) -> Result<Self, EscrowError> {
+        let buyer_keys = Keys::generate();
+        let seller_keys = Keys::generate();
+        let coordinator_keys = Keys::generate();
+        let buyer_wallet = MockWallet::new(Amount::from(TRADE_AMOUNT_SAT));
+        let seller_wallet = MockWallet::new(Amount::ZERO);
+        let mut contract = TradeContract {
+            trade_description: "Purchase of one watermelon".to_string(),
+            trade_amount_sat: TRADE_AMOUNT_SAT,
+            coordinator_fee_sat: 0,
+            unit: CurrencyUnit::Sat,
+            mint_url: MintUrl::from_str("https://mint.example.com")?,
+            npubkey_seller: seller_keys.public_key(),
+            npubkey_buyer: buyer_keys.public_key(),
+            npubkey_coordinator: coordinator_keys.public_key(),
+            expiry: Timestamp::now() + Duration::from_secs(60 * 60),
+            seller_ecash_public_key: seller_wallet.trade_pubkey().to_string(),
+            buyer_ecash_public_key: buyer_wallet.trade_pubkey().to_string(),
+            buyer_refund_public_key: None,
+            milestones: Vec::new(),
+            oracle_pubkey: None,
+            required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+            sig_flag: SigFlag::SigAll,
+            additional_coordinators: Vec::new(),
+            coordinator_threshold: None,
+            fiat_price: None,
+            digital_goods: false,
+        };
+        customize(&mut contract);
+        Ok(Self {
+            relay: MockRelay::run().await?,
+            contract,
+            buyer_keys,
+            seller_keys,
+            coordinator_keys,
+            buyer_wallet,
+            seller_wallet,
+        })
+    }
+
+    /// Connects the traders and the coordinator to the relay.
+    async fn connect(self) -> Result<Parties, EscrowError> {
+        let buyer = InitEscrowClient::new(
+            self.relay
+                .client(self.buyer_keys, MessagingScheme::GiftWrap)
+                .await?,
+            self.buyer_wallet,
+            self.contract.clone(),
+            TradeMode::Buyer,
+        )
+        .with_message_timeout_secs(MESSAGE_TIMEOUT_SECS);
+        let seller = InitEscrowClient::new(
+            self.relay
+                .client(self.seller_keys, MessagingScheme::GiftWrap)
+                .await?,
+            self.seller_wallet,
+            self.contract.clone(),
+            TradeMode::Seller,
+        )
+        .with_message_timeout_secs(MESSAGE_TIMEOUT_SECS);
+        let coordinator = self
+            .relay
+            .client(self.coordinator_keys, MessagingScheme::GiftWrap)
+            .await?;
+        Ok(Parties {
+            _relay: self.relay,
+            contract: self.contract,
+            buyer,
+            seller,
+            coordinator,
+        })
+    }
+}
+
+/// The connected parties of a trade, the relay stops once they are dropped.
+struct Parties {
+    _relay: MockRelay,
+    contract: TradeContract,
+    buyer: EscrowClient,
+    seller: EscrowClient,
+    coordinator: NostrClient,
+}
+
+/// Runs the trade duties of a trader until the trade is settled.
+async fn settle(
+    trader: EscrowClient,
+) -> Result<SettledEscrowClient<NostrClient, MockWallet>, EscrowError> {
+    trader
+        .register_trade()
+        .await?
+        .exchange_trade_token()
+        .await?
+        .do_your_trade_duties()
+        .await
+}
+
+/// Checks that the seller ends with the whole trade amount, released by the buyer.
+async fn assert_seller_paid(
+    seller: &SettledEscrowClient<NostrClient, MockWallet>,
+) -> Result<(), EscrowError> {
+    let proofs = seller.escrow_token().proofs();
+    assert!(
+        proofs
+            .values()
+            .flatten()
+            .all(|proof| proof.witness.is_some()),
+        "Escrow token lacks release signatures"
+    );
+    assert!(
+        seller
+            .redeem_outputs()
+            .values()
+            .flat_map(|redeem_outputs| &redeem_outputs.outputs)
+            .all(|output| output.witness.is_some()),
+        "Redeem outputs lack release signatures"
+    );
+    assert_eq!(
+        seller.redeem_escrow_token().await?,
+        Amount::from(TRADE_AMOUNT_SAT)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn trade_over_relay() -> Result<(), EscrowError> {
+    let parties = Trade::new(|_| {}).await?.connect().await?;
+
+    let (_, buyer, seller) = tokio::try_join!(
+        run_mock_coordinator(parties.coordinator, &parties.contract),
+        settle(parties.buyer),
+        settle(parties.seller)
+    )?;
+
+    assert_eq!(seller.redeem_outputs().len(), 1);
+    assert_seller_paid(&seller).await?;
+    buyer.receipt().verify()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sig_inputs_trade_over_relay() -> Result<(), EscrowError> {
+    let parties = Trade::new(|contract| contract.sig_flag = SigFlag::SigInputs)
+        .await?
+        .connect()
+        .await?;
+
+    let (_, _, seller) = tokio::try_join!(
+        run_mock_coordinator(parties.coordinator, &parties.contract),
+        settle(parties.buyer),
+        settle(parties.seller)
+    )?;
+
+    assert!(seller.redeem_outputs().is_empty());
+    assert_seller_paid(&seller).await
+}
+
+#[tokio::test]
+async fn milestone_trade_over_relay() -> Result<(), EscrowError> {
+    let parties = Trade::new(|contract| {
+        contract.milestones = vec![Amount::from(2000), Amount::from(3000)];
+    })
+    .await?
+    .connect()
+    .await?;
+
+    let (_, _, seller) = tokio::try_join!(
+        run_mock_coordinator(parties.coordinator, &parties.contract),
+        settle(parties.buyer),
+        settle(parties.seller)
+    )?;
+
+    assert_seller_paid(&seller).await
+}
+
+/// The seller sends the digital goods before the delivery proof the buyer waits for first.
+#[tokio::test]
+async fn digital_goods_trade_with_oracle_over_relay() -> Result<(), EscrowError> {
+    let oracle_keys = Keys::generate();
+    let goods = b"the watermelon recipe".to_vec();
+    let parties = Trade::new(|contract| {
+        contract.oracle_pubkey = Some(oracle_keys.public_key());
+        contract.digital_goods = true;
+    })
+    .await?
+    .connect()
+    .await?;
+    let delivery_proof = DeliveryProof::sign(
+        parties.contract.escrow_id()?.to_lower_hex_string(),
+        &oracle_keys,
+    )?;
+
+    let seller = parties.seller.with_digital_goods(goods.clone());
+    let seller_trade = async {
+        let mut seller = seller
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?;
+        seller.deliver_digital_goods().await?;
+        seller.submit_delivery_proof(&delivery_proof).await?;
+        seller.do_your_trade_duties().await
+    };
+    let (_, buyer, seller) = tokio::try_join!(
+        run_mock_coordinator(parties.coordinator, &parties.contract),
+        settle(parties.buyer),
+        seller_trade
+    )?;
+
+    assert_eq!(buyer.digital_goods(), Some(&goods[..]));
+    assert_seller_paid(&seller).await
+}
@@ -1,93 +1,574 @@
 pub mod trade_contract;
 
 use super::*;
-use cashu_escrow_client::escrow_client::TradeMode;
+use anyhow::anyhow;
+use cashu_escrow_client::ecash::ProofSelection;
+use cashu_escrow_client::escrow_client::DEFAULT_MESSAGE_TIMEOUT_SECS;
+use cashu_escrow_client::escrow_client::{SellerPolicy, TradeMode};
+use cashu_escrow_client::rates::DEFAULT_PRICE_API_URL;
 use cashu_escrow_common::cli::get_user_input;
+use cashu_escrow_common::model::{
+    FiatCurrency, FiatPrice, TradeContract, DEFAULT_REQUIRED_SIGNATURES,
+};
 use cdk::nuts::nut01::PublicKey as EcashPubkey;
+use cdk::nuts::SigFlag;
+use clap::Subcommand;
 use nostr_sdk::prelude::*;
 use nostr_sdk::Keys as NostrKeys;
 use nostr_sdk::PublicKey as NostrPubkey;
-use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::{env, fs};
+
+/// Command line arguments, which can also be set in the environment.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+    /// Run a trade between an in-memory buyer and seller, without relays or a mint.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Write the log to this file instead of stderr, rotated once it exceeds --log-file-max-mb.
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, env = "LOG_FILE_MAX_MB", default_value_t = 10)]
+    pub log_file_max_mb: u64,
+    /// Print the npub and the ecash trade pubkey to hand to the trade partner, without trading.
+    #[arg(long)]
+    pub show_identity: bool,
+    /// Fund the escrow as buyer and release its milestones without confirming them first, for non-interactive use.
+    #[arg(long)]
+    pub yes: bool,
+    /// Print the counts of the sent and received messages, timeouts, disputes and settled trades on shutdown.
+    #[arg(long, env = "PRINT_METRICS")]
+    pub print_metrics: bool,
+    /// How the progress of the trade is printed on stdout: text, or json for a json event per line to script against.
+    #[arg(long, env = "OUTPUT_FORMAT", default_value = "text")]
+    pub output: OutputFormat,
+    /// File to write an encrypted backup of the wallet proofs to before funding the escrow, read it with show-backup.
+    #[arg(long, env = "BACKUP_FILE")]
+    pub backup_file: Option<PathBuf>,
+    /// Relay whose reported time the escrow start time of the coordinator is checked against, instead of the local clock.
+    #[arg(long, env = "TIME_RELAY")]
+    pub time_relay: Option<String>,
+    /// Lightning address or LNURL the seller pays the redeemed escrow funds out to right after the trade.
+    #[arg(long, env = "PAYOUT_LN_ADDRESS")]
+    pub payout_ln_address: Option<String>,
+    /// File of the digital goods the seller delivers encrypted, for trades of digital goods.
+    #[arg(long, env = "DIGITAL_GOODS_FILE")]
+    pub digital_goods_file: Option<PathBuf>,
+    /// File the buyer saves the received digital goods to [default: only their size is logged]
+    #[arg(long, env = "RECEIVED_GOODS_FILE")]
+    pub received_goods_file: Option<PathBuf>,
+    /// Directory to save the signed receipt of the finished trade in.
+    #[arg(long, env = "RECEIPT_DIR")]
+    pub receipt_dir: Option<PathBuf>,
+    /// Send the signed receipt of the finished trade to the coordinator as well.
+    #[arg(long, env = "SEND_RECEIPT_TO_COORDINATOR")]
+    pub send_receipt_to_coordinator: bool,
+    /// Negotiate the trade amount with the trade partner before the registration, the buyer proposes the contract.
+    #[arg(long, env = "NEGOTIATE_CONTRACT")]
+    pub negotiate: bool,
+    /// Comma separated relays to talk to the coordinator over exclusively, e.g. its private relay [default: the NOSTR_RELAYS]
+    #[arg(long, env = "COORDINATOR_RELAYS", value_delimiter = ',')]
+    pub coordinator_relays: Vec<String>,
+    /// Seconds to wait for each message of the coordinator or the trade partner.
+    #[arg(long, env = "MESSAGE_TIMEOUT_SECS", default_value_t = DEFAULT_MESSAGE_TIMEOUT_SECS)]
+    pub message_timeout_secs: u64,
+    /// Also receive the messages sent up to this many seconds before the start, in case the trade partner answered
+    /// before this client subscribed to the relays.
+    #[arg(long, env = "MESSAGE_LOOKBACK_SECS")]
+    pub message_lookback_secs: Option<u64>,
+    /// Wait for each message of the coordinator or the trade partner until it arrives or the client is interrupted,
+    /// instead of at most MESSAGE_TIMEOUT_SECS.
+    #[arg(long, env = "WAIT_FOREVER")]
+    pub wait_forever: bool,
+    /// Fee the coordinator charges for the escrow, paid by the buyer.
+    #[arg(long, env = "COORDINATOR_FEE_SAT", default_value_t = 0)]
+    pub coordinator_fee_sat: u64,
+    /// Unix time the trade expires at, must be the same for both traders [default: 3 days after the next UTC midnight]
+    #[arg(long, env = "TRADE_EXPIRY")]
+    trade_expiry: Option<u64>,
+    /// Price of the trade in fiat, e.g. 12.50, the buyer locks the sat amount at the registration. Must be the same for both traders.
+    #[arg(long, env = "TRADE_FIAT_PRICE")]
+    fiat_price: Option<String>,
+    /// Currency of the fiat price: EUR or USD.
+    #[arg(long, env = "TRADE_FIAT_CURRENCY", default_value = "EUR")]
+    fiat_currency: FiatCurrency,
+    /// Price api to look up the exchange rate of fiat priced trades at.
+    #[arg(long, env = "PRICE_API_URL", default_value = DEFAULT_PRICE_API_URL)]
+    pub price_api_url: String,
+    /// Comma separated sat amounts the trade amount is released in, must be the same for both traders.
+    #[arg(long = "milestones", env = "TRADE_MILESTONES", value_delimiter = ',')]
+    milestones_sat: Vec<u64>,
+    /// Npub of the oracle attesting the delivery, must be the same for both traders.
+    #[arg(long, env = "TRADE_ORACLE_NPUB")]
+    oracle_npub: Option<String>,
+    /// Ecash pubkey the escrow token is refunded to after the expiry, e.g. a cold key, must be the same for both traders [default: the buyer trade pubkey]
+    #[arg(long, env = "BUYER_REFUND_PUBKEY")]
+    refund_pubkey: Option<String>,
+    /// How many of the seller, buyer and coordinator keys must sign to release the escrow, more than the coordinators and at
+    /// most the two traders, must be the same for both traders.
+    #[arg(long, env = "ESCROW_REQUIRED_SIGNATURES", default_value_t = DEFAULT_REQUIRED_SIGNATURES)]
+    required_signatures: u64,
+    /// Whether the signatures releasing the escrow commit to the outputs too (SIG_ALL) or to the inputs only
+    /// (SIG_INPUTS), must be the same for both traders.
+    #[arg(long, env = "ESCROW_SIG_FLAG", default_value = "SIG_ALL")]
+    sig_flag: SigFlag,
+    /// Trade digital goods, delivered encrypted by the seller and decryptable once the escrow is released. Must be the
+    /// same for both traders.
+    #[arg(long, env = "TRADE_DIGITAL_GOODS")]
+    digital_goods: bool,
+    /// Comma separated npubs of further coordinators arbitrating the trade next to ESCROW_NPUB, must be the same for both traders.
+    #[arg(long, env = "ADDITIONAL_ESCROW_NPUBS", value_delimiter = ',')]
+    additional_coordinators: Vec<String>,
+    /// How many coordinators must decide a dispute alike, must be the same for both traders [default: a majority]
+    #[arg(long, env = "ESCROW_COORDINATOR_THRESHOLD")]
+    coordinator_threshold: Option<u64>,
+    /// Comma separated npubs of the buyers the seller trades with [default: any buyer]
+    #[arg(long, env = "SELLER_ALLOWED_BUYERS", value_delimiter = ',')]
+    allowed_buyers: Vec<String>,
+    /// Smallest trade amount the seller accepts.
+    #[arg(long, env = "SELLER_MIN_AMOUNT_SAT", default_value_t = 0)]
+    min_amount_sat: u64,
+    /// Largest trade amount the seller accepts.
+    #[arg(long, env = "SELLER_MAX_AMOUNT_SAT")]
+    max_amount_sat: Option<u64>,
+    /// How the buyer picks the wallet proofs locked into the escrow: fewest-proofs, largest-first, smallest-first or exact-match-preferred.
+    #[arg(long, env = "PROOF_SELECTION", default_value = "fewest-proofs")]
+    proof_selection: ProofSelection,
+    /// Contract shared by the trade partner with `new-contract`, as json or compact word, to trade on instead of the
+    /// contract built from the trade settings. `-` reads it from stdin after the mode, until its end.
+    #[arg(long, visible_alias = "contract", env = "CONTRACT_FILE")]
+    contract_file: Option<PathBuf>,
+    /// Bip39 mnemonic of the ecash wallet, to keep its funds and trade pubkey across runs [default: a fresh wallet]
+    #[arg(long, env = "WALLET_MNEMONIC", hide_env_values = true)]
+    pub wallet_mnemonic: Option<String>,
+    /// Nostr identity to trade with as bech32 nsec, instead of BUYER_NSEC or SELLER_NSEC.
+    #[arg(long, conflicts_with = "nsec_file")]
+    nsec: Option<String>,
+    /// File containing the bech32 nsec of the nostr identity to trade with.
+    #[arg(long)]
+    nsec_file: Option<PathBuf>,
+}
+
+/// Format of the trade progress printed on stdout, the logs always go to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// A [`cashu_escrow_client::escrow_client::TradeEvent`] per line.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown output format {}, use text or json", s)),
+        }
+    }
+}
+
+/// Operations run instead of a trade.
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Report the mint state of every proof of an escrow token, without trading.
+    CheckToken {
+        token: String,
+        /// Snapshot of the trade to check the spending conditions of the token against.
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+    },
+    /// List the coordinators publishing their terms on the relays, the cheapest first, without trading.
+    DiscoverCoordinators {
+        /// Only list the coordinators escrowing tokens of this mint.
+        #[arg(long)]
+        mint_url: Option<MintUrl>,
+        /// Seconds to wait for the relays to answer.
+        #[arg(long, default_value_t = 10)]
+        timeout_secs: u64,
+    },
+    /// Build the contract of the trade settings and print it to share with the trade partner, who trades on it with
+    /// --contract-file, without trading.
+    NewContract {
+        /// What is traded [default: asked for]
+        #[arg(long)]
+        description: Option<String>,
+        /// Trade amount, unless derived from TRADE_FIAT_PRICE [default: asked for]
+        #[arg(long)]
+        amount_sat: Option<u64>,
+        /// File to write the contract json to [default: stdout]
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+        /// Also print the contract as a single word, e.g. for a QR code.
+        #[arg(long)]
+        compact: bool,
+    },
+    /// Trade between a fresh buyer and seller over NOSTR_RELAYS, the MINT_URL mint and the running ESCROW_NPUB
+    /// coordinator, failing unless the seller redeems the trade amount.
+    LocalTrade {
+        #[arg(long, default_value_t = 5000)]
+        amount_sat: u64,
+    },
+    /// List the in-flight trades persisted in the snapshot directory, without trading.
+    ListTrades {
+        #[arg(long, env = "SNAPSHOT_DIR")]
+        snapshot_dir: PathBuf,
+    },
+    /// Check that the relays and mints are reachable and the configured keys are valid, without trading.
+    Doctor {
+        /// Seconds to wait for the relays to connect.
+        #[arg(long, default_value_t = 10)]
+        timeout_secs: u64,
+    },
+    /// Decrypt a wallet backup written with --backup-file and print its tokens to receive in any wallet, without
+    /// trading.
+    ShowBackup { file: PathBuf },
+    /// Report the arrival of the escrow messages to a trader, without its nsec and without trading. The messages stay
+    /// encrypted, gift wraps hide their sender.
+    Watch {
+        /// The watched trader [default: SELLER_NPUB]
+        #[arg(long, env = "SELLER_NPUB")]
+        npub: String,
+        /// Also report the stored messages of the last this many seconds.
+        #[arg(long)]
+        since_secs: Option<u64>,
+    },
+}
 
 #[derive(Debug)]
 struct RawCliInput {
+    shared_contract: Option<TradeContract>,
     buyer_npub: String,
     seller_npub: String,
     partner_ecash_pubkey: String,
     coordinator_npub: String,
-    nostr_nsec: String,
-    mode: TradeMode,
+    message_timeout: Option<Duration>,
+    coordinator_fee_sat: u64,
+    trade_expiry: Option<u64>,
+    fiat_price: Option<String>,
+    fiat_currency: FiatCurrency,
+    milestones_sat: Vec<u64>,
+    oracle_npub: Option<String>,
+    refund_pubkey: Option<String>,
+    required_signatures: u64,
+    sig_flag: SigFlag,
+    digital_goods: bool,
+    additional_coordinators: Vec<String>,
+    coordinator_threshold: Option<u64>,
+    allowed_buyers: Vec<String>,
+    min_amount_sat: u64,
+    max_amount_sat: Option<u64>,
+    proof_selection: ProofSelection,
+}
+
+/// The trade mode and nostr identity of the trader.
+#[derive(Debug)]
+pub struct TraderIdentity {
+    pub mode: TradeMode,
+    pub nostr_keys: NostrKeys,
 }
 
 #[derive(Debug)]
 pub struct ClientCliInput {
     pub mode: TradeMode,
     pub trader_nostr_keys: NostrKeys,
+    /// The contract of `--contract-file`, traded on instead of the one built from the input.
+    pub shared_contract: Option<TradeContract>,
     pub ecash_pubkey_partner: EcashPubkey,
     pub coordinator_nostr_pubkey: NostrPubkey,
     pub trade_partner_nostr_pubkey: NostrPubkey,
+    /// The time to wait for each message, forever if `None`.
+    pub message_timeout: Option<Duration>,
+    pub coordinator_fee_sat: u64,
+    pub trade_expiry: Option<Timestamp>,
+    pub fiat_price: Option<FiatPrice>,
+    pub milestones_sat: Vec<u64>,
+    pub oracle_nostr_pubkey: Option<NostrPubkey>,
+    pub buyer_refund_pubkey: Option<EcashPubkey>,
+    pub required_signatures: u64,
+    pub sig_flag: SigFlag,
+    pub digital_goods: bool,
+    pub additional_coordinator_nostr_pubkeys: Vec<NostrPubkey>,
+    pub coordinator_threshold: Option<u64>,
+    pub seller_policy: SellerPolicy,
+    pub proof_selection: ProofSelection,
 }
 
-impl RawCliInput {
-    async fn parse() -> anyhow::Result<Self> {
-        // information would be communicated OOB in production
-        let buyer_npub: String = env::var("BUYER_NPUB")?;
-        let seller_npub: String = env::var("SELLER_NPUB")?;
-        let coordinator_npub: String = env::var("ESCROW_NPUB")?;
-
-        let partner_ecash_pubkey: String;
-        let nostr_nsec: String;
-
-        let mode = match get_user_input("Select mode: (1) buyer, (2) seller: ")
+impl TraderIdentity {
+    /// Asks for the trade mode and loads the nostr identity of `--nsec`, `--nsec-file` or the env of the mode.
+    ///
+    /// Without any of them a fresh identity is generated.
+    pub async fn parse(args: &CliArgs) -> anyhow::Result<Self> {
+        let (mode, mode_nsec) = match get_user_input("Select mode: (1) buyer, (2) seller: ")
             .await?
             .as_str()
         {
-            "1" => {
-                nostr_nsec = env::var("BUYER_NSEC")?;
-                partner_ecash_pubkey = get_user_input("Enter seller's ecash pubkey: ").await?;
-                TradeMode::Buyer
-            }
-            "2" => {
-                nostr_nsec = env::var("SELLER_NSEC")?;
-                partner_ecash_pubkey = get_user_input("Enter buyer's ecash pubkey: ").await?;
-                TradeMode::Seller
-            }
+            "1" => (TradeMode::Buyer, env::var("BUYER_NSEC").ok()),
+            "2" => (TradeMode::Seller, env::var("SELLER_NSEC").ok()),
             _ => {
                 panic!("Wrong trading mode selected. Select either (1) buyer or (2) seller");
             }
         };
+        let nsec = match (&args.nsec, &args.nsec_file) {
+            (Some(nsec), _) => Some(nsec.clone()),
+            (None, Some(nsec_file)) => Some(
+                fs::read_to_string(nsec_file)
+                    .map_err(|e| anyhow!("Failed to read {}: {}", nsec_file.display(), e))?
+                    .trim()
+                    .to_string(),
+            ),
+            (None, None) => mode_nsec,
+        };
+        let nostr_keys = match nsec {
+            Some(nsec) => parse_nsec(&nsec)?,
+            None => {
+                let keys = NostrKeys::generate();
+                info!(
+                    "No nsec given, trading with the fresh identity {}",
+                    keys.public_key().to_bech32()?
+                );
+                keys
+            }
+        };
+        Ok(Self { mode, nostr_keys })
+    }
+}
+
+impl RawCliInput {
+    async fn parse(args: CliArgs, mode: TradeMode) -> anyhow::Result<Self> {
+        let shared_contract = args
+            .contract_file
+            .as_deref()
+            .map(read_shared_contract)
+            .transpose()?;
+        let (buyer_npub, seller_npub, coordinator_npub, partner_ecash_pubkey) =
+            match &shared_contract {
+                Some(contract) => (
+                    contract.npubkey_buyer.to_bech32()?,
+                    contract.npubkey_seller.to_bech32()?,
+                    contract.npubkey_coordinator.to_bech32()?,
+                    match mode {
+                        TradeMode::Buyer => contract.seller_ecash_public_key.clone(),
+                        TradeMode::Seller => contract.buyer_ecash_public_key.clone(),
+                    },
+                ),
+                // information would be communicated OOB in production
+                None => (
+                    env::var("BUYER_NPUB")?,
+                    env::var("SELLER_NPUB")?,
+                    env::var("ESCROW_NPUB")?,
+                    match mode {
+                        TradeMode::Buyer => get_user_input("Enter seller's ecash pubkey: ").await?,
+                        TradeMode::Seller => get_user_input("Enter buyer's ecash pubkey: ").await?,
+                    },
+                ),
+            };
         Ok(Self {
+            shared_contract,
             buyer_npub,
             seller_npub,
             partner_ecash_pubkey,
             coordinator_npub,
-            nostr_nsec,
-            mode,
+            message_timeout: (!args.wait_forever)
+                .then(|| Duration::from_secs(args.message_timeout_secs)),
+            coordinator_fee_sat: args.coordinator_fee_sat,
+            trade_expiry: args.trade_expiry,
+            fiat_price: args.fiat_price,
+            fiat_currency: args.fiat_currency,
+            milestones_sat: args.milestones_sat,
+            oracle_npub: args.oracle_npub,
+            refund_pubkey: args.refund_pubkey,
+            required_signatures: args.required_signatures,
+            sig_flag: args.sig_flag,
+            digital_goods: args.digital_goods,
+            additional_coordinators: args.additional_coordinators,
+            coordinator_threshold: args.coordinator_threshold,
+            allowed_buyers: args.allowed_buyers,
+            min_amount_sat: args.min_amount_sat,
+            max_amount_sat: args.max_amount_sat,
+            proof_selection: args.proof_selection,
         })
     }
 }
 
 impl ClientCliInput {
-    pub async fn parse() -> anyhow::Result<Self> {
-        let raw_input = RawCliInput::parse().await?;
+    pub async fn parse(args: CliArgs, identity: TraderIdentity) -> anyhow::Result<Self> {
+        let raw_input = RawCliInput::parse(args, identity.mode).await?;
         debug!("Raw parsed CLI input: {:?}", raw_input);
 
         let ecash_pubkey_partner = EcashPubkey::from_str(&raw_input.partner_ecash_pubkey)?;
 
-        let trader_nostr_keys = NostrKeys::from_str(&raw_input.nostr_nsec)?;
         let coordinator_nostr_pubkey = NostrPubkey::from_str(&raw_input.coordinator_npub)?;
-        let trade_partner_nostr_pubkey = match raw_input.mode {
+        let trade_partner_nostr_pubkey = match identity.mode {
             TradeMode::Buyer => NostrPubkey::from_bech32(&raw_input.seller_npub)?,
             TradeMode::Seller => NostrPubkey::from_bech32(&raw_input.buyer_npub)?,
         };
+        let oracle_nostr_pubkey = raw_input
+            .oracle_npub
+            .as_deref()
+            .map(NostrPubkey::from_bech32)
+            .transpose()?;
+        let additional_coordinator_nostr_pubkeys = raw_input
+            .additional_coordinators
+            .iter()
+            .map(NostrPubkey::from_bech32)
+            .collect::<Result<_, _>>()?;
+        let buyer_refund_pubkey = raw_input
+            .refund_pubkey
+            .as_deref()
+            .map(EcashPubkey::from_str)
+            .transpose()
+            .map_err(|e| anyhow!("Invalid refund pubkey: {}", e))?;
+        let fiat_price = raw_input
+            .fiat_price
+            .as_deref()
+            .map(|price| -> anyhow::Result<FiatPrice> {
+                Ok(FiatPrice {
+                    amount_cents: parse_fiat_cents(price)?,
+                    currency: raw_input.fiat_currency,
+                    rate: None,
+                })
+            })
+            .transpose()?;
+        let seller_policy = SellerPolicy {
+            allowed_buyers: raw_input
+                .allowed_buyers
+                .iter()
+                .map(NostrPubkey::from_bech32)
+                .collect::<Result<_, _>>()?,
+            min_amount_sat: raw_input.min_amount_sat,
+            max_amount_sat: raw_input.max_amount_sat,
+            ..Default::default()
+        };
 
         Ok(Self {
-            mode: raw_input.mode,
-            trader_nostr_keys,
+            mode: identity.mode,
+            trader_nostr_keys: identity.nostr_keys,
+            shared_contract: raw_input.shared_contract,
             ecash_pubkey_partner,
             coordinator_nostr_pubkey,
             trade_partner_nostr_pubkey,
+            message_timeout: raw_input.message_timeout,
+            coordinator_fee_sat: raw_input.coordinator_fee_sat,
+            trade_expiry: raw_input.trade_expiry.map(Timestamp::from),
+            fiat_price,
+            milestones_sat: raw_input.milestones_sat,
+            oracle_nostr_pubkey,
+            buyer_refund_pubkey,
+            required_signatures: raw_input.required_signatures,
+            sig_flag: raw_input.sig_flag,
+            digital_goods: raw_input.digital_goods,
+            additional_coordinator_nostr_pubkeys,
+            coordinator_threshold: raw_input.coordinator_threshold,
+            seller_policy,
+            proof_selection: raw_input.proof_selection,
         })
     }
 }
+
+/// Parses a fiat amount with at most two decimals, e.g. `12.5`, into cents.
+fn parse_fiat_cents(amount: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow!("Invalid fiat price {}, expected e.g. 12.50", amount);
+    let (units, cents) = amount.trim().split_once('.').unwrap_or((amount.trim(), ""));
+    if cents.len() > 2 {
+        return Err(invalid());
+    }
+    let units: u64 = units.parse().map_err(|_| invalid())?;
+    let cents: u64 = match cents.len() {
+        0 => 0,
+        1 => cents.parse::<u64>().map_err(|_| invalid())? * 10,
+        _ => cents.parse().map_err(|_| invalid())?,
+    };
+    Ok(units * 100 + cents)
+}
+
+/// Reads the contract of `contract_file`, or of stdin for `-`, and checks its terms.
+fn read_shared_contract(contract_file: &Path) -> anyhow::Result<TradeContract> {
+    let shared = match contract_file.to_str() {
+        Some("-") => io::read_to_string(io::stdin())?,
+        _ => fs::read_to_string(contract_file)
+            .map_err(|e| anyhow!("Failed to read {}: {}", contract_file.display(), e))?,
+    };
+    let contract = TradeContract::from_shared(&shared)?;
+    contract
+        .validate()
+        .map_err(|e| anyhow!("Invalid trade contract: {}", e))?;
+    Ok(contract)
+}
+
+/// Parses every configured key, returning the setting of each with its public key or why it is invalid.
+pub fn check_keys(args: &CliArgs) -> Vec<(String, anyhow::Result<String>)> {
+    let mut checks = Vec::new();
+    for (nsec_var, npub_var) in [("BUYER_NSEC", "BUYER_NPUB"), ("SELLER_NSEC", "SELLER_NPUB")] {
+        let npub = env::var(npub_var).ok();
+        if let Ok(nsec) = env::var(nsec_var) {
+            checks.push((nsec_var.to_string(), check_nsec(&nsec, npub.as_deref())));
+        }
+        if let Some(npub) = npub {
+            checks.push((npub_var.to_string(), check_npub(&npub)));
+        }
+    }
+    if let Ok(npub) = env::var("ESCROW_NPUB") {
+        checks.push(("ESCROW_NPUB".to_string(), check_npub(&npub)));
+    }
+    for npub in &args.additional_coordinators {
+        checks.push(("ADDITIONAL_ESCROW_NPUBS".to_string(), check_npub(npub)));
+    }
+    if let Some(npub) = &args.oracle_npub {
+        checks.push(("TRADE_ORACLE_NPUB".to_string(), check_npub(npub)));
+    }
+    if let Some(refund_pubkey) = &args.refund_pubkey {
+        let check = EcashPubkey::from_hex(refund_pubkey)
+            .map(|_| refund_pubkey.clone())
+            .map_err(|e| anyhow!("Invalid ecash pubkey: {}", e));
+        checks.push(("BUYER_REFUND_PUBKEY".to_string(), check));
+    }
+    match (&args.nsec, &args.nsec_file) {
+        (Some(nsec), _) => checks.push(("--nsec".to_string(), check_nsec(nsec, None))),
+        (None, Some(nsec_file)) => {
+            let check = fs::read_to_string(nsec_file)
+                .map_err(|e| anyhow!("Failed to read {}: {}", nsec_file.display(), e))
+                .and_then(|nsec| check_nsec(nsec.trim(), None));
+            checks.push(("--nsec-file".to_string(), check));
+        }
+        (None, None) => {}
+    }
+    checks
+}
+
+/// The npub of `nsec`, failing unless it is `npub` if given.
+fn check_nsec(nsec: &str, npub: Option<&str>) -> anyhow::Result<String> {
+    let public_key = parse_nsec(nsec)?.public_key();
+    if let Some(npub) = npub {
+        if NostrPubkey::from_bech32(npub).ok() != Some(public_key) {
+            return Err(anyhow!(
+                "Belongs to {}, not to {}",
+                public_key.to_bech32()?,
+                npub
+            ));
+        }
+    }
+    Ok(public_key.to_bech32()?)
+}
+
+fn check_npub(npub: &str) -> anyhow::Result<String> {
+    NostrPubkey::from_bech32(npub).map_err(|e| anyhow!("Invalid npub: {}", e))?;
+    Ok(npub.to_string())
+}
+
+fn parse_nsec(nsec: &str) -> anyhow::Result<NostrKeys> {
+    let secret_key = SecretKey::from_bech32(nsec).map_err(|e| {
+        anyhow!(
+            "Invalid nsec, expected a bech32 key starting with nsec1: {}",
+            e
+        )
+    })?;
+    Ok(NostrKeys::new(secret_key))
+}
@@ -2,12 +2,14 @@ use super::*;
 
 use cashu_escrow_client::escrow_client::TradeMode;
 use cashu_escrow_common::model::TradeContract;
+use cdk::{mint_url::MintUrl, nuts::CurrencyUnit};
 use nostr_sdk::prelude::*;
 
 pub trait FromClientCliInput {
     fn from_client_cli_input(
         cli_input: &ClientCliInput,
         trade_pubkey: String,
+        mint_url: MintUrl,
     ) -> anyhow::Result<TradeContract>;
 }
 
@@ -15,6 +17,7 @@ impl FromClientCliInput for TradeContract {
     fn from_client_cli_input(
         cli_input: &ClientCliInput,
         trade_pubkey: String,
+        mint_url: MintUrl,
     ) -> anyhow::Result<Self> {
         debug!("Constructing hard coded client trade contract...");
         let npubkey_seller: PublicKey;
@@ -36,16 +39,50 @@ impl FromClientCliInput for TradeContract {
             TradeMode::Buyer => (cli_input.ecash_pubkey_partner.to_string(), trade_pubkey),
         };
         // hardcoded trade contract
-        Ok(TradeContract {
+        let contract = TradeContract {
             trade_description:
                 "Purchase of one Watermelon for 5000 satoshi. 3 days delivery to ...".to_string(),
-            trade_amount_sat: 5000,
+            // derived from the fiat price once the buyer locks the exchange rate
+            trade_amount_sat: if cli_input.fiat_price.is_some() {
+                0
+            } else {
+                5000
+            },
+            coordinator_fee_sat: cli_input.coordinator_fee_sat,
+            unit: CurrencyUnit::Sat,
+            mint_url,
             npubkey_seller,
             npubkey_buyer,
             npubkey_coordinator: cli_input.coordinator_nostr_pubkey,
-            time_limit: 3 * 24 * 60 * 60,
+            expiry: cli_input.trade_expiry.unwrap_or_else(default_trade_expiry),
             seller_ecash_public_key: ecash_pubkey_seller,
             buyer_ecash_public_key: ecash_pubkey_buyer,
-        })
+            buyer_refund_public_key: cli_input.buyer_refund_pubkey.map(|pk| pk.to_string()),
+            milestones: cli_input
+                .milestones_sat
+                .iter()
+                .copied()
+                .map(Amount::from)
+                .collect(),
+            oracle_pubkey: cli_input.oracle_nostr_pubkey,
+            required_signatures: cli_input.required_signatures,
+            sig_flag: cli_input.sig_flag,
+            additional_coordinators: cli_input.additional_coordinator_nostr_pubkeys.clone(),
+            coordinator_threshold: cli_input.coordinator_threshold,
+            fiat_price: cli_input.fiat_price.clone(),
+            digital_goods: cli_input.digital_goods,
+        };
+        // malformed contracts fail here instead of during the registration with the coordinator
+        contract
+            .validate()
+            .map_err(|e| anyhow!("Invalid trade contract: {}", e))?;
+        Ok(contract)
     }
 }
+
+/// Three days after the next UTC midnight, so both traders derive the same expiry on the same day.
+fn default_trade_expiry() -> Timestamp {
+    const DAY_SECS: u64 = 24 * 60 * 60;
+    let next_midnight = (Timestamp::now().as_u64() / DAY_SECS + 1) * DAY_SECS;
+    Timestamp::from(next_midnight + 3 * DAY_SECS)
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use env_logger::{Target, WriteStyle};
+
+/// Rotated log files kept besides the current one, as `<log file>.1` (the newest) to `<log file>.5`.
+const MAX_ROTATED_LOG_FILES: usize = 5;
+
+/// Logs to stderr or, given `log_file`, to that file, rotated once it grows beyond `max_log_file_bytes`.
+///
+/// Levels default to trace for the client and info for all other crates, `RUST_LOG` overrides them per module, e.g.
+/// `RUST_LOG=cashu_escrow_client=debug,nostr_relay_pool=warn`.
+pub fn init_logging(log_file: Option<&Path>, max_log_file_bytes: u64) -> anyhow::Result<()> {
+    let mut builder = env_logger::builder();
+    builder
+        .filter_module("cashu_escrow_client", log::LevelFilter::Trace) // logging level of the client
+        .filter_level(log::LevelFilter::Info) // logging level of all other crates
+        .parse_default_env();
+    if let Some(log_file) = log_file {
+        let log_file = RotatingLogFile::open(log_file.to_path_buf(), max_log_file_bytes)?;
+        builder
+            .target(Target::Pipe(Box::new(log_file)))
+            .write_style(WriteStyle::Never);
+    }
+    builder.try_init()?;
+    Ok(())
+}
+
+/// Log file moved aside to `<path>.1` once it exceeds `max_bytes`, shifting the older ones up.
+struct RotatingLogFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    written_bytes: u64,
+}
+
+impl RotatingLogFile {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written_bytes = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file,
+            written_bytes,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for index in (1..MAX_ROTATED_LOG_FILES).rev() {
+            let older = self.rotated_path(index);
+            if older.exists() {
+                fs::rename(&older, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = File::create(&self.path)?;
+        self.written_bytes = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written_bytes > 0 && self.written_bytes + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written_bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -1,50 +1,711 @@
 mod cli;
+mod logging;
 
 use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use anyhow::anyhow;
+use cashu_escrow_client::clock::RelayClock;
+use cashu_escrow_client::dry_run;
 use cashu_escrow_client::ecash::ClientEcashWallet;
-use cashu_escrow_client::escrow_client::{InitEscrowClient, TradeMode};
-use cashu_escrow_common::model::TradeContract;
-use cashu_escrow_common::nostr::NostrClient;
+use cashu_escrow_client::ecash::EscrowWallet;
+use cashu_escrow_client::escrow_client::{
+    negotiate_contract, ContractResponder, EscrowSnapshot, EscrowStore, InitEscrowClient,
+    ProposalResponse, TradeEvent, TradeEventSink, TradeMode, DEFAULT_MAX_NEGOTIATION_ROUNDS,
+};
+use cashu_escrow_client::local_trade::{run_local_trade, LocalTradeConfig};
+use cashu_escrow_client::rates::{
+    ExchangeRateSource, PriceApiRateSource, MAX_RATE_DEVIATION_PERCENT,
+};
+use cashu_escrow_common::cli::get_user_input;
+use cashu_escrow_common::error::EscrowError;
+use cashu_escrow_common::model::{DeliveryProof, TradeContract};
+use cashu_escrow_common::nostr::{
+    keepalive_interval_from_env, messaging_scheme_from_env, proxy_from_env, relays_from_env,
+    shutdown_client, ConnectionRetry, EscrowTransport, MessageWatcher, NostrClient,
+};
 use cdk::amount::{Amount, SplitTarget};
+use cdk::mint_url::MintUrl;
+use cdk::nuts::{SecretKey as EcashSecretKey, Token};
+use clap::Parser;
 use cli::trade_contract::FromClientCliInput;
-use cli::ClientCliInput;
+use cli::{check_keys, CliArgs, CliCommand, ClientCliInput, OutputFormat, TraderIdentity};
 use dotenv::dotenv;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use nostr_sdk::{FromBech32, Keys, PublicKey, Timestamp, ToBech32};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
-    env_logger::builder()
-        .filter_module("cashu_escrow_client", log::LevelFilter::Trace) // logging level of the client
-        .filter_level(log::LevelFilter::Info) // logging level of all other crates
-        .init();
+    let mut args = CliArgs::parse();
+    logging::init_logging(args.log_file.as_deref(), args.log_file_max_mb * 1024 * 1024)?;
+    if args.dry_run {
+        let redeemed_amount = dry_run::run_dry_run_trade(5000).await?;
+        info!("Dry run finished, seller redeemed {} sat", redeemed_amount);
+        return Ok(());
+    }
+    if let Some(CliCommand::CheckToken { token, snapshot }) = &args.command {
+        return check_token(token, snapshot.as_deref()).await;
+    }
+    if let Some(CliCommand::DiscoverCoordinators {
+        mint_url,
+        timeout_secs,
+    }) = &args.command
+    {
+        return discover_coordinators(mint_url.as_ref(), *timeout_secs).await;
+    }
+    if let Some(CliCommand::LocalTrade { amount_sat }) = &args.command {
+        return local_trade(*amount_sat, &args).await;
+    }
+    if let Some(CliCommand::ListTrades { snapshot_dir }) = &args.command {
+        return list_trades(&EscrowStore::new(snapshot_dir));
+    }
+    if let Some(CliCommand::Doctor { timeout_secs }) = &args.command {
+        return doctor(&args, *timeout_secs).await;
+    }
+    if let Some(CliCommand::Watch { npub, since_secs }) = &args.command {
+        return watch(npub, *since_secs).await;
+    }
 
-    let mint_url = env::var("MINT_URL")?;
-    let escrow_wallet = ClientEcashWallet::new(&mint_url).await?;
+    let identity = TraderIdentity::parse(&args).await?;
+    // with a wallet mnemonic the trade key belongs to the wallet, else to the nostr identity
+    let trade_secret = match &args.wallet_mnemonic {
+        Some(mnemonic) => ClientEcashWallet::trade_secret_from_mnemonic(mnemonic)?,
+        None => ClientEcashWallet::trade_secret_from_nostr_keys(&identity.nostr_keys)?,
+    };
+    if args.show_identity {
+        println!("npub: {}", identity.nostr_keys.public_key().to_bech32()?);
+        println!("ecash trade pubkey: {}", trade_secret.public_key());
+        return Ok(());
+    }
+    if let Some(CliCommand::ShowBackup { file }) = &args.command {
+        let backup = ClientEcashWallet::read_backup(file, &trade_secret)?;
+        info!(
+            "Backup created at {}",
+            backup.created_at.to_human_datetime()
+        );
+        for token in backup.tokens {
+            println!("{}", token);
+        }
+        return Ok(());
+    }
+
+    // MINT_URL is only the default of the contract mint, the wallet is created for the mint agreed in the contract
+    let trade_mint_url =
+        MintUrl::from_str(&env::var("TRADE_MINT_URL").or_else(|_| env::var("MINT_URL"))?)?;
 
-    let cli_input = ClientCliInput::parse().await?;
+    let new_contract_command = match args.command.take() {
+        Some(command @ CliCommand::NewContract { .. }) => Some(command),
+        _ => None,
+    };
+    let wallet_mnemonic = args.wallet_mnemonic.clone();
+    let print_metrics = args.print_metrics;
+    let negotiate = args.negotiate;
+    let receipt_dir = args.receipt_dir.clone();
+    let backup_file = args.backup_file.clone();
+    let time_relay = args.time_relay.clone();
+    let payout_ln_address = args.payout_ln_address.clone();
+    let digital_goods_file = args.digital_goods_file.clone();
+    let received_goods_file = args.received_goods_file.clone();
+    let send_receipt_to_coordinator = args.send_receipt_to_coordinator;
+    let coordinator_relays = args.coordinator_relays.clone();
+    let message_lookback_secs = args.message_lookback_secs;
+    let confirm_trade_steps = !args.yes;
+    let event_printer = match args.output {
+        OutputFormat::Text => None,
+        OutputFormat::Json => Some(Arc::new(JsonEventPrinter::default())),
+    };
+    let rate_source = Arc::new(PriceApiRateSource::new(args.price_api_url.clone()));
+    let cli_input = ClientCliInput::parse(args, identity).await?;
+
+    if let Some(CliCommand::NewContract {
+        description,
+        amount_sat,
+        output_file,
+        compact,
+    }) = new_contract_command
+    {
+        let mut contract = TradeContract::from_client_cli_input(
+            &cli_input,
+            trade_secret.public_key().to_string(),
+            trade_mint_url,
+        )?;
+        contract.trade_description = match description {
+            Some(description) => description,
+            None => get_user_input("Enter the trade description: ").await?,
+        };
+        if contract.fiat_price.is_none() {
+            contract.trade_amount_sat = match amount_sat {
+                Some(amount_sat) => amount_sat,
+                None => {
+                    let amount = get_user_input("Enter the trade amount in sat: ").await?;
+                    amount
+                        .trim()
+                        .parse()
+                        .map_err(|e| anyhow!("Invalid trade amount {}: {}", amount, e))?
+                }
+            };
+        }
+        return share_contract(&contract, output_file.as_deref(), compact);
+    }
+    let mut escrow_contract = match cli_input.shared_contract.clone() {
+        Some(contract) => contract,
+        None => TradeContract::from_client_cli_input(
+            &cli_input,
+            trade_secret.public_key().to_string(),
+            trade_mint_url,
+        )?,
+    };
+
+    let mut nostr_client = NostrClient::new(
+        cli_input.trader_nostr_keys.clone(),
+        relays_from_env(),
+        messaging_scheme_from_env()?,
+        proxy_from_env()?,
+    )
+    .await?
+    .with_keepalive_interval(keepalive_interval_from_env()?);
+    if let Some(message_lookback_secs) = message_lookback_secs {
+        nostr_client.replay_messages_since(Timestamp::now() - message_lookback_secs);
+    }
+    if !coordinator_relays.is_empty() {
+        nostr_client
+            .add_private_relays(cli_input.coordinator_nostr_pubkey, coordinator_relays)
+            .await?;
+    }
+    if negotiate {
+        let initial_proposal = match cli_input.mode {
+            TradeMode::Buyer => Some(escrow_contract),
+            TradeMode::Seller => None,
+        };
+        escrow_contract = negotiate_contract(
+            &mut nostr_client,
+            cli_input.trade_partner_nostr_pubkey,
+            initial_proposal,
+            &mut UserResponder,
+            DEFAULT_MAX_NEGOTIATION_ROUNDS,
+            cli_input.message_timeout,
+        )
+        .await?;
+        info!(
+            "Agreed on {} sat for the trade",
+            escrow_contract.trade_amount_sat
+        );
+    }
+    let contract_mint_url = escrow_contract.mint_url.to_string();
+    let escrow_wallet = match wallet_mnemonic {
+        Some(mnemonic) => {
+            let wallet = ClientEcashWallet::from_mnemonic(
+                &contract_mint_url,
+                &accepted_mint_urls_from_env(),
+                &mnemonic,
+            )
+            .await?;
+            info!(
+                "Restored {} sat of the wallet mnemonic",
+                wallet.restore().await?
+            );
+            wallet
+        }
+        None => {
+            ClientEcashWallet::new(
+                &contract_mint_url,
+                &accepted_mint_urls_from_env(),
+                trade_secret,
+            )
+            .await?
+        }
+    }
+    .with_proof_selection(cli_input.proof_selection)
+    .with_backup_file(backup_file);
 
     //Ensure to have enough funds in the wallet.
-    if cli_input.mode == TradeMode::Buyer {
-        let mint_quote = escrow_wallet.wallet.mint_quote(Amount::from(5000)).await?;
-        escrow_wallet
-            .wallet
+    let mut funding_contract = escrow_contract.clone();
+    if let Some(price) = &escrow_contract.fiat_price {
+        if cli_input.mode == TradeMode::Buyer && funding_contract.needs_fiat_rate() {
+            // the rate is locked at the registration, so the funds are estimated with the current rate
+            funding_contract.lock_fiat_rate(rate_source.exchange_rate(price.currency).await?)?;
+        }
+    }
+    if cli_input.mode == TradeMode::Buyer
+        && escrow_wallet
+            .ensure_escrow_funds(&funding_contract)
+            .await
+            .is_err()
+    {
+        let trade_wallet = escrow_wallet.mint_wallet(&escrow_contract.mint_url)?;
+        // with margin for the rate to change until the registration
+        let funding_sat = funding_contract.buyer_total_sat()?.saturating_add(
+            funding_contract
+                .trade_amount_sat
+                .saturating_mul(MAX_RATE_DEVIATION_PERCENT)
+                / 100,
+        );
+        let mint_quote = trade_wallet.mint_quote(Amount::from(funding_sat)).await?;
+        trade_wallet
             .mint(&mint_quote.id, SplitTarget::None, None)
             .await?;
     }
 
-    let escrow_contract =
-        TradeContract::from_client_cli_input(&cli_input, escrow_wallet.trade_pubkey.clone())?;
-    let nostr_client = NostrClient::new(cli_input.trader_nostr_keys).await?;
+    // the redeemed escrow funds are paid out from the wallet of the contract mint
+    let payout_mint_url = escrow_contract.mint_url.clone();
+    // kept to disconnect from the relays after the escrow client took the nostr client
+    let relay_client = nostr_client.client.clone();
+    let metrics = nostr_client.metrics();
 
-    InitEscrowClient::new(nostr_client, escrow_wallet, escrow_contract, cli_input.mode)
-        .register_trade()
-        .await?
-        .exchange_trade_token()
-        .await?
-        .do_your_trade_duties()
+    let mut escrow_client =
+        InitEscrowClient::new(nostr_client, escrow_wallet, escrow_contract, cli_input.mode)
+            .with_message_timeout(cli_input.message_timeout)
+            .with_seller_policy(cli_input.seller_policy.clone())
+            .with_metrics(metrics.clone())
+            .with_rate_source(rate_source);
+    if let Ok(snapshot_dir) = env::var("SNAPSHOT_DIR") {
+        escrow_client = escrow_client.with_snapshot_dir(snapshot_dir);
+    }
+    if let Some(receipt_dir) = receipt_dir {
+        escrow_client = escrow_client.with_receipt_dir(receipt_dir);
+    }
+    if send_receipt_to_coordinator {
+        escrow_client = escrow_client.with_receipt_sent_to_coordinator();
+    }
+    if let Some(time_relay) = &time_relay {
+        escrow_client = escrow_client.with_time_source(Arc::new(RelayClock::new(time_relay)));
+    }
+    if confirm_trade_steps {
+        escrow_client = escrow_client
+            .with_funding_confirmation()
+            .with_milestone_confirmation();
+    }
+    if let Some(event_printer) = &event_printer {
+        escrow_client = escrow_client.with_event_sink(event_printer.clone());
+    }
+    if let (TradeMode::Seller, Some(digital_goods_file)) = (cli_input.mode, &digital_goods_file) {
+        escrow_client = escrow_client.with_digital_goods(fs::read(digital_goods_file)?);
+    }
+    let trade = async {
+        let token_exchanged_client = escrow_client
+            .register_trade()
+            .await?
+            .exchange_trade_token()
+            .await?;
+        if cli_input.mode == TradeMode::Seller && cli_input.oracle_nostr_pubkey.is_some() {
+            let delivery_proof: DeliveryProof = nostr_sdk::serde_json::from_str(
+                &get_user_input("Enter the delivery proof of the oracle: ").await?,
+            )?;
+            token_exchanged_client
+                .submit_delivery_proof(&delivery_proof)
+                .await?;
+        }
+        let settled_client = token_exchanged_client.do_your_trade_duties().await?;
+        if let Some(digital_goods) = settled_client.digital_goods() {
+            match &received_goods_file {
+                Some(received_goods_file) => {
+                    fs::write(received_goods_file, digital_goods)?;
+                    info!(
+                        "Saved the digital goods to {}",
+                        received_goods_file.display()
+                    );
+                }
+                None => info!("Received {} bytes of digital goods", digital_goods.len()),
+            }
+        }
+        if cli_input.mode == TradeMode::Seller {
+            let amount = settled_client.redeem_escrow_token().await?;
+            info!("Redeemed {} sat of the escrow token", amount);
+            if let Some(payout_ln_address) = &payout_ln_address {
+                // the trade is done, a failed payout leaves the funds in the wallet
+                if let Err(e) = settled_client
+                    .ecash_wallet()
+                    .melt_to_lightning_address(&payout_mint_url, payout_ln_address)
+                    .await
+                {
+                    warn!(
+                        "Failed to pay the funds out to {}, they stay in the wallet: {}",
+                        payout_ln_address, e
+                    );
+                }
+            }
+        }
+        Ok(())
+    };
+    let result: anyhow::Result<()> = tokio::select! {
+        result = trade => result,
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Interrupted, shutting down...");
+            Ok(())
+        }
+    };
+    if let (Err(e), Some(event_printer)) = (&result, &event_printer) {
+        event_printer.emit(TradeEvent::Failed {
+            escrow_id: event_printer.escrow_id(),
+            error: e.to_string(),
+        });
+    }
+    shutdown_client(&relay_client).await?;
+    if print_metrics {
+        println!("{}", metrics.snapshot());
+    }
+    result
+}
+
+/// Prints every trade event as a line of json on stdout, remembering the escrow id for the failure event.
+#[derive(Default)]
+struct JsonEventPrinter {
+    escrow_id: Mutex<Option<String>>,
+}
+
+impl JsonEventPrinter {
+    fn escrow_id(&self) -> Option<String> {
+        self.escrow_id
+            .lock()
+            .expect("Event printer lock poisoned")
+            .clone()
+    }
+}
+
+impl TradeEventSink for JsonEventPrinter {
+    fn emit(&self, event: TradeEvent) {
+        if let TradeEvent::StateChanged { escrow_id, .. } = &event {
+            *self.escrow_id.lock().expect("Event printer lock poisoned") = Some(escrow_id.clone());
+        }
+        match nostr_sdk::serde_json::to_string(&event) {
+            Ok(line) => println!("{}", line),
+            Err(e) => error!("Failed to serialize the trade event {:?}: {}", event, e),
+        }
+    }
+}
+
+/// Asks the user to accept, counter or reject the contract proposed by the trade partner.
+struct UserResponder;
+
+#[async_trait::async_trait]
+impl ContractResponder for UserResponder {
+    async fn respond(&mut self, proposal: &TradeContract) -> Result<ProposalResponse, EscrowError> {
+        println!(
+            "The trade partner proposes {} sat for \"{}\", expiring {}, at {}",
+            proposal.trade_amount_sat,
+            proposal.trade_description,
+            proposal.expiry.to_human_datetime(),
+            proposal.mint_url
+        );
+        let answer = get_user_input(
+            "Press enter to accept, enter another trade amount in sat to counter, or 'r' to reject: ",
+        )
         .await?;
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(ProposalResponse::Accept);
+        }
+        if answer.eq_ignore_ascii_case("r") {
+            return Ok(ProposalResponse::Reject("rejected by the user".to_string()));
+        }
+        let trade_amount_sat = answer
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid trade amount {}: {}", answer, e))?;
+        Ok(ProposalResponse::Counter(Box::new(TradeContract {
+            trade_amount_sat,
+            ..proposal.clone()
+        })))
+    }
+}
+
+/// Reads the further accepted mints from the comma separated `ACCEPTED_MINT_URLS` environment variable.
+fn accepted_mint_urls_from_env() -> Vec<String> {
+    env::var("ACCEPTED_MINT_URLS")
+        .map(|urls| urls.split(',').map(|url| url.trim().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Creates the wallet of the `MINT_URL` and `ACCEPTED_MINT_URLS` mints.
+async fn wallet_from_env(trade_secret: EcashSecretKey) -> anyhow::Result<ClientEcashWallet> {
+    let mint_url = env::var("MINT_URL")?;
+    Ok(ClientEcashWallet::new(&mint_url, &accepted_mint_urls_from_env(), trade_secret).await?)
+}
+
+/// Prints the mint state of every proof of `token`, and whether it matches the contract of the trade `snapshot`.
+async fn check_token(token: &str, snapshot: Option<&std::path::Path>) -> anyhow::Result<()> {
+    let escrow_token = Token::from_str(token)?;
+    // checking a token needs no trade key
+    let wallet = wallet_from_env(EcashSecretKey::generate()).await?;
+    for (index, (amount, state)) in wallet
+        .check_token_state(&escrow_token)
+        .await?
+        .into_iter()
+        .enumerate()
+    {
+        println!("proof {}: {} sat {}", index, amount, state);
+    }
+    if let Some(snapshot) = snapshot {
+        let snapshot = EscrowSnapshot::load(snapshot)?;
+        match wallet
+            .validate_escrow_token(
+                &escrow_token,
+                &snapshot.escrow_contract,
+                &snapshot.escrow_registrations(),
+            )
+            .await
+        {
+            Ok(()) => println!(
+                "token matches the contract of escrow {}",
+                snapshot.escrow_registration.escrow_id_hex
+            ),
+            Err(e) => println!(
+                "token does not match the contract of escrow {}: {}",
+                snapshot.escrow_registration.escrow_id_hex, e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Prints whether the relays and mints of the environment are reachable and the configured keys are valid, failing
+/// if any of them isn't.
+async fn doctor(args: &CliArgs, timeout_secs: u64) -> anyhow::Result<()> {
+    let mut failed_checks = 0;
+
+    // the relays are reported one by one, so the client is created without waiting for any of them
+    let nostr_client = NostrClient::new_with_connection_retry(
+        Keys::generate(),
+        relays_from_env(),
+        messaging_scheme_from_env()?,
+        proxy_from_env()?,
+        ConnectionRetry {
+            min_relays: 0,
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+        },
+    )
+    .await?;
+    let relay_count = nostr_client.connected_relays().await.len();
+    // the unreachable relays are listed below
+    let _ = nostr_client
+        .wait_for_connection(relay_count, Duration::from_secs(timeout_secs))
+        .await;
+    for (url, connected) in nostr_client.connected_relays().await {
+        match connected {
+            true => println!("relay {}: connected", url),
+            false => {
+                failed_checks += 1;
+                println!("relay {}: unreachable", url);
+            }
+        }
+    }
+    nostr_client.shutdown().await?;
+
+    // checking the mints needs no trade key
+    let wallet = wallet_from_env(EcashSecretKey::generate()).await?;
+    let mut mint_urls = wallet.accepted_mints();
+    mint_urls.sort_by_key(|mint_url| mint_url.to_string());
+    for mint_url in mint_urls {
+        let mint_info = match wallet.mint_wallet(mint_url)?.get_mint_info().await {
+            Ok(mint_info) => mint_info,
+            Err(e) => {
+                failed_checks += 1;
+                println!("mint {}: unreachable: {}", mint_url, e);
+                continue;
+            }
+        };
+        let (name, version) = mint_info
+            .map(|info| {
+                (
+                    info.name.unwrap_or_default(),
+                    info.version
+                        .map(|version| format!("{}/{}", version.name, version.version))
+                        .unwrap_or_default(),
+                )
+            })
+            .unwrap_or_default();
+        match wallet.active_keysets(mint_url).await {
+            Ok(keysets) if !keysets.is_empty() => println!(
+                "mint {}: {} {}, active keysets: {}",
+                mint_url,
+                name,
+                version,
+                keysets
+                    .iter()
+                    .map(|keyset| format!("{} ({})", keyset.id, keyset.unit))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Ok(_) => {
+                failed_checks += 1;
+                println!("mint {}: {} {}, no active keysets", mint_url, name, version);
+            }
+            Err(e) => {
+                failed_checks += 1;
+                println!("mint {}: failed to get the keysets: {}", mint_url, e);
+            }
+        }
+    }
+
+    for (setting, check) in check_keys(args) {
+        match check {
+            Ok(public_key) => println!("key {}: {}", setting, public_key),
+            Err(e) => {
+                failed_checks += 1;
+                println!("key {}: invalid: {}", setting, e);
+            }
+        }
+    }
+
+    if failed_checks > 0 {
+        return Err(anyhow!("{} checks failed", failed_checks));
+    }
+    Ok(())
+}
+
+/// Prints the coordinators found on the relays, only those escrowing tokens of `mint_url` if given.
+/// Prints a line for every message arriving for `npub` until interrupted.
+async fn watch(npub: &str, since_secs: Option<u64>) -> anyhow::Result<()> {
+    let watched = PublicKey::from_bech32(npub).map_err(|e| anyhow!("Invalid npub: {}", e))?;
+    let watcher = MessageWatcher::new(
+        watched,
+        relays_from_env(),
+        messaging_scheme_from_env()?,
+        proxy_from_env()?,
+    )
+    .await?
+    .with_keepalive_interval(keepalive_interval_from_env()?);
+    info!("Watching the messages to {}...", npub);
+    let since = since_secs.map(|secs| Timestamp::now() - secs);
+    let result = tokio::select! {
+        result = watcher.watch(since, |message| {
+            let sender = match message.sender {
+                Some(sender) => sender.to_bech32().unwrap_or_else(|_| sender.to_string()),
+                None => "a hidden sender".to_string(),
+            };
+            println!(
+                "{} message {} of kind {} from {} via {}",
+                message.created_at.to_human_datetime(),
+                message.event_id,
+                message.kind,
+                sender,
+                message.relay_url
+            );
+        }) => result.map_err(anyhow::Error::from),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    watcher.shutdown().await?;
+    result
+}
+
+async fn discover_coordinators(
+    mint_url: Option<&MintUrl>,
+    timeout_secs: u64,
+) -> anyhow::Result<()> {
+    // looking up the directory needs no identity
+    let nostr_client = NostrClient::new(
+        Keys::generate(),
+        relays_from_env(),
+        messaging_scheme_from_env()?,
+        proxy_from_env()?,
+    )
+    .await?;
+    let coordinators = nostr_client
+        .discover_coordinators(Duration::from_secs(timeout_secs))
+        .await;
+    nostr_client.shutdown().await?;
+    let coordinators: Vec<_> = coordinators?
+        .into_iter()
+        .filter(|coordinator| mint_url.is_none_or(|url| coordinator.info.supports_mint(url)))
+        .collect();
+    if coordinators.is_empty() {
+        println!("no coordinators found");
+        return Ok(());
+    }
+    for coordinator in coordinators {
+        let mints = match coordinator.info.supported_mints.is_empty() {
+            true => "any mint".to_string(),
+            false => coordinator
+                .info
+                .supported_mints
+                .iter()
+                .map(|url| url.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "{} for {} sat, mints: {}, relays: {}",
+            coordinator.npubkey.to_bech32()?,
+            coordinator.info.fee_sat,
+            mints,
+            coordinator.info.relays.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Prints `contract` as json, or writes it to `output_file`, for the trade partner to load with `--contract-file`.
+fn share_contract(
+    contract: &TradeContract,
+    output_file: Option<&std::path::Path>,
+    compact: bool,
+) -> anyhow::Result<()> {
+    contract
+        .validate()
+        .map_err(|e| anyhow!("Invalid trade contract: {}", e))?;
+    let contract_json = nostr_sdk::serde_json::to_string_pretty(contract)?;
+    match output_file {
+        Some(output_file) => {
+            std::fs::write(output_file, contract_json)?;
+            println!("contract written to {}", output_file.display());
+        }
+        None => println!("{}", contract_json),
+    }
+    if compact {
+        println!("{}", contract.to_compact()?);
+    }
+    Ok(())
+}
+
+/// Runs a trade of `amount_sat` against the local mint, relays and coordinator of the environment.
+async fn local_trade(amount_sat: u64, args: &CliArgs) -> anyhow::Result<()> {
+    let config = LocalTradeConfig {
+        mint_url: MintUrl::from_str(&env::var("MINT_URL")?)?,
+        relays: relays_from_env().ok_or_else(|| anyhow!("Set NOSTR_RELAYS to the local relay"))?,
+        messaging_scheme: messaging_scheme_from_env()?,
+        coordinator: PublicKey::from_bech32(&env::var("ESCROW_NPUB")?)?,
+        coordinator_fee_sat: args.coordinator_fee_sat,
+        trade_amount_sat: amount_sat,
+        message_timeout_secs: args.message_timeout_secs,
+    };
+    let redeemed_amount = run_local_trade(config).await?;
+    // the mint may charge input fees, but the seller must end up with funds
+    if redeemed_amount == Amount::ZERO || redeemed_amount > Amount::from(amount_sat) {
+        return Err(anyhow!(
+            "Seller redeemed {} sat of a {} sat trade",
+            redeemed_amount,
+            amount_sat
+        ));
+    }
+    info!(
+        "Local trade finished, seller redeemed {} sat",
+        redeemed_amount
+    );
+    Ok(())
+}
+
+/// Prints every in-flight trade of `store`, the most recently active first.
+fn list_trades(store: &EscrowStore) -> anyhow::Result<()> {
+    let escrows = store.list()?;
+    if escrows.is_empty() {
+        println!("no trades in {}", store.snapshot_dir().display());
+        return Ok(());
+    }
+    for escrow in escrows {
+        println!(
+            "{} {:?} with {} for {} sat, {}, last active {}",
+            escrow.escrow_id_hex(),
+            escrow.snapshot.trade_mode,
+            escrow.counterparty().to_bech32()?,
+            escrow.snapshot.escrow_contract.trade_amount_sat,
+            escrow.snapshot.state,
+            escrow.last_activity.to_human_datetime()
+        );
+    }
     Ok(())
 }
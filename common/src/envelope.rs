@@ -0,0 +1,348 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use cdk::nuts::Token;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{
+    error::EscrowError,
+    model::{
+        ContractAccepted, ContractProposal, ContractSubmission, CoordinatorError,
+        CoordinatorFeePayment, DeliveryKey, DeliveryPayload, DeliveryProof, DisputeClaim,
+        DisputeResolution, EscrowRegistration, FeeReceipt, MilestoneDelivered, TokenAccepted,
+        TokenChunk, TokenRejected, TokenReleaseSignature, TradeCancelled, TradeContract,
+        TradeReceipt, TradeRejection,
+    },
+};
+
+/// Version of the escrow message protocol, raised on incompatible changes of the message payloads.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// The type of the payload carried by an [`EscrowEnvelope`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    TradeContract,
+    ContractProposal,
+    ContractAccepted,
+    ContractSubmission,
+    EscrowRegistration,
+    CoordinatorError,
+    CoordinatorFeePayment,
+    FeeReceipt,
+    EscrowToken,
+    TokenChunk,
+    TokenAccepted,
+    TokenRejected,
+    TradeCancelled,
+    TradeRejection,
+    DeliveryProof,
+    DeliveryPayload,
+    DeliveryKey,
+    MilestoneDelivered,
+    TokenReleaseSignature,
+    DisputeClaim,
+    DisputeResolution,
+    TradeReceipt,
+}
+
+/// Wrapper of every message exchanged between the traders and the coordinator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EscrowEnvelope {
+    pub version: u8,
+    pub kind: MessageKind,
+    pub payload: Value,
+}
+
+impl EscrowEnvelope {
+    pub fn wrap<M: EscrowMessage>(message: &M) -> Result<Self, EscrowError> {
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            kind: M::KIND,
+            payload: message.to_payload()?,
+        })
+    }
+
+    /// Parses an envelope from json.
+    ///
+    /// Fails with [`EscrowError::UnsupportedVersion`] if it was sent in another protocol version.
+    pub fn parse(message: &str) -> Result<Self, EscrowError> {
+        let envelope: Self = serde_json::from_str(message)
+            .map_err(|e| anyhow!("Failed to parse escrow message envelope: {}", e))?;
+        envelope.check_version()?;
+        Ok(envelope)
+    }
+
+    fn check_version(&self) -> Result<(), EscrowError> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(EscrowError::UnsupportedVersion {
+                supported: PROTOCOL_VERSION,
+                actual: self.version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Takes the payload out of the envelope, failing if it is not of the kind of `M`.
+    pub fn open<M: EscrowMessage>(self) -> Result<M, EscrowError> {
+        if self.kind != M::KIND {
+            return Err(anyhow!(
+                "Expected a {:?} message, got a {:?} message",
+                M::KIND,
+                self.kind
+            )
+            .into());
+        }
+        M::from_payload(self.payload)
+            .map_err(|e| anyhow!("Failed to parse {:?} message: {}", M::KIND, e).into())
+    }
+}
+
+/// A payload which can be sent in an [`EscrowEnvelope`].
+pub trait EscrowMessage: Sized {
+    const KIND: MessageKind;
+
+    fn to_payload(&self) -> Result<Value, EscrowError>;
+
+    fn from_payload(payload: Value) -> Result<Self, EscrowError>;
+}
+
+macro_rules! impl_escrow_message {
+    ($($message:ident),* $(,)?) => {
+        $(
+            impl EscrowMessage for $message {
+                const KIND: MessageKind = MessageKind::$message;
+
+                fn to_payload(&self) -> Result<Value, EscrowError> {
+                    Ok(serde_json::to_value(self)?)
+                }
+
+                fn from_payload(payload: Value) -> Result<Self, EscrowError> {
+                    Ok(serde_json::from_value(payload)?)
+                }
+            }
+        )*
+    };
+}
+
+impl_escrow_message!(
+    TradeContract,
+    ContractProposal,
+    ContractAccepted,
+    ContractSubmission,
+    EscrowRegistration,
+    CoordinatorError,
+    CoordinatorFeePayment,
+    FeeReceipt,
+    TokenChunk,
+    TokenAccepted,
+    TokenRejected,
+    TradeCancelled,
+    TradeRejection,
+    DeliveryProof,
+    DeliveryPayload,
+    DeliveryKey,
+    MilestoneDelivered,
+    TokenReleaseSignature,
+    DisputeClaim,
+    DisputeResolution,
+    TradeReceipt,
+);
+
+/// The escrow token is sent in its serialized form, as wallets would exchange it.
+impl EscrowMessage for Token {
+    const KIND: MessageKind = MessageKind::EscrowToken;
+
+    fn to_payload(&self) -> Result<Value, EscrowError> {
+        Ok(Value::String(self.to_string()))
+    }
+
+    fn from_payload(payload: Value) -> Result<Self, EscrowError> {
+        let token = payload
+            .as_str()
+            .ok_or_else(|| anyhow!("Escrow token payload is not a string"))?;
+        Token::from_str(token).map_err(|e| anyhow!("Invalid escrow token: {}", e).into())
+    }
+}
+
+/// Every message a coordinator sends to the traders, to match on whichever of them arrives.
+///
+/// It is serialized as the [`EscrowEnvelope`] of the wrapped message, whose kind tags the variant.
+#[derive(Debug, Clone)]
+pub enum CoordinatorMessage {
+    Registration(EscrowRegistration),
+    FeeReceived(FeeReceipt),
+    DisputeResolution(DisputeResolution),
+    /// The refusal of a contract submission.
+    Error(CoordinatorError),
+}
+
+impl CoordinatorMessage {
+    /// The envelope kinds of coordinator messages.
+    pub const KINDS: [MessageKind; 4] = [
+        MessageKind::EscrowRegistration,
+        MessageKind::FeeReceipt,
+        MessageKind::DisputeResolution,
+        MessageKind::CoordinatorError,
+    ];
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Self::Registration(_) => MessageKind::EscrowRegistration,
+            Self::FeeReceived(_) => MessageKind::FeeReceipt,
+            Self::DisputeResolution(_) => MessageKind::DisputeResolution,
+            Self::Error(_) => MessageKind::CoordinatorError,
+        }
+    }
+
+    pub fn wrap(&self) -> Result<EscrowEnvelope, EscrowError> {
+        match self {
+            Self::Registration(registration) => EscrowEnvelope::wrap(registration),
+            Self::FeeReceived(fee_receipt) => EscrowEnvelope::wrap(fee_receipt),
+            Self::DisputeResolution(resolution) => EscrowEnvelope::wrap(resolution),
+            Self::Error(error) => EscrowEnvelope::wrap(error),
+        }
+    }
+
+    /// Takes the coordinator message out of `envelope`, failing for the messages of traders.
+    pub fn open(envelope: EscrowEnvelope) -> Result<Self, EscrowError> {
+        match envelope.kind {
+            MessageKind::EscrowRegistration => Ok(Self::Registration(envelope.open()?)),
+            MessageKind::FeeReceipt => Ok(Self::FeeReceived(envelope.open()?)),
+            MessageKind::DisputeResolution => Ok(Self::DisputeResolution(envelope.open()?)),
+            MessageKind::CoordinatorError => Ok(Self::Error(envelope.open()?)),
+            kind => Err(anyhow!("Expected a coordinator message, got a {:?} message", kind).into()),
+        }
+    }
+}
+
+impl Serialize for CoordinatorMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.wrap()
+            .map_err(ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CoordinatorMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let envelope = EscrowEnvelope::deserialize(deserializer)?;
+        envelope.check_version().map_err(de::Error::custom)?;
+        Self::open(envelope).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::DisputeDecision;
+    use cdk::nuts::SecretKey;
+    use nostr_sdk::{Keys, Timestamp};
+    use serde_json::json;
+
+    /// The payload is only parsed when opening the envelope.
+    fn envelope_of_version(version: u8) -> String {
+        json!({"version": version, "kind": "TradeCancelled", "payload": {}}).to_string()
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let coordinator_error = CoordinatorError {
+            nonce: "nonce".to_string(),
+            reason: "expired".to_string(),
+        };
+        let message =
+            serde_json::to_string(&EscrowEnvelope::wrap(&coordinator_error).unwrap()).unwrap();
+
+        let envelope = EscrowEnvelope::parse(&message).unwrap();
+        assert_eq!(envelope.kind, MessageKind::CoordinatorError);
+        assert!(envelope.clone().open::<TradeCancelled>().is_err());
+        let opened: CoordinatorError = envelope.open().unwrap();
+        assert_eq!(opened.nonce, coordinator_error.nonce);
+        assert_eq!(opened.reason, coordinator_error.reason);
+    }
+
+    #[test]
+    fn parse_rejects_other_versions() {
+        for version in [PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let result = EscrowEnvelope::parse(&envelope_of_version(version));
+            assert!(
+                matches!(
+                    result,
+                    Err(EscrowError::UnsupportedVersion { supported: PROTOCOL_VERSION, actual })
+                        if actual == version
+                ),
+                "parsed version {}: {:?}",
+                version,
+                result
+            );
+        }
+        assert!(EscrowEnvelope::parse(&envelope_of_version(PROTOCOL_VERSION)).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_kinds() {
+        let message = json!({"version": PROTOCOL_VERSION, "kind": "Teleport", "payload": {}});
+
+        let result = EscrowEnvelope::parse(&message.to_string());
+        assert!(matches!(result, Err(EscrowError::Other(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn coordinator_message_round_trip() {
+        let coordinator_keys = Keys::generate();
+        let escrow_id_hex = "ab".repeat(32);
+        let messages = [
+            CoordinatorMessage::Registration(EscrowRegistration::new(
+                escrow_id_hex.clone(),
+                SecretKey::generate().public_key(),
+                Timestamp::now(),
+                50,
+                "nonce".to_string(),
+            )),
+            CoordinatorMessage::FeeReceived(
+                FeeReceipt::sign(escrow_id_hex.clone(), 50, &coordinator_keys).unwrap(),
+            ),
+            CoordinatorMessage::DisputeResolution(
+                DisputeResolution::sign(
+                    escrow_id_hex,
+                    DisputeDecision::Split {
+                        seller_milestones: 1,
+                    },
+                    vec!["signature".to_string()],
+                    Vec::new(),
+                    &coordinator_keys,
+                )
+                .unwrap(),
+            ),
+            CoordinatorMessage::Error(CoordinatorError {
+                nonce: "nonce".to_string(),
+                reason: "expired".to_string(),
+            }),
+        ];
+        for message in messages {
+            let json = serde_json::to_string(&message).unwrap();
+            // tagged like any other message by the kind of its envelope
+            assert_eq!(EscrowEnvelope::parse(&json).unwrap().kind, message.kind());
+
+            let received: CoordinatorMessage = serde_json::from_str(&json).unwrap();
+            assert_eq!(received.kind(), message.kind());
+            assert_eq!(serde_json::to_string(&received).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn coordinator_message_rejects_trader_messages_and_other_versions() {
+        let trader_message =
+            json!({"version": PROTOCOL_VERSION, "kind": "TradeCancelled", "payload": {}});
+        let newer_message = json!({
+            "version": PROTOCOL_VERSION + 1,
+            "kind": "CoordinatorError",
+            "payload": {"nonce": "nonce", "reason": "expired"},
+        });
+
+        for message in [trader_message, newer_message] {
+            assert!(serde_json::from_value::<CoordinatorMessage>(message).is_err());
+        }
+    }
+}
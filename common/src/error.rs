@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use cdk::{
+    nuts::{CurrencyUnit, Id},
+    Amount,
+};
+use nostr_sdk::{PublicKey, Timestamp};
+use thiserror::Error;
+
+/// The failures of the escrow library, from the transport up to the trade state machine.
+///
+/// Failures without a dedicated variant are kept as [`EscrowError::Other`] with their full context.
+#[derive(Debug, Error)]
+pub enum EscrowError {
+    #[error("Insufficient funds: have {have} sat, need {need} sat")]
+    InsufficientFunds { have: Amount, need: Amount },
+    /// `events_seen` counts the events which arrived meanwhile, e.g. messages of other senders or duplicates.
+    #[error("No message of {from} received within {} seconds, {events_seen} other events seen", waited.as_secs())]
+    Timeout {
+        from: PublicKey,
+        waited: Duration,
+        events_seen: usize,
+    },
+    /// The coordinator didn't register the trade, another coordinator should be chosen.
+    #[error("Coordinator {coordinator} did not answer {attempts} contract submissions")]
+    CoordinatorUnresponsive {
+        coordinator: PublicKey,
+        attempts: u32,
+    },
+    #[error("Relay pool shut down while waiting for a message")]
+    RelayDisconnected,
+    #[error("Escrow token amount mismatch: expected {expected} sat, got {actual} sat")]
+    AmountMismatch { expected: Amount, actual: Amount },
+    /// An amount of the contract or the escrow token exceeds what a u64 holds, e.g. a maliciously large trade amount.
+    #[error("Amount overflow: {0}")]
+    AmountOverflow(String),
+    #[error("Escrow token unit mismatch: expected {expected}, got {actual}")]
+    UnitMismatch {
+        expected: CurrencyUnit,
+        actual: CurrencyUnit,
+    },
+    #[error("DLEQ proof of escrow token proof {index} missing or invalid")]
+    DleqVerificationFailed { index: usize },
+    #[error(
+        "Escrow token proof {index} is of keyset {keyset_id}, which the mint doesn't sign with"
+    )]
+    InactiveKeyset { index: usize, keyset_id: Id },
+    #[error("Escrow token proof {index} is not locked as registered: {reason}")]
+    ConditionsMismatch { index: usize, reason: String },
+    #[error("Trade contract expired at {0}")]
+    ContractExpired(Timestamp),
+    #[error("Escrow token locktime {0} not reached yet")]
+    LocktimeNotReached(Timestamp),
+    #[error("Trade violates the seller policy: {0}")]
+    PolicyViolation(String),
+    #[error("Trade cancelled by the counterparty: {0}")]
+    TradeCancelled(String),
+    #[error("Escrow token rejected by the seller: {0}")]
+    TokenRejected(String),
+    #[error("Invalid escrow registration: {0}")]
+    InvalidRegistration(String),
+    #[error("Contract submission rejected by the coordinator: {0}")]
+    RegistrationRejected(String),
+    #[error("Unsupported escrow protocol version {actual}, supported is version {supported}")]
+    UnsupportedVersion { supported: u8, actual: u8 },
+    #[error("Relay error: {0}")]
+    Relay(#[from] nostr_sdk::client::Error),
+    #[error("Nostr key error: {0}")]
+    NostrKey(#[from] nostr_sdk::key::Error),
+    #[error("Invalid signature: {0}")]
+    Signature(#[from] nostr_sdk::secp256k1::Error),
+    #[error("Wallet error: {0}")]
+    Wallet(#[from] cdk::Error),
+    #[error("Failed to (de)serialize escrow data: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Failed to access escrow snapshot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// The errors of the cashu protocol parts, reported as wallet errors like the cdk does.
+macro_rules! impl_from_cdk_error {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for EscrowError {
+                fn from(error: $error) -> Self {
+                    EscrowError::Wallet(error.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_cdk_error!(
+    cdk::dhke::Error,
+    cdk::mint_url::Error,
+    cdk::nuts::nut01::Error,
+    cdk::nuts::nut02::Error,
+    cdk::nuts::nut11::Error,
+    cdk::nuts::nut12::Error,
+);
+
+/// Overflowing sums of amounts, e.g. the value of a token, are reported as [`EscrowError::AmountOverflow`].
+impl From<cdk::amount::Error> for EscrowError {
+    fn from(error: cdk::amount::Error) -> Self {
+        match error {
+            cdk::amount::Error::AmountOverflow => {
+                EscrowError::AmountOverflow("amounts add up to more than a u64".to_string())
+            }
+            error => EscrowError::Wallet(error.into()),
+        }
+    }
+}
+
+impl From<cdk::nuts::nut00::Error> for EscrowError {
+    fn from(error: cdk::nuts::nut00::Error) -> Self {
+        match error {
+            cdk::nuts::nut00::Error::Amount(error) => error.into(),
+            error => EscrowError::Wallet(error.into()),
+        }
+    }
+}
+
+/// Keeps the variant of an [`EscrowError`] wrapped in `error`, so it can still be matched on.
+impl From<anyhow::Error> for EscrowError {
+    fn from(error: anyhow::Error) -> Self {
+        error
+            .downcast::<EscrowError>()
+            .unwrap_or_else(EscrowError::Other)
+    }
+}
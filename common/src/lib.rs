@@ -1,4 +1,7 @@
 pub mod cli;
+pub mod envelope;
+pub mod error;
+pub mod metrics;
 pub mod model;
 pub mod nostr;
 
@@ -18,6 +21,15 @@ mod cdk_pubkey_serde {
         D: Deserializer<'de>,
     {
         let pubkey_hex = String::deserialize(deserializer)?;
-        PublicKey::from_hex(pubkey_hex).map_err(serde::de::Error::custom)
+        // P2PK spending conditions carry the keys compressed, so other encodings are rejected
+        if pubkey_hex.len() != 66 {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid ecash pubkey {}: expected 33 bytes of a compressed secp256k1 key",
+                pubkey_hex
+            )));
+        }
+        PublicKey::from_hex(&pubkey_hex).map_err(|e| {
+            serde::de::Error::custom(format!("Invalid ecash pubkey {}: {}", pubkey_hex, e))
+        })
     }
 }
@@ -0,0 +1,71 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters of the relay and trade events, shared between a [`crate::nostr::NostrClient`] and the escrow clients
+/// trading over it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    timeouts: AtomicU64,
+    disputes_opened: AtomicU64,
+    trades_settled: AtomicU64,
+}
+
+/// The counts of a [`Metrics`] at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub timeouts: u64,
+    pub disputes_opened: u64,
+    pub trades_settled: u64,
+}
+
+impl Metrics {
+    pub fn record_message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dispute_opened(&self) {
+        self.disputes_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_trade_settled(&self) {
+        self.trades_settled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            disputes_opened: self.disputes_opened.load(Ordering::Relaxed),
+            trades_settled: self.trades_settled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "messages_sent={} messages_received={} timeouts={} disputes_opened={} trades_settled={}",
+            self.messages_sent,
+            self.messages_received,
+            self.timeouts,
+            self.disputes_opened,
+            self.trades_settled
+        )
+    }
+}
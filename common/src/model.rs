@@ -1,17 +1,498 @@
-use cdk::nuts::PublicKey as CDKPubkey;
-use nostr_sdk::{PublicKey as NostrPubkey, Timestamp};
+use crate::error::EscrowError;
+use anyhow::anyhow;
+use cdk::{
+    mint_url::MintUrl,
+    nuts::{BlindedMessage, CurrencyUnit, Proofs, PublicKey as CDKPubkey, SigFlag, Token},
+    Amount,
+};
+use nostr_sdk::{
+    base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine,
+    },
+    hashes::hex::{DisplayHex, FromHex},
+    nips::nip44::v2::{self as nip44, ConversationKey},
+    secp256k1::{schnorr::Signature, Message},
+    Keys, PublicKey as NostrPubkey, Timestamp, SECP256K1,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Signatures required by default to spend the escrow token before the expiry.
+pub const DEFAULT_REQUIRED_SIGNATURES: u64 = 2;
+
+/// The keys of the traders locking the escrow next to the coordinator keys: seller and buyer.
+const TRADER_KEY_COUNT: u64 = 2;
+
+/// Prefix of the compact encoding of a contract, see [`TradeContract::to_compact`].
+const COMPACT_CONTRACT_PREFIX: &str = "cashuescrow";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TradeContract {
     pub trade_description: String,
     pub trade_amount_sat: u64,
+    /// Fee paid by the buyer to the coordinator on top of the trade amount.
+    pub coordinator_fee_sat: u64,
+    /// Currency unit of the escrow token, the trade amount and fee are denominated in it.
+    #[serde(default)]
+    pub unit: CurrencyUnit,
+    /// Mint the escrow token is issued by.
+    pub mint_url: MintUrl,
     pub npubkey_seller: NostrPubkey,
     pub npubkey_buyer: NostrPubkey,
     pub npubkey_coordinator: NostrPubkey,
-    pub time_limit: u64,
+    /// After this time the trade is void and the buyer can reclaim the escrow token.
+    pub expiry: Timestamp,
     pub seller_ecash_public_key: String,
     pub buyer_ecash_public_key: String,
+    /// Ecash key the escrow token is refunded to after the expiry, e.g. a cold key, the buyer ecash key if unset.
+    #[serde(default)]
+    pub buyer_refund_public_key: Option<String>,
+    /// Portions of the trade amount the buyer releases one after another, empty to release it at once.
+    #[serde(default)]
+    pub milestones: Vec<Amount>,
+    /// Oracle whose delivery proof lets the buyer release the escrow without further confirmation.
+    #[serde(default)]
+    pub oracle_pubkey: Option<NostrPubkey>,
+    /// How many of the seller, buyer and coordinator keys must sign to spend the escrow token before the expiry.
+    ///
+    /// NUT-11 knows a single threshold over all keys, so it must exceed the number of coordinators to keep them from
+    /// spending the token without a trader, while the two traders alone must still reach it.
+    #[serde(default = "default_required_signatures")]
+    pub required_signatures: u64,
+    /// Whether the signatures spending the escrow token must commit to the outputs as well, `SigAll` by default.
+    ///
+    /// With `SigAll` the redeeming trader creates the outputs of its swap beforehand, and the buyer or the coordinators
+    /// sign them along with the proofs they release.
+    #[serde(
+        default = "default_sig_flag",
+        skip_serializing_if = "is_default_sig_flag"
+    )]
+    pub sig_flag: SigFlag,
+    /// Further coordinators arbitrating the trade next to `npubkey_coordinator`, which alone charges the fee.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_coordinators: Vec<NostrPubkey>,
+    /// How many coordinators must decide a dispute alike, a majority of them if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinator_threshold: Option<u64>,
+    /// Price agreed in fiat, the trade amount is derived from it with the exchange rate locked at the registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fiat_price: Option<FiatPrice>,
+    /// The seller delivers digital goods in an encrypted [`DeliveryPayload`] before the buyer releases the escrow, and
+    /// the key decrypting them once it is released.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub digital_goods: bool,
+}
+
+/// Fiat currencies a trade can be priced in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum FiatCurrency {
+    Eur,
+    Usd,
+}
+
+impl FromStr for FiatCurrency {
+    type Err = EscrowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "EUR" => Ok(Self::Eur),
+            "USD" => Ok(Self::Usd),
+            _ => Err(anyhow!("Unknown fiat currency {}, use either EUR or USD", s).into()),
+        }
+    }
+}
+
+impl std::fmt::Display for FiatCurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Eur => write!(f, "EUR"),
+            Self::Usd => write!(f, "USD"),
+        }
+    }
+}
+
+/// The price of a trade in fiat cents, e.g. 1250 for 12.50 EUR.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FiatPrice {
+    pub amount_cents: u64,
+    pub currency: FiatCurrency,
+    /// The rate the trade amount was computed with, unset until the buyer locks it at the registration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<ExchangeRate>,
+}
+
+impl FiatPrice {
+    /// The price in sat at `sat_per_unit` sat per fiat unit, rounded down.
+    pub fn sat_amount(&self, sat_per_unit: u64) -> Result<u64, EscrowError> {
+        self.amount_cents
+            .checked_mul(sat_per_unit)
+            .map(|cents_sat| cents_sat / 100)
+            .ok_or_else(|| {
+                EscrowError::AmountOverflow(format!(
+                    "price of {} at {} sat per {}",
+                    self, sat_per_unit, self.currency
+                ))
+            })
+    }
+}
+
+impl std::fmt::Display for FiatPrice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{:02} {}",
+            self.amount_cents / 100,
+            self.amount_cents % 100,
+            self.currency
+        )
+    }
+}
+
+/// An exchange rate of sat per fiat unit, as fetched from `source` at `fetched_at`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExchangeRate {
+    pub sat_per_unit: u64,
+    pub source: String,
+    pub fetched_at: Timestamp,
+}
+
+fn default_required_signatures() -> u64 {
+    DEFAULT_REQUIRED_SIGNATURES
+}
+
+fn default_sig_flag() -> SigFlag {
+    SigFlag::SigAll
+}
+
+/// Contracts of the default flag serialize as before the flag was configurable, keeping their escrow id.
+fn is_default_sig_flag(sig_flag: &SigFlag) -> bool {
+    *sig_flag == default_sig_flag()
+}
+
+impl TradeContract {
+    /// The escrow id of the contract, the sha256 hash of its [`Self::canonical_json`].
+    pub fn escrow_id(&self) -> Result<[u8; 32], EscrowError> {
+        Ok(Sha256::digest(self.canonical_json()?.as_bytes()).into())
+    }
+
+    /// The json serialization of the contract every party derives the same escrow id from.
+    ///
+    /// The json object keys are sorted and all amounts are integers, so the serialization doesn't depend on the field
+    /// order of the struct or on the json library.
+    pub fn canonical_json(&self) -> Result<String, EscrowError> {
+        to_canonical_json(self)
+    }
+
+    /// The contract as a single url safe word to share it, e.g. in a QR code: its canonical json in base64, prefixed
+    /// like cashu tokens.
+    pub fn to_compact(&self) -> Result<String, EscrowError> {
+        Ok(format!(
+            "{}{}",
+            COMPACT_CONTRACT_PREFIX,
+            URL_SAFE_NO_PAD.encode(self.canonical_json()?)
+        ))
+    }
+
+    /// Parses a shared contract, either as json or in the encoding of [`Self::to_compact`].
+    pub fn from_shared(shared: &str) -> Result<Self, EscrowError> {
+        let shared = shared.trim();
+        let contract_json = match shared.strip_prefix(COMPACT_CONTRACT_PREFIX) {
+            Some(encoded) => URL_SAFE_NO_PAD
+                .decode(encoded)
+                .map_err(|e| anyhow!("Invalid compact contract: {}", e))?,
+            None => shared.as_bytes().to_vec(),
+        };
+        Ok(serde_json::from_slice(&contract_json)?)
+    }
+
+    /// The trade amount plus the coordinator fee, paid by the buyer.
+    pub fn buyer_total_sat(&self) -> Result<u64, EscrowError> {
+        self.trade_amount_sat
+            .checked_add(self.coordinator_fee_sat)
+            .ok_or_else(|| {
+                EscrowError::AmountOverflow(format!(
+                    "trade amount of {} sat plus coordinator fee of {} sat",
+                    self.trade_amount_sat, self.coordinator_fee_sat
+                ))
+            })
+    }
+
+    /// Fails if the terms of the contract can't be fulfilled.
+    ///
+    /// The amount must be non-zero and, with the coordinator fee, fit a u64, the seller, buyer and coordinators must be
+    /// distinct parties and the ecash keys of the traders distinct, valid public keys.
+    pub fn validate(&self) -> Result<(), EscrowError> {
+        match &self.fiat_price {
+            // the trade amount is only known once the exchange rate is locked
+            Some(FiatPrice { rate: None, .. }) => {}
+            Some(
+                price @ FiatPrice {
+                    rate: Some(rate), ..
+                },
+            ) if price.sat_amount(rate.sat_per_unit)? != self.trade_amount_sat => {
+                return Err(anyhow!(
+                    "Trade amount of {} sat does not match the price of {} at {} sat per {}",
+                    self.trade_amount_sat,
+                    price,
+                    rate.sat_per_unit,
+                    price.currency
+                )
+                .into());
+            }
+            _ if self.trade_amount_sat == 0 => {
+                return Err(anyhow!("Trade amount must be greater than zero").into());
+            }
+            _ => {}
+        }
+        self.buyer_total_sat()?;
+        if self.npubkey_buyer == self.npubkey_seller {
+            return Err(anyhow!("Buyer and seller must have different nostr pubkeys").into());
+        }
+        let coordinators = self.coordinators();
+        for (index, coordinator) in coordinators.iter().enumerate() {
+            if *coordinator == self.npubkey_buyer || *coordinator == self.npubkey_seller {
+                return Err(anyhow!("Coordinator must not be the buyer or the seller").into());
+            }
+            if coordinators[..index].contains(coordinator) {
+                return Err(anyhow!("Coordinator {} is named twice", coordinator).into());
+            }
+        }
+        if !(1..=coordinators.len() as u64).contains(&self.coordinator_threshold()) {
+            return Err(anyhow!(
+                "Coordinator threshold must be between 1 and {}, got {}",
+                coordinators.len(),
+                self.coordinator_threshold()
+            )
+            .into());
+        }
+        let seller_ecash_pubkey = CDKPubkey::from_hex(&self.seller_ecash_public_key)
+            .map_err(|e| anyhow!("Invalid seller ecash pubkey: {}", e))?;
+        let buyer_ecash_pubkey = CDKPubkey::from_hex(&self.buyer_ecash_public_key)
+            .map_err(|e| anyhow!("Invalid buyer ecash pubkey: {}", e))?;
+        if seller_ecash_pubkey == buyer_ecash_pubkey {
+            return Err(anyhow!("Buyer and seller must have different ecash pubkeys").into());
+        }
+        CDKPubkey::from_hex(self.buyer_refund_public_key())
+            .map_err(|e| anyhow!("Invalid buyer refund pubkey: {}", e))?;
+        self.milestone_amounts()?;
+        if self.required_signatures <= coordinators.len() as u64 {
+            return Err(anyhow!(
+                "Required signatures must exceed the {} coordinators, so they can't spend the escrow without a trader, got {}",
+                coordinators.len(),
+                self.required_signatures
+            )
+            .into());
+        }
+        // the traders release the escrow together, the coordinator threshold and the awarded trader on a dispute
+        let release_signatures = TRADER_KEY_COUNT.min(self.coordinator_threshold() + 1);
+        if self.required_signatures > release_signatures {
+            return Err(anyhow!(
+                "Required signatures must be at most {}, the signatures of a release or a dispute resolution, got {}",
+                release_signatures,
+                self.required_signatures
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Derives the trade amount from the fiat price at `rate` and records the rate in the contract.
+    ///
+    /// Milestones don't scale with the rate, so fiat priced contracts must release the trade amount at once.
+    pub fn lock_fiat_rate(&mut self, rate: ExchangeRate) -> Result<(), EscrowError> {
+        let price = self
+            .fiat_price
+            .as_mut()
+            .ok_or_else(|| anyhow!("Contract has no fiat price to lock the rate of"))?;
+        if !self.milestones.is_empty() {
+            return Err(anyhow!("Fiat priced contracts can't have milestones").into());
+        }
+        self.trade_amount_sat = price.sat_amount(rate.sat_per_unit)?;
+        price.rate = Some(rate);
+        self.validate()
+    }
+
+    /// Whether the trade amount still has to be derived from the fiat price, see [`TradeContract::lock_fiat_rate`].
+    pub fn needs_fiat_rate(&self) -> bool {
+        matches!(self.fiat_price, Some(FiatPrice { rate: None, .. }))
+    }
+
+    /// The coordinators registering the escrow, `npubkey_coordinator` first.
+    pub fn coordinators(&self) -> Vec<NostrPubkey> {
+        let mut coordinators = vec![self.npubkey_coordinator];
+        coordinators.extend(&self.additional_coordinators);
+        coordinators
+    }
+
+    /// How many coordinators must decide a dispute alike, a majority of them if not set in the contract.
+    pub fn coordinator_threshold(&self) -> u64 {
+        self.coordinator_threshold
+            .unwrap_or(self.coordinators().len() as u64 / 2 + 1)
+    }
+
+    /// Number of keys the escrow token is locked to before the expiry, the two traders and every coordinator.
+    pub fn escrow_key_count(&self) -> u64 {
+        TRADER_KEY_COUNT + self.coordinators().len() as u64
+    }
+
+    /// The ecash key the buyer reclaims the escrow token with after the expiry.
+    pub fn buyer_refund_public_key(&self) -> &str {
+        self.buyer_refund_public_key
+            .as_deref()
+            .unwrap_or(&self.buyer_ecash_public_key)
+    }
+
+    /// The amounts released in order, a single milestone of the trade amount if none are set.
+    ///
+    /// Fails if the milestones don't add up to the trade amount.
+    pub fn milestone_amounts(&self) -> Result<Vec<Amount>, EscrowError> {
+        let trade_amount = Amount::from(self.trade_amount_sat);
+        if self.milestones.is_empty() {
+            return Ok(vec![trade_amount]);
+        }
+        let milestones_total = Amount::try_sum(self.milestones.iter().copied())?;
+        if milestones_total != trade_amount || self.milestones.contains(&Amount::ZERO) {
+            return Err(anyhow!(
+                "Milestones of {} sat in total must be non-zero and add up to the trade amount of {} sat",
+                milestones_total,
+                trade_amount
+            ).into());
+        }
+        Ok(self.milestones.clone())
+    }
+}
+
+/// Splits escrow token proofs into the proofs of each milestone, taking them in order.
+///
+/// Fails if the proofs don't add up to the milestone amounts one after another.
+pub fn milestone_proofs(proofs: Proofs, milestones: &[Amount]) -> Result<Vec<Proofs>, EscrowError> {
+    let mut proofs = proofs.into_iter();
+    let mut milestone_proofs = Vec::with_capacity(milestones.len());
+    for (index, milestone_amount) in milestones.iter().enumerate() {
+        let mut proofs_of_milestone = Proofs::new();
+        let mut milestone_total = Amount::ZERO;
+        while milestone_total < *milestone_amount {
+            let proof = proofs
+                .next()
+                .ok_or_else(|| anyhow!("Escrow token lacks proofs for milestone {}", index))?;
+            milestone_total = milestone_total.checked_add(proof.amount).ok_or_else(|| {
+                EscrowError::AmountOverflow(format!("proofs of milestone {}", index))
+            })?;
+            proofs_of_milestone.push(proof);
+        }
+        if milestone_total != *milestone_amount {
+            return Err(anyhow!(
+                "Escrow token proofs don't match the {} sat of milestone {}",
+                milestone_amount,
+                index
+            )
+            .into());
+        }
+        milestone_proofs.push(proofs_of_milestone);
+    }
+    if proofs.next().is_some() {
+        return Err(anyhow!("Escrow token has proofs beyond the last milestone").into());
+    }
+    Ok(milestone_proofs)
+}
+
+/// Sent by a trader to register the contract at the coordinator.
+///
+/// The coordinator echoes the nonce in the registration, a resent submission gets the same registration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContractSubmission {
+    pub contract: TradeContract,
+    pub nonce: String,
+    /// The acceptance of the contract by the submitting trader.
+    pub acceptance: ContractAccepted,
+}
+
+/// Sent by the coordinator to a trader instead of the registration when refusing its [`ContractSubmission`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoordinatorError {
+    /// The nonce of the refused submission.
+    pub nonce: String,
+    pub reason: String,
+}
+
+/// Contract terms proposed by one trader to the other before the registration, answered by an acceptance, a counter
+/// proposal or a rejection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContractProposal {
+    /// Number of proposals exchanged before this one.
+    pub round: u32,
+    pub contract: TradeContract,
+}
+
+impl ContractProposal {
+    /// Fails if `counter` changes more than the amounts, the expiry and the mint of this proposal.
+    ///
+    /// The parties and their keys can't be negotiated.
+    pub fn check_counter(&self, counter: &TradeContract) -> Result<(), EscrowError> {
+        let contract = &self.contract;
+        if counter.npubkey_buyer != contract.npubkey_buyer
+            || counter.npubkey_seller != contract.npubkey_seller
+            || counter.npubkey_coordinator != contract.npubkey_coordinator
+            || counter.additional_coordinators != contract.additional_coordinators
+            || counter.coordinator_threshold != contract.coordinator_threshold
+            || counter.buyer_ecash_public_key != contract.buyer_ecash_public_key
+            || counter.seller_ecash_public_key != contract.seller_ecash_public_key
+            || counter.buyer_refund_public_key != contract.buyer_refund_public_key
+            || counter.oracle_pubkey != contract.oracle_pubkey
+        {
+            return Err(anyhow!("Counter proposal changes the parties of the contract").into());
+        }
+        counter.validate()
+    }
+}
+
+/// Acknowledgment of a trader agreeing to the exact terms of a contract, a schnorr signature over its tagged escrow id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContractAccepted {
+    pub escrow_id_hex: String,
+    pub signature: String,
+}
+
+impl ContractAccepted {
+    /// Accepts `contract` with the nostr keys of the trader.
+    pub fn sign(contract: &TradeContract, trader_keys: &Keys) -> Result<Self, EscrowError> {
+        let escrow_id_hex = contract.escrow_id()?.to_lower_hex_string();
+        let message = escrow_id_message(CONTRACT_ACCEPTED_TAG, &escrow_id_hex);
+        let signature = trader_keys.sign_schnorr(&message)?.to_string();
+        Ok(Self {
+            escrow_id_hex,
+            signature,
+        })
+    }
+
+    /// Fails if the acceptance is not signed by `trader` over the escrow id of `contract`.
+    pub fn verify(
+        &self,
+        contract: &TradeContract,
+        trader: &NostrPubkey,
+    ) -> Result<(), EscrowError> {
+        let escrow_id_hex = contract.escrow_id()?.to_lower_hex_string();
+        if self.escrow_id_hex != escrow_id_hex {
+            return Err(anyhow!(
+                "Acceptance of escrow {} instead of the contract escrow {}",
+                self.escrow_id_hex,
+                escrow_id_hex
+            )
+            .into());
+        }
+        verify_escrow_id_signature(
+            CONTRACT_ACCEPTED_TAG,
+            &self.escrow_id_hex,
+            &self.signature,
+            trader,
+        )
+        .map_err(|e| anyhow!("Invalid contract acceptance of {}: {}", trader, e).into())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,6 +501,10 @@ pub struct EscrowRegistration {
     #[serde(with = "crate::cdk_pubkey_serde")]
     pub coordinator_escrow_pubkey: CDKPubkey,
     pub escrow_start_time: Timestamp,
+    /// Fee the coordinator charges for the escrow.
+    pub coordinator_fee_sat: u64,
+    /// Nonce of the contract submission this registration answers.
+    pub nonce: String,
 }
 
 impl EscrowRegistration {
@@ -27,11 +512,783 @@ impl EscrowRegistration {
         trade_id_hex: String,
         coordinator_escrow_pubkey: CDKPubkey,
         escrow_start_time: Timestamp,
+        coordinator_fee_sat: u64,
+        nonce: String,
     ) -> Self {
         Self {
             escrow_id_hex: trade_id_hex,
             coordinator_escrow_pubkey,
             escrow_start_time,
+            coordinator_fee_sat,
+            nonce,
+        }
+    }
+}
+
+/// Sent by a trader to open (or answer) a dispute about an escrow.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisputeClaim {
+    pub escrow_id_hex: String,
+    pub claimant: NostrPubkey,
+    pub reason: String,
+    /// The disputed escrow token, the coordinators sign the proofs they award to a trader.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow_token: Option<String>,
+    /// The outputs the claimant redeems each milestone into by its index, for a contract locked with `SigAll`. The
+    /// coordinators sign the outputs of the milestones they award to the claimant.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redeem_outputs: BTreeMap<usize, Vec<BlindedMessage>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DisputeDecision {
+    ReleaseToSeller,
+    RefundToBuyer,
+    /// The first `seller_milestones` milestones go to the seller, the others back to the buyer.
+    Split {
+        seller_milestones: usize,
+    },
+}
+
+impl DisputeDecision {
+    /// Number of milestones awarded to the seller, counted from the first one.
+    pub fn seller_milestones(&self, milestone_count: usize) -> usize {
+        match self {
+            DisputeDecision::ReleaseToSeller => milestone_count,
+            DisputeDecision::RefundToBuyer => 0,
+            DisputeDecision::Split { seller_milestones } => {
+                (*seller_milestones).min(milestone_count)
+            }
+        }
+    }
+}
+
+/// The arbitration decision of a coordinator, sent to both traders.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisputeResolution {
+    pub escrow_id_hex: String,
+    pub decision: DisputeDecision,
+    /// Signatures of the coordinator escrow key over the escrow token proofs awarded to the receiving trader, in the
+    /// order of the proofs.
+    #[serde(default)]
+    pub token_signatures: Vec<String>,
+    /// Signatures of the coordinator escrow key over the redeem outputs the receiving trader claimed for the awarded
+    /// milestones, in their order. Empty unless the contract is locked with `SigAll`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output_signatures: Vec<String>,
+    /// Schnorr signature of the coordinator over the escrow id and the decision.
+    pub signature: String,
+}
+
+impl DisputeResolution {
+    /// Decides the dispute about the escrow `escrow_id_hex` with the keys of the coordinator.
+    pub fn sign(
+        escrow_id_hex: String,
+        decision: DisputeDecision,
+        token_signatures: Vec<String>,
+        output_signatures: Vec<String>,
+        coordinator_keys: &Keys,
+    ) -> Result<Self, EscrowError> {
+        let message = dispute_resolution_message(&escrow_id_hex, &decision)?;
+        let signature = coordinator_keys.sign_schnorr(&message)?.to_string();
+        Ok(Self {
+            escrow_id_hex,
+            decision,
+            token_signatures,
+            output_signatures,
+            signature,
+        })
+    }
+
+    /// Fails if the decision is not signed by `coordinator`.
+    pub fn verify(&self, coordinator: &NostrPubkey) -> Result<(), EscrowError> {
+        let message = dispute_resolution_message(&self.escrow_id_hex, &self.decision)?;
+        let signature = Signature::from_str(&self.signature)?;
+        SECP256K1
+            .verify_schnorr(&signature, &message, coordinator)
+            .map_err(|e| anyhow!("Invalid dispute resolution of {}: {}", coordinator, e).into())
+    }
+}
+
+/// Signatures of the buyer over the escrow token proofs of a milestone, releasing its funds to the seller.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenReleaseSignature {
+    pub escrow_id_hex: String,
+    /// Index of the released milestone in the contract.
+    #[serde(default)]
+    pub milestone: usize,
+    pub signatures: Vec<String>,
+    /// Signatures of the buyer over the redeem outputs the seller announced for the milestone, in their order. Empty
+    /// unless the contract is locked with `SigAll`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output_signatures: Vec<String>,
+}
+
+/// Sent by the seller to the buyer once the goods of a milestone are delivered, so the buyer releases it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MilestoneDelivered {
+    pub escrow_id_hex: String,
+    /// Index of the delivered milestone in the contract.
+    pub milestone: usize,
+    /// The outputs the seller redeems the milestone into, for a contract locked with `SigAll` the buyer signs them
+    /// with the release.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redeem_outputs: Vec<BlindedMessage>,
+}
+
+/// Sent by a trader to the counterparty and the coordinator to cancel a registered trade before it is funded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeCancelled {
+    pub escrow_id_hex: String,
+    pub cancelled_by: NostrPubkey,
+    pub reason: String,
+}
+
+/// Sent by a trader refusing the contract proposed by the trade partner.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeRejection {
+    pub escrow_id_hex: String,
+    pub reason: String,
+}
+
+/// Sent by the seller to the buyer after validating the escrow token, the buyer proceeds with the trade only then.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenAccepted {
+    pub escrow_id_hex: String,
+}
+
+/// Sent by the seller to the buyer when refusing the escrow token, the buyer can reclaim it after the expiry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenRejected {
+    pub escrow_id_hex: String,
+    pub reason: String,
+}
+
+/// Longest serialized escrow token sent in a single message, longer tokens are sent in [`TokenChunk`]s.
+///
+/// Some relays reject events beyond 64 KiB, and the encryption of the message grows it further.
+pub const MAX_TOKEN_MESSAGE_LEN: usize = 16 * 1024;
+
+/// A part of a serialized escrow token too long for a single message, see [`MAX_TOKEN_MESSAGE_LEN`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenChunk {
+    pub escrow_id_hex: String,
+    pub index: usize,
+    pub total: usize,
+    pub data: String,
+}
+
+impl TokenChunk {
+    /// Splits the serialized `escrow_token` into chunks of at most [`MAX_TOKEN_MESSAGE_LEN`] characters.
+    pub fn split(escrow_id_hex: &str, escrow_token: &Token) -> Vec<Self> {
+        let chars = escrow_token.to_string().chars().collect::<Vec<_>>();
+        let parts = chars.chunks(MAX_TOKEN_MESSAGE_LEN).collect::<Vec<_>>();
+        let total = parts.len();
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, part)| Self {
+                escrow_id_hex: escrow_id_hex.to_string(),
+                index,
+                total,
+                data: part.iter().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Collects the [`TokenChunk`]s of an escrow token, which may arrive in any order.
+#[derive(Debug)]
+pub struct TokenChunks {
+    escrow_id_hex: String,
+    parts: Vec<Option<String>>,
+}
+
+impl TokenChunks {
+    pub fn new(escrow_id_hex: String, total: usize) -> Self {
+        Self {
+            escrow_id_hex,
+            parts: vec![None; total],
+        }
+    }
+
+    /// Adds `chunk`, failing if it is not a chunk of the collected token.
+    pub fn insert(&mut self, chunk: TokenChunk) -> Result<(), EscrowError> {
+        if chunk.escrow_id_hex != self.escrow_id_hex
+            || chunk.total != self.parts.len()
+            || chunk.index >= chunk.total
+        {
+            return Err(anyhow!(
+                "Token chunk {}/{} of escrow {} doesn't belong to the {} chunks of escrow {}",
+                chunk.index,
+                chunk.total,
+                chunk.escrow_id_hex,
+                self.parts.len(),
+                self.escrow_id_hex
+            )
+            .into());
+        }
+        self.parts[chunk.index] = Some(chunk.data);
+        Ok(())
+    }
+
+    pub fn missing(&self) -> usize {
+        self.parts.iter().filter(|part| part.is_none()).count()
+    }
+
+    /// Joins the chunks to the escrow token, failing if chunks are missing.
+    pub fn join(self) -> Result<Token, EscrowError> {
+        let missing = self.missing();
+        let token = self
+            .parts
+            .into_iter()
+            .collect::<Option<String>>()
+            .ok_or_else(|| anyhow!("{} token chunks are missing", missing))?;
+        Token::from_str(&token).map_err(|e| anyhow!("Invalid chunked escrow token: {}", e).into())
+    }
+}
+
+/// The terms a coordinator publishes in its directory entry, see [`crate::nostr::NostrClient::discover_coordinators`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoordinatorInfo {
+    pub fee_sat: u64,
+    /// Mints the coordinator escrows tokens of, empty if it accepts any mint.
+    pub supported_mints: Vec<MintUrl>,
+    /// Relays the coordinator receives the trader messages on.
+    pub relays: Vec<String>,
+}
+
+impl CoordinatorInfo {
+    pub fn supports_mint(&self, mint_url: &MintUrl) -> bool {
+        self.supported_mints.is_empty() || self.supported_mints.contains(mint_url)
+    }
+
+    /// Whether the coordinator escrows `contract` for the fee and at the mint of the contract.
+    pub fn matches(&self, contract: &TradeContract) -> bool {
+        self.fee_sat == contract.coordinator_fee_sat && self.supports_mint(&contract.mint_url)
+    }
+}
+
+/// The coordinator fee, sent by the buyer as token locked to the coordinator escrow pubkey.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoordinatorFeePayment {
+    pub escrow_id_hex: String,
+    pub fee_token: String,
+}
+
+/// Attestation of the coordinator that the buyer paid the coordinator fee, sent to the seller.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeReceipt {
+    pub escrow_id_hex: String,
+    pub fee_sat: u64,
+    /// Schnorr signature of the coordinator over the escrow id and the fee.
+    pub signature: String,
+}
+
+impl FeeReceipt {
+    /// Confirms the fee of the escrow `escrow_id_hex` with the keys of the coordinator.
+    pub fn sign(
+        escrow_id_hex: String,
+        fee_sat: u64,
+        coordinator_keys: &Keys,
+    ) -> Result<Self, EscrowError> {
+        let message = fee_receipt_message(&escrow_id_hex, fee_sat);
+        let signature = coordinator_keys.sign_schnorr(&message)?.to_string();
+        Ok(Self {
+            escrow_id_hex,
+            fee_sat,
+            signature,
+        })
+    }
+
+    /// Fails if the receipt is not signed by `coordinator` over the escrow id and fee of `registration`.
+    pub fn verify(
+        &self,
+        registration: &EscrowRegistration,
+        coordinator: &NostrPubkey,
+    ) -> Result<(), EscrowError> {
+        if self.escrow_id_hex != registration.escrow_id_hex
+            || self.fee_sat != registration.coordinator_fee_sat
+        {
+            return Err(anyhow!(
+                "Fee receipt of {} sat for escrow {} instead of {} sat for escrow {}",
+                self.fee_sat,
+                self.escrow_id_hex,
+                registration.coordinator_fee_sat,
+                registration.escrow_id_hex
+            )
+            .into());
+        }
+        let message = fee_receipt_message(&self.escrow_id_hex, self.fee_sat);
+        let signature = Signature::from_str(&self.signature)?;
+        SECP256K1
+            .verify_schnorr(&signature, &message, coordinator)
+            .map_err(|e| anyhow!("Invalid fee receipt of {}: {}", coordinator, e).into())
+    }
+}
+
+/// Attestation of an oracle that the seller delivered the trade, a schnorr signature over the escrow id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryProof {
+    pub escrow_id_hex: String,
+    pub oracle_pubkey: NostrPubkey,
+    pub attestation: String,
+}
+
+impl DeliveryProof {
+    /// Attests the delivery of the escrow `escrow_id_hex` with the keys of the oracle.
+    pub fn sign(escrow_id_hex: String, oracle_keys: &Keys) -> Result<Self, EscrowError> {
+        <[u8; 32]>::from_hex(&escrow_id_hex)
+            .map_err(|e| anyhow!("Invalid escrow id {}: {}", escrow_id_hex, e))?;
+        let message = escrow_id_message(DELIVERY_PROOF_TAG, &escrow_id_hex);
+        let attestation = oracle_keys.sign_schnorr(&message)?.to_string();
+        Ok(Self {
+            escrow_id_hex,
+            oracle_pubkey: oracle_keys.public_key(),
+            attestation,
+        })
+    }
+
+    /// Fails if the attestation is not signed by the oracle over the tagged escrow id.
+    pub fn verify(&self) -> Result<(), EscrowError> {
+        verify_escrow_id_signature(
+            DELIVERY_PROOF_TAG,
+            &self.escrow_id_hex,
+            &self.attestation,
+            &self.oracle_pubkey,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "Invalid delivery attestation of {}: {}",
+                self.escrow_id_hex,
+                e
+            )
+            .into()
+        })
+    }
+}
+
+/// Largest digital goods delivered in a [`DeliveryPayload`], larger files are better delivered as a download link and
+/// its key.
+///
+/// The encryption and base64 encoding grow the goods to about [`MAX_TOKEN_MESSAGE_LEN`] in the message.
+pub const MAX_DIGITAL_GOODS_LEN: usize = 12 * 1024;
+
+/// Digital goods sent encrypted by the seller to the buyer before the buyer releases the escrow.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryPayload {
+    pub escrow_id_hex: String,
+    /// The goods, NIP-44 encrypted with the delivery key and base64 encoded.
+    pub ciphertext: String,
+    /// Sha256 hash of the delivery key, so the buyer recognizes the key released for these goods.
+    pub key_hash: String,
+}
+
+/// Sent by the seller to the buyer once the escrow is released, decrypting the [`DeliveryPayload`] of the trade.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryKey {
+    pub escrow_id_hex: String,
+    pub key: String,
+}
+
+impl DeliveryPayload {
+    /// Encrypts `goods` with the random 32 byte `key`, returning the payload and the key releasing it.
+    pub fn encrypt(
+        escrow_id_hex: String,
+        goods: &[u8],
+        key: [u8; 32],
+    ) -> Result<(Self, DeliveryKey), EscrowError> {
+        if goods.len() > MAX_DIGITAL_GOODS_LEN {
+            return Err(anyhow!(
+                "Digital goods of {} bytes exceed the limit of {} bytes",
+                goods.len(),
+                MAX_DIGITAL_GOODS_LEN
+            )
+            .into());
+        }
+        let ciphertext = nip44::encrypt_to_bytes(&ConversationKey::new(key), goods)
+            .map_err(|e| anyhow!("Failed to encrypt the digital goods: {}", e))?;
+        let payload = Self {
+            escrow_id_hex: escrow_id_hex.clone(),
+            ciphertext: STANDARD.encode(ciphertext),
+            key_hash: Sha256::digest(key).to_lower_hex_string(),
+        };
+        let delivery_key = DeliveryKey {
+            escrow_id_hex,
+            key: key.to_lower_hex_string(),
+        };
+        Ok((payload, delivery_key))
+    }
+
+    /// Decrypts the goods with the released `delivery_key`, failing if it is not the key of this payload.
+    pub fn decrypt(&self, delivery_key: &DeliveryKey) -> Result<Vec<u8>, EscrowError> {
+        let key = <[u8; 32]>::from_hex(&delivery_key.key)
+            .map_err(|e| anyhow!("Invalid delivery key: {}", e))?;
+        if delivery_key.escrow_id_hex != self.escrow_id_hex
+            || Sha256::digest(key).to_lower_hex_string() != self.key_hash
+        {
+            return Err(anyhow!(
+                "Delivery key of escrow {} doesn't decrypt the goods of escrow {}",
+                delivery_key.escrow_id_hex,
+                self.escrow_id_hex
+            )
+            .into());
+        }
+        let ciphertext = STANDARD
+            .decode(&self.ciphertext)
+            .map_err(|e| anyhow!("Invalid digital goods encoding: {}", e))?;
+        Ok(
+            nip44::decrypt_to_bytes(&ConversationKey::new(key), ciphertext)
+                .map_err(|e| anyhow!("Failed to decrypt the digital goods: {}", e))?,
+        )
+    }
+}
+
+const CONTRACT_ACCEPTED_TAG: &str = "contract_accepted";
+const DELIVERY_PROOF_TAG: &str = "delivery_proof";
+
+/// The purpose is part of the signed message, so an acceptance can't be passed off as a delivery proof or vice versa.
+fn escrow_id_message(tag: &str, escrow_id_hex: &str) -> Message {
+    let digest = Sha256::digest(format!("{}:{}", tag, escrow_id_hex).as_bytes());
+    Message::from_digest(digest.into())
+}
+
+/// The fee is part of the signed message, so a receipt can't be reused for another fee.
+fn fee_receipt_message(escrow_id_hex: &str, fee_sat: u64) -> Message {
+    let digest = Sha256::digest(format!("fee_receipt:{}:{}", escrow_id_hex, fee_sat).as_bytes());
+    Message::from_digest(digest.into())
+}
+
+/// The decision is part of the signed message, so a resolution can't be reused for another decision.
+fn dispute_resolution_message(
+    escrow_id_hex: &str,
+    decision: &DisputeDecision,
+) -> Result<Message, EscrowError> {
+    let digest = Sha256::digest(
+        format!(
+            "dispute_resolution:{}:{}",
+            escrow_id_hex,
+            to_canonical_json(decision)?
+        )
+        .as_bytes(),
+    );
+    Ok(Message::from_digest(digest.into()))
+}
+
+/// Serializes `value` to compact json with the keys of all objects sorted, for hashing and signing.
+pub fn to_canonical_json<T: Serialize>(value: &T) -> Result<String, EscrowError> {
+    Ok(canonical_json(serde_json::to_value(value)?).to_string())
+}
+
+/// Sorts the keys of all json objects in `value`.
+fn canonical_json(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.into_iter().collect();
+            entries.sort_by(|(key, _), (other_key, _)| key.cmp(other_key));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical_json(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical_json).collect()),
+        value => value,
+    }
+}
+
+/// The final state of a trade a [`TradeReceipt`] is issued for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TradeOutcome {
+    /// All milestones were released to the seller.
+    Settled,
+    /// The buyer reclaimed the unreleased milestones after the expiry.
+    Reclaimed,
+    /// The trader received the milestones the coordinators awarded in a dispute.
+    Resolved,
+}
+
+/// What a [`TradeReceipt`] attests.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeReceiptContent {
+    pub escrow_id_hex: String,
+    pub npubkey_buyer: NostrPubkey,
+    pub npubkey_seller: NostrPubkey,
+    pub trade_amount_sat: u64,
+    pub coordinator_fee_sat: u64,
+    /// Sha256 of the serialized escrow token.
+    pub escrow_token_hash: String,
+    /// Sha256 of the serialized token the trader ends up with, for the seller including the release signatures.
+    pub final_token_hash: String,
+    pub escrow_start_time: Timestamp,
+    pub finished_at: Timestamp,
+    pub outcome: TradeOutcome,
+}
+
+/// Record of a finished trade, signed by the nostr key of the trader to prove the outcome later.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeReceipt {
+    #[serde(flatten)]
+    pub content: TradeReceiptContent,
+    pub signer: NostrPubkey,
+    pub signature: String,
+}
+
+impl TradeReceipt {
+    /// Signs the sha256 hash of the canonical json serialization of `content` with the nostr keys of the trader.
+    pub fn sign(content: TradeReceiptContent, trader_keys: &Keys) -> Result<Self, EscrowError> {
+        let message = trade_receipt_message(&content)?;
+        let signature = trader_keys.sign_schnorr(&message)?.to_string();
+        Ok(Self {
+            content,
+            signer: trader_keys.public_key(),
+            signature,
+        })
+    }
+
+    /// Fails if the receipt is not signed by its signer.
+    pub fn verify(&self) -> Result<(), EscrowError> {
+        let message = trade_receipt_message(&self.content)?;
+        let signature = Signature::from_str(&self.signature)?;
+        SECP256K1
+            .verify_schnorr(&signature, &message, &self.signer)
+            .map_err(|e| anyhow!("Invalid trade receipt of {}: {}", self.signer, e).into())
+    }
+}
+
+/// The sha256 hash of a serialized token, as hex.
+pub fn token_hash(token: &Token) -> String {
+    Sha256::digest(token.to_string().as_bytes()).to_lower_hex_string()
+}
+
+fn trade_receipt_message(content: &TradeReceiptContent) -> Result<Message, EscrowError> {
+    Ok(Message::from_digest(
+        Sha256::digest(to_canonical_json(content)?.as_bytes()).into(),
+    ))
+}
+
+fn verify_escrow_id_signature(
+    tag: &str,
+    escrow_id_hex: &str,
+    signature: &str,
+    signer: &NostrPubkey,
+) -> Result<(), EscrowError> {
+    <[u8; 32]>::from_hex(escrow_id_hex)
+        .map_err(|e| anyhow!("Invalid escrow id {}: {}", escrow_id_hex, e))?;
+    let message = escrow_id_message(tag, escrow_id_hex);
+    let signature = Signature::from_str(signature)?;
+    Ok(SECP256K1.verify_schnorr(&signature, &message, signer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdk::{
+        nuts::{Id, Proof, SecretKey as CDKSecretKey},
+        secret::Secret,
+    };
+    use nostr_sdk::SecretKey;
+
+    fn nostr_keys(seed: u8) -> Keys {
+        Keys::new(SecretKey::from_slice(&[seed; 32]).unwrap())
+    }
+
+    fn ecash_public_key(seed: u8) -> String {
+        CDKSecretKey::from_slice(&[seed; 32])
+            .unwrap()
+            .public_key()
+            .to_hex()
+    }
+
+    fn proofs(amounts: &[u64]) -> Proofs {
+        let keyset_id = Id::from_str("009a1f293253e41e").unwrap();
+        amounts
+            .iter()
+            .map(|amount| {
+                Proof::new(
+                    Amount::from(*amount),
+                    keyset_id,
+                    Secret::generate(),
+                    CDKSecretKey::generate().public_key(),
+                )
+            })
+            .collect()
+    }
+
+    fn amounts(proofs: &[Proofs]) -> Vec<Vec<u64>> {
+        proofs
+            .iter()
+            .map(|proofs| proofs.iter().map(|proof| u64::from(proof.amount)).collect())
+            .collect()
+    }
+
+    /// A valid contract with fixed keys and expiry, so its escrow id never changes.
+    fn contract() -> TradeContract {
+        TradeContract {
+            trade_description: "Purchase of one watermelon".to_string(),
+            trade_amount_sat: 5000,
+            coordinator_fee_sat: 50,
+            unit: CurrencyUnit::Sat,
+            mint_url: MintUrl::from_str("https://mint.example.com").unwrap(),
+            npubkey_seller: nostr_keys(1).public_key(),
+            npubkey_buyer: nostr_keys(2).public_key(),
+            npubkey_coordinator: nostr_keys(3).public_key(),
+            expiry: Timestamp::from(1_700_000_000),
+            seller_ecash_public_key: ecash_public_key(4),
+            buyer_ecash_public_key: ecash_public_key(5),
+            buyer_refund_public_key: None,
+            milestones: Vec::new(),
+            oracle_pubkey: None,
+            required_signatures: DEFAULT_REQUIRED_SIGNATURES,
+            sig_flag: SigFlag::SigAll,
+            additional_coordinators: Vec::new(),
+            coordinator_threshold: None,
+            fiat_price: None,
+            digital_goods: false,
+        }
+    }
+
+    #[test]
+    fn escrow_id_of_known_contract() {
+        let contract = contract();
+        contract.validate().unwrap();
+
+        assert_eq!(
+            contract.escrow_id().unwrap().to_lower_hex_string(),
+            "3fbc3a60ec53965a6ed589ed1c254f38a945d1ed2e25d79d0e68015a4b956c9f"
+        );
+    }
+
+    #[test]
+    fn sig_inputs_contract_has_another_escrow_id() {
+        let mut sig_inputs = contract();
+        sig_inputs.sig_flag = SigFlag::SigInputs;
+        sig_inputs.validate().unwrap();
+
+        // the default SIG_ALL flag is left out, keeping the escrow ids of contracts from before the flag was settable
+        assert!(!contract().canonical_json().unwrap().contains("sig_flag"));
+        assert_ne!(
+            sig_inputs.escrow_id().unwrap(),
+            contract().escrow_id().unwrap()
+        );
+    }
+
+    #[test]
+    fn canonical_json_is_independent_of_field_order() {
+        let contract = contract();
+        // serializes the fields in struct order, unlike the canonical json
+        let struct_order_json = serde_json::to_string(&contract).unwrap();
+        let received: TradeContract = serde_json::from_str(&struct_order_json).unwrap();
+
+        assert_ne!(struct_order_json, contract.canonical_json().unwrap());
+        assert_eq!(
+            received.canonical_json().unwrap().as_bytes(),
+            contract.canonical_json().unwrap().as_bytes()
+        );
+        assert_eq!(
+            to_canonical_json(&serde_json::json!({"b": 1, "a": {"d": [{"f": 2, "e": 3}], "c": 4}}))
+                .unwrap(),
+            r#"{"a":{"c":4,"d":[{"e":3,"f":2}]},"b":1}"#
+        );
+    }
+
+    #[test]
+    fn shared_contract_round_trip() {
+        let contract = contract();
+        let compact = contract.to_compact().unwrap();
+        let json = serde_json::to_string_pretty(&contract).unwrap();
+
+        assert!(compact.starts_with(COMPACT_CONTRACT_PREFIX));
+        for shared in [compact, json] {
+            let parsed = TradeContract::from_shared(&format!("{}\n", shared)).unwrap();
+            assert_eq!(parsed.escrow_id().unwrap(), contract.escrow_id().unwrap());
+        }
+        assert!(TradeContract::from_shared("cashuescrow!!!").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_contracts() {
+        type Invalidate = fn(&mut TradeContract);
+        let cases: [(&str, Invalidate); 11] = [
+            ("zero amount", |c| c.trade_amount_sat = 0),
+            ("buyer is seller", |c| c.npubkey_buyer = c.npubkey_seller),
+            ("coordinator is buyer", |c| {
+                c.npubkey_coordinator = c.npubkey_buyer
+            }),
+            ("coordinator named twice", |c| {
+                c.additional_coordinators = vec![c.npubkey_coordinator]
+            }),
+            ("same ecash keys", |c| {
+                c.buyer_ecash_public_key = c.seller_ecash_public_key.clone()
+            }),
+            ("garbage seller ecash key", |c| {
+                c.seller_ecash_public_key = "not a key".to_string()
+            }),
+            ("buyer ecash key off the curve", |c| {
+                c.buyer_ecash_public_key = format!("02{}", "00".repeat(32))
+            }),
+            ("garbage refund key", |c| {
+                c.buyer_refund_public_key = Some(String::new())
+            }),
+            ("milestones short of the amount", |c| {
+                c.milestones = vec![Amount::from(1000), Amount::from(2000)]
+            }),
+            ("coordinator alone can spend", |c| c.required_signatures = 1),
+            ("traders can't release", |c| c.required_signatures = 3),
+        ];
+        for (case, invalidate) in cases {
+            let mut contract = contract();
+            invalidate(&mut contract);
+            assert!(contract.validate().is_err(), "accepted {}", case);
+        }
+    }
+
+    #[test]
+    fn milestone_proofs_split_in_order() {
+        let milestones = [Amount::from(3), Amount::from(4)];
+
+        let split = milestone_proofs(proofs(&[1, 2, 4]), &milestones).unwrap();
+        assert_eq!(amounts(&split), vec![vec![1, 2], vec![4]]);
+
+        // the proofs must add up to each milestone, not just to their total
+        assert!(milestone_proofs(proofs(&[2, 4, 1]), &milestones).is_err());
+        assert!(milestone_proofs(proofs(&[1, 2]), &milestones).is_err());
+        assert!(milestone_proofs(proofs(&[1, 2, 4, 1]), &milestones).is_err());
+    }
+
+    #[test]
+    fn oversized_amounts_overflow_cleanly() {
+        let mut oversized_fee = contract();
+        oversized_fee.trade_amount_sat = u64::MAX - 10;
+        let mut oversized_milestones = contract();
+        oversized_milestones.milestones = vec![Amount::from(u64::MAX), Amount::from(1)];
+        let oversized_price = FiatPrice {
+            amount_cents: u64::MAX / 100,
+            currency: FiatCurrency::Eur,
+            rate: None,
+        };
+
+        let results = [
+            ("buyer total", oversized_fee.buyer_total_sat().map(|_| ())),
+            ("validation", oversized_fee.validate()),
+            (
+                "milestones",
+                oversized_milestones.milestone_amounts().map(|_| ()),
+            ),
+            ("fiat price", oversized_price.sat_amount(1_000).map(|_| ())),
+            (
+                "milestone proofs",
+                milestone_proofs(proofs(&[1, u64::MAX]), &[Amount::from(u64::MAX)]).map(|_| ()),
+            ),
+        ];
+        for (case, result) in results {
+            assert!(
+                matches!(result, Err(EscrowError::AmountOverflow(_))),
+                "{} didn't overflow: {:?}",
+                case,
+                result
+            );
         }
     }
 }
@@ -0,0 +1,84 @@
+use super::*;
+use crate::model::CoordinatorInfo;
+
+/// Kind of the replaceable event a coordinator publishes its [`CoordinatorInfo`] in.
+pub const COORDINATOR_INFO_KIND: u16 = 38_390;
+
+/// `d` tag of the coordinator info event, so every coordinator has a single directory entry.
+const COORDINATOR_INFO_IDENTIFIER: &str = "cashu-escrow-coordinator";
+
+/// A coordinator found on the relays, with the terms of its latest directory entry.
+#[derive(Debug, Clone)]
+pub struct DiscoveredCoordinator {
+    pub npubkey: PublicKey,
+    pub info: CoordinatorInfo,
+    pub published_at: Timestamp,
+}
+
+impl NostrClient {
+    /// Publishes the directory entry of this client as coordinator, replacing its previous one.
+    pub async fn publish_coordinator_info(
+        &self,
+        info: &CoordinatorInfo,
+    ) -> Result<EventId, EscrowError> {
+        let builder = EventBuilder::new(
+            Kind::from(COORDINATOR_INFO_KIND),
+            serde_json::to_string(info)?,
+            [Tag::identifier(COORDINATOR_INFO_IDENTIFIER)],
+        );
+        let output = self.client.send_event_builder(builder).await?;
+        if output.success.is_empty() {
+            return Err(anyhow!(
+                "No relay accepted the coordinator info {}: {:?}",
+                output.val,
+                output.failed
+            )
+            .into());
+        }
+        Ok(output.val)
+    }
+
+    /// Queries the relays for the directory entries of the coordinators, waiting at most `timeout` for them.
+    ///
+    /// Returns the latest entry of every coordinator, the cheapest first. Malformed entries are skipped.
+    pub async fn discover_coordinators(
+        &self,
+        timeout: Duration,
+    ) -> Result<Vec<DiscoveredCoordinator>, EscrowError> {
+        let filter = Filter::new()
+            .kind(Kind::from(COORDINATOR_INFO_KIND))
+            .identifier(COORDINATOR_INFO_IDENTIFIER);
+        let events = self
+            .client
+            .get_events_of(vec![filter], EventSource::relays(Some(timeout)))
+            .await?;
+        let mut coordinators: HashMap<PublicKey, DiscoveredCoordinator> = HashMap::new();
+        for event in events {
+            let info: CoordinatorInfo = match serde_json::from_str(&event.content) {
+                Ok(info) => info,
+                Err(e) => {
+                    debug!("Skipping coordinator info of {}: {}", event.pubkey, e);
+                    continue;
+                }
+            };
+            // relays may still serve entries the coordinator replaced since
+            if coordinators
+                .get(&event.pubkey)
+                .is_some_and(|known| known.published_at >= event.created_at)
+            {
+                continue;
+            }
+            coordinators.insert(
+                event.pubkey,
+                DiscoveredCoordinator {
+                    npubkey: event.pubkey,
+                    info,
+                    published_at: event.created_at,
+                },
+            );
+        }
+        let mut coordinators: Vec<_> = coordinators.into_values().collect();
+        coordinators.sort_by_key(|coordinator| coordinator.info.fee_sat);
+        Ok(coordinators)
+    }
+}
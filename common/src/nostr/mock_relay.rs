@@ -0,0 +1,223 @@
+//! A relay on localhost keeping its events in memory, to exchange escrow messages between [`NostrClient`]s in tests
+//! without network access.
+
+use std::{net::Ipv4Addr, sync::Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use super::*;
+
+/// Events published while a connection is busy are dropped beyond this many.
+const LIVE_EVENT_CAPACITY: usize = 1024;
+
+/// Relay answering the NIP-01 messages of the clients connected to it, stopped when dropped.
+///
+/// Events of every kind, including gift wraps, are stored and delivered to the matching subscriptions. Signatures
+/// and expirations are not checked.
+pub struct MockRelay {
+    url: String,
+    events: Arc<Mutex<Vec<Event>>>,
+    disconnects: broadcast::Sender<()>,
+    server: JoinHandle<()>,
+}
+
+impl MockRelay {
+    /// Starts the relay on a free port of localhost.
+    pub async fn run() -> Result<Self, EscrowError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(|e| anyhow!("Failed to bind the mock relay: {}", e))?;
+        let url = format!(
+            "ws://{}",
+            listener.local_addr().map_err(anyhow::Error::from)?
+        );
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (live_events, _) = broadcast::channel(LIVE_EVENT_CAPACITY);
+        let (disconnects, _) = broadcast::channel(1);
+        let server = tokio::spawn(serve(
+            listener,
+            events.clone(),
+            live_events,
+            disconnects.clone(),
+        ));
+        debug!("Mock relay listening on {}", url);
+        Ok(Self {
+            url,
+            events,
+            disconnects,
+            server,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The events published to the relay so far, the oldest first.
+    pub fn events(&self) -> Vec<Event> {
+        self.events
+            .lock()
+            .expect("Mock relay lock poisoned")
+            .clone()
+    }
+
+    /// Drops the connections of all clients, which reconnect like after a relay outage. Stored events are kept.
+    pub fn disconnect_clients(&self) {
+        // nobody may be connected
+        let _ = self.disconnects.send(());
+    }
+
+    /// Creates a client of `keys` connected to this relay only.
+    pub async fn client(
+        &self,
+        keys: Keys,
+        messaging_scheme: MessagingScheme,
+    ) -> Result<NostrClient, EscrowError> {
+        NostrClient::new(keys, Some(vec![self.url.clone()]), messaging_scheme, None).await
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    events: Arc<Mutex<Vec<Event>>>,
+    live_events: broadcast::Sender<Event>,
+    disconnects: broadcast::Sender<()>,
+) {
+    let mut connections = Vec::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        connections.retain(|connection: &JoinHandle<()>| !connection.is_finished());
+        connections.push(tokio::spawn(serve_connection(
+            stream,
+            events.clone(),
+            live_events.clone(),
+            disconnects.subscribe(),
+        )));
+    }
+    for connection in connections {
+        connection.abort();
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    events: Arc<Mutex<Vec<Event>>>,
+    live_events: broadcast::Sender<Event>,
+    mut disconnect: broadcast::Receiver<()>,
+) {
+    let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            debug!("Mock relay rejected a connection: {}", e);
+            return;
+        }
+    };
+    let mut live_receiver = live_events.subscribe();
+    let mut subscriptions: HashMap<SubscriptionId, Vec<Filter>> = HashMap::new();
+    loop {
+        let replies = tokio::select! {
+            // subscriptions are handled before the events published meanwhile
+            biased;
+            message = websocket.next() => match message {
+                Some(Ok(WsMessage::Text(message))) => {
+                    handle_client_message(&message, &mut subscriptions, &events, &live_events)
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            event = live_receiver.recv() => match event {
+                Ok(event) => subscriptions
+                    .iter()
+                    .filter(|(_, filters)| filters.iter().any(|filter| filter.match_event(&event)))
+                    .map(|(id, _)| RelayMessage::event(id.clone(), event.clone()))
+                    .collect(),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Mock relay dropped {} events of a slow connection", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = disconnect.recv() => break,
+        };
+        for reply in replies {
+            if websocket
+                .send(WsMessage::Text(reply.as_json()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// Answers a message of a client, storing and broadcasting the events it publishes.
+fn handle_client_message(
+    message: &str,
+    subscriptions: &mut HashMap<SubscriptionId, Vec<Filter>>,
+    events: &Mutex<Vec<Event>>,
+    live_events: &broadcast::Sender<Event>,
+) -> Vec<RelayMessage> {
+    match ClientMessage::from_json(message) {
+        Ok(ClientMessage::Event(event)) => {
+            let event_id = event.id;
+            events
+                .lock()
+                .expect("Mock relay lock poisoned")
+                .push((*event).clone());
+            // nobody may be subscribed yet
+            let _ = live_events.send(*event);
+            vec![RelayMessage::ok(event_id, true, "")]
+        }
+        Ok(ClientMessage::Req {
+            subscription_id,
+            filters,
+        }) => {
+            let mut replies: Vec<RelayMessage> = stored_events(events, &filters)
+                .into_iter()
+                .map(|event| RelayMessage::event(subscription_id.clone(), event))
+                .collect();
+            replies.push(RelayMessage::eose(subscription_id.clone()));
+            subscriptions.insert(subscription_id, filters);
+            replies
+        }
+        Ok(ClientMessage::Close(subscription_id)) => {
+            subscriptions.remove(&subscription_id);
+            Vec::new()
+        }
+        Ok(_) => vec![RelayMessage::notice("Unsupported message")],
+        Err(e) => vec![RelayMessage::notice(format!("Invalid message: {}", e))],
+    }
+}
+
+/// The stored events matching any of `filters`, at most the limit of each filter of its newest events.
+fn stored_events(events: &Mutex<Vec<Event>>, filters: &[Filter]) -> Vec<Event> {
+    let events = events.lock().expect("Mock relay lock poisoned");
+    let mut matching: Vec<Event> = Vec::new();
+    for filter in filters {
+        let limit = filter.limit.unwrap_or(usize::MAX);
+        for event in events
+            .iter()
+            .rev()
+            .filter(|event| filter.match_event(event))
+            .take(limit)
+        {
+            if !matching.iter().any(|known| known.id == event.id) {
+                matching.push(event.clone());
+            }
+        }
+    }
+    matching.sort_by_key(|event| event.created_at);
+    matching
+}
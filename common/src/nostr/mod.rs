@@ -1,69 +1,613 @@
-use std::time::Duration;
+mod directory;
+#[cfg(any(test, feature = "test-util"))]
+mod mock_relay;
+mod transport;
+mod watch;
 
-use crate::model::EscrowRegistration;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    envelope::CoordinatorMessage,
+    error::EscrowError,
+    metrics::Metrics,
+    model::{
+        ContractAccepted, EscrowRegistration, TradeContract, TradeReceipt, TradeReceiptContent,
+    },
+};
 use anyhow::anyhow;
+pub use directory::{DiscoveredCoordinator, COORDINATOR_INFO_KIND};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+#[cfg(any(test, feature = "test-util"))]
+pub use mock_relay::MockRelay;
 use nostr_sdk::prelude::*;
-use tokio::{
-    sync::broadcast::{error::RecvError, Receiver},
-    time::timeout,
-};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+pub use transport::{EscrowTransport, MessageDeadline};
+pub use watch::{MessageWatcher, ObservedMessage};
+
+/// Escrow messages expire this long after the contract expiry, leaving time for disputes and refunds after it.
+pub const MESSAGE_EXPIRATION_GRACE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// When relays may delete the messages of a trade expiring at `contract_expiry`.
+pub fn message_expiration(contract_expiry: Timestamp) -> Timestamp {
+    contract_expiry + MESSAGE_EXPIRATION_GRACE_SECS
+}
+
+/// Relays used when no relay list is configured.
+pub const DEFAULT_RELAYS: [&str; 5] = [
+    "wss://relay.damus.io",
+    "wss://relay.primal.net",
+    "wss://relay.nostr.band",
+    "wss://ftp.halifax.rwth-aachen.de/nostr",
+    "wss://nostr.mom",
+    //"wss://relay.nostrplebs.com", (having errors)
+];
+
+/// How often the message subscription is renewed while waiting, see [`NostrClient::with_keepalive_interval`].
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time to wait for the relays to connect when creating a [`NostrClient`].
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often and how patiently [`NostrClient::new_with_connection_retry`] retries to reach a relay.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionRetry {
+    /// Relays which must be connected, the client is returned as soon as they are.
+    pub min_relays: usize,
+    pub max_attempts: u32,
+    /// Wait time after the first failed attempt, doubled after every further attempt.
+    pub backoff: Duration,
+}
+
+impl Default for ConnectionRetry {
+    fn default() -> Self {
+        Self {
+            min_relays: 1,
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How private messages are encrypted and transported.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MessagingScheme {
+    /// NIP-17 private direct messages, wrapped in NIP-59 gift wraps.
+    #[default]
+    GiftWrap,
+    /// NIP-04 encrypted direct messages, for relays rejecting gift wraps. Leaks the message metadata.
+    Nip04,
+}
+
+impl FromStr for MessagingScheme {
+    type Err = EscrowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gift-wrap" => Ok(Self::GiftWrap),
+            "nip04" => Ok(Self::Nip04),
+            _ => Err(anyhow!(
+                "Unknown messaging scheme {}, use either gift-wrap or nip04",
+                s
+            )
+            .into()),
+        }
+    }
+}
 
 pub struct NostrClient {
     keys: Keys,
     pub client: Client,
+    messaging_scheme: MessagingScheme,
     subscription_id: SubscriptionId,
     notifications_receiver: Receiver<RelayPoolNotification>,
+    /// Received messages of senders nobody waited for yet.
+    pending_messages: VecDeque<(PublicKey, String)>,
+    /// Ids of the received events, as every relay delivers the same event again.
+    seen_event_ids: HashSet<EventId>,
+    metrics: Arc<Metrics>,
+    /// Expiration of the sent messages, see [`EscrowTransport::set_message_expiration`].
+    message_expiration: Option<Timestamp>,
+    keepalive_interval: Option<Duration>,
+    /// The only relays the messages to a receiver are sent over, see [`NostrClient::add_private_relays`].
+    private_relays: HashMap<PublicKey, Vec<Url>>,
+    /// Start of the message history the next wait receives again, see [`EscrowTransport::replay_messages_since`].
+    replay_since: Option<Timestamp>,
+    /// Messages sent before are dropped while replaying, as the history reaches further back for backdated gift wraps.
+    history_start: Option<Timestamp>,
+    /// The relays which haven't sent all stored events of the replayed history yet.
+    replaying_relays: HashSet<Url>,
+    /// The relays reconnecting since they disconnected, to receive the messages sent meanwhile once they are back.
+    disconnected_relays: HashMap<Url, Timestamp>,
 }
 
 impl NostrClient {
-    pub async fn new(keys: Keys) -> anyhow::Result<Self> {
-        let client = Client::new(&keys);
+    /// Creates a client connected to the given relays, or to [`DEFAULT_RELAYS`] if none are given.
+    ///
+    /// Fails listing every relay which could not be added, instead of continuing with a subset.
+    ///
+    /// With a `proxy`, e.g. the SOCKS5 port of a Tor daemon, every relay is connected through it, `.onion` relays
+    /// included.
+    pub async fn new(
+        keys: Keys,
+        relays: Option<Vec<String>>,
+        messaging_scheme: MessagingScheme,
+        proxy: Option<SocketAddr>,
+    ) -> Result<Self, EscrowError> {
+        Self::new_with_connection_retry(
+            keys,
+            relays,
+            messaging_scheme,
+            proxy,
+            ConnectionRetry::default(),
+        )
+        .await
+    }
 
-        client.add_relay("wss://relay.damus.io").await?;
-        client.add_relay("wss://relay.primal.net").await?;
-        client.add_relay("wss://relay.nostr.band").await?;
-        client
-            .add_relay("wss://ftp.halifax.rwth-aachen.de/nostr")
-            .await?;
-        client.add_relay("wss://nostr.mom").await?;
-        //client.add_relay("wss://relay.nostrplebs.com").await?; (having errors)
+    /// Like [`NostrClient::new`], retrying with exponential backoff until enough relays are connected.
+    pub async fn new_with_connection_retry(
+        keys: Keys,
+        relays: Option<Vec<String>>,
+        messaging_scheme: MessagingScheme,
+        proxy: Option<SocketAddr>,
+        connection_retry: ConnectionRetry,
+    ) -> Result<Self, EscrowError> {
+        let client = Client::with_opts(&keys, relay_options(proxy));
+        add_relays(&client, relays, proxy).await?;
 
-        // Connect to relays
-        client.connect().await;
+        connect_with_retry(&client, connection_retry).await?;
 
-        let (_subscription_id, notifications_receiver) = init_subscription(&keys, &client).await?;
+        let (_subscription_id, notifications_receiver) =
+            init_subscription(&client, message_filter(keys.public_key(), messaging_scheme)).await?;
 
-        Ok(Self {
+        let nostr_client = Self {
             keys,
             client,
+            messaging_scheme,
             subscription_id: _subscription_id,
             notifications_receiver,
-        })
+            pending_messages: VecDeque::new(),
+            seen_event_ids: HashSet::new(),
+            metrics: Arc::default(),
+            message_expiration: None,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            private_relays: HashMap::new(),
+            replay_since: None,
+            history_start: None,
+            replaying_relays: HashSet::new(),
+            disconnected_relays: HashMap::new(),
+        };
+        Ok(nostr_client)
+    }
+
+    /// The counters of the messages and timeouts of this client, to share with the escrow clients using it.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns the url of every added relay together with its connection state.
+    pub async fn connected_relays(&self) -> Vec<(String, bool)> {
+        let mut relays = Vec::new();
+        for (url, relay) in self.client.relays().await {
+            relays.push((url.to_string(), relay.is_connected().await));
+        }
+        relays
+    }
+
+    async fn connected_relay_count(&self) -> usize {
+        connected_relay_count(&self.client).await
+    }
+
+    /// Waits until at least `min_relays` relays are connected, failing after `timeout`.
+    pub async fn wait_for_connection(
+        &self,
+        min_relays: usize,
+        timeout: Duration,
+    ) -> Result<(), EscrowError> {
+        let start = tokio::time::Instant::now();
+        loop {
+            let connected = self.connected_relay_count().await;
+            if connected >= min_relays {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "Only {} of {} required relays connected after {} seconds",
+                    connected,
+                    min_relays,
+                    timeout.as_secs()
+                )
+                .into());
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
     }
 
     pub fn public_key(&self) -> PublicKey {
         self.keys.public_key()
     }
 
-    pub async fn receive_escrow_message(&mut self, timeout_secs: u64) -> anyhow::Result<String> {
+    pub fn keys(&self) -> &Keys {
+        &self.keys
+    }
+
+    /// Filter matching the private messages to this client in the configured messaging scheme.
+    pub fn message_filter(&self) -> Filter {
+        message_filter(self.keys.public_key(), self.messaging_scheme)
+    }
+
+    /// Decrypts a private message event of the configured messaging scheme.
+    ///
+    /// Returns the sender and content of the message, or `None` if the event is no private message. Gift wraps of
+    /// other rumors than direct messages are logged as warning.
+    pub async fn decrypt_message(
+        &self,
+        event: &Event,
+    ) -> Result<Option<(PublicKey, String)>, EscrowError> {
+        Ok(self
+            .decrypt_timed_message(event)
+            .await?
+            .map(|(sender, content, _)| (sender, content)))
+    }
+
+    /// Decrypts a private message event like [`NostrClient::decrypt_message`], also returning when it was sent.
+    async fn decrypt_timed_message(
+        &self,
+        event: &Event,
+    ) -> Result<Option<(PublicKey, String, Timestamp)>, EscrowError> {
+        match self.messaging_scheme {
+            MessagingScheme::GiftWrap => {
+                if event.kind != Kind::GiftWrap {
+                    return Ok(None);
+                }
+                // unlike the gift wrap, the rumor is not backdated
+                let rumor = self.client.unwrap_gift_wrap(event).await?.rumor;
+                if rumor.kind != Kind::PrivateDirectMessage {
+                    // escrow messages are always sent as direct messages, anything else hints at a protocol bug
+                    warn!(
+                        "Ignoring rumor of unexpected kind {} from {} in gift wrap {}",
+                        rumor.kind, rumor.pubkey, event.id
+                    );
+                    return Ok(None);
+                }
+                Ok(Some((rumor.pubkey, rumor.content, rumor.created_at)))
+            }
+            MessagingScheme::Nip04 => {
+                if event.kind != Kind::EncryptedDirectMessage {
+                    return Ok(None);
+                }
+                let content =
+                    nip04::decrypt(self.keys.secret_key()?, &event.pubkey, &event.content)
+                        .map_err(|e| anyhow!("Failed to decrypt message: {}", e))?;
+                Ok(Some((event.pubkey, content, event.created_at)))
+            }
+        }
+    }
+
+    /// Talks to `receiver` over `relays` only, e.g. the private relay a coordinator runs for the escrow messages.
+    ///
+    /// The relays are added to the pool and subscribed to, the messages to everybody else keep going over the other
+    /// relays only.
+    pub async fn add_private_relays(
+        &mut self,
+        receiver: PublicKey,
+        relays: Vec<String>,
+    ) -> Result<(), EscrowError> {
+        let mut urls = Vec::new();
+        for relay in relays {
+            let url = Url::parse(&relay).map_err(|e| anyhow!("Invalid relay {}: {}", relay, e))?;
+            self.client.add_relay(url.clone()).await?;
+            self.client.connect_relay(url.clone()).await?;
+            urls.push(url);
+        }
+        if urls.is_empty() {
+            return Err(anyhow!("No private relays given for {}", receiver).into());
+        }
+        self.client
+            .subscribe_with_id_to(
+                urls.clone(),
+                self.subscription_id.clone(),
+                vec![self.message_filter()],
+                None,
+            )
+            .await?;
+        debug!("Talking to {} over the private relays {:?}", receiver, urls);
+        self.private_relays.insert(receiver, urls);
+        Ok(())
+    }
+
+    /// The relays the messages to `receiver` are sent over, its private relays or else all relays private to nobody.
+    async fn relays_for(&self, receiver: PublicKey) -> Vec<Url> {
+        if let Some(urls) = self.private_relays.get(&receiver) {
+            return urls.clone();
+        }
+        let private_urls: HashSet<&Url> = self.private_relays.values().flatten().collect();
+        self.client
+            .relays()
+            .await
+            .into_keys()
+            .filter(|url| !private_urls.contains(url))
+            .collect()
+    }
+
+    /// Sends a private message to `receiver` in the configured messaging scheme.
+    ///
+    /// Returns the number of relays which accepted the message, failing if none did.
+    pub async fn send_private_message(
+        &self,
+        receiver: PublicKey,
+        message: &str,
+    ) -> Result<usize, EscrowError> {
+        self.send_private_message_expiring(receiver, message, self.message_expiration)
+            .await
+    }
+
+    /// Sends a private message to `receiver` which relays may delete after `expiration`.
+    ///
+    /// The message carries the relays it is sent over as hints where to answer.
+    pub async fn send_private_message_expiring(
+        &self,
+        receiver: PublicKey,
+        message: &str,
+        expiration: Option<Timestamp>,
+    ) -> Result<usize, EscrowError> {
+        let relays = self.relays_for(receiver).await;
+        let relay_hints = Tag::custom(TagKind::Relays, relays.iter().map(Url::to_string));
+        let output = match self.messaging_scheme {
+            MessagingScheme::GiftWrap => {
+                // NIP-17 message rumor, sealed and gift wrapped for the receiver
+                let rumor = EventBuilder::new(
+                    Kind::PrivateDirectMessage,
+                    message,
+                    [Tag::public_key(receiver), relay_hints],
+                );
+                self.client
+                    .gift_wrap_to(relays, receiver, rumor, expiration)
+                    .await?
+            }
+            MessagingScheme::Nip04 => {
+                let content = nip04::encrypt(self.keys.secret_key()?, &receiver, message)
+                    .map_err(|e| anyhow!("Failed to encrypt message: {}", e))?;
+                let mut tags = vec![Tag::public_key(receiver), relay_hints];
+                tags.extend(expiration.map(Tag::expiration));
+                let builder = EventBuilder::new(Kind::EncryptedDirectMessage, content, tags);
+                self.client.send_event_builder_to(relays, builder).await?
+            }
+        };
+        if output.success.is_empty() {
+            return Err(anyhow!(
+                "No relay accepted the message {}: {:?}",
+                output.val,
+                output.failed
+            )
+            .into());
+        }
+        self.metrics.record_message_sent();
+        debug!(
+            "Event {} accepted by {} relays, rejected by {:?}",
+            output.val,
+            output.success.len(),
+            output.failed
+        );
+        Ok(output.success.len())
+    }
+
+    /// Re-issues the message subscription every `keepalive_interval` while waiting for a message, so relays don't
+    /// drop the idle connection. `None` disables it.
+    pub fn with_keepalive_interval(mut self, keepalive_interval: Option<Duration>) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval
+    }
+
+    /// Sends the message subscription to the relays again, keeping idle connections and subscriptions alive.
+    pub async fn keep_subscription_alive(&self) -> Result<(), EscrowError> {
+        trace!("Renewing the message subscription...");
+        self.client
+            .subscribe_with_id(
+                self.subscription_id.clone(),
+                vec![self.subscription_filter()],
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Filter of the messages to this client sent since `since`.
+    fn history_filter(&self, since: Timestamp) -> Filter {
+        messages_since(self.keys.public_key(), self.messaging_scheme, since)
+    }
+
+    /// Filter renewing the message subscription, still including the history while the relays replay it.
+    ///
+    /// Replacing the history filter by one without stored events would drop the rest of the replayed messages.
+    fn subscription_filter(&self) -> Filter {
+        match self.history_start {
+            Some(start) => self.history_filter(start),
+            None => self.message_filter(),
+        }
+    }
+
+    /// Subscribes to the messages to this client since `since` again, so the messages sent while it was offline arrive.
+    ///
+    /// Events received before are skipped as duplicates.
+    async fn replay_history(&self, since: Timestamp) -> Result<(), EscrowError> {
+        debug!("Receiving the messages since {} again...", since);
+        self.client
+            .subscribe_with_id(
+                self.subscription_id.clone(),
+                vec![self.history_filter(since)],
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Marks the replay of `relay_url` as done, receiving the messages of any date again once no relay replays.
+    ///
+    /// Later duplicates are only skipped by their event id then.
+    fn finish_replay(&mut self, relay_url: &Url) {
+        if self.replaying_relays.remove(relay_url) && self.replaying_relays.is_empty() {
+            if let Some(start) = self.history_start.take() {
+                debug!("Received the messages since {} again", start);
+            }
+        }
+    }
+
+    /// Waits for the next private message of `from` to this client.
+    ///
+    /// Like [`NostrClient::receive_escrow_message_of_any`] with `from` as only sender.
+    pub async fn receive_escrow_message(
+        &mut self,
+        from: PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<String, EscrowError> {
+        let (_, content) = self.receive_escrow_message_of_any(&[from], timeout).await?;
+        Ok(content)
+    }
+
+    /// Waits for the next private message of any of `senders` to this client, returning it with its sender.
+    ///
+    /// A timeout is reported for the first of `senders`.
+    ///
+    /// Messages of other senders are kept until somebody waits for them.
+    ///
+    /// Relays disconnecting meanwhile are reconnected and subscribed again since they disconnected, so a short outage
+    /// doesn't end the wait or lose the messages sent during it.
+    ///
+    /// Events which can't be decrypted are skipped.
+    ///
+    /// Fails with [`EscrowError::Timeout`] if no message arrives within `timeout` and with
+    /// [`EscrowError::RelayDisconnected`] if the relay pool shuts down meanwhile. Without `timeout` it waits until a
+    /// message arrives or the wait is dropped, e.g. on ctrl-c, and the subscription is left to [`shutdown_client`].
+    /// Fails right away if `senders` is empty.
+    pub async fn receive_escrow_message_of_any(
+        &mut self,
+        senders: &[PublicKey],
+        timeout: Option<Duration>,
+    ) -> Result<(PublicKey, String), EscrowError> {
+        let Some(&first_sender) = senders.first() else {
+            return Err(anyhow!("No senders to wait for messages of").into());
+        };
+        if let Some(since) = self.replay_since.take() {
+            self.replay_history(since).await?;
+            self.history_start = Some(since);
+            self.replaying_relays = self.client.relays().await.into_keys().collect();
+        }
+        if let Some(index) = self
+            .pending_messages
+            .iter()
+            .position(|(sender, _)| senders.contains(sender))
+        {
+            let message = self.pending_messages.remove(index).expect("Index is valid");
+            self.metrics.record_message_received();
+            return Ok(message);
+        }
+
+        let mut events_seen = 0;
+        let mut keepalive = self
+            .keepalive_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
         let loop_future = async {
             loop {
-                match self.notifications_receiver.recv().await {
-                    Ok(notification) => {
-                        if let RelayPoolNotification::Event { event, .. } = notification {
-                            let rumor = self.client.unwrap_gift_wrap(&event).await?.rumor;
-                            if rumor.kind == Kind::PrivateDirectMessage {
-                                break Ok(rumor.content) as anyhow::Result<String>;
+                let notification = tokio::select! {
+                    notification = self.notifications_receiver.recv() => notification,
+                    _ = keepalive_tick(&mut keepalive) => {
+                        self.keep_subscription_alive().await?;
+                        continue;
+                    }
+                };
+                match notification {
+                    Ok(RelayPoolNotification::Event { event, .. }) => {
+                        events_seen += 1;
+                        if !self.seen_event_ids.insert(event.id) {
+                            trace!("Skipping duplicate event {}", event.id);
+                            continue;
+                        }
+                        // a malformed message of a hostile sender or relay must not end the wait
+                        match self.decrypt_timed_message(&event).await {
+                            Ok(Some((sender, _, sent_at)))
+                                if self.history_start.is_some_and(|start| sent_at < start) =>
+                            {
+                                trace!("Skipping message of {} sent at {}", sender, sent_at);
+                            }
+                            Ok(Some((sender, content, _))) => {
+                                if senders.contains(&sender) {
+                                    break Ok((sender, content))
+                                        as Result<(PublicKey, String), EscrowError>;
+                                }
+                                debug!("Keeping message of {} for later", sender);
+                                self.pending_messages.push_back((sender, content));
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("Skipping undecryptable event {}: {}", event.id, e),
+                        }
+                    }
+                    Ok(RelayPoolNotification::RelayStatus { relay_url, status }) => match status {
+                        RelayStatus::Disconnected | RelayStatus::Terminated => {
+                            warn!("Relay {} disconnected, reconnecting...", relay_url);
+                            self.finish_replay(&relay_url);
+                            if let Err(e) = self.client.connect_relay(relay_url.clone()).await {
+                                warn!("Failed to reconnect relay {}: {}", relay_url, e);
                             }
+                            self.disconnected_relays
+                                .entry(relay_url)
+                                .or_insert_with(Timestamp::now);
+                        }
+                        RelayStatus::Connected
+                            if self.disconnected_relays.contains_key(&relay_url) =>
+                        {
+                            let disconnected_at = self
+                                .disconnected_relays
+                                .remove(&relay_url)
+                                .expect("Relay is disconnected");
+                            debug!(
+                                "Relay {} reconnected, receiving the messages since {} again...",
+                                relay_url, disconnected_at
+                            );
+                            // drops the messages backdated before the outage, which were received already
+                            self.history_start.get_or_insert(disconnected_at);
+                            self.replaying_relays.insert(relay_url.clone());
+                            self.client
+                                .subscribe_with_id_to(
+                                    [relay_url],
+                                    self.subscription_id.clone(),
+                                    vec![self.history_filter(disconnected_at)],
+                                    None,
+                                )
+                                .await?;
                         }
+                        _ => {}
+                    },
+                    Ok(RelayPoolNotification::Message {
+                        relay_url,
+                        message: RelayMessage::EndOfStoredEvents(subscription_id),
+                    }) if subscription_id == self.subscription_id => {
+                        self.finish_replay(&relay_url);
                     }
+                    Ok(RelayPoolNotification::Shutdown) => {
+                        break Err(EscrowError::RelayDisconnected);
+                    }
+                    Ok(_) => {}
                     Err(RecvError::Closed) => {
                         error!("Relay pool closed subscription, restarting a new one...");
                         self.client.unsubscribe(self.subscription_id.clone()).await;
                         (self.subscription_id, self.notifications_receiver) =
-                            init_subscription(&self.keys, &self.client).await?;
+                            init_subscription(&self.client, self.subscription_filter()).await?;
+                        if self.history_start.is_some() {
+                            // every relay replays the history to the new subscription
+                            self.replaying_relays =
+                                self.client.relays().await.into_keys().collect();
+                        }
                     }
                     Err(RecvError::Lagged(count)) => {
                         warn!("Lost {} events, proceeding after that...", count);
@@ -71,46 +615,513 @@ impl NostrClient {
                 }
             }
         };
-        let result = match timeout(Duration::from_secs(timeout_secs), loop_future).await {
-            Ok(result) => result,
-            Err(e) => Err(anyhow!("Timeout, {}", e)),
+        let result = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, loop_future).await {
+                Ok(result) => result,
+                Err(_) => Err(EscrowError::Timeout {
+                    from: first_sender,
+                    waited: timeout,
+                    events_seen,
+                }),
+            },
+            None => loop_future.await,
         };
-
+        match &result {
+            Ok(_) => self.metrics.record_message_received(),
+            Err(EscrowError::Timeout { .. }) => self.metrics.record_timeout(),
+            Err(_) => {}
+        }
         result
     }
 
+    /// Puts the received `message` of `sender` back, so the next wait for messages of `sender` returns it first.
+    pub fn requeue_escrow_message(&mut self, sender: PublicKey, message: String) {
+        self.pending_messages.push_front((sender, message));
+    }
+
+    /// Waits for up to `count` private messages of `from`, all within `timeout`, skipping duplicates and
+    /// malformed events like [`NostrClient::receive_escrow_message`].
+    ///
+    /// Returns the messages which arrived before the timeout, failing with [`EscrowError::Timeout`] only if none did.
+    pub async fn receive_escrow_messages(
+        &mut self,
+        from: PublicKey,
+        count: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<String>, EscrowError> {
+        let deadline = MessageDeadline::after(timeout);
+        let mut messages = Vec::with_capacity(count);
+        while messages.len() < count {
+            let Ok(remaining) = deadline.remaining(from, 0) else {
+                break;
+            };
+            match self.receive_escrow_message(from, remaining).await {
+                Ok(message) => messages.push(message),
+                Err(EscrowError::Timeout { events_seen, .. }) if messages.is_empty() => {
+                    return Err(deadline.timed_out(from, events_seen));
+                }
+                Err(EscrowError::Timeout { .. }) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if messages.is_empty() && count > 0 {
+            return Err(deadline.timed_out(from, 0));
+        }
+        if messages.len() < count {
+            debug!(
+                "Received {} of {} messages of {} within {:?}",
+                messages.len(),
+                count,
+                from,
+                timeout
+            );
+        }
+        Ok(messages)
+    }
+
+    /// Unsubscribes from the relays and disconnects from them.
+    pub async fn shutdown(self) -> Result<(), EscrowError> {
+        shutdown_client(&self.client).await
+    }
+
     // coordinator specific function?
     pub async fn send_escrow_registration(
         &self,
-        receivers: (PublicKey, PublicKey),
-        id: &[u8; 32],
-        trade_pk: &str,
-    ) -> anyhow::Result<()> {
-        let registration_json = serde_json::to_string(&EscrowRegistration {
-            escrow_id_hex: hex::encode(id),
-            coordinator_escrow_pubkey: cdk::nuts::PublicKey::from_hex(trade_pk)?,
-            escrow_start_time: Timestamp::now(),
-        })?;
-        // todo: replace deprecated method
-        self.client
-            .send_private_msg(receivers.0, &registration_json, None)
-            .await?;
-        self.client
-            .send_private_msg(receivers.1, &registration_json, None)
-            .await?;
+        receiver: PublicKey,
+        registration: &EscrowRegistration,
+        contract_expiry: Timestamp,
+    ) -> Result<(), EscrowError> {
+        let message =
+            serde_json::to_string(&CoordinatorMessage::Registration(registration.clone()))?;
+        self.send_private_message_expiring(
+            receiver,
+            &message,
+            Some(message_expiration(contract_expiry)),
+        )
+        .await?;
         Ok(())
     }
 }
 
+/// Client options connecting every relay through `proxy`, if given.
+fn relay_options(proxy: Option<SocketAddr>) -> Options {
+    let mut connection = Connection::new();
+    if let Some(proxy) = proxy {
+        debug!("Connecting to the relays through the proxy {}", proxy);
+        connection = connection.proxy(proxy).target(ConnectionTarget::All);
+    }
+    Options::new().connection(connection)
+}
+
+/// Adds the given relays, or [`DEFAULT_RELAYS`] if none are given, failing listing every relay which could not be added.
+async fn add_relays(
+    client: &Client,
+    relays: Option<Vec<String>>,
+    proxy: Option<SocketAddr>,
+) -> Result<(), EscrowError> {
+    let relays = relays.unwrap_or_else(|| DEFAULT_RELAYS.map(String::from).to_vec());
+    let mut failed_relays = Vec::new();
+    for relay in relays {
+        if proxy.is_none() && is_onion_relay(&relay) {
+            failed_relays.push(format!("{} (onion relays need a proxy)", relay));
+            continue;
+        }
+        match client.add_relay(relay.as_str()).await {
+            Ok(true) => debug!("Added relay {}", relay),
+            Ok(false) => debug!("Skipping duplicate relay {}", relay),
+            Err(e) => failed_relays.push(format!("{} ({})", relay, e)),
+        }
+    }
+    if !failed_relays.is_empty() {
+        return Err(anyhow!("Failed to add relays: {}", failed_relays.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Connects `client` to its relays, retrying with exponential backoff until at least one relay is connected.
+async fn connect_with_retry(
+    client: &Client,
+    connection_retry: ConnectionRetry,
+) -> Result<(), EscrowError> {
+    let mut backoff = connection_retry.backoff;
+    for attempt in 1..=connection_retry.max_attempts {
+        debug!(
+            "Connecting to relays (attempt {} of {})...",
+            attempt, connection_retry.max_attempts
+        );
+        client.connect().await;
+        let start = tokio::time::Instant::now();
+        let mut connected = connected_relay_count(client).await;
+        while connected < connection_retry.min_relays && start.elapsed() < CONNECT_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            connected = connected_relay_count(client).await;
+        }
+        if connected >= connection_retry.min_relays {
+            return Ok(());
+        }
+        if attempt < connection_retry.max_attempts {
+            warn!(
+                "Only {} of {} required relays connected, retrying in {:?}...",
+                connected, connection_retry.min_relays, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    Err(anyhow!(
+        "Less than {} relays reachable after {} attempts",
+        connection_retry.min_relays,
+        connection_retry.max_attempts
+    )
+    .into())
+}
+
+async fn connected_relay_count(client: &Client) -> usize {
+    let mut connected = 0;
+    for relay in client.relays().await.values() {
+        if relay.is_connected().await {
+            connected += 1;
+        }
+    }
+    connected
+}
+
+/// Unsubscribes all subscriptions of `client` and disconnects it from its relays.
+///
+/// Also shuts down a [`NostrClient`] through a clone of its `client` when the [`NostrClient`] itself was moved elsewhere.
+pub async fn shutdown_client(client: &Client) -> Result<(), EscrowError> {
+    client.unsubscribe_all().await;
+    client.disconnect().await?;
+    debug!("Disconnected from all relays");
+    Ok(())
+}
+
+/// Reads a comma separated relay list from the `NOSTR_RELAYS` environment variable.
+pub fn relays_from_env() -> Option<Vec<String>> {
+    let relays: Vec<String> = std::env::var("NOSTR_RELAYS")
+        .ok()?
+        .split(',')
+        .map(|relay| relay.trim().to_string())
+        .filter(|relay| !relay.is_empty())
+        .collect();
+    (!relays.is_empty()).then_some(relays)
+}
+
+/// Reads the SOCKS5 proxy to connect to the relays through from the `NOSTR_PROXY` environment variable, e.g.
+/// `127.0.0.1:9050` for Tor.
+pub fn proxy_from_env() -> Result<Option<SocketAddr>, EscrowError> {
+    let Ok(proxy) = std::env::var("NOSTR_PROXY") else {
+        return Ok(None);
+    };
+    let proxy = proxy.trim();
+    if proxy.is_empty() {
+        return Ok(None);
+    }
+    let addr = proxy
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Proxy {} resolves to no address", proxy))?;
+    Ok(Some(addr))
+}
+
+fn is_onion_relay(relay: &str) -> bool {
+    Url::parse(relay)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.ends_with(".onion")))
+        .unwrap_or(false)
+}
+
+/// Reads the keepalive interval in seconds from the `NOSTR_KEEPALIVE_SECS` environment variable, 0 disables it.
+pub fn keepalive_interval_from_env() -> Result<Option<Duration>, EscrowError> {
+    match std::env::var("NOSTR_KEEPALIVE_SECS") {
+        Ok(secs) => {
+            let secs: u64 = secs
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("Invalid NOSTR_KEEPALIVE_SECS {}: {}", secs, e))?;
+            Ok((secs > 0).then(|| Duration::from_secs(secs)))
+        }
+        Err(_) => Ok(Some(DEFAULT_KEEPALIVE_INTERVAL)),
+    }
+}
+
+/// Completes on the next tick of `keepalive`, never if there is none.
+pub async fn keepalive_tick(keepalive: &mut Option<tokio::time::Interval>) {
+    match keepalive {
+        Some(keepalive) => {
+            keepalive.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads the messaging scheme from the `NOSTR_MESSAGING_SCHEME` environment variable, defaulting to gift wraps.
+pub fn messaging_scheme_from_env() -> Result<MessagingScheme, EscrowError> {
+    match std::env::var("NOSTR_MESSAGING_SCHEME") {
+        Ok(scheme) => scheme.parse(),
+        Err(_) => Ok(MessagingScheme::default()),
+    }
+}
+
+/// Filter matching the private messages to `receiver` sent from now on.
+fn message_filter(receiver: PublicKey, messaging_scheme: MessagingScheme) -> Filter {
+    messages_to(receiver, messaging_scheme).limit(0)
+}
+
+/// Filter matching all private messages to `receiver`, stored ones included.
+fn messages_to(receiver: PublicKey, messaging_scheme: MessagingScheme) -> Filter {
+    let kind = match messaging_scheme {
+        MessagingScheme::GiftWrap => Kind::GiftWrap,
+        MessagingScheme::Nip04 => Kind::EncryptedDirectMessage,
+    };
+    Filter::new().kind(kind).pubkey(receiver)
+}
+
+/// Filter matching the private messages to `receiver` sent since `since`.
+fn messages_since(
+    receiver: PublicKey,
+    messaging_scheme: MessagingScheme,
+    since: Timestamp,
+) -> Filter {
+    let since = match messaging_scheme {
+        // gift wraps are backdated by up to two days to hide when they were sent
+        MessagingScheme::GiftWrap => since - nip59::RANGE_RANDOM_TIMESTAMP_TWEAK.end,
+        MessagingScheme::Nip04 => since,
+    };
+    messages_to(receiver, messaging_scheme).since(since)
+}
+
 async fn init_subscription(
-    keys: &Keys,
     client: &Client,
-) -> Result<(SubscriptionId, Receiver<RelayPoolNotification>), anyhow::Error> {
-    let message_filter = Filter::new()
-        .kind(Kind::GiftWrap)
-        .pubkey(keys.public_key())
-        .limit(0);
+    message_filter: Filter,
+) -> Result<(SubscriptionId, Receiver<RelayPoolNotification>), EscrowError> {
     let _subscription_id = client.subscribe(vec![message_filter], None).await?.val;
     let notifications_receiver = client.notifications();
     Ok((_subscription_id, notifications_receiver))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TradeCancelled;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn registration(nonce: &str) -> EscrowRegistration {
+        EscrowRegistration::new(
+            "00".repeat(32),
+            cdk::nuts::SecretKey::generate().public_key(),
+            Timestamp::now(),
+            0,
+            nonce.to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn registration_round_trip_through_mock_relay() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut trader = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let coordinator = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+
+        let registration = registration("nonce");
+        coordinator
+            .send_escrow_registration(
+                trader.public_key(),
+                &registration,
+                Timestamp::now() + 60 * 60,
+            )
+            .await?;
+
+        let CoordinatorMessage::Registration(received) = trader
+            .receive_coordinator_message(coordinator.public_key(), Some(TEST_TIMEOUT))
+            .await?
+        else {
+            panic!("Expected a registration");
+        };
+        assert_eq!(received.nonce, registration.nonce);
+        assert_eq!(
+            received.coordinator_escrow_pubkey,
+            registration.coordinator_escrow_pubkey
+        );
+        assert_eq!(relay.events().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nip04_payload_round_trip_through_mock_relay() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut receiver = relay
+            .client(Keys::generate(), MessagingScheme::Nip04)
+            .await?;
+        let sender = relay
+            .client(Keys::generate(), MessagingScheme::Nip04)
+            .await?;
+
+        let cancellation = TradeCancelled {
+            escrow_id_hex: "00".repeat(32),
+            cancelled_by: sender.public_key(),
+            reason: "changed my mind".to_string(),
+        };
+        sender
+            .send_payload(receiver.public_key(), &cancellation)
+            .await?;
+
+        let received: TradeCancelled = receiver
+            .receive_payload(sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(received.reason, cancellation.reason);
+        assert_eq!(relay.events()[0].kind, Kind::EncryptedDirectMessage);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn receive_survives_relay_disconnect() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut receiver = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let sender_pubkey = sender.public_key();
+        let receiver_pubkey = receiver.public_key();
+
+        let send_after_outage = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            relay.disconnect_clients();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // the relays reconnect on their own retry interval
+            sender
+                .wait_for_connection(1, Duration::from_secs(30))
+                .await?;
+            sender
+                .send_private_message(receiver_pubkey, "after the outage")
+                .await
+        };
+        let (message, _) = tokio::try_join!(
+            receiver.receive_escrow_message(sender_pubkey, Some(Duration::from_secs(30))),
+            send_after_outage
+        )?;
+        assert_eq!(message, "after the outage");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn receive_returns_message_of_requested_sender_only() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut receiver = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let first_sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let second_sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+
+        first_sender
+            .send_private_message(receiver.public_key(), "first")
+            .await?;
+        second_sender
+            .send_private_message(receiver.public_key(), "second")
+            .await?;
+
+        let message = receiver
+            .receive_escrow_message(second_sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(message, "second");
+        // the message of the other sender is kept for its own wait
+        let message = receiver
+            .receive_escrow_message(first_sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(message, "first");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gift_wrapped_message_round_trip() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut receiver = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+
+        sender
+            .send_private_message(receiver.public_key(), "hello")
+            .await?;
+
+        let event = relay.events().remove(0);
+        assert_eq!(event.kind, Kind::GiftWrap);
+        // the gift wrap is signed by a one-time key, only the rumor names the sender
+        assert_ne!(event.pubkey, sender.public_key());
+        assert_eq!(
+            receiver.decrypt_message(&event).await?,
+            Some((sender.public_key(), "hello".to_string()))
+        );
+        let message = receiver
+            .receive_escrow_message(sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(message, "hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn receive_skips_malformed_gift_wrap() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let mut receiver = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+
+        let malformed = EventBuilder::new(
+            Kind::GiftWrap,
+            "not an encrypted seal",
+            [Tag::public_key(receiver.public_key())],
+        )
+        .to_event(&Keys::generate())
+        .map_err(anyhow::Error::from)?;
+        sender.client.send_event(malformed).await?;
+        sender
+            .send_private_message(receiver.public_key(), "valid")
+            .await?;
+
+        let message = receiver
+            .receive_escrow_message(sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(message, "valid");
+        assert_eq!(relay.events().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_receives_message_sent_before_subscribing() -> Result<(), EscrowError> {
+        let relay = MockRelay::run().await?;
+        let receiver_keys = Keys::generate();
+        let sender = relay
+            .client(Keys::generate(), MessagingScheme::GiftWrap)
+            .await?;
+        let trade_start = Timestamp::now() - 60;
+
+        sender
+            .send_private_message(receiver_keys.public_key(), "early reply")
+            .await?;
+
+        let mut receiver = relay
+            .client(receiver_keys, MessagingScheme::GiftWrap)
+            .await?;
+        receiver.replay_messages_since(trade_start);
+        let message = receiver
+            .receive_escrow_message(sender.public_key(), Some(TEST_TIMEOUT))
+            .await?;
+        assert_eq!(message, "early reply");
+        Ok(())
+    }
+}
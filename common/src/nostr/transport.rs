@@ -0,0 +1,240 @@
+use super::*;
+
+use async_trait::async_trait;
+
+use crate::envelope::{CoordinatorMessage, EscrowEnvelope, EscrowMessage, MessageKind};
+
+/// The end of a wait spanning several received messages, never for a wait without timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageDeadline {
+    timeout: Option<Duration>,
+    wait_until: Option<tokio::time::Instant>,
+}
+
+impl MessageDeadline {
+    /// Starts a wait of `timeout` now, `None` to wait until the messages arrive or the client is interrupted.
+    pub fn after(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            wait_until: timeout.map(|timeout| tokio::time::Instant::now() + timeout),
+        }
+    }
+
+    /// The timeout of the next receive, `None` to wait forever.
+    ///
+    /// Fails with [`EscrowError::Timeout`] of `from` once the deadline passed.
+    pub fn remaining(
+        &self,
+        from: PublicKey,
+        events_seen: usize,
+    ) -> Result<Option<Duration>, EscrowError> {
+        let Some(wait_until) = self.wait_until else {
+            return Ok(None);
+        };
+        match wait_until.checked_duration_since(tokio::time::Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+            _ => Err(self.timed_out(from, events_seen)),
+        }
+    }
+
+    /// The [`EscrowError::Timeout`] of the whole wait for `from`, after `events_seen` other events.
+    pub fn timed_out(&self, from: PublicKey, events_seen: usize) -> EscrowError {
+        EscrowError::Timeout {
+            from,
+            waited: self.timeout.unwrap_or_default(),
+            events_seen,
+        }
+    }
+}
+
+/// Delivers the escrow messages between the traders and the coordinator.
+///
+/// Implemented by [`NostrClient`], test doubles can replace it to run trades without relays.
+#[async_trait]
+pub trait EscrowTransport: Send + Sync {
+    fn public_key(&self) -> PublicKey;
+
+    /// Accepts the terms of `contract`, signed by the identity of this transport.
+    fn accept_contract(&self, contract: &TradeContract) -> Result<ContractAccepted, EscrowError>;
+
+    /// Signs the receipt of a finished trade with the identity of this transport.
+    fn sign_trade_receipt(&self, content: TradeReceiptContent)
+        -> Result<TradeReceipt, EscrowError>;
+
+    /// Waits until at least `min_relays` relays are connected, failing after `timeout`.
+    async fn wait_for_connection(
+        &self,
+        min_relays: usize,
+        timeout: Duration,
+    ) -> Result<(), EscrowError>;
+
+    /// Lets relays delete the messages sent afterwards after `expiration`, never if `None`.
+    ///
+    /// Transports without expiring messages ignore it.
+    fn set_message_expiration(&mut self, _expiration: Option<Timestamp>) {}
+
+    /// Receives the messages sent since `since` again on the next wait, e.g. the messages a resumed trader missed
+    /// while offline or the trade partner sent just before this transport subscribed.
+    ///
+    /// Transports without a message history ignore it.
+    fn replay_messages_since(&mut self, _since: Timestamp) {}
+
+    /// Sends `message` to `receiver`, returning the number of relays which accepted it.
+    async fn send_to(&self, receiver: PublicKey, message: &str) -> Result<usize, EscrowError>;
+
+    /// Waits for the next message of any of `senders`, returning it with its sender.
+    ///
+    /// Fails with [`EscrowError::Timeout`] for the first of `senders` if no message arrives within `timeout`, waits
+    /// forever without `timeout`.
+    async fn receive_from_any(
+        &mut self,
+        senders: &[PublicKey],
+        timeout: Option<Duration>,
+    ) -> Result<(PublicKey, String), EscrowError>;
+
+    /// Puts the received `message` of `sender` back, so the next wait for messages of `sender` returns it first.
+    fn requeue_message(&mut self, sender: PublicKey, message: String);
+
+    /// Waits for the next message of `sender`.
+    ///
+    /// Fails with [`EscrowError::Timeout`] if no message arrives within `timeout`, waits forever without `timeout`.
+    async fn receive_from(
+        &mut self,
+        sender: PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<String, EscrowError> {
+        let (_, message) = self.receive_from_any(&[sender], timeout).await?;
+        Ok(message)
+    }
+
+    /// Sends `payload` in an [`EscrowEnvelope`] to `receiver`, returning the number of relays which accepted it.
+    async fn send_payload<P: EscrowMessage + Sync>(
+        &self,
+        receiver: PublicKey,
+        payload: &P,
+    ) -> Result<usize, EscrowError> {
+        let message = serde_json::to_string(&EscrowEnvelope::wrap(payload)?)
+            .map_err(|e| anyhow!("Failed to serialize escrow message: {}", e))?;
+        self.send_to(receiver, &message).await
+    }
+
+    /// Sends a coordinator `message` to `receiver`, returning the number of relays which accepted it.
+    async fn send_coordinator_message(
+        &self,
+        receiver: PublicKey,
+        message: &CoordinatorMessage,
+    ) -> Result<usize, EscrowError> {
+        let message = serde_json::to_string(message)
+            .map_err(|e| anyhow!("Failed to serialize coordinator message: {}", e))?;
+        self.send_to(receiver, &message).await
+    }
+
+    /// Waits for the next message of `sender` and parses its envelope.
+    ///
+    /// Fails with [`EscrowError::UnsupportedVersion`] if `sender` runs another protocol version.
+    async fn receive_envelope(
+        &mut self,
+        sender: PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<EscrowEnvelope, EscrowError> {
+        let message = self.receive_from(sender, timeout).await?;
+        EscrowEnvelope::parse(&message)
+            .inspect_err(|e| warn!("Invalid escrow message of {}: {}", sender, e))
+    }
+
+    /// Waits for the next message of `sender` of one of `kinds`, skipping the messages of other kinds, e.g. the
+    /// replayed messages of earlier trade steps.
+    ///
+    /// Fails with [`EscrowError::Timeout`] if no such message arrives within `timeout`.
+    async fn receive_envelope_of(
+        &mut self,
+        sender: PublicKey,
+        kinds: &[MessageKind],
+        timeout: Option<Duration>,
+    ) -> Result<EscrowEnvelope, EscrowError> {
+        let deadline = MessageDeadline::after(timeout);
+        let mut events_seen = 0;
+        loop {
+            let remaining = deadline.remaining(sender, events_seen)?;
+            let envelope = self.receive_envelope(sender, remaining).await?;
+            if kinds.contains(&envelope.kind) {
+                return Ok(envelope);
+            }
+            debug!("Skipping {:?} message of {}", envelope.kind, sender);
+            events_seen += 1;
+        }
+    }
+
+    /// Waits for the next message of the `coordinator`, skipping messages which are no [`CoordinatorMessage`].
+    ///
+    /// Fails with [`EscrowError::Timeout`] if no coordinator message arrives within `timeout`.
+    async fn receive_coordinator_message(
+        &mut self,
+        coordinator: PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<CoordinatorMessage, EscrowError> {
+        let envelope = self
+            .receive_envelope_of(coordinator, &CoordinatorMessage::KINDS, timeout)
+            .await?;
+        CoordinatorMessage::open(envelope)
+    }
+
+    /// Waits for the next message of `sender`, failing if it doesn't carry a `P`.
+    async fn receive_payload<P: EscrowMessage>(
+        &mut self,
+        sender: PublicKey,
+        timeout: Option<Duration>,
+    ) -> Result<P, EscrowError> {
+        self.receive_envelope(sender, timeout).await?.open()
+    }
+}
+
+#[async_trait]
+impl EscrowTransport for NostrClient {
+    fn public_key(&self) -> PublicKey {
+        NostrClient::public_key(self)
+    }
+
+    fn accept_contract(&self, contract: &TradeContract) -> Result<ContractAccepted, EscrowError> {
+        ContractAccepted::sign(contract, &self.keys)
+    }
+
+    fn sign_trade_receipt(
+        &self,
+        content: TradeReceiptContent,
+    ) -> Result<TradeReceipt, EscrowError> {
+        TradeReceipt::sign(content, &self.keys)
+    }
+
+    async fn wait_for_connection(
+        &self,
+        min_relays: usize,
+        timeout: Duration,
+    ) -> Result<(), EscrowError> {
+        NostrClient::wait_for_connection(self, min_relays, timeout).await
+    }
+
+    fn set_message_expiration(&mut self, expiration: Option<Timestamp>) {
+        self.message_expiration = expiration;
+    }
+
+    fn replay_messages_since(&mut self, since: Timestamp) {
+        self.replay_since = Some(since);
+    }
+
+    async fn send_to(&self, receiver: PublicKey, message: &str) -> Result<usize, EscrowError> {
+        self.send_private_message(receiver, message).await
+    }
+
+    async fn receive_from_any(
+        &mut self,
+        senders: &[PublicKey],
+        timeout: Option<Duration>,
+    ) -> Result<(PublicKey, String), EscrowError> {
+        self.receive_escrow_message_of_any(senders, timeout).await
+    }
+
+    fn requeue_message(&mut self, sender: PublicKey, message: String) {
+        self.requeue_escrow_message(sender, message);
+    }
+}
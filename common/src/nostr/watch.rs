@@ -0,0 +1,124 @@
+use super::*;
+
+/// A private message to the watched key, observed without decrypting it.
+#[derive(Debug, Clone)]
+pub struct ObservedMessage {
+    pub event_id: EventId,
+    pub kind: Kind,
+    /// The sender of a NIP-04 message, gift wraps are signed by a one time key hiding the sender.
+    pub sender: Option<PublicKey>,
+    /// When the event claims to be created, gift wraps are backdated by up to two days.
+    pub created_at: Timestamp,
+    pub relay_url: Url,
+}
+
+/// Observes the private messages to a public key without holding its private key, e.g. to monitor the trades of a
+/// seller signing on another machine.
+///
+/// The messages can't be decrypted, only their arrival is reported.
+pub struct MessageWatcher {
+    client: Client,
+    watched: PublicKey,
+    messaging_scheme: MessagingScheme,
+    keepalive_interval: Option<Duration>,
+}
+
+impl MessageWatcher {
+    /// Creates a watcher connected to the given relays, or to [`DEFAULT_RELAYS`] if none are given.
+    pub async fn new(
+        watched: PublicKey,
+        relays: Option<Vec<String>>,
+        messaging_scheme: MessagingScheme,
+        proxy: Option<SocketAddr>,
+    ) -> Result<Self, EscrowError> {
+        let client = Client::builder().opts(relay_options(proxy)).build();
+        add_relays(&client, relays, proxy).await?;
+        connect_with_retry(&client, ConnectionRetry::default()).await?;
+        Ok(Self {
+            client,
+            watched,
+            messaging_scheme,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+        })
+    }
+
+    /// Renews the subscription at this interval, see [`NostrClient::with_keepalive_interval`].
+    pub fn with_keepalive_interval(mut self, keepalive_interval: Option<Duration>) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    pub fn watched(&self) -> PublicKey {
+        self.watched
+    }
+
+    /// Reports every message to the watched key to `on_message` until the relay pool shuts down.
+    ///
+    /// With `since` the stored messages sent since then are reported first, for gift wraps also those backdated up to
+    /// two days before.
+    pub async fn watch(
+        &self,
+        since: Option<Timestamp>,
+        mut on_message: impl FnMut(ObservedMessage),
+    ) -> Result<(), EscrowError> {
+        let filter = match since {
+            Some(since) => messages_since(self.watched, self.messaging_scheme, since),
+            None => message_filter(self.watched, self.messaging_scheme),
+        };
+        let mut notifications = self.client.notifications();
+        let subscription_id = self.client.subscribe(vec![filter], None).await?.val;
+        let mut keepalive = self
+            .keepalive_interval
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        // every relay delivers the same event again
+        let mut seen_event_ids = HashSet::new();
+        loop {
+            let notification = tokio::select! {
+                notification = notifications.recv() => notification,
+                _ = keepalive_tick(&mut keepalive) => {
+                    trace!("Renewing the watch subscription...");
+                    self.client
+                        .subscribe_with_id(
+                            subscription_id.clone(),
+                            vec![message_filter(self.watched, self.messaging_scheme)],
+                            None,
+                        )
+                        .await?;
+                    continue;
+                }
+            };
+            match notification {
+                Ok(RelayPoolNotification::Event {
+                    relay_url, event, ..
+                }) => {
+                    if !event.public_keys().any(|pubkey| *pubkey == self.watched)
+                        || !seen_event_ids.insert(event.id)
+                    {
+                        continue;
+                    }
+                    let sender = match self.messaging_scheme {
+                        MessagingScheme::GiftWrap => None,
+                        MessagingScheme::Nip04 => Some(event.pubkey),
+                    };
+                    on_message(ObservedMessage {
+                        event_id: event.id,
+                        kind: event.kind,
+                        sender,
+                        created_at: event.created_at,
+                        relay_url,
+                    });
+                }
+                Ok(RelayPoolNotification::Shutdown) | Err(RecvError::Closed) => return Ok(()),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Missed {} relay notifications while watching", skipped)
+                }
+            }
+        }
+    }
+
+    /// Disconnects from the relays, ending [`MessageWatcher::watch`].
+    pub async fn shutdown(&self) -> Result<(), EscrowError> {
+        shutdown_client(&self.client).await
+    }
+}
@@ -0,0 +1,79 @@
+use cashu_escrow_common::cli::get_user_input;
+use cashu_escrow_common::model::DisputeDecision;
+use log::{error, warn};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// A dispute argued by both traders, waiting for the operator to decide it.
+pub(super) struct DisputeRequest {
+    pub escrow_id_hex: String,
+    pub milestone_count: usize,
+}
+
+/// The decision of the operator on a [`DisputeRequest`].
+pub(super) struct DecidedDispute {
+    pub escrow_id_hex: String,
+    pub decision: DisputeDecision,
+}
+
+/// Asks the operator on the terminal to decide the requested disputes one after another and hands the decisions back
+/// to the coordinator, so its event loop keeps handling messages meanwhile.
+///
+/// Ends once the coordinator drops either channel.
+pub(super) async fn decide_disputes(
+    mut requests: UnboundedReceiver<DisputeRequest>,
+    decisions: UnboundedSender<DecidedDispute>,
+) {
+    while let Some(request) = requests.recv().await {
+        let decision = match ask_decision(&request).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!(
+                    "Failed to read the decision on the dispute of {}: {}",
+                    request.escrow_id_hex, e
+                );
+                continue;
+            }
+        };
+        let decided = DecidedDispute {
+            escrow_id_hex: request.escrow_id_hex,
+            decision,
+        };
+        if decisions.send(decided).is_err() {
+            break;
+        }
+    }
+}
+
+async fn ask_decision(request: &DisputeRequest) -> std::io::Result<DisputeDecision> {
+    let milestone_count = request.milestone_count;
+    let prompt = match milestone_count {
+        1 => format!(
+            "Resolve dispute of {}: (1) release to seller, (2) refund to buyer: ",
+            request.escrow_id_hex
+        ),
+        _ => format!(
+            "Resolve dispute of {}: (1) release to seller, (2) refund to buyer, (3) split by milestones: ",
+            request.escrow_id_hex
+        ),
+    };
+    loop {
+        match get_user_input(&prompt).await?.as_str() {
+            "1" => return Ok(DisputeDecision::ReleaseToSeller),
+            "2" => return Ok(DisputeDecision::RefundToBuyer),
+            "3" if milestone_count > 1 => {
+                let input = get_user_input(&format!(
+                    "Milestones released to the seller (0 to {}): ",
+                    milestone_count
+                ))
+                .await?;
+                match input.parse::<usize>() {
+                    Ok(seller_milestones) if seller_milestones <= milestone_count => {
+                        return Ok(DisputeDecision::Split { seller_milestones })
+                    }
+                    _ => warn!("Enter a number of milestones"),
+                }
+            }
+            _ => warn!("Select one of the listed resolutions"),
+        }
+    }
+}
@@ -1,73 +1,150 @@
+mod dispute;
+
 use super::*;
 use anyhow::anyhow;
-use cashu_escrow_common::model::TradeContract;
-use cdk::nuts::SecretKey as CDKSecretKey;
+use cashu_escrow_common::envelope::{CoordinatorMessage, EscrowEnvelope, MessageKind};
+use cashu_escrow_common::model::{
+    milestone_proofs, ContractAccepted, ContractSubmission, CoordinatorError,
+    CoordinatorFeePayment, CoordinatorInfo, DeliveryProof, DisputeClaim, DisputeResolution,
+    EscrowRegistration, FeeReceipt, TradeCancelled, TradeContract, TradeReceipt,
+};
+use cashu_escrow_common::nostr::{keepalive_tick, EscrowTransport};
+use cdk::mint_url::MintUrl;
+use cdk::nuts::{
+    Proofs, PublicKey as CDKPubkey, SecretKey as CDKSecretKey, SpendingConditions, Token,
+};
+use cdk::Amount;
+use dispute::{decide_disputes, DecidedDispute, DisputeRequest};
 use hashes::hex::DisplayHex;
 use ndk::prelude::*;
-use ndk::{Filter, Kind, RelayPoolNotification};
+use ndk::RelayPoolNotification;
 use nostr_sdk as ndk;
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::str::FromStr;
 use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 pub struct EscrowCoordinator {
     nostr_client: NostrClient,
-    pending_contracts: HashMap<[u8; 32], TradeContract>, // k: hash of contract json
+    coordinator_fee_sat: u64,
+    /// Mints of the escrowed tokens, any mint if empty.
+    supported_mints: Vec<MintUrl>,
+    pending_contracts: HashMap<[u8; 32], PendingTrade>, // k: hash of contract json
     active_contracts: HashMap<[u8; 32], ActiveTade>,
+    /// Disputes handed to the operator, decided on the terminal by a separate task.
+    dispute_requests: UnboundedSender<DisputeRequest>,
+    dispute_decisions: UnboundedReceiver<DecidedDispute>,
+}
+
+/// A contract submitted by only one of the traders yet.
+struct PendingTrade {
+    trade_contract: TradeContract,
+    nonces: HashMap<PublicKey, String>, // k: trader, v: nonce of the latest submission
+    acceptances: HashMap<PublicKey, ContractAccepted>, // k: trader, v: its signed acceptance of the contract
 }
 
 struct ActiveTade {
-    _trade_contract: TradeContract,
-    _coordinator_secret: CDKSecretKey,
+    trade_contract: TradeContract,
+    coordinator_secret: CDKSecretKey,
+    escrow_start_time: Timestamp,
+    coordinator_fee_sat: u64,
+    fee_token: Option<String>,
+    dispute_claims: Vec<DisputeClaim>,
+    delivery_proof: Option<DeliveryProof>,
+    cancelled: bool,
+    /// Whether the dispute waits for the decision of the operator.
+    dispute_requested: bool,
+}
+
+impl ActiveTade {
+    fn registration(&self, escrow_id: &[u8; 32], nonce: String) -> EscrowRegistration {
+        EscrowRegistration::new(
+            hex::encode(escrow_id),
+            self.coordinator_secret.public_key(),
+            self.escrow_start_time,
+            self.coordinator_fee_sat,
+            nonce,
+        )
+    }
 }
 
 impl EscrowCoordinator {
-    pub fn new(nostr_client: NostrClient) -> anyhow::Result<Self> {
+    /// Creates a coordinator charging `coordinator_fee_sat` for every escrow.
+    ///
+    /// Spawns the task asking the operator to decide the disputes, so it must be called within a tokio runtime.
+    pub fn new(nostr_client: NostrClient, coordinator_fee_sat: u64) -> anyhow::Result<Self> {
+        let (dispute_requests, requests_receiver) = mpsc::unbounded_channel();
+        let (decisions_sender, dispute_decisions) = mpsc::unbounded_channel();
+        tokio::spawn(decide_disputes(requests_receiver, decisions_sender));
         Ok(Self {
             nostr_client,
+            coordinator_fee_sat,
+            supported_mints: Vec::new(),
             pending_contracts: HashMap::new(),
             active_contracts: HashMap::new(),
+            dispute_requests,
+            dispute_decisions,
         })
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        let my_pubkey = self.nostr_client.public_key();
-        let filter_note = Filter::new()
-            .kind(Kind::GiftWrap)
-            .pubkey(my_pubkey)
-            .limit(0);
+    /// Only escrows the tokens of `supported_mints`.
+    pub fn with_supported_mints(mut self, supported_mints: Vec<MintUrl>) -> Self {
+        self.supported_mints = supported_mints;
+        self
+    }
 
-        self.nostr_client
-            .client
-            .subscribe(vec![filter_note], None)
-            .await?;
+    /// Publishes the fee, mints and relays of this coordinator for the traders to discover it.
+    pub async fn publish_info(&self) -> anyhow::Result<EventId> {
+        let info = CoordinatorInfo {
+            fee_sat: self.coordinator_fee_sat,
+            supported_mints: self.supported_mints.clone(),
+            relays: self
+                .nostr_client
+                .connected_relays()
+                .await
+                .into_iter()
+                .map(|(url, _)| url)
+                .collect(),
+        };
+        Ok(self.nostr_client.publish_coordinator_info(&info).await?)
+    }
+
+    /// Handles the messages of the traders until the relay pool shuts down.
+    ///
+    /// The message subscription of the nostr client is renewed at its keepalive interval, so idle relays keep it. The
+    /// dispute decisions of the operator are sent to the traders as they come in.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
         let mut notifications = self.nostr_client.client.notifications();
+        let mut keepalive = self
+            .nostr_client
+            .keepalive_interval()
+            .map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
 
         loop {
-            match notifications.recv().await {
+            let notification = tokio::select! {
+                notification = notifications.recv() => notification,
+                Some(decided) = self.dispute_decisions.recv() => {
+                    if let Err(e) = self.resolve_dispute(decided).await {
+                        error!("{:#}", e.context("Got error while resolving a dispute"));
+                    }
+                    continue;
+                }
+                _ = keepalive_tick(&mut keepalive) => {
+                    if let Err(e) = self.nostr_client.keep_subscription_alive().await {
+                        warn!("Failed to renew the message subscription: {}", e);
+                    }
+                    continue;
+                }
+            };
+            match notification {
                 Ok(notification) => {
                     if let RelayPoolNotification::Event { event, .. } = notification {
-                        if let Ok(unwrapped_gift) =
-                            self.nostr_client.client.unwrap_gift_wrap(&event).await
+                        if let Ok(Some((sender, content))) =
+                            self.nostr_client.decrypt_message(&event).await
                         {
-                            let rumor = unwrapped_gift.rumor;
-                            if rumor.kind == Kind::PrivateDirectMessage {
-                                if let Ok((contract_hash, contract)) =
-                                    EscrowCoordinator::parse_contract(&rumor.content)
-                                {
-                                    debug!("Received contract: {}", &contract.trade_description);
-                                    if self.pending_contracts.contains_key(&contract_hash) {
-                                        self.pending_contracts.remove(&contract_hash);
-                                        let _ = self
-                                            .begin_trade(&contract_hash, &contract)
-                                            .await
-                                            .inspect_err(|e| {
-                                                error!("Got error while beginning a trade: {}", e);
-                                            });
-                                    } else {
-                                        self.pending_contracts.insert(contract_hash, contract);
-                                    }
-                                }
+                            match EscrowEnvelope::parse(&content) {
+                                Ok(envelope) => self.handle_envelope(sender, envelope).await,
+                                Err(e) => warn!("Ignoring message of {}: {}", sender, e),
                             }
                         }
                     } else if RelayPoolNotification::Shutdown == notification {
@@ -88,42 +165,554 @@ impl EscrowCoordinator {
         }
     }
 
+    /// Dispatches a received message to its handler by the kind of its payload.
+    async fn handle_envelope(&mut self, sender: PublicKey, envelope: EscrowEnvelope) {
+        let result = match envelope.kind {
+            MessageKind::ContractSubmission => match envelope.open::<ContractSubmission>() {
+                Ok(submission) => {
+                    let nonce = submission.nonce.clone();
+                    let result = self.handle_contract_submission(sender, submission).await;
+                    if let Err(e) = &result {
+                        self.reject_contract_submission(sender, nonce, e).await;
+                    }
+                    result.map_err(|e| e.context("Got error while registering a trade"))
+                }
+                Err(e) => Err(e.into()),
+            },
+            MessageKind::CoordinatorFeePayment => match envelope.open() {
+                Ok(fee_payment) => self
+                    .handle_fee_payment(sender, fee_payment)
+                    .await
+                    .map_err(|e| e.context("Got error while receiving a fee")),
+                Err(e) => Err(e.into()),
+            },
+            MessageKind::DisputeClaim => match envelope.open() {
+                Ok(dispute_claim) => self
+                    .handle_dispute_claim(sender, dispute_claim)
+                    .await
+                    .map_err(|e| e.context("Got error while handling a dispute")),
+                Err(e) => Err(e.into()),
+            },
+            MessageKind::TradeCancelled => match envelope.open() {
+                Ok(cancellation) => self
+                    .handle_cancellation(sender, cancellation)
+                    .map_err(|e| e.context("Got error while cancelling a trade")),
+                Err(e) => Err(e.into()),
+            },
+            MessageKind::DeliveryProof => match envelope.open() {
+                Ok(delivery_proof) => self
+                    .handle_delivery_proof(sender, delivery_proof)
+                    .map_err(|e| e.context("Got error while receiving a delivery proof")),
+                Err(e) => Err(e.into()),
+            },
+            MessageKind::TradeReceipt => match envelope.open() {
+                Ok(receipt) => self
+                    .handle_trade_receipt(sender, receipt)
+                    .map_err(|e| e.context("Got error while receiving a trade receipt")),
+                Err(e) => Err(e.into()),
+            },
+            kind => Err(anyhow!("Unexpected {:?} message", kind)),
+        };
+        if let Err(e) = result {
+            error!("{:#}", e);
+        }
+    }
+
+    /// Begins the trade once both traders submitted the contract with their signed acceptance.
+    ///
+    /// A trader resubmitting the contract of an active trade gets its registration again.
+    async fn handle_contract_submission(
+        &mut self,
+        sender: PublicKey,
+        submission: ContractSubmission,
+    ) -> anyhow::Result<()> {
+        let contract = submission.contract;
+        if sender != contract.npubkey_buyer && sender != contract.npubkey_seller {
+            return Err(anyhow!("Contract not submitted by one of its traders"));
+        }
+        submission.acceptance.verify(&contract, &sender)?;
+        if !contract
+            .coordinators()
+            .contains(&self.nostr_client.public_key())
+        {
+            return Err(anyhow!("Contract not coordinated by this coordinator"));
+        }
+        if !self.supported_mints.is_empty() && !self.supported_mints.contains(&contract.mint_url) {
+            return Err(anyhow!("Mint {} not supported", contract.mint_url));
+        }
+        let contract_hash = contract.escrow_id()?;
+        debug!("Received contract: {}", &contract.trade_description);
+
+        if let Some(active_trade) = self.active_contracts.get(&contract_hash) {
+            debug!(
+                "Resending registration of {}",
+                contract_hash.to_hex_string(hashes::hex::Case::Lower)
+            );
+            let registration = active_trade.registration(&contract_hash, submission.nonce);
+            self.nostr_client
+                .send_escrow_registration(sender, &registration, active_trade.trade_contract.expiry)
+                .await?;
+            return Ok(());
+        }
+
+        let pending_trade = self
+            .pending_contracts
+            .entry(contract_hash)
+            .or_insert_with(|| PendingTrade {
+                trade_contract: contract,
+                nonces: HashMap::new(),
+                acceptances: HashMap::new(),
+            });
+        pending_trade.nonces.insert(sender, submission.nonce);
+        pending_trade
+            .acceptances
+            .insert(sender, submission.acceptance);
+        if pending_trade.acceptances.len() < 2 {
+            debug!("Waiting for the counterparty to submit the contract...");
+            return Ok(());
+        }
+        let pending_trade = self
+            .pending_contracts
+            .remove(&contract_hash)
+            .expect("Pending trade exists");
+        self.begin_trade(&contract_hash, pending_trade).await
+    }
+
+    /// Tells the trader why its contract submission failed, so it doesn't wait for the registration in vain.
+    async fn reject_contract_submission(
+        &self,
+        sender: PublicKey,
+        nonce: String,
+        error: &anyhow::Error,
+    ) {
+        let rejection = CoordinatorError {
+            nonce,
+            reason: error.to_string(),
+        };
+        if let Err(e) = self
+            .nostr_client
+            .send_coordinator_message(sender, &CoordinatorMessage::Error(rejection))
+            .await
+        {
+            warn!("Failed to send the rejection to {}: {}", sender, e);
+        }
+    }
+
     async fn begin_trade(
         &mut self,
         contract_hash: &[u8; 32],
-        trade: &TradeContract,
+        pending_trade: PendingTrade,
     ) -> anyhow::Result<()> {
         debug!(
             "Beginning trade: {}",
             contract_hash.to_hex_string(hashes::hex::Case::Lower)
         );
         let contract_secret = CDKSecretKey::generate();
-        self.active_contracts.insert(
-            contract_hash.clone(),
-            ActiveTade {
-                _trade_contract: trade.clone(),
-                _coordinator_secret: contract_secret.clone(),
-            },
+        // additional coordinators of the contract arbitrate for the fee of the main coordinator
+        let coordinator_fee_sat = match pending_trade.trade_contract.npubkey_coordinator
+            == self.nostr_client.public_key()
+        {
+            true => self.coordinator_fee_sat,
+            false => 0,
+        };
+        let active_trade = ActiveTade {
+            trade_contract: pending_trade.trade_contract,
+            coordinator_secret: contract_secret,
+            escrow_start_time: Timestamp::now(),
+            coordinator_fee_sat,
+            fee_token: None,
+            dispute_claims: Vec::new(),
+            delivery_proof: None,
+            cancelled: false,
+            dispute_requested: false,
+        };
+        for (receiver, nonce) in pending_trade.nonces {
+            let registration = active_trade.registration(contract_hash, nonce);
+            self.nostr_client
+                .send_escrow_registration(
+                    receiver,
+                    &registration,
+                    active_trade.trade_contract.expiry,
+                )
+                .await?;
+        }
+        self.active_contracts.insert(*contract_hash, active_trade);
+        Ok(())
+    }
+
+    /// Keeps the fee token of the buyer of an active trade.
+    /// Stores the fee token of the buyer and confirms the payment to the seller with a signed receipt.
+    async fn handle_fee_payment(
+        &mut self,
+        sender: PublicKey,
+        fee_payment: CoordinatorFeePayment,
+    ) -> anyhow::Result<()> {
+        let active_trade = self
+            .active_contracts
+            .get_mut(&parse_escrow_id(&fee_payment.escrow_id_hex)?)
+            .ok_or_else(|| anyhow!("Fee for unknown escrow {}", fee_payment.escrow_id_hex))?;
+        if sender != active_trade.trade_contract.npubkey_buyer {
+            return Err(anyhow!(
+                "Fee for {} not sent by its buyer",
+                fee_payment.escrow_id_hex
+            ));
+        }
+        if active_trade.cancelled {
+            return Err(anyhow!(
+                "Fee for the cancelled trade {}",
+                fee_payment.escrow_id_hex
+            ));
+        }
+        let fee_amount = Token::from_str(&fee_payment.fee_token)?.value()?;
+        if fee_amount != Amount::from(active_trade.coordinator_fee_sat) {
+            return Err(anyhow!(
+                "Fee token of {} sat instead of {} sat",
+                fee_amount,
+                active_trade.coordinator_fee_sat
+            ));
+        }
+        info!(
+            "Received fee of {} sat for {}",
+            fee_amount, fee_payment.escrow_id_hex
         );
+        active_trade.fee_token = Some(fee_payment.fee_token);
+        let fee_receipt = FeeReceipt::sign(
+            fee_payment.escrow_id_hex,
+            active_trade.coordinator_fee_sat,
+            self.nostr_client.keys(),
+        )?;
         self.nostr_client
-            .send_escrow_registration(
-                (trade.npubkey_buyer, trade.npubkey_seller),
-                contract_hash,
-                &contract_secret.public_key().to_hex(),
+            .send_coordinator_message(
+                active_trade.trade_contract.npubkey_seller,
+                &CoordinatorMessage::FeeReceived(fee_receipt),
             )
             .await?;
         Ok(())
     }
 
-    fn parse_contract(content: &str) -> anyhow::Result<([u8; 32], TradeContract)> {
-        let contract: TradeContract = serde_json::from_str(content)?;
+    /// Marks an active trade cancelled by one of its traders before it was funded, no fee is owed for it.
+    ///
+    /// A trader cancelling a contract the counterparty didn't submit yet withdraws its submission.
+    fn handle_cancellation(
+        &mut self,
+        sender: PublicKey,
+        cancellation: TradeCancelled,
+    ) -> anyhow::Result<()> {
+        let escrow_id = parse_escrow_id(&cancellation.escrow_id_hex)?;
+        if let Some(pending_trade) = self.pending_contracts.get_mut(&escrow_id) {
+            if sender != cancellation.cancelled_by
+                || pending_trade.acceptances.remove(&sender).is_none()
+            {
+                return Err(anyhow!(
+                    "Withdrawal of {} not sent by a trader who submitted it",
+                    cancellation.escrow_id_hex
+                ));
+            }
+            pending_trade.nonces.remove(&sender);
+            if pending_trade.acceptances.is_empty() {
+                self.pending_contracts.remove(&escrow_id);
+            }
+            info!(
+                "Contract {} withdrawn by {}: {}",
+                cancellation.escrow_id_hex,
+                sender.to_bech32()?,
+                cancellation.reason
+            );
+            return Ok(());
+        }
+        let active_trade = self.active_contracts.get_mut(&escrow_id).ok_or_else(|| {
+            anyhow!(
+                "Cancellation of unknown escrow {}",
+                cancellation.escrow_id_hex
+            )
+        })?;
+        let contract = &active_trade.trade_contract;
+        if sender != cancellation.cancelled_by
+            || (sender != contract.npubkey_buyer && sender != contract.npubkey_seller)
+        {
+            return Err(anyhow!(
+                "Cancellation of {} not sent by one of its traders",
+                cancellation.escrow_id_hex
+            ));
+        }
+        if active_trade.fee_token.is_some() {
+            return Err(anyhow!(
+                "Trade {} is funded already and can't be cancelled",
+                cancellation.escrow_id_hex
+            ));
+        }
+        info!(
+            "Trade {} cancelled by {}: {}",
+            cancellation.escrow_id_hex,
+            sender.to_bech32()?,
+            cancellation.reason
+        );
+        active_trade.cancelled = true;
+        Ok(())
+    }
+
+    /// Keeps the delivery proof of the contract oracle sent by the seller, to consider it in a dispute.
+    fn handle_delivery_proof(
+        &mut self,
+        sender: PublicKey,
+        delivery_proof: DeliveryProof,
+    ) -> anyhow::Result<()> {
+        let active_trade = self
+            .active_contracts
+            .get_mut(&parse_escrow_id(&delivery_proof.escrow_id_hex)?)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Delivery proof for unknown escrow {}",
+                    delivery_proof.escrow_id_hex
+                )
+            })?;
+        let contract = &active_trade.trade_contract;
+        if sender != contract.npubkey_seller {
+            return Err(anyhow!(
+                "Delivery proof for {} not sent by its seller",
+                delivery_proof.escrow_id_hex
+            ));
+        }
+        if contract.oracle_pubkey != Some(delivery_proof.oracle_pubkey) {
+            return Err(anyhow!(
+                "Delivery proof for {} not attested by the contract oracle",
+                delivery_proof.escrow_id_hex
+            ));
+        }
+        delivery_proof.verify()?;
+        info!(
+            "Oracle {} attested the delivery of {}",
+            delivery_proof.oracle_pubkey.to_bech32()?,
+            delivery_proof.escrow_id_hex
+        );
+        active_trade.delivery_proof = Some(delivery_proof);
+        Ok(())
+    }
+
+    /// Logs the signed receipt a trader sent for a finished trade.
+    fn handle_trade_receipt(
+        &mut self,
+        sender: PublicKey,
+        receipt: TradeReceipt,
+    ) -> anyhow::Result<()> {
+        let active_trade = self
+            .active_contracts
+            .get(&parse_escrow_id(&receipt.content.escrow_id_hex)?)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Trade receipt for unknown escrow {}",
+                    receipt.content.escrow_id_hex
+                )
+            })?;
+        let contract = &active_trade.trade_contract;
+        if receipt.signer != sender
+            || (sender != contract.npubkey_buyer && sender != contract.npubkey_seller)
+        {
+            return Err(anyhow!(
+                "Trade receipt for {} not signed by one of its traders",
+                receipt.content.escrow_id_hex
+            ));
+        }
+        receipt.verify()?;
+        info!(
+            "Trader {} reported escrow {} as {:?}",
+            sender.to_bech32()?,
+            receipt.content.escrow_id_hex,
+            receipt.content.outcome
+        );
+        Ok(())
+    }
+
+    /// Collects the dispute claims of both traders and hands the dispute to the operator once both arrived.
+    async fn handle_dispute_claim(
+        &mut self,
+        sender: PublicKey,
+        dispute_claim: DisputeClaim,
+    ) -> anyhow::Result<()> {
+        let escrow_id = parse_escrow_id(&dispute_claim.escrow_id_hex)?;
+        let active_trade = self
+            .active_contracts
+            .get_mut(&escrow_id)
+            .ok_or_else(|| anyhow!("Dispute for unknown escrow {}", dispute_claim.escrow_id_hex))?;
+        let contract = &active_trade.trade_contract;
+        if sender != dispute_claim.claimant
+            || (sender != contract.npubkey_buyer && sender != contract.npubkey_seller)
+        {
+            return Err(anyhow!(
+                "Dispute claim for {} not sent by one of its traders",
+                dispute_claim.escrow_id_hex
+            ));
+        }
+        info!(
+            "Dispute claim for {} by {}: {}",
+            dispute_claim.escrow_id_hex,
+            sender.to_bech32()?,
+            dispute_claim.reason
+        );
+        active_trade
+            .dispute_claims
+            .retain(|claim| claim.claimant != sender);
+        active_trade.dispute_claims.push(dispute_claim);
+        if active_trade.dispute_claims.len() < 2 {
+            debug!("Waiting for the counterparty to answer the dispute...");
+            return Ok(());
+        }
+        if active_trade.dispute_requested {
+            debug!("The dispute waits for the decision of the operator already");
+            return Ok(());
+        }
+
+        if active_trade.delivery_proof.is_some() {
+            info!("The contract oracle attested the delivery of the trade");
+        }
+        let milestone_proofs = milestone_proofs(
+            disputed_escrow_proofs(active_trade)?,
+            &contract.milestone_amounts()?,
+        )?;
+        self.dispute_requests
+            .send(DisputeRequest {
+                escrow_id_hex: hex::encode(escrow_id),
+                milestone_count: milestone_proofs.len(),
+            })
+            .map_err(|_| anyhow!("The operator stopped deciding disputes"))?;
+        active_trade.dispute_requested = true;
+        Ok(())
+    }
+
+    /// Sends the decision of the operator on a dispute to the traders, each with the escrow signatures of the proofs
+    /// awarded to it and of the outputs it claimed for them.
+    async fn resolve_dispute(&mut self, decided: DecidedDispute) -> anyhow::Result<()> {
+        let escrow_id = parse_escrow_id(&decided.escrow_id_hex)?;
+        let active_trade = self
+            .active_contracts
+            .get_mut(&escrow_id)
+            .ok_or_else(|| anyhow!("Decision on unknown escrow {}", decided.escrow_id_hex))?;
+        active_trade.dispute_requested = false;
+        let contract = &active_trade.trade_contract;
+        let milestone_proofs = milestone_proofs(
+            disputed_escrow_proofs(active_trade)?,
+            &contract.milestone_amounts()?,
+        )?;
+        let decision = decided.decision;
+        info!(
+            "Resolving the dispute of {}: {:?}",
+            decided.escrow_id_hex, decision
+        );
+
+        // each trader gets the escrow signatures of the proofs awarded to it only
+        let seller_milestones = decision.seller_milestones(milestone_proofs.len());
+        let (seller_proofs, buyer_proofs) = milestone_proofs.split_at(seller_milestones);
+        for (receiver, awarded_proofs, awarded) in [
+            (contract.npubkey_seller, seller_proofs, 0..seller_milestones),
+            (
+                contract.npubkey_buyer,
+                buyer_proofs,
+                seller_milestones..milestone_proofs.len(),
+            ),
+        ] {
+            let token_signatures = awarded_proofs
+                .iter()
+                .flatten()
+                .map(|proof| {
+                    Ok(active_trade
+                        .coordinator_secret
+                        .sign(&proof.secret.to_bytes())?
+                        .to_string())
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            // with SIG_ALL the mint only swaps the proofs into outputs signed like them
+            let output_signatures = active_trade
+                .dispute_claims
+                .iter()
+                .filter(|claim| claim.claimant == receiver)
+                .flat_map(|claim| claim.redeem_outputs.range(awarded.clone()))
+                .flat_map(|(_, outputs)| outputs)
+                .map(|output| {
+                    Ok(active_trade
+                        .coordinator_secret
+                        .sign(&output.blinded_secret.to_bytes())?
+                        .to_string())
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            let resolution = DisputeResolution::sign(
+                hex::encode(escrow_id),
+                decision,
+                token_signatures,
+                output_signatures,
+                self.nostr_client.keys(),
+            )?;
+            self.nostr_client
+                .send_coordinator_message(
+                    receiver,
+                    &CoordinatorMessage::DisputeResolution(resolution),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
 
-        // create a Sha256 object
-        let mut hasher = Sha256::new();
-        // write input message
-        hasher.update(content.as_bytes());
-        // read hash digest and consume hasher
-        let trade_hash: [u8; 32] = hasher.finalize().into();
-        Ok((trade_hash, contract))
+/// The proofs of the escrow token the traders dispute.
+///
+/// Fails unless the claims name the same token, worth the trade amount and locked to the seller and the escrow key of
+/// this coordinator, so no other proofs of that key get signed.
+fn disputed_escrow_proofs(active_trade: &ActiveTade) -> anyhow::Result<Proofs> {
+    let contract = &active_trade.trade_contract;
+    let mut escrow_proofs: Option<Proofs> = None;
+    for escrow_token in active_trade
+        .dispute_claims
+        .iter()
+        .filter_map(|claim| claim.escrow_token.as_deref())
+    {
+        let token = Token::from_str(escrow_token)?;
+        let mut mint_proofs = token.proofs().into_iter();
+        let proofs = match (mint_proofs.next(), mint_proofs.next()) {
+            (Some((mint_url, proofs)), None) if mint_url == contract.mint_url => proofs,
+            _ => return Err(anyhow!("Disputed escrow token not of the contract mint")),
+        };
+        match &escrow_proofs {
+            Some(known)
+                if known
+                    .iter()
+                    .map(|proof| &proof.secret)
+                    .ne(proofs.iter().map(|proof| &proof.secret)) =>
+            {
+                return Err(anyhow!("The traders dispute different escrow tokens"))
+            }
+            Some(_) => {}
+            None => escrow_proofs = Some(proofs),
+        }
     }
+    let proofs = escrow_proofs.ok_or_else(|| anyhow!("No dispute claim names the escrow token"))?;
+
+    if Amount::try_sum(proofs.iter().map(|proof| proof.amount))?
+        != Amount::from(contract.trade_amount_sat)
+    {
+        return Err(anyhow!("Disputed escrow token not worth the trade amount"));
+    }
+    let seller_pubkey = CDKPubkey::from_str(&contract.seller_ecash_public_key)?;
+    let escrow_pubkey = active_trade.coordinator_secret.public_key();
+    for proof in &proofs {
+        let locked_to_escrow = match SpendingConditions::try_from(&proof.secret) {
+            Ok(SpendingConditions::P2PKConditions { data, conditions }) => {
+                data == seller_pubkey
+                    && conditions
+                        .and_then(|conditions| conditions.pubkeys)
+                        .is_some_and(|pubkeys| pubkeys.contains(&escrow_pubkey))
+            }
+            _ => false,
+        };
+        if !locked_to_escrow {
+            return Err(anyhow!(
+                "Disputed escrow token has a proof not locked to the escrow keys"
+            ));
+        }
+    }
+    Ok(proofs)
+}
+
+fn parse_escrow_id(escrow_id_hex: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(escrow_id_hex)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid escrow id {}", escrow_id_hex))
 }
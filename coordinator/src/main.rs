@@ -2,7 +2,11 @@ mod escrow_coordinator;
 
 use std::{env, str::FromStr};
 
-use cashu_escrow_common::nostr::NostrClient;
+use cashu_escrow_common::nostr::{
+    keepalive_interval_from_env, messaging_scheme_from_env, proxy_from_env, relays_from_env,
+    NostrClient,
+};
+use cdk::mint_url::MintUrl;
 use dotenv::dotenv;
 use escrow_coordinator::EscrowCoordinator;
 #[allow(unused_imports)]
@@ -18,11 +22,34 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let keys = Keys::from_str(&env::var("ESCROW_NSEC")?)?;
-    let nostr_client = NostrClient::new(keys).await?;
+    let nostr_client = NostrClient::new(
+        keys,
+        relays_from_env(),
+        messaging_scheme_from_env()?,
+        proxy_from_env()?,
+    )
+    .await?
+    .with_keepalive_interval(keepalive_interval_from_env()?);
     info!(
         "Coordinator npub: {}",
         nostr_client.public_key().to_bech32()?
     );
+    let coordinator_fee_sat = match env::var("COORDINATOR_FEE_SAT") {
+        Ok(fee) => fee.parse()?,
+        Err(_) => 0,
+    };
+    info!("Coordinator fee: {} sat", coordinator_fee_sat);
+    let supported_mints = match env::var("COORDINATOR_MINT_URLS") {
+        Ok(urls) => urls
+            .split(',')
+            .map(|url| MintUrl::from_str(url.trim()))
+            .collect::<Result<Vec<_>, _>>()?,
+        Err(_) => Vec::new(),
+    };
+    let mut coordinator = EscrowCoordinator::new(nostr_client, coordinator_fee_sat)?
+        .with_supported_mints(supported_mints);
+    let info_event_id = coordinator.publish_info().await?;
+    info!("Published the coordinator info {}", info_event_id);
     info!("Starting service and waiting for trades...");
-    return EscrowCoordinator::new(nostr_client)?.run().await;
+    return coordinator.run().await;
 }